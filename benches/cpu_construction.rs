@@ -0,0 +1,20 @@
+//! Benchmarks of creating a CPU, which is done once per program (and per test)
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use riscv_emulator::emulator::cpu::Cpu32Bit;
+
+const ENTRYPOINT: u32 = 0x0040_0000;
+
+fn new_cpu(c: &mut Criterion) {
+    // li a7, 10; ecall
+    let text: Vec<u8> = [0x00a0_0893u32, 0x0000_0073]
+        .iter()
+        .flat_map(|word| word.to_le_bytes())
+        .collect();
+    let data = b"Hello, World!\n\0";
+    c.bench_function("new cpu", |b| {
+        b.iter(|| Cpu32Bit::new(black_box(&text), black_box(data), ENTRYPOINT, None).unwrap());
+    });
+}
+
+criterion_group!(benches, new_cpu);
+criterion_main!(benches);
@@ -0,0 +1,48 @@
+//! Benchmarks of the decoded instruction cache
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use riscv_emulator::emulator::cpu::Cpu32Bit;
+
+const ENTRYPOINT: u32 = 0x0040_0000;
+
+/// Counts down from 1000, so every instruction of the loop body is fetched 1000 times.
+const COUNTED_LOOP: &[u32] = &[
+    0x3e80_0293, // li t0, 1000
+    0xfff2_8293, // addi t0, t0, -1
+    0xfe02_9ee3, // bnez t0, -4
+];
+
+fn cpu() -> Cpu32Bit {
+    let text: Vec<u8> = COUNTED_LOOP
+        .iter()
+        .flat_map(|word| word.to_le_bytes())
+        .collect();
+    Cpu32Bit::new(&text, &[], ENTRYPOINT, None).unwrap()
+}
+
+fn fetch_and_decode(c: &mut Criterion) {
+    let mut group = c.benchmark_group("fetch_and_decode");
+    let mut cpu = cpu();
+    group.bench_function("cached", |b| {
+        b.iter(|| cpu.fetch_and_decode(black_box(ENTRYPOINT + 4)).unwrap());
+    });
+    group.bench_function("uncached", |b| {
+        b.iter(|| {
+            cpu.memory.clear_decode_cache();
+            cpu.fetch_and_decode(black_box(ENTRYPOINT + 4)).unwrap()
+        });
+    });
+    group.finish();
+}
+
+fn counted_loop(c: &mut Criterion) {
+    c.bench_function("counted loop", |b| {
+        b.iter(|| {
+            let mut cpu = cpu();
+            // the li, then the 1000 iterations of the loop body
+            cpu.run(Some(1 + 2 * 1000)).unwrap()
+        });
+    });
+}
+
+criterion_group!(benches, fetch_and_decode, counted_loop);
+criterion_main!(benches);
@@ -0,0 +1,103 @@
+/*
+MIT License
+
+Copyright (c) 2024 Anthony Rubick
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+//! Tracking of the calls that haven't returned yet, so the debugger knows where the current function returns to
+use crate::instruction_set_definition::{
+    operations::{ITypeOperation, UJTypeOperation},
+    Rv32imInstruction,
+};
+
+use super::registers::{RegisterFile32Bit, RegisterMapping};
+
+/// A call that hasn't returned yet.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct CallFrame {
+    /// Where the function returns to
+    pub return_address: u32,
+    /// The stack pointer at the call, which the function restores before returning
+    pub sp: u32,
+}
+
+/// The calls that haven't returned yet, innermost last.
+///
+/// Calls are recognized as jumps that link to `ra` (`jal ra, ...`, `jalr ra, ...`), and returns as `jalr` jumps
+/// (that don't link) to the return address of an outstanding call, as in `CalleeSavedChecker`.
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub struct CallStack {
+    frames: Vec<CallFrame>,
+}
+
+impl CallStack {
+    /// get the innermost call that hasn't returned yet, if any
+    #[must_use]
+    pub fn innermost(&self) -> Option<CallFrame> {
+        self.frames.last().copied()
+    }
+
+    /// Update the outstanding calls after `instruction` executed.
+    ///
+    /// `registers` and `pc` are the state after the instruction executed.
+    pub fn observe(
+        &mut self,
+        instruction: &Rv32imInstruction,
+        registers: &RegisterFile32Bit,
+        pc: u32,
+    ) {
+        match *instruction {
+            // a call
+            Rv32imInstruction::UJType {
+                operation: UJTypeOperation::Jal,
+                rd: RegisterMapping::Ra,
+                ..
+            }
+            | Rv32imInstruction::IType {
+                operation: ITypeOperation::Jalr,
+                rd: RegisterMapping::Ra,
+                ..
+            } => self.frames.push(CallFrame {
+                return_address: registers[RegisterMapping::Ra],
+                sp: registers[RegisterMapping::Sp],
+            }),
+            // possibly a return
+            Rv32imInstruction::IType {
+                operation: ITypeOperation::Jalr,
+                rd: RegisterMapping::Zero,
+                ..
+            } => self.returned_to(pc),
+            _ => {}
+        }
+    }
+
+    /// Pop the call returning to `pc`, if there is one, along with any calls made after it that never returned
+    /// (e.g. tail calls).
+    pub fn returned_to(&mut self, pc: u32) {
+        if let Some(returned) = self
+            .frames
+            .iter()
+            .rposition(|frame| frame.return_address == pc)
+        {
+            self.frames.truncate(returned);
+        }
+    }
+}
@@ -0,0 +1,149 @@
+/*
+MIT License
+
+Copyright (c) 2024 Anthony Rubick
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+//! Best-effort detection of functions that don't preserve the callee-saved registers
+use anyhow::Result;
+
+use crate::{
+    emulator::trap::Trap,
+    instruction_set_definition::{
+        operations::{ITypeOperation, UJTypeOperation},
+        Rv32imInstruction,
+    },
+};
+
+use super::registers::{RegisterFile32Bit, RegisterMapping};
+
+/// The registers a function must restore before returning, in the standard calling convention.
+const CALLEE_SAVED: [RegisterMapping; 13] = [
+    RegisterMapping::Sp,
+    RegisterMapping::S0,
+    RegisterMapping::S1,
+    RegisterMapping::S2,
+    RegisterMapping::S3,
+    RegisterMapping::S4,
+    RegisterMapping::S5,
+    RegisterMapping::S6,
+    RegisterMapping::S7,
+    RegisterMapping::S8,
+    RegisterMapping::S9,
+    RegisterMapping::S10,
+    RegisterMapping::S11,
+];
+
+/// A call that hasn't returned yet.
+#[derive(Debug, Clone)]
+struct Frame {
+    /// The address of the called function
+    function: u32,
+    /// Where the function returns to
+    return_address: u32,
+    /// The values of the callee-saved registers at the call
+    saved: [u32; CALLEE_SAVED.len()],
+}
+
+/// Watches calls, and traps when a function returns without having restored a callee-saved register (`sp`, `s0`-`s11`).
+///
+/// Calls are recognized as jumps that link to `ra` (`jal ra, ...`, `jalr ra, ...`), and returns as `jalr` jumps
+/// (that don't link) to the return address of an outstanding call.
+#[derive(Debug, Clone, Default)]
+pub struct CalleeSavedChecker {
+    frames: Vec<Frame>,
+}
+
+impl CalleeSavedChecker {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// get the number of calls that haven't returned yet
+    #[must_use]
+    pub const fn depth(&self) -> usize {
+        self.frames.len()
+    }
+
+    /// Update the outstanding calls after `instruction` executed, checking the callee-saved registers if it returned.
+    ///
+    /// `registers` and `pc` are the state after the instruction executed.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Trap::CalleeSavedRegisterClobbered` if the instruction returned from a function
+    /// that changed a callee-saved register.
+    pub fn observe(
+        &mut self,
+        instruction: &Rv32imInstruction,
+        registers: &RegisterFile32Bit,
+        pc: u32,
+    ) -> Result<()> {
+        match *instruction {
+            // a call
+            Rv32imInstruction::UJType {
+                operation: UJTypeOperation::Jal,
+                rd: RegisterMapping::Ra,
+                ..
+            }
+            | Rv32imInstruction::IType {
+                operation: ITypeOperation::Jalr,
+                rd: RegisterMapping::Ra,
+                ..
+            } => self.frames.push(Frame {
+                function: pc,
+                return_address: registers[RegisterMapping::Ra],
+                saved: CALLEE_SAVED.map(|register| registers[register]),
+            }),
+            // possibly a return, which may also unwind calls that never returned (e.g. tail calls)
+            Rv32imInstruction::IType {
+                operation: ITypeOperation::Jalr,
+                rd: RegisterMapping::Zero,
+                ..
+            } => {
+                let Some(returned) = self
+                    .frames
+                    .iter()
+                    .rposition(|frame| frame.return_address == pc)
+                else {
+                    return Ok(());
+                };
+                let frame = &self.frames[returned];
+                let clobbered = CALLEE_SAVED
+                    .iter()
+                    .zip(frame.saved)
+                    .find(|&(&register, value)| registers[register] != value);
+                if let Some((&register, _)) = clobbered {
+                    // the frame is kept, as the return is rolled back
+                    return Err(Trap::CalleeSavedRegisterClobbered {
+                        function: frame.function,
+                        register,
+                    }
+                    .into());
+                }
+                self.frames.truncate(returned);
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
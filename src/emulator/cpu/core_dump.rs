@@ -0,0 +1,153 @@
+/*
+MIT License
+
+Copyright (c) 2024 Anthony Rubick
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+//! Post-mortem dumps of the CPU's state
+use std::path::Path;
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use super::{
+    memory::{MemoryBus, STACK_CEILING},
+    registers::{RegisterFile32Bit, RegisterMapping},
+    Cpu32Bit,
+};
+
+/// A copy of a contiguous range of memory.
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct MemorySegment {
+    pub base: u32,
+    pub bytes: Vec<u8>,
+}
+
+/// A snapshot of the CPU's state, taken when it faults, that can be written to a file for post-mortem analysis.
+///
+/// Only the interesting parts of memory are kept: the static data and heap (with trailing zeros trimmed), and the live portion of the stack.
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct CoreDump {
+    /// The error the CPU faulted with.
+    pub fault: String,
+    pub pc: u32,
+    pub registers: RegisterFile32Bit,
+    pub memory: Vec<MemorySegment>,
+}
+
+impl CoreDump {
+    /// Capture the state of the given CPU, which faulted with the given error.
+    #[must_use]
+    pub fn new(cpu: &Cpu32Bit, fault: &anyhow::Error) -> Self {
+        Self {
+            fault: format!("{fault:#}"),
+            pc: cpu.pc,
+            registers: cpu.registers,
+            memory: [
+                data_segment(&cpu.memory),
+                stack_segment(&cpu.memory, cpu.registers[RegisterMapping::Sp]),
+            ]
+            .into_iter()
+            .flatten()
+            .collect(),
+        }
+    }
+
+    /// Serialize the core dump as JSON and write it to the given path.
+    ///
+    /// # Errors
+    ///
+    /// This method will return an error if the file cannot be written.
+    pub fn write_to(&self, path: &Path) -> Result<()> {
+        let file = std::fs::File::create(path)?;
+        serde_json::to_writer(std::io::BufWriter::new(file), self)?;
+        Ok(())
+    }
+
+    /// Read a core dump previously written with `write_to`.
+    ///
+    /// # Errors
+    ///
+    /// This method will return an error if the file cannot be read or isn't a valid core dump.
+    pub fn read_from(path: &Path) -> Result<Self> {
+        let file = std::fs::File::open(path)?;
+        Ok(serde_json::from_reader(std::io::BufReader::new(file))?)
+    }
+}
+
+/// the static data and the heap, with trailing zeros trimmed
+fn data_segment(memory: &MemoryBus) -> Option<MemorySegment> {
+    let base = memory.dram_start();
+    let mut bytes = memory.read_bytes(base, memory.heap_end() - base).ok()?;
+    let len = bytes
+        .iter()
+        .rposition(|&byte| byte != 0)
+        .map_or(0, |i| i + 1);
+    bytes.truncate(len);
+    Some(MemorySegment { base, bytes })
+}
+
+/// the stack, from the stack pointer up to the top of the stack
+///
+/// if the stack pointer is corrupted (e.g. it points into the heap or past the top of the stack), nothing is captured
+fn stack_segment(memory: &MemoryBus, sp: u32) -> Option<MemorySegment> {
+    if sp < memory.heap_end() || sp > STACK_CEILING {
+        return None;
+    }
+    // the word at the stack ceiling is part of the stack
+    let bytes = memory.read_bytes(sp, STACK_CEILING - sp + 4).ok()?;
+    Some(MemorySegment { base: sp, bytes })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::words_to_bytes;
+
+    #[test]
+    fn test_core_dump_contains_faulting_pc() -> Result<()> {
+        // addi a0, zero, 1; lw a1, 4(zero)
+        let text = words_to_bytes(&[0x0010_0513_u32, 0x0040_2583]);
+        let mut cpu = Cpu32Bit::new(&text, &[1, 2, 3, 4], 0x0040_0000, None)?;
+
+        let fault = cpu
+            .run(None)
+            .expect_err("loading from address 4 should fault");
+        let path = std::env::temp_dir().join(format!("core-dump-test-{}.json", std::process::id()));
+        CoreDump::new(&cpu, &fault).write_to(&path)?;
+        let dump = CoreDump::read_from(&path);
+        std::fs::remove_file(&path)?;
+        let dump = dump?;
+
+        assert_eq!(dump.pc, 0x0040_0004);
+        assert_eq!(dump.fault, format!("{fault:#}"));
+        assert_eq!(dump.registers[RegisterMapping::A0], 1);
+        assert_eq!(
+            dump.memory[0],
+            MemorySegment {
+                base: cpu.memory.dram_start(),
+                bytes: vec![1, 2, 3, 4],
+            }
+        );
+        assert_eq!(dump.memory[1].base, STACK_CEILING);
+        Ok(())
+    }
+}
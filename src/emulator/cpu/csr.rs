@@ -0,0 +1,237 @@
+/*
+MIT License
+
+Copyright (c) 2024 Anthony Rubick
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+//! The control and status registers (CSRs) of the hart
+use anyhow::{bail, Result};
+
+use super::Counters;
+
+pub const MSTATUS: u16 = 0x300;
+pub const MISA: u16 = 0x301;
+pub const MIE: u16 = 0x304;
+pub const MTVEC: u16 = 0x305;
+pub const MSCRATCH: u16 = 0x340;
+pub const MEPC: u16 = 0x341;
+pub const MCAUSE: u16 = 0x342;
+pub const MTVAL: u16 = 0x343;
+pub const MIP: u16 = 0x344;
+pub const MCYCLE: u16 = 0xB00;
+pub const MINSTRET: u16 = 0xB02;
+pub const MCYCLEH: u16 = 0xB80;
+pub const MINSTRETH: u16 = 0xB82;
+pub const CYCLE: u16 = 0xC00;
+pub const TIME: u16 = 0xC01;
+pub const INSTRET: u16 = 0xC02;
+pub const CYCLEH: u16 = 0xC80;
+pub const TIMEH: u16 = 0xC81;
+pub const INSTRETH: u16 = 0xC82;
+pub const MHARTID: u16 = 0xF14;
+
+/// The address and name of every implemented CSR.
+pub const CSR_NAMES: &[(u16, &str)] = &[
+    (MSTATUS, "mstatus"),
+    (MISA, "misa"),
+    (MIE, "mie"),
+    (MTVEC, "mtvec"),
+    (MSCRATCH, "mscratch"),
+    (MEPC, "mepc"),
+    (MCAUSE, "mcause"),
+    (MTVAL, "mtval"),
+    (MIP, "mip"),
+    (MCYCLE, "mcycle"),
+    (MINSTRET, "minstret"),
+    (MCYCLEH, "mcycleh"),
+    (MINSTRETH, "minstreth"),
+    (CYCLE, "cycle"),
+    (TIME, "time"),
+    (INSTRET, "instret"),
+    (CYCLEH, "cycleh"),
+    (TIMEH, "timeh"),
+    (INSTRETH, "instreth"),
+    (MHARTID, "mhartid"),
+];
+
+/// `misa` for RV32IM: MXL = 1 (32-bit), and the I and M extension bits
+const MISA_RV32IM: u32 = (1 << 30) | (1 << 8) | (1 << 12);
+/// the `misa` bit of the C extension, which the CPU sets while compressed instructions are enabled
+pub const MISA_C: u32 = 1 << 2;
+
+/// Look up the address of the CSR called `name`, e.g. "mtvec".
+#[must_use]
+pub fn csr_address(name: &str) -> Option<u16> {
+    CSR_NAMES
+        .iter()
+        .find(|(_, csr_name)| *csr_name == name)
+        .map(|&(addr, _)| addr)
+}
+
+/// Look up the name of the CSR at `addr`.
+#[must_use]
+pub fn csr_name(addr: u16) -> Option<&'static str> {
+    CSR_NAMES
+        .iter()
+        .find(|&&(csr_addr, _)| csr_addr == addr)
+        .map(|&(_, name)| name)
+}
+
+/// The machine-mode CSRs with storage of their own.
+///
+/// The counters aren't stored here, they're read from the CPU's `Counters`.
+/// Their user-mode shadows (`cycle`, `time`, and `instret`) are read-only, while writing `mcycle` or `minstret`
+/// moves the counter by adjusting its offset from the number of instructions retired.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub struct CsrFile {
+    pub mstatus: u32,
+    pub mie: u32,
+    pub mtvec: u32,
+    pub mscratch: u32,
+    pub mepc: u32,
+    pub mcause: u32,
+    pub mtval: u32,
+    pub mip: u32,
+    /// added to the number of instructions retired to get `mcycle`
+    pub cycle_offset: u64,
+    /// added to the number of instructions retired to get `minstret`
+    pub instret_offset: u64,
+}
+
+impl CsrFile {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Read the CSR at `addr`, or `None` if it isn't implemented.
+    #[must_use]
+    #[allow(clippy::cast_possible_truncation)] // the counters are split into their low and high halves
+    pub const fn read(&self, addr: u16, counters: Counters) -> Option<u32> {
+        Some(match addr {
+            MSTATUS => self.mstatus,
+            MISA => MISA_RV32IM,
+            MIE => self.mie,
+            MTVEC => self.mtvec,
+            MSCRATCH => self.mscratch,
+            MEPC => self.mepc,
+            MCAUSE => self.mcause,
+            MTVAL => self.mtval,
+            MIP => self.mip,
+            MCYCLE | CYCLE => counters.cycle as u32,
+            MINSTRET | INSTRET => counters.instret as u32,
+            MCYCLEH | CYCLEH => (counters.cycle >> 32) as u32,
+            MINSTRETH | INSTRETH => (counters.instret >> 32) as u32,
+            TIME => counters.time as u32,
+            TIMEH => (counters.time >> 32) as u32,
+            MHARTID => 0,
+            _ => return None,
+        })
+    }
+
+    /// Write `value` to the CSR at `addr`, given the current values of the `counters`.
+    ///
+    /// # Errors
+    ///
+    /// This method will return an error if the CSR isn't implemented, or is read-only.
+    pub fn write(&mut self, addr: u16, value: u32, counters: Counters) -> Result<()> {
+        let value = u64::from(value);
+        let (offset, current, new) = match addr {
+            MCYCLE => (
+                &mut self.cycle_offset,
+                counters.cycle,
+                counters.cycle & !0xFFFF_FFFF | value,
+            ),
+            MCYCLEH => (
+                &mut self.cycle_offset,
+                counters.cycle,
+                counters.cycle & 0xFFFF_FFFF | value << 32,
+            ),
+            MINSTRET => (
+                &mut self.instret_offset,
+                counters.instret,
+                counters.instret & !0xFFFF_FFFF | value,
+            ),
+            MINSTRETH => (
+                &mut self.instret_offset,
+                counters.instret,
+                counters.instret & 0xFFFF_FFFF | value << 32,
+            ),
+            #[allow(clippy::cast_possible_truncation)] // the value was widened from a u32
+            _ => return self.write_register(addr, value as u32),
+        };
+        *offset = offset.wrapping_add(new.wrapping_sub(current));
+        Ok(())
+    }
+
+    /// Write `value` to the CSR at `addr`, which is stored here (rather than being a counter).
+    fn write_register(&mut self, addr: u16, value: u32) -> Result<()> {
+        let csr = match addr {
+            MSTATUS => &mut self.mstatus,
+            MIE => &mut self.mie,
+            MTVEC => &mut self.mtvec,
+            MSCRATCH => &mut self.mscratch,
+            MEPC => &mut self.mepc,
+            MCAUSE => &mut self.mcause,
+            MTVAL => &mut self.mtval,
+            MIP => &mut self.mip,
+            _ => match csr_name(addr) {
+                Some(name) => bail!("CSR {name} ({addr:#05x}) is read-only"),
+                None => bail!("Unsupported CSR: {addr:#05x}"),
+            },
+        };
+        *csr = value;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_counters() -> Result<()> {
+        let mut csrs = CsrFile::new();
+        let counters = Counters {
+            cycle: 0x1_0000_0005,
+            time: 0,
+            instret: 0x1_0000_0005,
+        };
+        csrs.write(MCYCLE, 2, counters)?;
+        assert_eq!(
+            csrs.cycle_offset.wrapping_add(counters.cycle),
+            0x1_0000_0002
+        );
+        csrs.write(MINSTRETH, 0, counters)?;
+        assert_eq!(csrs.instret_offset.wrapping_add(counters.instret), 5);
+        // the user-mode shadows are read-only
+        assert!(csrs.write(CYCLE, 0, counters).is_err());
+        assert!(csrs.write(TIME, 0, counters).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_names() {
+        assert_eq!(csr_address("mtvec"), Some(MTVEC));
+        assert_eq!(csr_name(INSTRET), Some("instret"));
+        assert_eq!(csr_address("not a csr"), None);
+    }
+}
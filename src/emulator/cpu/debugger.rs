@@ -0,0 +1,470 @@
+/*
+MIT License
+
+Copyright (c) 2024 Anthony Rubick
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+//! The interactive debugger
+use crate::{
+    emulator::{decode::Decode32BitInstruction as _, fetch::instruction_width},
+    instruction_set_definition::Rv32imInstruction,
+    utils::parse_address,
+};
+
+use super::{registers::RegisterMapping, Cpu32Bit, Size};
+
+pub fn clear_screen() {
+    print!("{esc}[2J{esc}[1;1H", esc = 27 as char);
+}
+
+pub fn print_screen(cpu: &Cpu32Bit) {
+    // print cpu state
+    println!("CPU state:");
+    println!("{cpu}");
+    //print instructions
+    println!("Press 'c' to continue to the next breakpoint");
+    println!("Press 's' or the Enter key to step to the next instruction");
+    println!("Type 's <n>' to step <n> instructions");
+    println!("Press 'm' to print the memory map");
+    println!("Type 'p pc' to print the program counter");
+    println!("Type 'setpc <addr>' to move the program counter to <addr>");
+    println!("Type 'di <addr>' or 'di @<reg>' to disassemble the instruction at <addr> or the address in <reg>");
+    println!("Type 'x/<n><fmt><size> <addr>' to print <n> values at <addr> (or @<reg>), e.g. 'x/10dw' for 10 signed words, formats are x, d, and u, sizes are b, h, and w");
+    println!("Press 'r' or type 'back' to undo the last step");
+    println!("Type 'rc' to run backwards to the previous ebreak, or 'rc <addr>' to the previous time the pc was <addr>");
+    println!("Type 'fin' or 'finish' to continue until the current function returns");
+    println!("Type 'tw <reg>' to continue until the next instruction that writes <reg>");
+    println!("Press 'q' to quit the program");
+}
+
+/// Render the memory layout of the emulated program as a table,
+/// along with the current heap break and stack pointer.
+pub fn memory_map(cpu: &Cpu32Bit) -> String {
+    let mut lines = vec![String::from("start      end        size       perm name")];
+    lines.extend(cpu.memory.regions().iter().map(|region| {
+        format!(
+            "{:#010x} {:#010x} {:#010x} {}  {}",
+            region.base,
+            region.base.wrapping_add(region.size),
+            region.size,
+            region.permissions,
+            region.name,
+        )
+    }));
+    lines.push(format!(
+        "heap: {:#010x}..{:#010x}",
+        cpu.memory.heap_base(),
+        cpu.memory.heap_end()
+    ));
+    lines.push(format!(
+        "sp:   {:#010x}",
+        cpu.registers[RegisterMapping::Sp]
+    ));
+    lines.join("\n")
+}
+
+/// Render the value of the given target for the `p` command.
+pub fn print_target(cpu: &Cpu32Bit, target: &str) -> String {
+    match target {
+        "pc" => format!("pc = {:#010x}", cpu.pc),
+        _ => format!("Unknown print target: {target}"),
+    }
+}
+
+/// Render the disassembly of the instruction at `addr` for the `di` command.
+///
+/// The address doesn't have to be in the text section, e.g. it can be an entry of a jump table.
+pub fn disassemble_at(cpu: &Cpu32Bit, addr: u32) -> String {
+    // only read the second halfword of full width instructions, a compressed one can be the last in memory
+    let instruction = cpu.memory.read(addr, Size::Half).and_then(|low_half| {
+        if instruction_width(low_half) == Size::Half {
+            #[allow(clippy::cast_possible_truncation)] // a halfword read fits in 16 bits
+            Rv32imInstruction::from_compressed_machine_code(low_half as u16)
+        } else {
+            let word = cpu.memory.read(addr, Size::Word)?;
+            Rv32imInstruction::from_machine_code_or_custom(word, cpu.custom_decoder.as_ref())
+        }
+    });
+    match instruction {
+        Ok(instruction) => format!("{addr:#010x}: {}", instruction.disassemble(addr)),
+        Err(e) => format!(
+            "{addr:#010x}: {}",
+            e.to_string().lines().next().unwrap_or_default()
+        ),
+    }
+}
+
+/// How the `x` command prints values.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ExamineFormat {
+    /// in hexadecimal, the default
+    Hex,
+    /// as signed decimals
+    Signed,
+    /// as unsigned decimals
+    Unsigned,
+}
+
+/// how many values the `x` command prints per line
+const EXAMINE_VALUES_PER_LINE: u32 = 4;
+
+/// Render `count` `size`-bit values starting at `addr` in the given `format`, for the `x` command.
+pub fn examine(cpu: &Cpu32Bit, addr: u32, count: u32, size: Size, format: ExamineFormat) -> String {
+    let values = match cpu.memory.read_array(addr, count, size) {
+        Ok(values) => values,
+        Err(e) => return format!("Error: {e}"),
+    };
+    let bits = size as u32;
+    let lines: Vec<String> = (0..)
+        .step_by(EXAMINE_VALUES_PER_LINE as usize)
+        .zip(values.chunks(EXAMINE_VALUES_PER_LINE as usize))
+        .map(|(index, chunk)| {
+            let values: Vec<String> = chunk
+                .iter()
+                .map(|&value| match format {
+                    ExamineFormat::Hex => {
+                        format!("{value:#0width$x}", width = (bits / 4 + 2) as usize)
+                    }
+                    // sign extend the value from its size
+                    #[allow(clippy::cast_possible_wrap)]
+                    ExamineFormat::Signed => {
+                        (((value << (32 - bits)) as i32) >> (32 - bits)).to_string()
+                    }
+                    ExamineFormat::Unsigned => value.to_string(),
+                })
+                .collect();
+            format!(
+                "{:#010x}: {}",
+                addr.wrapping_add(index * size.bytes()),
+                values.join(" ")
+            )
+        })
+        .collect();
+    lines.join("\n")
+}
+
+/// An address given to a debugger command, either directly or as the register holding it (`@reg`).
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum AddressOperand {
+    Absolute(u32),
+    Register(RegisterMapping),
+}
+
+impl AddressOperand {
+    /// Parse an address (see `parse_address`), or a register name prefixed with `@`, e.g. `@a0`.
+    fn parse(s: &str) -> Option<Self> {
+        s.strip_prefix('@').map_or_else(
+            || parse_address(s).ok().map(Self::Absolute),
+            |register| register.parse().ok().map(Self::Register),
+        )
+    }
+
+    /// Get the address, reading the register if it's given by one.
+    #[must_use]
+    pub fn resolve(self, cpu: &Cpu32Bit) -> u32 {
+        match self {
+            Self::Absolute(addr) => addr,
+            Self::Register(register) => cpu.registers[register],
+        }
+    }
+}
+
+#[allow(clippy::module_name_repetitions)]
+#[derive(Debug, PartialEq, Eq)]
+pub enum DebuggerCommand {
+    ContinueToNextBreakpoint,
+    /// Execute the given number of instructions before prompting again
+    Step(u64),
+    ExitProgram,
+    PrintMemoryMap,
+    /// Print the value of the given target, e.g. `pc`
+    Print(String),
+    /// Move the program counter to the given address
+    SetPc(u32),
+    /// Disassemble the instruction at the given address
+    Disassemble(AddressOperand),
+    /// Print `count` `size`-bit values at the given address, like gdb's `x/<count><format><size>`
+    Examine {
+        addr: AddressOperand,
+        count: u32,
+        size: Size,
+        format: ExamineFormat,
+    },
+    /// Undo the last step
+    Back,
+    /// Undo steps until the previous breakpoint (an ebreak, or the given address)
+    ReverseContinue(Option<u32>),
+    /// Continue until the current function returns
+    Finish,
+    /// Continue until the given register is next written
+    TrapOnWrite(RegisterMapping),
+    Unknown,
+}
+
+impl From<&str> for DebuggerCommand {
+    fn from(s: &str) -> Self {
+        let mut words = s.split_whitespace();
+        match (words.next(), words.next(), words.next()) {
+            (Some("c"), None, None) => Self::ContinueToNextBreakpoint,
+            (Some("s") | None, None, None) => Self::Step(1),
+            (Some("s"), Some(count), None) => count
+                .parse()
+                .ok()
+                .filter(|&count| count > 0)
+                .map_or(Self::Unknown, Self::Step),
+            (Some("q"), None, None) => Self::ExitProgram,
+            (Some("m"), None, None) => Self::PrintMemoryMap,
+            (Some("r" | "back"), None, None) => Self::Back,
+            (Some("rc" | "reverse-continue"), None, None) => Self::ReverseContinue(None),
+            (Some("rc" | "reverse-continue"), Some(addr), None) => {
+                parse_address(addr).map_or(Self::Unknown, |addr| Self::ReverseContinue(Some(addr)))
+            }
+            (Some("fin" | "finish"), None, None) => Self::Finish,
+            // writes to zero are discarded, so it's never written
+            (Some("tw"), Some(register), None) => register
+                .parse()
+                .ok()
+                .filter(|&register| register != RegisterMapping::Zero)
+                .map_or(Self::Unknown, Self::TrapOnWrite),
+            (Some("p"), Some(target), None) => Self::Print(target.to_string()),
+            (Some("setpc"), Some(addr), None) => {
+                parse_address(addr).map_or(Self::Unknown, Self::SetPc)
+            }
+            (Some("di"), Some(addr), None) => {
+                AddressOperand::parse(addr).map_or(Self::Unknown, Self::Disassemble)
+            }
+            (Some(examine), Some(addr), None) if examine.starts_with('x') => {
+                parse_examine(examine, addr).unwrap_or(Self::Unknown)
+            }
+            _ => Self::Unknown,
+        }
+    }
+}
+
+/// Parse the `x/<count><format><size> <addr>` command, where each part of the suffix is optional, as is the suffix itself.
+fn parse_examine(command: &str, addr: &str) -> Option<DebuggerCommand> {
+    let suffix = match command.strip_prefix('x')? {
+        "" => "",
+        suffix => suffix.strip_prefix('/')?,
+    };
+    let letters = suffix.trim_start_matches(|c: char| c.is_ascii_digit());
+    let count = match &suffix[..suffix.len() - letters.len()] {
+        "" => 1,
+        count => count.parse().ok().filter(|&count| count > 0)?,
+    };
+    let (mut size, mut format) = (Size::Word, ExamineFormat::Hex);
+    for letter in letters.chars() {
+        match letter {
+            'b' => size = Size::Byte,
+            'h' => size = Size::Half,
+            'w' => size = Size::Word,
+            'x' => format = ExamineFormat::Hex,
+            'd' => format = ExamineFormat::Signed,
+            'u' => format = ExamineFormat::Unsigned,
+            _ => return None,
+        }
+    }
+    Some(DebuggerCommand::Examine {
+        addr: AddressOperand::parse(addr)?,
+        count,
+        size,
+        format,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::words_to_bytes;
+
+    #[test]
+    fn test_parse_setpc() {
+        assert_eq!(
+            DebuggerCommand::from("setpc 0x0040_0008"),
+            DebuggerCommand::SetPc(0x0040_0008)
+        );
+        assert_eq!(
+            DebuggerCommand::from("setpc 4194312"),
+            DebuggerCommand::SetPc(0x0040_0008)
+        );
+        assert_eq!(DebuggerCommand::from("setpc"), DebuggerCommand::Unknown);
+        assert_eq!(DebuggerCommand::from("setpc xyz"), DebuggerCommand::Unknown);
+    }
+
+    #[test]
+    fn test_parse_step() {
+        assert_eq!(DebuggerCommand::from(""), DebuggerCommand::Step(1));
+        assert_eq!(DebuggerCommand::from("s"), DebuggerCommand::Step(1));
+        assert_eq!(DebuggerCommand::from("s 100"), DebuggerCommand::Step(100));
+        assert_eq!(DebuggerCommand::from("s 0"), DebuggerCommand::Unknown);
+        assert_eq!(DebuggerCommand::from("s many"), DebuggerCommand::Unknown);
+    }
+
+    #[test]
+    fn test_parse_reverse_continue() {
+        assert_eq!(
+            DebuggerCommand::from("rc"),
+            DebuggerCommand::ReverseContinue(None)
+        );
+        assert_eq!(
+            DebuggerCommand::from("reverse-continue 0x00400004"),
+            DebuggerCommand::ReverseContinue(Some(0x0040_0004))
+        );
+        assert_eq!(DebuggerCommand::from("rc here"), DebuggerCommand::Unknown);
+    }
+
+    #[test]
+    fn test_parse_finish() {
+        assert_eq!(DebuggerCommand::from("fin"), DebuggerCommand::Finish);
+        assert_eq!(DebuggerCommand::from("finish"), DebuggerCommand::Finish);
+    }
+
+    #[test]
+    fn test_parse_trap_on_write() {
+        assert_eq!(
+            DebuggerCommand::from("tw a0"),
+            DebuggerCommand::TrapOnWrite(RegisterMapping::A0)
+        );
+        assert_eq!(
+            DebuggerCommand::from("tw x2"),
+            DebuggerCommand::TrapOnWrite(RegisterMapping::Sp)
+        );
+        assert_eq!(DebuggerCommand::from("tw zero"), DebuggerCommand::Unknown);
+        assert_eq!(DebuggerCommand::from("tw"), DebuggerCommand::Unknown);
+    }
+
+    #[test]
+    fn test_parse_print() {
+        assert_eq!(
+            DebuggerCommand::from("p pc"),
+            DebuggerCommand::Print(String::from("pc"))
+        );
+    }
+
+    #[test]
+    fn test_parse_disassemble() {
+        assert_eq!(
+            DebuggerCommand::from("di @a0"),
+            DebuggerCommand::Disassemble(AddressOperand::Register(RegisterMapping::A0))
+        );
+        assert_eq!(
+            DebuggerCommand::from("di 0x0040_0004"),
+            DebuggerCommand::Disassemble(AddressOperand::Absolute(0x0040_0004))
+        );
+        assert_eq!(DebuggerCommand::from("di @b0"), DebuggerCommand::Unknown);
+    }
+
+    #[test]
+    fn test_disassemble_at_register() {
+        let text = words_to_bytes(&[
+            0x0010_0513u32, // addi a0, zero, 1
+            0x00b5_0533,    // add a0, a0, a1
+        ]);
+        let mut cpu = Cpu32Bit::new(&text, &[], 0x0040_0000, None).unwrap();
+        cpu.registers[RegisterMapping::A0] = 0x0040_0004;
+
+        let DebuggerCommand::Disassemble(operand) = DebuggerCommand::from("di @a0") else {
+            panic!("di @a0 didn't parse");
+        };
+        assert_eq!(
+            disassemble_at(&cpu, operand.resolve(&cpu)),
+            "0x00400004: add a0, a0, a1"
+        );
+    }
+
+    #[test]
+    fn test_disassemble_compressed_at_the_end_of_text() {
+        // addi a0, zero, 1, then c.li a0, 1 in the last halfword of the text
+        let cpu = Cpu32Bit::new(
+            &[0x13, 0x05, 0x10, 0x00, 0x05, 0x45],
+            &[],
+            0x0040_0000,
+            None,
+        )
+        .unwrap();
+        assert_eq!(
+            disassemble_at(&cpu, 0x0040_0004),
+            "0x00400004: addi a0, zero, 1"
+        );
+    }
+
+    #[test]
+    fn test_parse_examine() {
+        assert_eq!(
+            DebuggerCommand::from("x/10dw 0x10000000"),
+            DebuggerCommand::Examine {
+                addr: AddressOperand::Absolute(0x1000_0000),
+                count: 10,
+                size: Size::Word,
+                format: ExamineFormat::Signed,
+            }
+        );
+        assert_eq!(
+            DebuggerCommand::from("x @sp"),
+            DebuggerCommand::Examine {
+                addr: AddressOperand::Register(RegisterMapping::Sp),
+                count: 1,
+                size: Size::Word,
+                format: ExamineFormat::Hex,
+            }
+        );
+        assert_eq!(
+            DebuggerCommand::from("x/hu 0x10000000"),
+            DebuggerCommand::Examine {
+                addr: AddressOperand::Absolute(0x1000_0000),
+                count: 1,
+                size: Size::Half,
+                format: ExamineFormat::Unsigned,
+            }
+        );
+        assert_eq!(DebuggerCommand::from("x/0w 0x10"), DebuggerCommand::Unknown);
+        assert_eq!(DebuggerCommand::from("x/4q 0x10"), DebuggerCommand::Unknown);
+        assert_eq!(DebuggerCommand::from("x10 0x10"), DebuggerCommand::Unknown);
+    }
+
+    #[test]
+    fn test_examine_array() -> anyhow::Result<()> {
+        let data: Vec<u8> = [1_i32, -2, 3, 40, 500]
+            .iter()
+            .flat_map(|value| value.to_le_bytes())
+            .collect();
+        let cpu = Cpu32Bit::new(&[0; 4], &data, 0x0040_0000, None).unwrap();
+        let array = cpu.memory.dram_start();
+
+        assert_eq!(
+            cpu.memory.read_array(array, 5, Size::Word)?,
+            [1, 0xffff_fffe, 3, 40, 500]
+        );
+        assert_eq!(
+            examine(&cpu, array, 5, Size::Word, ExamineFormat::Signed),
+            format!("{array:#010x}: 1 -2 3 40\n{:#010x}: 500", array + 16)
+        );
+        assert_eq!(
+            examine(&cpu, array + 4, 2, Size::Half, ExamineFormat::Hex),
+            format!("{:#010x}: 0xfffe 0xffff", array + 4)
+        );
+        assert_eq!(
+            examine(&cpu, array + 4, 1, Size::Byte, ExamineFormat::Unsigned),
+            format!("{:#010x}: 254", array + 4)
+        );
+        Ok(())
+    }
+}
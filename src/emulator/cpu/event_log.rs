@@ -0,0 +1,339 @@
+/*
+MIT License
+
+Copyright (c) 2024 Anthony Rubick
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+//! Event logs of whole runs, which can be replayed or visualized later without re-executing the program
+use std::{
+    io::{self, BufRead, Write},
+    sync::{Arc, Mutex, PoisonError},
+};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    emulator::execute::{ProgramExit, Syscall},
+    instruction_set_definition::{operations::ITypeOperation, Rv32imInstruction},
+};
+
+use super::{io::ProgramInput, registers::RegisterMapping, Cpu32Bit};
+
+/// Something that happened during a run, as recorded by `Cpu32Bit::run_recorded`.
+///
+/// Events are written as JSON, one per line, in the order they happened.
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum TraceEvent {
+    /// An instruction retired
+    Retired {
+        pc: u32,
+        /// The instruction's disassembly, `None` if a hook ran instead (see `Cpu32Bit::hooks`)
+        instruction: Option<String>,
+        next_pc: u32,
+        /// The registers written, by ABI name, with their new values
+        registers: Vec<(String, u32)>,
+        /// The address and value of the load or store the instruction made, if any
+        memory: Option<MemoryEvent>,
+    },
+    /// A syscall ran, with the input it read and the output it wrote
+    Syscall {
+        pc: u32,
+        number: u32,
+        name: String,
+        input: String,
+        output: String,
+    },
+    /// An `ecall` jumped to the trap handler at `handler` instead of running a syscall, see `Cpu32Bit::bare_metal`
+    Trap { pc: u32, cause: u32, handler: u32 },
+    /// The program exited with `code`
+    Exit { code: u32 },
+    /// The run stopped with an error
+    Fault { pc: u32, message: String },
+}
+
+/// A load or store, see `TraceEvent::Retired`.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+pub struct MemoryEvent {
+    pub addr: u32,
+    /// The number of bytes accessed
+    pub bytes: u32,
+    pub value: u32,
+    pub store: bool,
+}
+
+impl TraceEvent {
+    /// Write the event to `log` as a line of JSON.
+    ///
+    /// # Errors
+    ///
+    /// This method will return an error if writing to `log` fails.
+    pub fn write_to(&self, log: &mut dyn Write) -> Result<()> {
+        serde_json::to_writer(&mut *log, self)?;
+        writeln!(log)?;
+        Ok(())
+    }
+}
+
+/// Read back the events written by `Cpu32Bit::run_recorded`.
+///
+/// # Errors
+///
+/// This function will return an error if `log` can't be read, or a line isn't a valid event.
+pub fn read_events(log: impl BufRead) -> Result<Vec<TraceEvent>> {
+    let mut events = Vec::new();
+    for line in log.lines() {
+        events.push(serde_json::from_str(&line?)?);
+    }
+    Ok(events)
+}
+
+/// Steps a CPU, logging the events of each step, see `Cpu32Bit::run_recorded`.
+pub(super) struct Recorder {
+    /// Everything the program has read from its input, since the last syscall was logged
+    input: SharedBuffer,
+}
+
+impl Recorder {
+    /// Start recording the CPU's input, so syscalls can be logged with what they read.
+    pub fn attach(cpu: &mut Cpu32Bit) -> Self {
+        let input = SharedBuffer::default();
+        cpu.input = std::mem::take(&mut cpu.input).recorded(Box::new(input.clone()));
+        Self { input }
+    }
+
+    /// Stop recording the CPU's input, restoring the input it had before `attach`.
+    pub fn detach(cpu: &mut Cpu32Bit) {
+        if let ProgramInput::Recorded { source, .. } = std::mem::take(&mut cpu.input) {
+            cpu.input = *source;
+        }
+    }
+
+    /// Step the CPU, logging the syscall it makes (or the trap it takes, when running bare-metal) if any,
+    /// then the instruction retiring, or the exit or fault ending the run.
+    pub fn step(&self, cpu: &mut Cpu32Bit, log: &mut dyn Write) -> Result<()> {
+        let pc = cpu.pc;
+        let ecall = !cpu.hooks.contains_key(&pc)
+            && matches!(
+                cpu.fetch_and_decode(pc),
+                Ok(Rv32imInstruction::IType {
+                    operation: ITypeOperation::Ecall,
+                    ..
+                })
+            );
+        let syscall = (ecall && !cpu.bare_metal).then(|| cpu.registers[RegisterMapping::A7]);
+        let output_start = cpu.output.len();
+
+        let result = cpu.step_reported();
+
+        if ecall && cpu.bare_metal && result.is_ok() {
+            TraceEvent::Trap {
+                pc,
+                cause: cpu.csrs.mcause,
+                handler: cpu.pc,
+            }
+            .write_to(log)?;
+        }
+        if let Some(number) = syscall {
+            TraceEvent::Syscall {
+                pc,
+                number,
+                name: Syscall::from(number).name().to_string(),
+                input: String::from_utf8_lossy(&self.input.take()).into_owned(),
+                output: cpu
+                    .output
+                    .get(output_start..)
+                    .unwrap_or_default()
+                    .to_string(),
+            }
+            .write_to(log)?;
+        }
+        let report = match result {
+            Ok(report) => report,
+            Err(e) => {
+                let event = e.downcast_ref::<ProgramExit>().map_or_else(
+                    || TraceEvent::Fault {
+                        pc,
+                        message: format!("{e:#}"),
+                    },
+                    |exit| TraceEvent::Exit { code: exit.code },
+                );
+                event.write_to(log)?;
+                return Err(e);
+            }
+        };
+        TraceEvent::Retired {
+            pc,
+            instruction: report
+                .instruction
+                .map(|instruction| instruction.disassemble(pc)),
+            next_pc: report.next_pc,
+            registers: report
+                .registers
+                .iter()
+                .map(|(reg, value)| (format!("{reg:#}"), *value))
+                .collect(),
+            memory: report.memory_access.map(|access| MemoryEvent {
+                addr: access.addr,
+                bytes: access.size.bytes(),
+                value: access.value,
+                store: access.store,
+            }),
+        }
+        .write_to(log)
+    }
+}
+
+/// A buffer that's written to through one handle, and drained through another.
+#[derive(Clone, Default)]
+struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+impl SharedBuffer {
+    /// Take everything written so far.
+    fn take(&self) -> Vec<u8> {
+        std::mem::take(&mut *self.0.lock().unwrap_or_else(PoisonError::into_inner))
+    }
+}
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{emulator::cpu::io::ProgramOutput, utils::words_to_bytes};
+
+    #[test]
+    fn test_recorded_run() -> Result<()> {
+        let text = words_to_bytes(&[
+            0x0050_0893_u32, // li a7, 5
+            0x0000_0073,     // ecall (ReadInt)
+            0x0015_0513,     // addi a0, a0, 1
+            0x0010_0893,     // li a7, 1
+            0x0000_0073,     // ecall (PrintInt)
+            0x00a0_0893,     // li a7, 10
+            0x0000_0073,     // ecall (Exit)
+        ]);
+        let mut cpu = Cpu32Bit::new(&text, &[], 0x0040_0000, None)?;
+        cpu.input = ProgramInput::Reader(Box::new(io::Cursor::new("41\n")));
+        cpu.output_sink = ProgramOutput::Writer(Box::new(io::sink()));
+
+        let mut log = Vec::new();
+        let error = cpu
+            .run_recorded(None, &mut log)
+            .expect_err("the program should exit");
+        assert!(error.is::<ProgramExit>());
+        // the input is no longer recorded
+        assert!(!matches!(cpu.input, ProgramInput::Recorded { .. }));
+
+        let events = read_events(log.as_slice())?;
+        let summary: Vec<_> = events
+            .iter()
+            .map(|event| match event {
+                TraceEvent::Retired { pc, .. } => format!("retired {pc:#x}"),
+                TraceEvent::Syscall {
+                    name,
+                    input,
+                    output,
+                    ..
+                } => format!("{name} {input:?} {output:?}"),
+                TraceEvent::Trap { cause, .. } => format!("trap {cause}"),
+                TraceEvent::Exit { code } => format!("exit {code}"),
+                TraceEvent::Fault { message, .. } => format!("fault {message}"),
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                "retired 0x400000",
+                "ReadInt \"41\\n\" \"\"",
+                "retired 0x400004",
+                "retired 0x400008",
+                "retired 0x40000c",
+                "PrintInt \"\" \"42\"",
+                "retired 0x400010",
+                "retired 0x400014",
+                "Exit \"\" \"\"",
+                "exit 0",
+            ]
+        );
+        assert_eq!(
+            events[1],
+            TraceEvent::Syscall {
+                pc: 0x0040_0004,
+                number: 5,
+                name: String::from("ReadInt"),
+                input: String::from("41\n"),
+                output: String::new(),
+            }
+        );
+        assert!(matches!(
+            &events[2],
+            TraceEvent::Retired { registers, .. } if registers == &[(String::from("a0"), 41)]
+        ));
+        Ok(())
+    }
+    #[test]
+    fn test_recorded_bare_metal_ecall() -> Result<()> {
+        let text = words_to_bytes(&[
+            0x0000_0297_u32, // auipc t0, 0
+            0x0102_8293,     // addi t0, t0, 16
+            0x3052_9073,     // csrw mtvec, t0
+            0x0000_0073,     // ecall
+            0x0000_0013,     // handler: nop
+        ]);
+        let mut cpu = Cpu32Bit::new(&text, &[], 0x0040_0000, None)?;
+        cpu.bare_metal = true;
+        // a7 selects Exit, which the ecall mustn't run
+        cpu.registers[RegisterMapping::A7] = 10;
+
+        let mut log = Vec::new();
+        cpu.run_recorded(Some(4), &mut log)?;
+
+        let events = read_events(log.as_slice())?;
+        assert_eq!(events.len(), 5);
+        assert_eq!(
+            events[3],
+            TraceEvent::Trap {
+                pc: 0x0040_000c,
+                cause: 11,
+                handler: 0x0040_0010,
+            }
+        );
+        assert!(!events
+            .iter()
+            .any(|event| matches!(event, TraceEvent::Syscall { .. })));
+        Ok(())
+    }
+}
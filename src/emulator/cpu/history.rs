@@ -0,0 +1,138 @@
+/*
+MIT License
+
+Copyright (c) 2024 Anthony Rubick
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+//! A bounded history of the state changes made by each step, so that steps can be undone
+use std::collections::VecDeque;
+
+use super::{
+    call_stack::CallStack, callee_saved::CalleeSavedChecker, csr::CsrFile, memory::MemoryWrite,
+    ra_guard::ReturnAddressGuard, registers::RegisterFile32Bit,
+};
+
+/// How many steps the debugger can undo by default, the history is disabled otherwise.
+pub const DEFAULT_UNDO_DEPTH: usize = 64;
+
+/// The state overwritten by a single step, i.e. what needs to be restored to undo it.
+#[derive(Debug, Clone)]
+pub struct UndoRecord {
+    /// The program counter before the step
+    pub pc: u32,
+    /// The registers before the step, along with which of them had been written
+    pub registers: RegisterFile32Bit,
+    /// The memory written by the step, with the previous values, in the order the writes happened
+    pub memory: Vec<MemoryWrite>,
+    /// The CSRs before the step
+    pub csrs: CsrFile,
+    /// The program break before the step, `sbrk` (and allocating the TLS block) moves it
+    pub heap_end: u32,
+    /// Whether the program was halted before the step
+    pub halted: bool,
+    /// The number of instructions retired before the step
+    pub instret: u64,
+    /// The calls being tracked before the step, only recorded for steps that can make or return from a call
+    pub calls: Option<Box<CallTracking>>,
+}
+
+/// The state of the call tracking, which makes and returns from calls update.
+#[derive(Debug, Clone)]
+pub struct CallTracking {
+    pub call_stack: CallStack,
+    pub ra_guard: Option<ReturnAddressGuard>,
+    pub callee_saved_checker: Option<CalleeSavedChecker>,
+}
+
+/// Why reverse execution (see `Cpu32Bit::reverse_continue`) stopped.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ReverseStop {
+    /// The program counter reached a breakpoint.
+    Breakpoint,
+    /// Every recorded step was undone, the history doesn't go back any further.
+    StartOfHistory,
+}
+
+/// A ring buffer of the undo records of the most recent steps.
+#[derive(Debug, Clone)]
+pub struct History {
+    depth: usize,
+    records: VecDeque<UndoRecord>,
+}
+
+impl Default for History {
+    /// A disabled history, recording a step on every step costs time and memory that's wasted without a debugger.
+    fn default() -> Self {
+        Self::new(0)
+    }
+}
+
+impl History {
+    /// Create a history that remembers the last `depth` steps, `usize::MAX` remembers every step.
+    #[must_use]
+    pub fn new(depth: usize) -> Self {
+        Self {
+            depth,
+            // the history may be unbounded, so it only preallocates as much as the default would
+            records: VecDeque::with_capacity(depth.min(DEFAULT_UNDO_DEPTH)),
+        }
+    }
+
+    /// get the maximum number of steps that can be undone
+    #[must_use]
+    pub const fn depth(&self) -> usize {
+        self.depth
+    }
+
+    /// get the number of steps that can currently be undone
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    /// whether there are no steps to undo
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    /// whether steps are being recorded, i.e. the depth isn't 0
+    #[must_use]
+    pub const fn is_enabled(&self) -> bool {
+        self.depth > 0
+    }
+
+    /// Remember a step, forgetting the oldest one if the history is full.
+    pub fn push(&mut self, record: UndoRecord) {
+        if self.depth == 0 {
+            return;
+        }
+        if self.records.len() == self.depth {
+            self.records.pop_front();
+        }
+        self.records.push_back(record);
+    }
+
+    /// Take the record of the most recent step, if any.
+    pub fn pop(&mut self) -> Option<UndoRecord> {
+        self.records.pop_back()
+    }
+}
@@ -0,0 +1,274 @@
+/*
+MIT License
+
+Copyright (c) 2024 Anthony Rubick
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+//! The sources and sinks the program's syscalls read from and write to
+use std::{
+    fmt::Write as _,
+    io::{self, BufRead, Write},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context as _, Result};
+
+/// Where the input syscalls (`ReadInt`, `ReadString`, `ReadChar`) read from.
+#[derive(Default)]
+pub enum ProgramInput {
+    /// Read from the terminal.
+    #[default]
+    Stdin,
+    /// Read from the given reader, e.g. a file of canned input.
+    Reader(Box<dyn BufRead + Send>),
+    /// Read from `source`, copying everything read to `record`,
+    /// so the session can be replayed by reading the recording back with `Reader`.
+    Recorded {
+        source: Box<Self>,
+        record: Box<dyn Write + Send>,
+    },
+}
+
+impl ProgramInput {
+    /// Read a line of input, including the trailing newline (if any), and append it to `buf`.
+    ///
+    /// Returns the number of bytes read, 0 means the input is exhausted.
+    ///
+    /// # Errors
+    ///
+    /// This method will return an error if the underlying source cannot be read.
+    pub fn read_line(&mut self, buf: &mut String) -> io::Result<usize> {
+        match self {
+            Self::Stdin => io::stdin().read_line(buf),
+            Self::Reader(reader) => reader.read_line(buf),
+            Self::Recorded { source, record } => {
+                let start = buf.len();
+                let read = source.read_line(buf)?;
+                record.write_all(&buf.as_bytes()[start..])?;
+                record.flush()?;
+                Ok(read)
+            }
+        }
+    }
+
+    /// Record everything read from this input to `record`, see `Recorded`.
+    #[must_use]
+    pub fn recorded(self, record: Box<dyn Write + Send>) -> Self {
+        Self::Recorded {
+            source: Box::new(self),
+            record,
+        }
+    }
+}
+
+/// Where the `Time` syscall reads the time from.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum Clock {
+    /// Read the system's wall-clock time.
+    #[default]
+    System,
+    /// Always read the given time since the unix epoch, e.g. for reproducible runs.
+    Fixed(Duration),
+}
+
+impl Clock {
+    /// Get the current time since the unix epoch.
+    ///
+    /// # Errors
+    ///
+    /// This method will return an error if the system clock is set before the unix epoch.
+    pub fn now(self) -> Result<Duration> {
+        match self {
+            Self::System => SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .context("Error getting time"),
+            Self::Fixed(time) => Ok(time),
+        }
+    }
+}
+
+/// The pseudo-random number generator the random syscalls (`RandSeed`, `RandInt`, `RandIntRange`) draw from.
+///
+/// This is xorshift64*, which is fast and reproducible from its seed, but not cryptographically secure.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    /// Create a generator that always produces the same sequence for the same `seed`.
+    #[must_use]
+    pub const fn seeded(seed: u64) -> Self {
+        // xorshift gets stuck at 0, so the seed is mixed with a constant that isn't
+        let state = seed ^ 0x9e37_79b9_7f4a_7c15;
+        Self {
+            state: if state == 0 { 1 } else { state },
+        }
+    }
+
+    /// Get the next pseudo-random number.
+    pub const fn next_u32(&mut self) -> u32 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        #[allow(clippy::cast_possible_truncation)] // the high bits are the most random
+        let random = (self.state.wrapping_mul(0x2545_f491_4f6c_dd1d) >> 32) as u32;
+        random
+    }
+
+    /// Get the next pseudo-random number in `0..bound`, `bound` must not be 0.
+    pub const fn next_below(&mut self, bound: u32) -> u32 {
+        #[allow(clippy::cast_possible_truncation)]
+        // the product of two u32s shifted down by 32 bits fits in a u32
+        let random = ((self.next_u32() as u64 * bound as u64) >> 32) as u32;
+        random
+    }
+}
+
+/// Seeded from the system time, so every run differs.
+impl Default for Rng {
+    fn default() -> Self {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |time| time.subsec_nanos());
+        Self::seeded(u64::from(nanos) ^ u64::from(std::process::id()) << 32)
+    }
+}
+
+/// Where the output syscalls (`PrintInt`, `PrintString`, etc.) write to.
+///
+/// Regardless of the sink, the output is also accumulated in `Cpu32Bit::output`.
+#[derive(Default)]
+pub enum ProgramOutput {
+    /// Write to the terminal.
+    #[default]
+    Stdout,
+    /// Write to the given writer, e.g. a file.
+    Writer(Box<dyn Write + Send>),
+    /// Write to the given writer, e.g. a log file, with control characters escaped (see `escape_control`)
+    /// so the program can't corrupt whatever displays it.
+    Escaped(Box<dyn Write + Send>),
+}
+
+impl ProgramOutput {
+    /// Write `text` to the sink.
+    ///
+    /// # Errors
+    ///
+    /// This method will return an error if the underlying sink cannot be written to.
+    pub fn write_str(&mut self, text: &str) -> io::Result<()> {
+        match self {
+            Self::Stdout => {
+                print!("{text}");
+                Ok(())
+            }
+            Self::Writer(writer) => writer.write_all(text.as_bytes()),
+            Self::Escaped(writer) => writer.write_all(escape_control(text).as_bytes()),
+        }
+    }
+
+    /// Flush any output buffered by the sink.
+    ///
+    /// # Errors
+    ///
+    /// This method will return an error if the underlying sink cannot be flushed.
+    pub fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::Stdout => io::stdout().flush(),
+            Self::Writer(writer) | Self::Escaped(writer) => writer.flush(),
+        }
+    }
+}
+
+/// Escape the control characters in `text` (other than newlines and tabs) as `\xNN`,
+/// so that e.g. terminal escape sequences are shown instead of interpreted.
+#[must_use]
+pub fn escape_control(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if c.is_control() && c != '\n' && c != '\t' {
+            for byte in c.encode_utf8(&mut [0; 4]).bytes() {
+                let _ = write!(escaped, "\\x{byte:02x}");
+            }
+        } else {
+            escaped.push(c);
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_escape_control() {
+        assert_eq!(escape_control("\x1b[2Jcleared"), "\\x1b[2Jcleared");
+        assert_eq!(escape_control("a\tb\n"), "a\tb\n");
+        assert_eq!(escape_control("bell\x07 \u{85}"), "bell\\x07 \\xc2\\x85");
+        assert_eq!(escape_control("héllo"), "héllo");
+    }
+
+    #[test]
+    fn test_rng_is_reproducible() {
+        let draw = |seed| {
+            let mut rng = Rng::seeded(seed);
+            [rng.next_u32(), rng.next_u32(), rng.next_below(10)]
+        };
+        assert_eq!(draw(42), draw(42));
+        assert_ne!(draw(42), draw(43));
+        assert!(draw(0)[2] < 10);
+    }
+
+    #[test]
+    fn test_recorded_input_replays() -> io::Result<()> {
+        /// a writer whose contents can be inspected after it's been boxed
+        #[derive(Clone, Default)]
+        struct Shared(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+        impl Write for Shared {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0.lock().unwrap().write(buf)
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let read_all = |mut input: ProgramInput| -> io::Result<Vec<String>> {
+            let mut lines = Vec::new();
+            let mut line = String::new();
+            while input.read_line(&mut line)? > 0 {
+                lines.push(std::mem::take(&mut line));
+            }
+            Ok(lines)
+        };
+
+        let record = Shared::default();
+        let input = ProgramInput::Reader(Box::new(io::Cursor::new("40\n2\nno newline")));
+        let recorded = read_all(input.recorded(Box::new(record.clone())))?;
+        assert_eq!(recorded, ["40\n", "2\n", "no newline"]);
+
+        let recording = record.0.lock().unwrap().clone();
+        let replayed = read_all(ProgramInput::Reader(Box::new(io::Cursor::new(recording))))?;
+        assert_eq!(replayed, recorded);
+        Ok(())
+    }
+}
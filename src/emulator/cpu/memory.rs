@@ -22,9 +22,30 @@ OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 SOFTWARE.
 */
 
-use anyhow::{bail, Result};
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    fmt,
+    hash::{BuildHasherDefault, DefaultHasher, Hash as _, Hasher},
+};
 
-use crate::emulator::cpu::Size;
+use anyhow::{bail, Context as _, Result};
+use elf::{
+    abi::{PF_X, PT_LOAD},
+    endian::AnyEndian,
+    segment::ProgramHeader,
+    ElfBytes,
+};
+
+use crate::{
+    emulator::{
+        cpu::{MemoryAccess, Size},
+        decode::Decode32BitInstruction as _,
+        fetch::instruction_width,
+        trap::Trap,
+    },
+    instruction_set_definition::Rv32imInstruction,
+};
 
 // /// The base address of the text section.
 // pub const TEXT_BASE: u32 = 0x0040_0000; // where the pc starts
@@ -37,14 +58,52 @@ pub const TEXT_SIZE: u32 = 0x0040_0000;
 /// and grows upwards to `0x1000_0000` + 4MB
 /// the end of the data portion which is at `0x7FFF_FFFF`, and it is the start of the stack, wich grows downwards
 /// the heap starts at the end of the data section and grows upwards
+#[allow(clippy::too_long_first_doc_paragraph)] // the notes are about the memory layout as a whole
 pub const STATIC_DATA_SIZE: u32 = 0x0040_0000;
 pub const STACK_CEILING: u32 = 0x7FFF_EFFC;
 pub const DRAM_END: u32 = 0x8000_0000;
+/// By default, accesses to the first 4KB of the address space are treated as null pointer dereferences.
+pub const NULL_GUARD_SIZE: u32 = 0x1000;
+
+/// The size of the pages memory regions are allocated in.
+pub const PAGE_SIZE: u32 = 0x1000;
+
+type Page = Box<[u8; PAGE_SIZE as usize]>;
+
+/// Hashes page numbers by multiplying them by a large odd constant (Fibonacci hashing),
+/// which is plenty for small distinct integers, and much cheaper than the default `SipHash`.
+#[derive(Default)]
+struct PageNumberHasher(u64);
+
+impl Hasher for PageNumberHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.write_u64(self.0.rotate_left(8) ^ u64::from(byte));
+        }
+    }
+
+    fn write_u32(&mut self, number: u32) {
+        self.write_u64(u64::from(number));
+    }
+
+    fn write_u64(&mut self, number: u64) {
+        self.0 = number.wrapping_mul(0x9E37_79B9_7F4A_7C15);
+    }
+}
 
+/// A memory region, allocated a page at a time as it's written to, so that large regions
+/// (like the ~2GB data region) are cheap to create.
 struct MemoryRegion {
     base: u32,
     size: u32,
-    data: Box<[u8]>,
+    /// The pages that have been written to, by page number (the offset into the region divided by `PAGE_SIZE`).
+    ///
+    /// Pages that haven't been written to read as zero.
+    pages: HashMap<u32, Page, BuildHasherDefault<PageNumberHasher>>,
 }
 
 impl MemoryRegion {
@@ -52,7 +111,7 @@ impl MemoryRegion {
         Self {
             base,
             size,
-            data: vec![0; size as usize].into_boxed_slice(),
+            pages: HashMap::default(),
         }
     }
 
@@ -63,102 +122,483 @@ impl MemoryRegion {
             data.len() <= self.size as usize,
             "Data is too large for the memory region"
         );
-        self.data[..data.len()].copy_from_slice(data);
+        self.copy_in(0, data);
     }
 
     /// Load `size`-bit data from the memory.
     ///
     /// addr is the unadjusted address, the base address of the memory region is removed from it before reading.
     pub fn read(&self, addr: u32, size: Size) -> Result<u32> {
-        if addr < self.base || addr > self.base + self.size {
+        if !self.contains_range(addr, size.bytes()) {
             bail!("Address {:08x} is out of bounds", addr);
         }
-        match size {
-            Size::Byte => Ok(self.read8(addr)),
-            Size::Half => Ok(self.read16(addr)),
-            Size::Word => Ok(self.read32(addr)),
-        }
+        let mut bytes = [0; 4];
+        self.copy_out(addr - self.base, &mut bytes[..size.bytes() as usize]);
+        Ok(u32::from_le_bytes(bytes))
     }
 
     /// Store `size`-bit data to the memory.
     ///
     /// addr is the unadjusted address, the base address of the memory region is removed from it before writing.
     pub fn write(&mut self, addr: u32, value: u32, size: Size) -> Result<()> {
-        if addr < self.base || addr > self.base + self.size {
+        if !self.contains_range(addr, size.bytes()) {
             bail!("Address {:08x} is out of bounds", addr);
         }
-        match size {
-            Size::Byte => self.write8(addr, value),
-            Size::Half => self.write16(addr, value),
-            Size::Word => self.write32(addr, value),
-        }
+        self.copy_in(
+            addr - self.base,
+            &value.to_le_bytes()[..size.bytes() as usize],
+        );
         Ok(())
     }
 
-    /// Write a byte to the memory.
-    fn write8(&mut self, addr: u32, val: u32) {
-        let index = (addr - self.base) as usize;
-        self.data[index] = (val & 0xff) as u8;
+    /// whether `addr` is within the memory region
+    #[must_use]
+    pub const fn contains(&self, addr: u32) -> bool {
+        addr >= self.base && addr - self.base < self.size
     }
 
-    /// Write 2 bytes to the memory with little endian.
-    fn write16(&mut self, addr: u32, val: u32) {
-        let index = (addr - self.base) as usize;
-        self.data[index] = (val & 0xff) as u8;
-        self.data[index + 1] = ((val >> 8) & 0xff) as u8;
+    /// whether all `len` bytes starting at `addr` are within the memory region
+    #[must_use]
+    pub const fn contains_range(&self, addr: u32, len: u32) -> bool {
+        addr >= self.base && (addr - self.base) as u64 + len as u64 <= self.size as u64
     }
 
-    /// Write 4 bytes to the memory with little endian.
-    fn write32(&mut self, addr: u32, val: u32) {
-        let index = (addr - self.base) as usize;
-        self.data[index] = (val & 0xff) as u8;
-        self.data[index + 1] = ((val >> 8) & 0xff) as u8;
-        self.data[index + 2] = ((val >> 16) & 0xff) as u8;
-        self.data[index + 3] = ((val >> 24) & 0xff) as u8;
+    /// Copy `len` bytes of the memory, starting at `addr`.
+    ///
+    /// addr is the unadjusted address, the base address of the memory region is removed from it before reading.
+    pub fn bytes(&self, addr: u32, len: u32) -> Result<Vec<u8>> {
+        if !self.contains_range(addr, len) {
+            bail!("Address range {:08x}+{} is out of bounds", addr, len);
+        }
+        let mut bytes = vec![0; len as usize];
+        self.copy_out(addr - self.base, &mut bytes);
+        Ok(bytes)
     }
 
-    /// Read a byte from the memory.
-    const fn read8(&self, addr: u32) -> u32 {
-        let index = (addr - self.base) as usize;
-        self.data[index] as u32
+    /// Fill `buf` with the bytes starting at `offset` into the region, looking each page up once.
+    fn copy_out(&self, offset: u32, buf: &mut [u8]) {
+        let mut offset = offset;
+        let mut buf = buf;
+        while !buf.is_empty() {
+            // copy up to the end of the page (or the buffer) at a time
+            let start = (offset % PAGE_SIZE) as usize;
+            let count = (PAGE_SIZE as usize - start).min(buf.len());
+            let (head, rest) = buf.split_at_mut(count);
+            match self.pages.get(&(offset / PAGE_SIZE)) {
+                Some(page) => head.copy_from_slice(&page[start..start + count]),
+                None => head.fill(0),
+            }
+            #[allow(clippy::cast_possible_truncation)] // at most a page
+            {
+                offset += count as u32;
+            }
+            buf = rest;
+        }
+    }
+
+    /// Write `bytes` starting at `offset` into the region, looking each page up (or allocating it) once.
+    fn copy_in(&mut self, offset: u32, bytes: &[u8]) {
+        let mut offset = offset;
+        let mut bytes = bytes;
+        while !bytes.is_empty() {
+            // copy up to the end of the page (or the bytes) at a time
+            let start = (offset % PAGE_SIZE) as usize;
+            let count = (PAGE_SIZE as usize - start).min(bytes.len());
+            let (head, rest) = bytes.split_at(count);
+            self.page_mut(offset / PAGE_SIZE)[start..start + count].copy_from_slice(head);
+            #[allow(clippy::cast_possible_truncation)] // at most a page
+            {
+                offset += count as u32;
+            }
+            bytes = rest;
+        }
     }
 
-    /// Read 2 bytes from the memory with little endian.
-    const fn read16(&self, addr: u32) -> u32 {
-        let index = (addr - self.base) as usize;
-        (self.data[index] as u32) | ((self.data[index + 1] as u32) << 8)
+    /// Feed the contents of the region to `state`, so regions with the same contents hash the same.
+    ///
+    /// Pages of zeros read the same as pages that were never written, so they're skipped.
+    fn hash_contents(&self, state: &mut impl Hasher) {
+        let mut numbers: Vec<_> = self.pages.keys().copied().collect();
+        numbers.sort_unstable();
+        for number in numbers {
+            let page = &self.pages[&number];
+            if page.iter().any(|&byte| byte != 0) {
+                number.hash(state);
+                page.hash(state);
+            }
+        }
     }
 
-    /// Read 4 bytes from the memory with little endian.
-    const fn read32(&self, addr: u32) -> u32 {
-        let index = (addr - self.base) as usize;
-        (self.data[index] as u32)
-            | ((self.data[index + 1] as u32) << 8)
-            | ((self.data[index + 2] as u32) << 16)
-            | ((self.data[index + 3] as u32) << 24)
+    /// Get the page with the given number, allocating it if it hasn't been written to yet.
+    fn page_mut(&mut self, number: u32) -> &mut Page {
+        self.pages
+            .entry(number)
+            .or_insert_with(|| Box::new([0; PAGE_SIZE as usize]))
     }
 }
 
+/// Find the address of the symbol called `name` in the ELF file.
+///
+/// # Errors
+///
+/// This function will return an error if the symbol table can't be parsed.
+pub fn find_symbol(elf: &ElfBytes<AnyEndian>, name: &str) -> Result<Option<u32>> {
+    #[allow(clippy::cast_possible_truncation)]
+    Ok(elf.symbol_table()?.and_then(|(symbols, names)| {
+        symbols
+            .iter()
+            .find(|symbol| names.get(symbol.st_name as usize).is_ok_and(|n| n == name))
+            .map(|symbol| symbol.st_value as u32)
+    }))
+}
+
+/// The address of the byte `offset` bytes past `addr`, or an error if it's past the end of the address space.
+fn byte_address(addr: u32, offset: usize) -> Result<u32> {
+    u32::try_from(offset)
+        .ok()
+        .and_then(|offset| addr.checked_add(offset))
+        .with_context(|| {
+            format!("Address {addr:#010x}+{offset} is past the end of the address space")
+        })
+}
+
+/// The range of memory the `(address, contents, size in memory)` pieces of a program span, as its base address and size,
+/// including the gaps between the pieces.
+///
+/// Returns `None` if there are no pieces.
+fn span(pieces: &[(u64, &[u8], u64)]) -> Result<Option<(u32, u32)>> {
+    let Some(base) = pieces.iter().map(|&(addr, ..)| addr).min() else {
+        return Ok(None);
+    };
+    let end = pieces
+        .iter()
+        .map(|&(addr, _, size)| addr + size)
+        .max()
+        .unwrap_or(base);
+    match (u32::try_from(base), u32::try_from(end - base)) {
+        (Ok(base), Ok(size)) if end <= 1 << 32 => Ok(Some((base, size))),
+        _ => bail!("The program at {base:#010x}..{end:#010x} doesn't fit in the address space"),
+    }
+}
+
+impl TryFrom<&ElfBytes<'_, AnyEndian>> for MemoryBus {
+    type Error = anyhow::Error;
+
+    /// Load an ELF executable, see `MemoryBus::from_elf`.
+    fn try_from(elf: &ElfBytes<'_, AnyEndian>) -> Result<Self> {
+        Self::from_elf(elf).map(|(memory, ..)| memory)
+    }
+}
+
+/// The access permissions of a memory region.
+#[allow(clippy::struct_excessive_bools)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Permissions {
+    pub read: bool,
+    pub write: bool,
+    pub execute: bool,
+}
+
+impl fmt::Display for Permissions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}{}{}",
+            if self.read { 'r' } else { '-' },
+            if self.write { 'w' } else { '-' },
+            if self.execute { 'x' } else { '-' }
+        )
+    }
+}
+
+/// A description of one of the memory regions attached to the bus.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct RegionInfo {
+    pub name: &'static str,
+    pub base: u32,
+    pub size: u32,
+    pub permissions: Permissions,
+}
+
+/// A store to memory, along with the value it overwrote.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct MemoryWrite {
+    pub addr: u32,
+    pub size: Size,
+    /// the value at `addr` before the store
+    pub previous: u32,
+}
+
+/// How effective the decoded instruction cache has been, see `MemoryBus::decode_cache_stats`.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub struct DecodeCacheStats {
+    /// fetches of an instruction that was already decoded
+    pub hits: u64,
+    /// fetches that had to decode the instruction
+    pub misses: u64,
+    /// the number of instructions currently cached
+    pub entries: usize,
+}
+
 /// The system bus.
 #[allow(clippy::module_name_repetitions)]
 pub struct MemoryBus {
     dram: MemoryRegion,
     text: MemoryRegion,
+    /// the address execution starts at, somewhere in the text section
+    entrypoint: u32,
+    /// the start of the heap, just past the static data
+    heap_base: u32,
+    /// the current program break, the heap spans `heap_base..heap_end`
+    heap_end: u32,
+    /// whether halfword and word accesses must be aligned to their size
+    strict_alignment: bool,
+    /// whether gp-relative accesses must be within the static data, see `set_gp_check`
+    gp_check: bool,
+    /// accesses to `0..null_guard` trap as null pointer dereferences
+    null_guard: u32,
+    /// the address of the exit device, if any, see `set_tohost`
+    tohost: Option<u32>,
+    /// the stores made since the journal was last taken
+    journal: Vec<MemoryWrite>,
+    /// the last load or store made with `load` or `store`, see `take_access`
+    access: Cell<Option<MemoryAccess>>,
+    /// the instructions (and their widths) already decoded from the text section, by address
+    ///
+    /// the text section is read-only, so entries only go stale when the cache is explicitly cleared (e.g. by `fence.i`)
+    decode_cache: RefCell<HashMap<u32, (Rv32imInstruction, Size)>>,
+    decode_cache_hits: Cell<u64>,
+    decode_cache_misses: Cell<u64>,
 }
 
 impl MemoryBus {
     /// Create a new `MemoryBus` object.
-    #[must_use]
-    pub fn new(entrypoint: u32, code: &[u8], data: &[u8]) -> Self {
-        #[allow(clippy::cast_possible_truncation)] // we know that the code length is less than 4GB
-        let dram_start = entrypoint + code.len() as u32 + 0x1000;
-        let mut dram = MemoryRegion::new(dram_start, DRAM_END - dram_start);
-        dram.initialize(data);
-        #[allow(clippy::cast_possible_truncation)] // we know that the code length is less than 4GB
-        let mut text = MemoryRegion::new(entrypoint, code.len() as u32 + 4);
-        text.initialize(code);
+    ///
+    /// The data section is placed 4KB past the end of the text section.
+    ///
+    /// # Errors
+    ///
+    /// This method will return an error if the text or data section doesn't fit in memory.
+    pub fn new(entrypoint: u32, code: &[u8], data: &[u8]) -> Result<Self> {
+        let dram_start = u32::try_from(code.len())
+            .ok()
+            .and_then(|len| entrypoint.checked_add(len)?.checked_add(0x1000))
+            .with_context(|| {
+                format!("The text section at {entrypoint:#010x} doesn't fit in memory")
+            })?;
+        Self::with_data_base(entrypoint, code, data, dram_start)
+    }
+
+    /// Create a new `MemoryBus` object, with the data section placed at `dram_start`,
+    /// e.g. the address the `.data` section was linked at.
+    ///
+    /// The data region ends at `DRAM_END`, or, for data placed at or above it (like bare-metal programs,
+    /// which are usually linked at `0x8000_0000`), at the end of the address space.
+    ///
+    /// Data linked below the text section ends at the start of the text section instead.
+    ///
+    /// # Errors
+    ///
+    /// This method will return an error if the data section would overlap the text section,
+    /// or either wouldn't fit in its region.
+    pub fn with_data_base(
+        entrypoint: u32,
+        code: &[u8],
+        data: &[u8],
+        dram_start: u32,
+    ) -> Result<Self> {
+        let code_size =
+            u32::try_from(code.len()).context("The text section doesn't fit in memory")?;
+        let data_size =
+            u32::try_from(data.len()).context("The data section doesn't fit in memory")?;
+        let mut memory = Self::with_layout(entrypoint, code_size, dram_start, data_size)?;
+        memory.text.initialize(code);
+        memory.dram.initialize(data);
+        Ok(memory)
+    }
+
+    /// Create an empty `MemoryBus` object, with a `code_size` byte text section at `text_base`,
+    /// and `data_size` bytes of static data at `dram_start`, see `with_data_base`.
+    fn with_layout(
+        text_base: u32,
+        code_size: u32,
+        dram_start: u32,
+        data_size: u32,
+    ) -> Result<Self> {
+        let text_end = u64::from(text_base) + u64::from(code_size);
+        if text_end > 1 << 32 {
+            bail!("The text section at {text_base:#010x} doesn't fit in the address space");
+        }
+        let data_end = u64::from(dram_start) + u64::from(data_size);
+        // the data region (and the heap in it) can't start in the text, and data below the text can't run into it
+        let starts_in_text = (u64::from(text_base)..text_end).contains(&u64::from(dram_start));
+        if starts_in_text || (dram_start < text_base && data_end > u64::from(text_base)) {
+            bail!(
+                "The data section at {dram_start:#010x}..{data_end:#010x} overlaps the text section at {text_base:#010x}..{text_end:#010x}"
+            );
+        }
+        let mut dram_end = if dram_start < DRAM_END {
+            u64::from(DRAM_END)
+        } else {
+            1 << 32
+        };
+        if dram_start < text_base {
+            dram_end = dram_end.min(u64::from(text_base));
+        }
+        if data_end > dram_end {
+            bail!("The data section at {dram_start:#010x} doesn't fit below {dram_end:#010x}");
+        }
+        #[allow(clippy::cast_possible_truncation)] // the region is at most 4GB minus the text
+        let dram = MemoryRegion::new(dram_start, (dram_end - u64::from(dram_start)) as u32);
+        let text = MemoryRegion::new(text_base, code_size);
+
+        // the heap starts at the end of the static data, aligned to a word boundary
+        let heap_base =
+            dram_start.saturating_add(STATIC_DATA_SIZE.max(data_size.next_multiple_of(4)));
+
+        Ok(Self {
+            dram,
+            text,
+            entrypoint: text_base,
+            heap_base,
+            heap_end: heap_base,
+            strict_alignment: false,
+            gp_check: false,
+            null_guard: NULL_GUARD_SIZE,
+            tohost: None,
+            journal: Vec::new(),
+            access: Cell::new(None),
+            decode_cache: RefCell::new(HashMap::new()),
+            decode_cache_hits: Cell::new(0),
+            decode_cache_misses: Cell::new(0),
+        })
+    }
+
+    /// Load an ELF executable, with its code and data placed where they were linked
+    /// (so absolute references into the data, e.g. from `la`, are correct).
+    ///
+    /// The program is loaded by its `PT_LOAD` segments: the executable ones make up the text section, and the rest the data,
+    /// with the memory past the end of each segment's contents zeroed. Programs without program headers are loaded
+    /// by their `.text` and `.data` sections instead.
+    ///
+    /// Returns the memory, the entrypoint, and the global pointer (the `__global_pointer$` symbol), if any.
+    /// The global pointer isn't checked, see `check_global_pointer`.
+    ///
+    /// # Errors
+    ///
+    /// This method will return an error if the ELF file has no executable segment (or `.text` section),
+    /// the text isn't whole instructions, the entrypoint isn't in the text, or the program doesn't fit in the address space.
+    pub fn from_elf(elf: &ElfBytes<AnyEndian>) -> Result<(Self, u32, Option<u32>)> {
+        // the entrypoint should fit in a u32, if it doesn't, the file is invalid
+        let entrypoint = u32::try_from(elf.ehdr.e_entry)?;
+
+        let segments: Vec<_> = elf
+            .segments()
+            .into_iter()
+            .flatten()
+            .filter(|segment| segment.p_type == PT_LOAD && segment.p_memsz > 0)
+            .collect();
+        let mut memory = if segments.is_empty() {
+            Self::from_sections(elf)?
+        } else {
+            Self::from_segments(elf, &segments)?
+        };
+        // with compressed instructions, the text only needs to be whole halfwords
+        if !memory.code_size().is_multiple_of(2) {
+            bail!("Text section length is not a multiple of 2, this is not a valid RISC-V binary");
+        }
+        if entrypoint.wrapping_sub(memory.text_base()) >= memory.code_size() {
+            bail!("The entrypoint {entrypoint:#010x} is outside of the text section");
+        }
+        memory.entrypoint = entrypoint;
+
+        // `__global_pointer$` is a symbol, not a section
+        let gp = find_symbol(elf, "__global_pointer$")?;
+        Ok((memory, entrypoint, gp))
+    }
+
+    /// Load the executable `segments` as the text section, and the rest as the data section, see `from_elf`.
+    fn from_segments(elf: &ElfBytes<AnyEndian>, segments: &[ProgramHeader]) -> Result<Self> {
+        // the first segment usually maps the ELF and program headers too, which aren't code
+        let ehdr = &elf.ehdr;
+        let headers_end = (ehdr.e_phoff + u64::from(ehdr.e_phnum) * u64::from(ehdr.e_phentsize))
+            .max(u64::from(ehdr.e_ehsize))
+            .next_multiple_of(2);
+
+        let mut text = Vec::new();
+        let mut data = Vec::new();
+        for segment in segments {
+            if segment.p_filesz > segment.p_memsz {
+                bail!(
+                    "The segment at {:#010x} is larger in the file than in memory",
+                    segment.p_vaddr
+                );
+            }
+            let contents = elf.segment_data(segment)?;
+            if segment.p_flags & PF_X == 0 {
+                data.push((segment.p_vaddr, contents, segment.p_memsz));
+            } else {
+                let skip = headers_end
+                    .saturating_sub(segment.p_offset)
+                    .min(segment.p_filesz);
+                text.push((
+                    segment.p_vaddr + skip,
+                    &contents[usize::try_from(skip)?..],
+                    segment.p_memsz - skip,
+                ));
+            }
+        }
 
-        Self { dram, text }
+        let Some((text_base, code_size)) = span(&text)? else {
+            bail!("No executable segment found")
+        };
+        // without data, the data section is placed like `new` does
+        let (dram_start, data_size) = match span(&data)? {
+            Some(span) => span,
+            None => (
+                text_base
+                    .checked_add(code_size)
+                    .and_then(|text_end| text_end.checked_add(0x1000))
+                    .with_context(|| {
+                        format!("The text section at {text_base:#010x} doesn't fit in memory")
+                    })?,
+                0,
+            ),
+        };
+        let mut memory = Self::with_layout(text_base, code_size, dram_start, data_size)?;
+        // only the contents are copied in, the rest of each segment reads as zero without being allocated
+        for (addr, contents, _) in text {
+            #[allow(clippy::cast_possible_truncation)] // within the text section
+            memory
+                .text
+                .copy_in((addr - u64::from(text_base)) as u32, contents);
+        }
+        for (addr, contents, _) in data {
+            #[allow(clippy::cast_possible_truncation)] // within the data section
+            memory
+                .dram
+                .copy_in((addr - u64::from(dram_start)) as u32, contents);
+        }
+        Ok(memory)
+    }
+
+    /// Load the `.text` and `.data` sections, for programs without program headers, see `from_elf`.
+    fn from_sections(elf: &ElfBytes<AnyEndian>) -> Result<Self> {
+        let Some(text_header) = elf.section_header_by_name(".text")? else {
+            bail!("No .text section found")
+        };
+        let (text, _) = elf.section_data(&text_header)?;
+        let text_base = u32::try_from(text_header.sh_addr)?;
+
+        match elf.section_header_by_name(".data")? {
+            Some(data_header) => Self::with_data_base(
+                text_base,
+                text,
+                elf.section_data(&data_header)?.0,
+                u32::try_from(data_header.sh_addr)?,
+            ),
+            None => Self::new(text_base, text, &[]),
+        }
     }
 
     /// get the size of the text segment in bytes
@@ -169,6 +609,12 @@ impl MemoryBus {
     /// get the entrypoint of the program
     #[must_use]
     pub const fn entrypoint(&self) -> u32 {
+        self.entrypoint
+    }
+
+    /// get the address the text segment starts at, which is the entrypoint unless the program was loaded with `from_elf`
+    #[must_use]
+    pub const fn text_base(&self) -> u32 {
         self.text.base
     }
 
@@ -182,23 +628,328 @@ impl MemoryBus {
         self.dram.size
     }
 
+    /// get the address the heap starts at
+    #[must_use]
+    pub const fn heap_base(&self) -> u32 {
+        self.heap_base
+    }
+
+    /// get the current program break (the first address past the end of the heap)
+    #[must_use]
+    pub const fn heap_end(&self) -> u32 {
+        self.heap_end
+    }
+
+    /// get the highest address the heap may grow to
+    ///
+    /// the heap and the stack grow towards each other, so this is the top of the stack
+    #[must_use]
+    pub const fn heap_ceiling(&self) -> u32 {
+        STACK_CEILING
+    }
+
+    /// Move the program break by `increment` bytes.
+    ///
+    /// Returns the previous program break, i.e. the start of the newly allocated memory when growing the heap.
+    ///
+    /// # Errors
+    ///
+    /// This method will return an error if the new program break would be below the heap base or above the heap ceiling.
+    pub fn sbrk(&mut self, increment: i32) -> Result<u32> {
+        let previous = self.heap_end;
+        match previous.checked_add_signed(increment) {
+            Some(new_end) if new_end >= self.heap_base && new_end <= self.heap_ceiling() => {
+                self.heap_end = new_end;
+                Ok(previous)
+            }
+            _ => bail!(
+                "Cannot move the program break from {previous:#010x} by {increment} bytes, the heap spans {:#010x}..{:#010x}",
+                self.heap_base,
+                self.heap_ceiling()
+            ),
+        }
+    }
+
+    /// Check that the global pointer `gp` points into the data region, within reach of the static data.
+    ///
+    /// Returns a description of the problem if it doesn't, as the program may still run if it doesn't use gp.
+    #[must_use]
+    pub fn check_global_pointer(&self, gp: u32) -> Option<String> {
+        if !self.dram.contains(gp) {
+            Some(format!(
+                "__global_pointer$ ({gp:#010x}) is outside of the data region ({:#010x}..{DRAM_END:#010x})",
+                self.dram_start()
+            ))
+        } else if !self.global_pointer_reaches_static_data(gp) {
+            Some(format!(
+                "__global_pointer$ ({gp:#010x}) is out of reach of the static data at {:#010x}, gp-relative accesses will miss it",
+                self.dram_start()
+            ))
+        } else {
+            None
+        }
+    }
+
+    /// whether gp-relative accesses, whose offsets are 12-bit signed immediates, can reach the static data
+    /// (i.e. `gp - 2048..gp + 2048` overlaps `dram_start..heap_base`)
+    #[must_use]
+    pub const fn global_pointer_reaches_static_data(&self, gp: u32) -> bool {
+        gp.saturating_sub(2048) < self.heap_base && gp.saturating_add(2048) > self.dram_start()
+    }
+
+    /// whether misaligned halfword and word accesses trap
+    #[must_use]
+    pub const fn strict_alignment(&self) -> bool {
+        self.strict_alignment
+    }
+
+    /// Set whether misaligned halfword and word accesses trap.
+    ///
+    /// The RISC-V spec allows misaligned accesses to either be handled or trap, by default they are handled.
+    pub const fn set_strict_alignment(&mut self, strict_alignment: bool) {
+        self.strict_alignment = strict_alignment;
+    }
+
+    /// whether gp-relative accesses outside of the static data trap
+    #[must_use]
+    pub const fn gp_check(&self) -> bool {
+        self.gp_check
+    }
+
+    /// Set whether gp-relative accesses (loads and stores whose base register is `gp`) outside of the static data trap.
+    ///
+    /// Linker relaxation rewrites accesses to global data as gp-relative ones,
+    /// which only reach the data if it's laid out where the linker expected, so accesses that miss it indicate a layout mismatch.
+    pub const fn set_gp_check(&mut self, gp_check: bool) {
+        self.gp_check = gp_check;
+    }
+
+    /// whether `addr` is in the static data, i.e. `dram_start..heap_base`
+    #[must_use]
+    pub const fn in_static_data(&self, addr: u32) -> bool {
+        addr >= self.dram_start() && addr < self.heap_base
+    }
+
+    /// Check a gp-relative access (a store, if `store`) to `addr`, see `set_gp_check`.
+    ///
+    /// # Errors
+    ///
+    /// This method will return a `Trap::GpRelativeAccessOutsideStaticData` if the check is enabled and `addr` is outside of the static data.
+    pub fn check_gp_relative(&self, addr: u32, store: bool) -> Result<()> {
+        if self.gp_check && !self.in_static_data(addr) {
+            return Err(Trap::GpRelativeAccessOutsideStaticData { addr, store }.into());
+        }
+        Ok(())
+    }
+
+    /// get the size of the guard range at the bottom of the address space
+    #[must_use]
+    pub const fn null_guard(&self) -> u32 {
+        self.null_guard
+    }
+
+    /// Set the size of the guard range at the bottom of the address space.
+    ///
+    /// Loads and stores to `0..null_guard` trap as null pointer dereferences, a size of 0 disables the guard.
+    pub const fn set_null_guard(&mut self, null_guard: u32) {
+        self.null_guard = null_guard;
+    }
+
+    /// get the address of the exit device, if any
+    #[must_use]
+    pub const fn tohost(&self) -> Option<u32> {
+        self.tohost
+    }
+
+    /// Map the exit device used by bare-metal test programs (e.g. riscv-tests) at `tohost`, or unmap it with `None`.
+    ///
+    /// A store to `tohost` halts the machine with a `Trap::Halt` carrying the stored value,
+    /// which is 1 if the program passed, and a failure code otherwise.
+    pub const fn set_tohost(&mut self, tohost: Option<u32>) {
+        self.tohost = tohost;
+    }
+
+    /// Look up the instruction decoded from `addr`, counting the lookup as a hit or a miss.
+    pub(crate) fn cached_instruction(&self, addr: u32) -> Option<(Rv32imInstruction, Size)> {
+        let cached = self.decode_cache.borrow().get(&addr).copied();
+        let counter = if cached.is_some() {
+            &self.decode_cache_hits
+        } else {
+            &self.decode_cache_misses
+        };
+        counter.set(counter.get() + 1);
+        cached
+    }
+
+    /// Remember the instruction decoded from `addr`.
+    pub(crate) fn cache_instruction(&self, addr: u32, instruction: Rv32imInstruction, width: Size) {
+        self.decode_cache
+            .borrow_mut()
+            .insert(addr, (instruction, width));
+    }
+
+    /// Forget every decoded instruction, so they're decoded from memory again when next fetched.
+    pub fn clear_decode_cache(&mut self) {
+        self.decode_cache.get_mut().clear();
+    }
+
+    /// get the hit and miss counts of the decoded instruction cache
+    #[must_use]
+    pub fn decode_cache_stats(&self) -> DecodeCacheStats {
+        DecodeCacheStats {
+            hits: self.decode_cache_hits.get(),
+            misses: self.decode_cache_misses.get(),
+            entries: self.decode_cache.borrow().len(),
+        }
+    }
+
+    /// A checksum of the contents of memory (the text and data regions), for quickly comparing states.
+    ///
+    /// Memory with the same contents has the same checksum, regardless of which pages were written to reach it.
+    #[must_use]
+    pub fn checksum(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.text.hash_contents(&mut hasher);
+        self.dram.hash_contents(&mut hasher);
+        hasher.finish()
+    }
+
+    /// whether `addr` is within the null pointer guard range
+    const fn is_null(&self, addr: u32) -> bool {
+        addr < self.null_guard
+    }
+
+    /// whether `addr` violates the alignment requirements for a `size`-bit access
+    const fn is_misaligned(&self, addr: u32, size: Size) -> bool {
+        self.strict_alignment && !addr.is_multiple_of(size.bytes())
+    }
+
+    /// Check that a `size`-bit access at `addr` doesn't start in one memory region and run past its end,
+    /// e.g. into the gap between the text and data regions.
+    fn check_span(&self, addr: u32, size: Size) -> Result<()> {
+        let spans = [&self.text, &self.dram]
+            .into_iter()
+            .any(|region| region.contains(addr) && !region.contains_range(addr, size.bytes()));
+        if spans {
+            bail!(
+                "Access of {} bytes at {addr:#010x} spans multiple memory regions",
+                size.bytes()
+            );
+        }
+        Ok(())
+    }
+
     /// Load a `size`-bit data from the device that connects to the system bus.
     ///
     /// This method is used to read from the memory.
     ///
     /// # Errors
     ///
-    /// This method will return an error if the address is out of bounds.
+    /// This method will return an error if the address is out of bounds or within the null pointer guard range,
+    /// or if strict alignment is enabled and the address is misaligned.
     pub fn read(&self, addr: u32, size: Size) -> Result<u32> {
+        if self.is_null(addr) {
+            return Err(Trap::NullPointerDereference { addr, store: false }.into());
+        }
+        if self.is_misaligned(addr, size) {
+            return Err(Trap::LoadAddressMisaligned { addr }.into());
+        }
+        self.check_span(addr, size)?;
         match addr {
-            addr if addr >= self.entrypoint() && addr <= self.entrypoint() + self.code_size() => {
-                self.text.read(addr, size)
-            }
-            addr if addr >= self.dram_start() && addr <= DRAM_END => self.dram.read(addr, size),
+            addr if self.text.contains(addr) => self.text.read(addr, size),
+            addr if self.dram.contains(addr) => self.dram.read(addr, size),
+            _ => bail!("Unkown or Out-Of-Bounds memory region addressed"),
+        }
+    }
+
+    /// Describe the memory regions attached to the bus, in order of increasing address.
+    #[must_use]
+    pub const fn regions(&self) -> [RegionInfo; 2] {
+        [
+            RegionInfo {
+                name: "text",
+                base: self.text.base,
+                size: self.text.size,
+                permissions: Permissions {
+                    read: true,
+                    write: false,
+                    execute: true,
+                },
+            },
+            RegionInfo {
+                name: "data",
+                base: self.dram.base,
+                size: self.dram.size,
+                permissions: Permissions {
+                    read: true,
+                    write: true,
+                    execute: false,
+                },
+            },
+        ]
+    }
+
+    /// Read `count` consecutive `size`-bit values starting at `addr`, e.g. the elements of an array.
+    ///
+    /// # Errors
+    ///
+    /// This method will return an error if any of the values can't be read, see `read`.
+    pub fn read_array(&self, addr: u32, count: u32, size: Size) -> Result<Vec<u32>> {
+        (0..count)
+            .map(|i| self.read(addr.wrapping_add(i.wrapping_mul(size.bytes())), size))
+            .collect()
+    }
+
+    /// Copy `len` bytes of memory starting at `addr`.
+    ///
+    /// # Errors
+    ///
+    /// This method will return an error if the range isn't entirely within one memory region.
+    pub fn read_bytes(&self, addr: u32, len: u32) -> Result<Vec<u8>> {
+        match addr {
+            addr if self.text.contains(addr) => self.text.bytes(addr, len),
+            addr if self.dram.contains(addr) => self.dram.bytes(addr, len),
             _ => bail!("Unkown or Out-Of-Bounds memory region addressed"),
         }
     }
 
+    /// Store `bytes` to memory starting at `addr`, a byte at a time, as the program would.
+    ///
+    /// # Errors
+    ///
+    /// This method will return the first error returned by `write`, leaving the bytes before it written,
+    /// or an error if the bytes would run past the end of the address space.
+    pub fn write_bytes(&mut self, addr: u32, bytes: &[u8]) -> Result<()> {
+        for (offset, &byte) in bytes.iter().enumerate() {
+            self.write(byte_address(addr, offset)?, u32::from(byte), Size::Byte)?;
+        }
+        Ok(())
+    }
+
+    /// Copy `bytes` into memory starting at `addr`, bypassing the checks stores are subject to,
+    /// e.g. to install code in the read-only text section while setting up a test.
+    ///
+    /// Nothing is journaled, so the write can't be undone, and the decoded instruction cache is cleared.
+    ///
+    /// # Errors
+    ///
+    /// This method will return an error if the range isn't entirely within one memory region.
+    pub fn force_write_bytes(&mut self, addr: u32, bytes: &[u8]) -> Result<()> {
+        let len = u32::try_from(bytes.len())?;
+        let region = if self.text.contains_range(addr, len) {
+            &mut self.text
+        } else if self.dram.contains_range(addr, len) {
+            &mut self.dram
+        } else {
+            bail!("Address range {addr:#010x}+{len} isn't within a memory region");
+        };
+        for (offset, &byte) in bytes.iter().enumerate() {
+            region.write(byte_address(addr, offset)?, u32::from(byte), Size::Byte)?;
+        }
+        self.clear_decode_cache();
+        Ok(())
+    }
+
     /// Store a `size`-bit data to the device that connects to the system bus.
     ///
     /// This method is used to write to the memory.
@@ -207,15 +958,592 @@ impl MemoryBus {
     ///
     /// This method will return an error if the address is out of bounds.
     /// or if the address is in the text section. (self modifying code is not supported)
+    /// or if the address is within the null pointer guard range.
+    /// or if strict alignment is enabled and the address is misaligned.
     pub fn write(&mut self, addr: u32, value: u32, size: Size) -> Result<()> {
+        if self.tohost == Some(addr) {
+            return Err(Trap::Halt { value }.into());
+        }
+        if self.is_null(addr) {
+            return Err(Trap::NullPointerDereference { addr, store: true }.into());
+        }
+        if self.is_misaligned(addr, size) {
+            return Err(Trap::StoreAddressMisaligned { addr }.into());
+        }
+        self.check_span(addr, size)?;
         match addr {
-            addr if addr >= self.entrypoint() && addr <= self.entrypoint() + self.code_size() => {
-                bail!("Self modifying code is not supported")
+            addr if self.text.contains(addr) => {
+                bail!(self.describe_text_write(addr, value, size))
             }
-            addr if addr >= self.dram_start() && addr <= DRAM_END => {
-                self.dram.write(addr, value, size)
+            addr if self.dram.contains(addr) => {
+                let previous = self.dram.read(addr, size)?;
+                self.dram.write(addr, value, size)?;
+                self.journal.push(MemoryWrite {
+                    addr,
+                    size,
+                    previous,
+                });
+                Ok(())
             }
             _ => bail!("Unkown memory region addressed"),
         }
     }
+
+    /// Push `value` onto the stack: decrement `sp` by a word, and store `value` where it now points.
+    ///
+    /// `sp` is left unchanged if the push fails.
+    ///
+    /// # Errors
+    ///
+    /// This method will return an error if `sp` isn't word aligned, if the push would grow the stack into
+    /// the heap, or if the store fails (see `write`).
+    pub fn push_word(&mut self, sp: &mut u32, value: u32) -> Result<()> {
+        if !sp.is_multiple_of(4) {
+            bail!("Cannot push with a misaligned stack pointer ({sp:#010x})");
+        }
+        let new_sp = sp.wrapping_sub(4);
+        if new_sp < self.heap_end || new_sp >= STACK_CEILING {
+            bail!(
+                "Stack overflow: cannot push below {sp:#010x}, the stack spans {:#010x}..{STACK_CEILING:#010x}",
+                self.heap_end
+            );
+        }
+        self.write(new_sp, value, Size::Word)?;
+        *sp = new_sp;
+        Ok(())
+    }
+
+    /// Pop a word off of the stack: load the value `sp` points to, and increment `sp` past it.
+    ///
+    /// `sp` is left unchanged if the pop fails.
+    ///
+    /// # Errors
+    ///
+    /// This method will return an error if `sp` isn't word aligned, if the stack is empty (`sp` is at or above
+    /// the top of the stack), or if the load fails (see `read`).
+    pub fn pop_word(&self, sp: &mut u32) -> Result<u32> {
+        if !sp.is_multiple_of(4) {
+            bail!("Cannot pop with a misaligned stack pointer ({sp:#010x})");
+        }
+        if *sp < self.heap_end || *sp >= STACK_CEILING {
+            bail!(
+                "Stack underflow: cannot pop at {sp:#010x}, the stack spans {:#010x}..{STACK_CEILING:#010x}",
+                self.heap_end
+            );
+        }
+        let value = self.read(*sp, Size::Word)?;
+        *sp += 4;
+        Ok(value)
+    }
+
+    /// Describe a rejected store of `value` to `addr` in the text section, and the instruction it would have modified.
+    fn describe_text_write(&self, addr: u32, value: u32, size: Size) -> String {
+        // find the instruction containing `addr` by walking the instructions from the start of the text,
+        // with compressed instructions, a word boundary isn't necessarily an instruction boundary
+        let mut pc = self.text_base();
+        let instruction = loop {
+            let Ok(low_half) = self.read(pc, Size::Half) else {
+                break None;
+            };
+            let width = instruction_width(low_half);
+            if addr.wrapping_sub(pc) < width.bytes() {
+                break Some((pc, width));
+            }
+            pc = pc.wrapping_add(width.bytes());
+        };
+        // decoded directly, rather than fetched, so this error path doesn't fill the decode cache
+        let instruction = instruction.and_then(|(pc, width)| {
+            let machine_code = self.read(pc, width).ok()?;
+            let instruction = Rv32imInstruction::from_machine_code_with_width(machine_code, width);
+            Some((pc, instruction.ok()?))
+        });
+        let target = instruction.map_or_else(
+            || "an invalid instruction".to_string(),
+            |(pc, instruction)| format!("`{}` at {pc:#010x}", instruction.disassemble(pc)),
+        );
+        format!(
+            "Self modifying code is not supported: storing {value:#x} ({} bits) to {addr:#010x} (offset {:#x} into the text section) would modify {target}",
+            size as u32,
+            addr - self.text_base()
+        )
+    }
+
+    /// Load a `size`-bit value from `addr` on behalf of the program, like `read`,
+    /// remembering the access so it can be reported (see `take_access`).
+    ///
+    /// Custom executors should load through this, so their loads are reported like those of the built-in instructions.
+    ///
+    /// # Errors
+    ///
+    /// This method will return an error if the read fails, see `read`.
+    pub fn load(&self, addr: u32, size: Size) -> Result<u32> {
+        let value = self.read(addr, size)?;
+        self.access.set(Some(MemoryAccess {
+            addr,
+            size,
+            value,
+            store: false,
+        }));
+        Ok(value)
+    }
+
+    /// Store a `size`-bit value to `addr` on behalf of the program, like `write`,
+    /// remembering the access so it can be reported (see `take_access`).
+    ///
+    /// Custom executors should store through this, so their stores are reported like those of the built-in instructions.
+    ///
+    /// # Errors
+    ///
+    /// This method will return an error if the write fails, see `write`.
+    pub fn store(&mut self, addr: u32, value: u32, size: Size) -> Result<()> {
+        self.write(addr, value, size)?;
+        self.access.set(Some(MemoryAccess {
+            addr,
+            size,
+            value: value & (u32::MAX >> (32 - size as u32)),
+            store: true,
+        }));
+        Ok(())
+    }
+
+    /// Take the last load or store made with `load` or `store` since the access was last taken.
+    pub fn take_access(&mut self) -> Option<MemoryAccess> {
+        self.access.take()
+    }
+
+    /// Take the stores made since the journal was last taken, in the order they were made.
+    pub fn take_journal(&mut self) -> Vec<MemoryWrite> {
+        std::mem::take(&mut self.journal)
+    }
+
+    /// Revert a store by writing back the value it overwrote.
+    ///
+    /// The revert itself isn't journaled.
+    ///
+    /// # Errors
+    ///
+    /// This method will return an error if the address of the store is out of bounds.
+    pub fn revert(&mut self, write: &MemoryWrite) -> Result<()> {
+        self.dram.write(write.addr, write.previous, write.size)
+    }
+
+    /// Move the program break back to `heap_end`, undoing the `sbrk` calls made since it was there.
+    pub const fn revert_heap_end(&mut self, heap_end: u32) {
+        self.heap_end = heap_end;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        emulator::{cpu::registers::RegisterMapping, fetch::Fetch32BitInstruction as _},
+        instruction_set_definition::{assembly::program::Program, operations::ITypeOperation},
+        utils::words_to_bytes,
+    };
+
+    const ENTRYPOINT: u32 = 0x0040_0000;
+
+    #[test]
+    fn test_instructions_are_fetched_little_endian() -> Result<()> {
+        // addi a0, a1, -2 (0xffe5_8513), least significant byte first
+        let memory = MemoryBus::new(ENTRYPOINT, &[0x13, 0x85, 0xe5, 0xff], &[])?;
+        assert_eq!(memory.read(ENTRYPOINT, Size::Word)?, 0xffe5_8513);
+        assert_eq!(
+            memory.fetch_and_decode(ENTRYPOINT)?,
+            Rv32imInstruction::IType {
+                operation: ITypeOperation::Addi,
+                rd: RegisterMapping::A0,
+                funct3: 0,
+                rs1: RegisterMapping::A1,
+                imm: -2,
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_program_that_doesnt_fit_is_an_error() {
+        // the data section would start past the end of the address space
+        assert!(MemoryBus::new(0xFFFF_F000, &[0; 4], &[]).is_err());
+        // or run past the end of it
+        assert!(MemoryBus::new(ENTRYPOINT, &[0; 4], &[0; 4]).is_ok());
+        assert!(MemoryBus::new(0xFFFF_E000, &[0; 4], &[0; 0x1000]).is_err());
+        // programs can be placed past the end of the usual data region, like bare-metal ones are
+        let memory = MemoryBus::new(DRAM_END, &[0; 4], &[0; 4]).unwrap();
+        assert_eq!(memory.dram_start(), DRAM_END + 0x1004);
+        assert_eq!(memory.dram_size(), 0u32.wrapping_sub(DRAM_END + 0x1004));
+    }
+
+    #[test]
+    fn test_write_bytes_at_the_end_of_the_address_space() -> Result<()> {
+        // the data region ends at 2^32
+        let mut memory = MemoryBus::new(DRAM_END, &[0; 4], &[0; 4])?;
+        memory.force_write_bytes(0xFFFF_FFFF, &[7])?;
+        memory.write_bytes(0xFFFF_FFFE, &[1, 2])?;
+        assert_eq!(memory.read_bytes(0xFFFF_FFFE, 2)?, [1, 2]);
+
+        let error = memory
+            .write_bytes(0xFFFF_FFFF, &[3, 4])
+            .expect_err("the second byte is past the end of the address space");
+        assert!(
+            error
+                .to_string()
+                .contains("past the end of the address space"),
+            "{error}"
+        );
+        // the bytes before it are written
+        assert_eq!(memory.read(0xFFFF_FFFF, Size::Byte)?, 3);
+        Ok(())
+    }
+
+    #[test]
+    fn test_push_and_pop_words() -> Result<()> {
+        let mut memory = MemoryBus::new(ENTRYPOINT, &[0; 4], &[])?;
+        let mut sp = STACK_CEILING;
+        for value in [1, 2, 3] {
+            memory.push_word(&mut sp, value)?;
+        }
+        assert_eq!(sp, STACK_CEILING - 12);
+        assert_eq!(memory.read(sp, Size::Word)?, 3);
+
+        // last in, first out
+        for value in [3, 2, 1] {
+            assert_eq!(memory.pop_word(&mut sp)?, value);
+        }
+        assert_eq!(sp, STACK_CEILING);
+
+        // the stack is empty
+        assert!(memory.pop_word(&mut sp).is_err());
+        assert_eq!(sp, STACK_CEILING);
+
+        let mut misaligned = STACK_CEILING - 2;
+        assert!(memory.push_word(&mut misaligned, 1).is_err());
+        assert!(memory.pop_word(&mut misaligned).is_err());
+        assert_eq!(misaligned, STACK_CEILING - 2);
+
+        // the stack can't grow into the heap
+        let mut sp = memory.heap_end();
+        assert!(memory.push_word(&mut sp, 1).is_err());
+        assert_eq!(sp, memory.heap_end());
+        Ok(())
+    }
+
+    #[test]
+    fn test_self_modifying_code_names_the_instruction() {
+        let code = words_to_bytes(&[
+            0x0015_0513_u32, // addi a0, a0, 1
+            0x00b5_0533,     // add a0, a0, a1
+        ]);
+        let mut memory = MemoryBus::new(ENTRYPOINT, &code, &[]).unwrap();
+        let error = memory
+            .write(ENTRYPOINT + 6, 0xab, Size::Byte)
+            .expect_err("the text section is read-only");
+        assert_eq!(
+            error.to_string(),
+            "Self modifying code is not supported: storing 0xab (8 bits) to 0x00400006 (offset 0x6 into the text section) would modify `add a0, a0, a1` at 0x00400004"
+        );
+        assert_eq!(
+            memory.read(ENTRYPOINT + 4, Size::Word).unwrap(),
+            0x00b5_0533
+        );
+    }
+
+    #[test]
+    fn test_self_modifying_code_at_the_end_of_the_address_space() -> Result<()> {
+        // addi a0, a0, 1, as the last word of the address space
+        let mut memory = MemoryBus::with_data_base(
+            0xFFFF_FFFC,
+            &0x0015_0513_u32.to_le_bytes(),
+            &[],
+            0x1000_0000,
+        )?;
+        let error = memory
+            .write(0xFFFF_FFFF, 0xab, Size::Byte)
+            .expect_err("the text section is read-only");
+        assert!(
+            error
+                .to_string()
+                .ends_with("would modify `addi a0, a0, 1` at 0xfffffffc"),
+            "{error}"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_self_modifying_code_after_a_compressed_instruction() {
+        let mut code = 0x0001_u16.to_le_bytes().to_vec(); // c.nop
+        code.extend(0x00b5_0533_u32.to_le_bytes()); // add a0, a0, a1
+        code.extend(0x0001_u16.to_le_bytes()); // c.nop
+        let mut memory = MemoryBus::new(ENTRYPOINT, &code, &[]).unwrap();
+        // the word boundary at offset 4 is in the middle of the `add`
+        let error = memory
+            .write(ENTRYPOINT + 4, 0xab, Size::Byte)
+            .expect_err("the text section is read-only");
+        assert!(
+            error
+                .to_string()
+                .ends_with("would modify `add a0, a0, a1` at 0x00400002"),
+            "{error}"
+        );
+        let error = memory
+            .write(ENTRYPOINT + 6, 0xab, Size::Byte)
+            .expect_err("the text section is read-only");
+        assert!(
+            error
+                .to_string()
+                .ends_with("would modify `addi zero, zero, 0` at 0x00400006"),
+            "{error}"
+        );
+        // the error path doesn't fill the decode cache
+        assert!(memory.cached_instruction(ENTRYPOINT + 2).is_none());
+    }
+
+    #[test]
+    fn test_misaligned_word_load_permissive() -> Result<()> {
+        let memory = MemoryBus::new(ENTRYPOINT, &[0; 4], &[0x11, 0x22, 0x33, 0x44, 0x55, 0x66])?;
+        assert!(!memory.strict_alignment());
+        assert_eq!(
+            memory.read(memory.dram_start() + 1, Size::Word)?,
+            0x5544_3322
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_misaligned_word_load_strict() -> Result<()> {
+        let mut memory =
+            MemoryBus::new(ENTRYPOINT, &[0; 4], &[0x11, 0x22, 0x33, 0x44, 0x55, 0x66])?;
+        memory.set_strict_alignment(true);
+        let addr = memory.dram_start() + 1;
+
+        let error = memory
+            .read(addr, Size::Word)
+            .expect_err("misaligned word load should trap");
+        assert_eq!(
+            error.downcast_ref::<Trap>(),
+            Some(&Trap::LoadAddressMisaligned { addr })
+        );
+        let error = memory
+            .write(addr, 0, Size::Word)
+            .expect_err("misaligned word store should trap");
+        assert_eq!(
+            error.downcast_ref::<Trap>(),
+            Some(&Trap::StoreAddressMisaligned { addr })
+        );
+
+        // aligned accesses, and byte accesses, are still fine
+        assert_eq!(memory.read(addr - 1, Size::Word)?, 0x4433_2211);
+        assert_eq!(memory.read(addr, Size::Byte)?, 0x22);
+        Ok(())
+    }
+
+    #[test]
+    fn test_with_data_base() -> Result<()> {
+        let memory = MemoryBus::with_data_base(ENTRYPOINT, &[0; 4], &[1, 2, 3, 4], 0x1000_0000)?;
+        assert_eq!(memory.dram_start(), 0x1000_0000);
+        assert_eq!(memory.read(0x1000_0000, Size::Word)?, 0x0403_0201);
+
+        assert!(
+            MemoryBus::with_data_base(ENTRYPOINT, &[0; 4], &[], ENTRYPOINT).is_err(),
+            "data overlapping the text section accepted"
+        );
+        assert!(
+            MemoryBus::with_data_base(ENTRYPOINT, &[0; 4], &[0; 8], DRAM_END - 4).is_err(),
+            "data past the end of memory accepted"
+        );
+
+        // data linked below the text ends where the text starts
+        let memory = MemoryBus::with_data_base(0x8000_0000, &[0; 4], &[1, 2, 3, 4], 0x1000_0000)?;
+        assert_eq!(memory.dram_size(), 0x7000_0000);
+        assert!(
+            MemoryBus::with_data_base(0x1000_0004, &[0; 4], &[0; 8], 0x1000_0000).is_err(),
+            "data running into the text section accepted"
+        );
+        // text can end at the end of the address space
+        let memory = MemoryBus::with_data_base(0xFFFF_FFFC, &[1, 2, 3, 4], &[], 0x1000_0000)?;
+        assert_eq!(memory.read(0xFFFF_FFFC, Size::Word)?, 0x0403_0201);
+        Ok(())
+    }
+
+    #[test]
+    fn test_segments_are_loaded_lazily() -> Result<()> {
+        let program = Program {
+            text: vec![0x13, 0, 0, 0], // nop
+            data: vec![1, 2, 3, 4],
+            entrypoint: ENTRYPOINT,
+            data_address: 0x1000_0000,
+        };
+        let mut bytes = program.to_elf();
+        // give the data segment 1MB of .bss past its contents
+        let memsz = 52 + 32 + 20;
+        bytes[memsz..memsz + 4].copy_from_slice(&0x0010_0000_u32.to_le_bytes());
+        let elf = ElfBytes::<AnyEndian>::minimal_parse(&bytes)?;
+
+        let (memory, ..) = MemoryBus::from_elf(&elf)?;
+        assert_eq!(memory.read(0x1000_0000, Size::Word)?, 0x0403_0201);
+        assert_eq!(memory.read(0x100F_FFFC, Size::Word)?, 0);
+        // the heap starts past the .bss
+        assert_eq!(memory.heap_base, 0x1000_0000 + STATIC_DATA_SIZE);
+        // only the pages with contents are allocated
+        assert_eq!(memory.text.pages.len(), 1);
+        assert_eq!(memory.dram.pages.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_null_pointer_dereference() {
+        let mut memory = MemoryBus::new(ENTRYPOINT, &[0; 4], &[]).unwrap();
+
+        let error = memory
+            .read(4, Size::Word)
+            .expect_err("load from the zero page should trap");
+        assert_eq!(
+            error.downcast_ref::<Trap>(),
+            Some(&Trap::NullPointerDereference {
+                addr: 4,
+                store: false
+            })
+        );
+        let error = memory
+            .write(0, 0, Size::Byte)
+            .expect_err("store to the zero page should trap");
+        assert_eq!(
+            error.downcast_ref::<Trap>(),
+            Some(&Trap::NullPointerDereference {
+                addr: 0,
+                store: true
+            })
+        );
+
+        // with the guard disabled, the zero page is just unmapped memory
+        memory.set_null_guard(0);
+        let error = memory
+            .read(4, Size::Word)
+            .expect_err("the zero page is not mapped");
+        assert!(error.downcast_ref::<Trap>().is_none());
+    }
+
+    #[test]
+    fn test_tohost_write_halts() -> Result<()> {
+        let mut memory = MemoryBus::new(0x0040_0000, &[0; 8], &[])?;
+        let tohost = memory.dram_start() + 0x100;
+
+        // without the device, the address is ordinary memory
+        memory.write(tohost, 1, Size::Word)?;
+
+        memory.set_tohost(Some(tohost));
+        let error = memory
+            .write(tohost, 1, Size::Word)
+            .expect_err("a store to tohost should halt");
+        let trap = error.downcast_ref::<Trap>();
+        assert_eq!(trap, Some(&Trap::Halt { value: 1 }));
+        assert!(trap.is_some_and(Trap::passed));
+        Ok(())
+    }
+
+    #[test]
+    fn test_access_spanning_regions() -> Result<()> {
+        // the data section directly follows the text section
+        let memory = MemoryBus::with_data_base(0x0040_0000, &[0x11; 8], &[0x22; 8], 0x0040_0008)?;
+
+        assert_eq!(memory.read(0x0040_0004, Size::Word)?, 0x1111_1111);
+        assert_eq!(memory.read(0x0040_0008, Size::Word)?, 0x2222_2222);
+        let error = memory
+            .read(0x0040_0006, Size::Word)
+            .expect_err("a word straddling the end of the text section should fail");
+        assert!(error.to_string().contains("spans multiple memory regions"));
+
+        // the same goes for the top of memory
+        let mut memory = MemoryBus::new(0x0040_0000, &[0; 8], &[])?;
+        assert!(memory.read(DRAM_END - 2, Size::Word).is_err());
+        assert!(memory.write(DRAM_END - 2, 0, Size::Word).is_err());
+        memory.write(DRAM_END - 4, 0x1234_5678, Size::Word)?;
+        assert_eq!(memory.read(DRAM_END - 4, Size::Word)?, 0x1234_5678);
+        Ok(())
+    }
+
+    #[test]
+    fn test_region_bounds_account_for_the_access_size() -> Result<()> {
+        let mut region = MemoryRegion::new(0x1000, 8);
+        let last = 0x1000 + 7;
+
+        // the last byte is accessible on its own
+        region.write(last, 0xab, Size::Byte)?;
+        assert_eq!(region.read(last, Size::Byte)?, 0xab);
+        // but wider accesses starting there run past the end of the region
+        assert!(region.read(last, Size::Half).is_err());
+        assert!(region.read(last, Size::Word).is_err());
+        assert!(region.write(last, 0, Size::Word).is_err());
+        assert!(region.read(0x1000 + 5, Size::Word).is_err());
+        assert_eq!(region.read(0x1000 + 4, Size::Word)?, 0xab00_0000);
+        // and nothing is accessible at base + size
+        assert!(region.read(0x1000 + 8, Size::Byte).is_err());
+        assert!(region.write(0x1000 + 8, 0, Size::Byte).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_global_pointer() -> Result<()> {
+        let memory = MemoryBus::with_data_base(0x0040_0000, &[0; 8], &[0; 8], 0x1000_0000)?;
+        assert_eq!(memory.check_global_pointer(0x1000_0800), None);
+        assert!(memory.global_pointer_reaches_static_data(0x1000_0800));
+        // just below the data, but still in reach of it
+        assert!(memory
+            .check_global_pointer(0x0fff_fc00)
+            .is_some_and(|problem| problem.contains("outside of the data region")));
+        assert!(memory.global_pointer_reaches_static_data(0x0fff_fc00));
+        // in the data region, but out of reach of the static data
+        assert!(memory
+            .check_global_pointer(STACK_CEILING)
+            .is_some_and(|problem| problem.contains("out of reach of the static data")));
+        assert!(!memory.global_pointer_reaches_static_data(STACK_CEILING));
+        Ok(())
+    }
+
+    #[test]
+    fn test_checksum() -> Result<()> {
+        let mut memory = MemoryBus::new(0x0040_0000, &[0; 8], &[1, 2, 3, 4])?;
+        let reference = MemoryBus::new(0x0040_0000, &[0; 8], &[1, 2, 3, 4])?;
+        assert_eq!(memory.checksum(), reference.checksum());
+
+        let addr = memory.dram_start() + 3 * PAGE_SIZE + 5;
+        memory.write(addr, 1, Size::Byte)?;
+        assert_ne!(memory.checksum(), reference.checksum());
+
+        // writing the byte back leaves a page of zeros, which is the same as never writing it
+        memory.write(addr, 0, Size::Byte)?;
+        assert_eq!(memory.checksum(), reference.checksum());
+        Ok(())
+    }
+
+    #[test]
+    fn test_sparse_accesses() -> Result<()> {
+        let mut memory = MemoryBus::new(0x0040_0000, &[0; 8], &[1, 2, 3, 4])?;
+        let base = memory.dram_start();
+
+        // memory that was never written reads as zero
+        assert_eq!(memory.read(base + 0x0100_0000, Size::Word)?, 0);
+        assert_eq!(memory.read(DRAM_END - 4, Size::Word)?, 0);
+
+        // accesses far apart, and straddling a page boundary
+        let boundary = base + 16 * PAGE_SIZE;
+        memory.write(DRAM_END - 4, 0xdead_beef, Size::Word)?;
+        memory.write(boundary - 2, 0x1234_5678, Size::Word)?;
+        memory.write(STACK_CEILING, 0xab, Size::Byte)?;
+        assert_eq!(memory.read(base, Size::Word)?, 0x0403_0201);
+        assert_eq!(memory.read(DRAM_END - 4, Size::Word)?, 0xdead_beef);
+        assert_eq!(memory.read(boundary - 2, Size::Word)?, 0x1234_5678);
+        assert_eq!(memory.read(boundary - 2, Size::Half)?, 0x5678);
+        assert_eq!(memory.read(boundary, Size::Half)?, 0x1234);
+        assert_eq!(memory.read(STACK_CEILING, Size::Word)?, 0xab);
+
+        // copying a range spanning written and unwritten pages
+        assert_eq!(
+            memory.read_bytes(boundary - 4, 8)?,
+            [0, 0, 0x78, 0x56, 0x34, 0x12, 0, 0]
+        );
+        assert_eq!(memory.read_bytes(boundary + PAGE_SIZE, 3)?, [0, 0, 0]);
+        Ok(())
+    }
 }
@@ -22,24 +22,226 @@ OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 SOFTWARE.
 */
 
+mod call_stack;
+pub mod callee_saved;
+pub mod core_dump;
+pub mod csr;
+mod debugger;
+pub mod event_log;
+pub mod history;
+pub mod io;
 pub mod memory;
+pub mod profile;
+pub mod ra_guard;
 pub mod registers;
+pub mod snapshot;
 
-use std::fmt;
+use std::{
+    collections::HashMap,
+    fmt,
+    hash::{DefaultHasher, Hash as _, Hasher as _},
+    io::Write,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, RecvTimeoutError},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
 
-use anyhow::Result;
+use anyhow::{anyhow, bail, Context as _, Result};
 
+use call_stack::CallStack;
+use callee_saved::CalleeSavedChecker;
+use csr::CsrFile;
 use debugger::DebuggerCommand;
+use event_log::Recorder;
+use history::{CallTracking, History, ReverseStop, UndoRecord};
+use io::{Clock, ProgramInput, ProgramOutput, Rng};
 use memory::MemoryBus;
+use profile::HotProfile;
+use ra_guard::ReturnAddressGuard;
 use registers::{RegisterFile32Bit, RegisterMapping};
 
 use self::memory::STACK_CEILING;
 
-use super::{execute::Execute32BitInstruction as _, fetch::Fetch32BitInstruction as _};
+use crate::instruction_set_definition::{
+    isa::Isa,
+    operations::{Extension, ITypeOperation},
+    Rv32imInstruction,
+};
+
+use super::{
+    decode::CustomDecoder,
+    execute::{CustomExecutor, DivByZeroPolicy, Execute32BitInstruction as _, ProgramExit},
+    fetch::{
+        is_pc_aligned, Fetch32BitInstruction as _, COMPRESSED_INSTRUCTION_ALIGN, INSTRUCTION_ALIGN,
+    },
+    trap::{mcause, Trap},
+};
 
 /// the number of registers in the RISC-V ISA
 pub const REGISTERS_COUNT: u8 = 32;
 
+/// The smallest thread-local storage block `Cpu32Bit::set_up_tls` allocates, enough for `errno` and a little more.
+pub const MIN_TLS_SIZE: u32 = 64;
+
+/// the alignment of the thread-local storage block
+const TLS_ALIGN: u32 = 16;
+
+/// How many instructions `Cpu32Bit::run` executes between checks of the interrupt flag.
+pub const INTERRUPT_CHECK_INTERVAL: u64 = 1024;
+
+/// How many instructions a debugger continue runs for by default before prompting again, see `Cpu32Bit::continue_budget`.
+pub const DEFAULT_CONTINUE_BUDGET: u64 = 100_000_000;
+
+/// A host-side implementation of the function at some address, see `Cpu32Bit::hooks`.
+///
+/// It's called in place of the emulated function, with the arguments in the argument registers,
+/// and sets up the return value (e.g. in `a0`) before returning.
+pub type FunctionHook = Box<dyn FnMut(&mut Cpu32Bit) -> Result<()> + Send>;
+
+/// The reason `Cpu32Bit::run` stopped without an error.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum RunOutcome {
+    /// The maximum number of steps was executed.
+    StepLimitReached,
+    /// The interrupt flag was raised, e.g. by `run_with_timeout`.
+    Interrupted,
+    /// The program made the halt syscall, it can be resumed by running or stepping the CPU again.
+    Halted,
+    /// The condition given to `Cpu32Bit::run_until` became true.
+    ConditionMet,
+    /// The program executed an `ebreak`, which only stops runs without a debugger, see `Cpu32Bit::run_to_completion`.
+    Breakpoint,
+}
+
+/// How a program run by `Cpu32Bit::run_to_completion` finished.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct RunResult {
+    /// The code the program exited with, `None` if it stopped without exiting (see `outcome`)
+    pub exit_code: Option<u32>,
+    /// Why the run stopped, if the program didn't exit
+    pub outcome: Option<RunOutcome>,
+    /// Everything the program printed during the run
+    pub output: String,
+    /// The number of instructions retired during the run, an `ecall` that exits doesn't retire
+    pub instructions: u64,
+}
+
+/// What a single step did, see `Cpu32Bit::step_reported`.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct StepReport {
+    /// The address of the instruction executed
+    pub pc: u32,
+    /// The instruction executed, `None` if a hook ran instead (see `Cpu32Bit::hooks`)
+    pub instruction: Option<Rv32imInstruction>,
+    /// The machine code of the instruction executed, and its width (`Size::Half` if compressed)
+    pub encoding: Option<(u32, Size)>,
+    /// The program counter after the step
+    pub next_pc: u32,
+    /// The registers the step wrote, with their new values.
+    ///
+    /// This is the registers whose values changed, plus the instruction's destination register even if
+    /// it was written with the value it already held.
+    pub registers: Vec<(RegisterMapping, u32)>,
+    /// The memory the instruction loaded from or stored to, if it's a load or store
+    pub memory_access: Option<MemoryAccess>,
+}
+
+impl StepReport {
+    /// Format the step as a line of Spike's commit log (`--log-commits`), so traces can be diffed against it.
+    ///
+    /// Steps are reported as retired by hart 0 in machine mode, the register writes are followed by the address
+    /// of a load, or the address and value of a store.
+    /// Returns `None` if a hook ran instead of an instruction.
+    #[must_use]
+    pub fn spike_commit_log(&self) -> Option<String> {
+        use std::fmt::Write as _;
+
+        let (machine_code, width) = self.encoding?;
+        let mut line = format!(
+            "core   0: 3 0x{:08x} (0x{machine_code:0digits$x})",
+            self.pc,
+            digits = width as usize / 4
+        );
+        for &(reg, value) in &self.registers {
+            // writing to a `String` can't fail
+            let _ = write!(line, " x{:<2} 0x{value:08x}", reg as u8);
+        }
+        match self.memory_access {
+            Some(MemoryAccess {
+                addr, store: false, ..
+            }) => {
+                let _ = write!(line, " mem 0x{addr:08x}");
+            }
+            Some(MemoryAccess {
+                addr,
+                size,
+                value,
+                store: true,
+            }) => {
+                let _ = write!(
+                    line,
+                    " mem 0x{addr:08x} 0x{value:0digits$x}",
+                    digits = size as usize / 4
+                );
+            }
+            None => {}
+        }
+        Some(line)
+    }
+}
+
+/// A load or store made by a step, see `StepReport`.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct MemoryAccess {
+    /// The effective address, i.e. the base register plus the offset
+    pub addr: u32,
+    /// The size of the value loaded or stored
+    pub size: Size,
+    /// The value loaded (before sign extension) or stored (truncated to `size`)
+    pub value: u32,
+    /// whether the access was a store
+    pub store: bool,
+}
+
+/// A snapshot of the counters read by `rdcycle`, `rdtime`, and `rdinstret`.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub struct Counters {
+    /// The number of cycles executed.
+    pub cycle: u64,
+    /// The wall-clock time since the CPU was created, in microseconds.
+    pub time: u64,
+    /// The number of instructions retired.
+    pub instret: u64,
+}
+
+impl Counters {
+    /// The cost model the cycle counter follows.
+    pub const COST_MODEL: &'static str = "1 cycle per instruction";
+
+    /// A summary of the instructions and cycles executed, and the cycles per instruction (CPI) to two decimals,
+    /// labelled with the cost model.
+    #[must_use]
+    pub fn timing_report(&self) -> String {
+        if self.instret == 0 {
+            return format!(
+                "no instructions executed (cost model: {})",
+                Self::COST_MODEL
+            );
+        }
+        #[allow(clippy::cast_precision_loss)] // the ratio only needs two decimals
+        let cpi = self.cycle as f64 / self.instret as f64;
+        format!(
+            "{} instructions, {} cycles, CPI {cpi:.2} (cost model: {})",
+            self.instret,
+            self.cycle,
+            Self::COST_MODEL
+        )
+    }
+}
+
 /// The size of a memory access.
 #[repr(u8)]
 #[derive(Debug, PartialEq, Eq, Clone, Copy, Hash, PartialOrd, Ord)]
@@ -49,23 +251,114 @@ pub enum Size {
     Word = 32,
 }
 
+impl Size {
+    /// The number of bytes accessed.
+    #[must_use]
+    pub const fn bytes(self) -> u32 {
+        self as u32 / 8
+    }
+}
+
 #[allow(clippy::module_name_repetitions)]
+#[allow(clippy::struct_excessive_bools)] // these are independent flags, not a state machine
 pub struct Cpu32Bit {
     pub registers: RegisterFile32Bit,
     pub pc: u32,
     pub memory: MemoryBus,
     /// Whether the CPU should pause before executing the next instruction.
     pub debug: bool,
+    /// Whether the last instruction executed was the halt syscall.
+    pub halted: bool,
     /// The programs stdout
     pub output: String,
+    /// Whether the debugger streams the programs output as it's produced, instead of clearing the screen
+    /// and reprinting all of it at every prompt
+    pub watch_output: bool,
+    /// How much of `output` the debugger has shown, when watching the output
+    output_shown: usize,
+    /// The number of instructions retired so far
+    pub instret: u64,
+    /// The control and status registers, other than the counters
+    pub csrs: CsrFile,
+    /// When the CPU was created, the time counter counts up from here
+    started: Instant,
+    /// Where the programs stdout is written to
+    pub output_sink: ProgramOutput,
+    /// Where the programs stdin is read from
+    pub input: ProgramInput,
+    /// Where the `Time` syscall reads the time from
+    pub clock: Clock,
+    /// What the random syscalls draw from
+    pub rng: Rng,
+    /// Set from another thread to ask `run` to stop at its next check.
+    interrupt: Arc<AtomicBool>,
+    /// The state changes made by the most recent steps, so they can be undone
+    history: History,
+    /// The number of instructions left to execute before the debugger prompts again
+    pending_steps: u64,
+    /// The most instructions a debugger continue runs before prompting again, `None` for no limit
+    ///
+    /// this keeps continuing through an infinite loop from hanging the debugger
+    pub continue_budget: Option<u64>,
+    /// What's left of the continue budget, while continuing
+    continue_remaining: Option<u64>,
+    /// The calls that haven't returned yet, so the current function can be finished
+    call_stack: CallStack,
+    /// Where (and with what stack pointer) to stop when finishing the current function
+    return_stop: Option<(u32, u32)>,
+    /// The register whose next write stops execution, see `trap_on_write`
+    write_trap: Option<RegisterMapping>,
+    /// When set, stores that clobber a saved return address trap
+    pub ra_guard: Option<ReturnAddressGuard>,
+    /// When set, returning from a function that didn't restore a callee-saved register traps
+    pub callee_saved_checker: Option<CalleeSavedChecker>,
+    /// When set, counts how many times each instruction is executed
+    pub hot_profile: Option<HotProfile>,
+    /// When set, instructions outside of this ISA (e.g. `mul` without the M extension) are rejected
+    pub isa: Option<Isa>,
+    /// What dividing by zero does, the spec-defined results by default
+    pub div_by_zero: DivByZeroPolicy,
+    /// When set, every `ecall` overwrites the temporaries (`t0`-`t6`) with `ECALL_POISON`,
+    /// exposing programs that wrongly rely on them surviving a syscall
+    pub poison_temporaries: bool,
+    /// When set, `run_traced` logs faulting loads and stores to the trace and skips over them instead of stopping
+    pub continue_on_fault: bool,
+    /// When set, the program runs bare-metal, handling its own exceptions: `ecall`, `ebreak`, and the traps that have
+    /// an exception code jump to the trap handler at `mtvec` (see `take_trap`), rather than making syscalls
+    /// or stopping the program
+    pub bare_metal: bool,
+    /// Consulted for instructions outside of RV32IM, before giving up on decoding them
+    pub custom_decoder: Option<CustomDecoder>,
+    /// Executes the instructions decoded by `custom_decoder`
+    pub custom_executor: Option<CustomExecutor>,
+    /// Functions implemented natively, by their address.
+    ///
+    /// When the pc reaches a hooked address, the hook runs instead of the instruction there,
+    /// then the CPU returns to `ra` like a `ret` would, so the emulated function body is skipped entirely.
+    pub hooks: HashMap<u32, FunctionHook>,
 }
 
 impl Cpu32Bit {
     /// Load the given program into the CPU's memory and set the program counter to the given entrypoint.
     ///
     /// also resets the CPU's registers and memory to their default state
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the program doesn't fit in memory, see `MemoryBus::new`.
+    pub fn new(text: &[u8], data: &[u8], entrypoint: u32, gp: Option<u32>) -> Result<Self> {
+        Ok(Self::with_memory(
+            MemoryBus::new(entrypoint, text, data)?,
+            gp,
+        ))
+    }
+
+    /// Create a CPU around an already loaded `memory`, with the program counter at its entrypoint.
+    ///
+    /// Use this instead of `new` to control the memory layout, e.g. with `MemoryBus::with_data_base`.
     #[must_use]
-    pub fn new(text: &[u8], data: &[u8], entrypoint: u32, gp: Option<u32>) -> Self {
+    pub fn with_memory(memory: MemoryBus, gp: Option<u32>) -> Self {
+        let entrypoint = memory.entrypoint();
         // init registers
         let mut registers = RegisterFile32Bit::new();
         // set the stack pointer to the top of the stack (highest address in the stack region)
@@ -80,12 +373,560 @@ impl Cpu32Bit {
         Self {
             registers,
             pc: entrypoint,
-            memory: MemoryBus::new(entrypoint, text, data),
+            memory,
             debug: false,
+            halted: false,
             output: String::new(),
+            watch_output: false,
+            output_shown: 0,
+            instret: 0,
+            csrs: CsrFile::new(),
+            started: Instant::now(),
+            output_sink: ProgramOutput::default(),
+            input: ProgramInput::default(),
+            clock: Clock::default(),
+            rng: Rng::default(),
+            interrupt: Arc::new(AtomicBool::new(false)),
+            history: History::default(),
+            pending_steps: 0,
+            continue_budget: Some(DEFAULT_CONTINUE_BUDGET),
+            continue_remaining: None,
+            call_stack: CallStack::default(),
+            return_stop: None,
+            write_trap: None,
+            ra_guard: None,
+            callee_saved_checker: None,
+            hot_profile: None,
+            isa: None,
+            div_by_zero: DivByZeroPolicy::default(),
+            poison_temporaries: false,
+            continue_on_fault: false,
+            bare_metal: false,
+            custom_decoder: None,
+            custom_executor: None,
+            hooks: HashMap::new(),
         }
     }
 
+    /// get the memory mutably, e.g. to seed it with `MemoryBus::write_bytes` or `MemoryBus::force_write_bytes` before running
+    pub const fn memory_mut(&mut self) -> &mut MemoryBus {
+        &mut self.memory
+    }
+
+    /// Read the current value of the counters.
+    ///
+    /// Every instruction is modelled as taking a single cycle, and no time passes on a fixed clock.
+    #[must_use]
+    #[allow(clippy::cast_possible_truncation)] // 2^64 microseconds is over half a million years
+    pub fn counters(&self) -> Counters {
+        let time = match self.clock {
+            Clock::System => self.started.elapsed().as_micros() as u64,
+            Clock::Fixed(_) => 0,
+        };
+        Counters {
+            cycle: self.instret.wrapping_add(self.csrs.cycle_offset),
+            time,
+            instret: self.instret.wrapping_add(self.csrs.instret_offset),
+        }
+    }
+
+    /// Set up a thread-local storage block on the heap and point `tp` at it, for programs (e.g. newlib's `errno`) that use TLS.
+    ///
+    /// The block holds the initial values in `tdata` (the `.tdata` section) followed by `tbss_size` zeroed bytes (the `.tbss` section),
+    /// and is at least `MIN_TLS_SIZE` bytes. RISC-V uses TLS variant I, so `tp` points at the start of the block.
+    /// Returns the address of the block.
+    ///
+    /// # Errors
+    ///
+    /// This method will return an error if the block doesn't fit on the heap.
+    pub fn set_up_tls(&mut self, tdata: &[u8], tbss_size: u32) -> Result<u32> {
+        let size = u32::try_from(tdata.len())?
+            .checked_add(tbss_size)
+            .context("The TLS block is too large")?
+            .max(MIN_TLS_SIZE)
+            .next_multiple_of(TLS_ALIGN);
+        let heap_end = self.memory.heap_end();
+        let start = heap_end.next_multiple_of(TLS_ALIGN);
+        self.memory
+            .sbrk(i32::try_from(start - heap_end + size).context("The TLS block is too large")?)?;
+        // the heap hasn't been written to yet, so the rest of the block is already zeroed
+        self.memory.force_write_bytes(start, tdata)?;
+        self.registers[RegisterMapping::Tp] = start;
+        Ok(start)
+    }
+
+    /// Make every nondeterministic syscall reproducible from `seed`, so runs with the same seed are identical.
+    ///
+    /// The random number generator is seeded with `seed`, the clock is fixed at `seed` seconds after the unix epoch
+    /// (so sleeping returns immediately), and the input is empty, unless it's replaced afterwards.
+    pub fn set_deterministic(&mut self, seed: u64) {
+        self.rng = Rng::seeded(seed);
+        self.clock = Clock::Fixed(Duration::from_secs(seed));
+        self.input = ProgramInput::Reader(Box::new(std::io::empty()));
+    }
+
+    /// A hash of the architectural state: the pc, the registers, and the contents of memory (see `MemoryBus::checksum`).
+    ///
+    /// CPUs in the same state hash the same, so tests can compare states without serializing them.
+    #[must_use]
+    pub fn state_hash(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.pc.hash(&mut hasher);
+        self.registers.hash(&mut hasher);
+        self.memory.checksum().hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /// A compact one-line summary of the CPU state, e.g. for trace output and logs:
+    /// the pc, instret, `sp`, `ra`, `a0`, and the instruction about to be executed.
+    #[must_use]
+    pub fn status_line(&self) -> String {
+        let instruction = self.fetch_and_decode(self.pc).map_or_else(
+            |_| "<invalid instruction>".to_string(),
+            |instruction| instruction.disassemble(self.pc),
+        );
+        format!(
+            "pc={:#010x} instret={} sp={:#010x} ra={:#010x} a0={:#010x} | {instruction}",
+            self.pc,
+            self.instret,
+            self.registers[RegisterMapping::Sp],
+            self.registers[RegisterMapping::Ra],
+            self.registers[RegisterMapping::A0],
+        )
+    }
+
+    /// Read the CSR at `addr`, or `None` if it isn't implemented.
+    ///
+    /// `misa` reports the C extension while compressed instructions are enabled, see `compressed_enabled`.
+    #[must_use]
+    pub fn read_csr(&self, addr: u16) -> Option<u32> {
+        let value = self.csrs.read(addr, self.counters())?;
+        Some(if addr == csr::MISA && self.compressed_enabled() {
+            value | csr::MISA_C
+        } else {
+            value
+        })
+    }
+
+    /// Write `value` to the CSR at `addr`.
+    ///
+    /// # Errors
+    ///
+    /// This method will return an error if the CSR isn't implemented, or is read-only (like the counters).
+    pub fn write_csr(&mut self, addr: u16, value: u32) -> Result<()> {
+        self.csrs.write(addr, value, self.counters())
+    }
+
+    /// Read the CSR called `name` (e.g. "mstatus"), or `None` if it isn't implemented.
+    #[must_use]
+    pub fn read_csr_by_name(&self, name: &str) -> Option<u32> {
+        csr::csr_address(name).and_then(|addr| self.read_csr(addr))
+    }
+
+    /// Write `value` to the CSR called `name` (e.g. "mtvec").
+    ///
+    /// # Errors
+    ///
+    /// This method will return an error if there's no such CSR, or it's read-only.
+    pub fn write_csr_by_name(&mut self, name: &str, value: u32) -> Result<()> {
+        let Some(addr) = csr::csr_address(name) else {
+            bail!("Unknown CSR: {name}");
+        };
+        self.write_csr(addr, value)
+    }
+
+    /// Get a handle to the flag used to interrupt `run`.
+    ///
+    /// Setting the flag to `true` makes `run` return `RunOutcome::Interrupted` the next time it checks it,
+    /// which happens every `INTERRUPT_CHECK_INTERVAL` instructions.
+    #[must_use]
+    pub fn interrupt_handle(&self) -> Arc<AtomicBool> {
+        Arc::clone(&self.interrupt)
+    }
+
+    /// Repeatedly `step` the CPU until an error occurs, `max_steps` instructions have been executed,
+    /// the program halts, or the interrupt flag is raised.
+    ///
+    /// The interrupt flag is cleared when it's observed, so the CPU can be run again afterwards.
+    ///
+    /// # Errors
+    ///
+    /// This method will return the first error returned by `step`.
+    pub fn run(&mut self, max_steps: Option<u64>) -> Result<RunOutcome> {
+        self.run_with(max_steps, Self::step)
+    }
+
+    /// Like `run`, but also stops (with `RunOutcome::ConditionMet`) as soon as `condition` holds after a step,
+    /// e.g. to run until the pc reaches an address, or a register holds some value.
+    ///
+    /// The condition is only checked after each step, so it can be used repeatedly, e.g. to stop at every
+    /// iteration of a loop, even if it holds before the first step.
+    ///
+    /// # Errors
+    ///
+    /// This method will return the first error returned by `step`.
+    pub fn run_until(
+        &mut self,
+        condition: impl FnMut(&Self) -> bool,
+        max_steps: Option<u64>,
+    ) -> Result<RunOutcome> {
+        self.run_with_until(max_steps, Self::step, condition)
+    }
+
+    /// Like `run`, but writes each instruction to `trace` (as `pc: disassembly`) before executing it,
+    /// then waits for `step_delay` (if any) so the execution can be watched.
+    ///
+    /// With `continue_on_fault`, a load or store that faults is logged to `trace` and skipped, so the rest of the execution can be seen.
+    ///
+    /// # Errors
+    ///
+    /// This method will return the first error returned by `step` (other than the skipped faults), or by writing to `trace`.
+    pub fn run_traced(
+        &mut self,
+        max_steps: Option<u64>,
+        trace: &mut dyn Write,
+        step_delay: Option<Duration>,
+    ) -> Result<RunOutcome> {
+        self.run_with(max_steps, |cpu| {
+            let fetched = cpu
+                .memory
+                .fetch_and_decode_with(cpu.pc, cpu.custom_decoder.as_ref());
+            match fetched {
+                Ok((instruction, _)) => {
+                    writeln!(
+                        trace,
+                        "{:#010x}: {}",
+                        cpu.pc,
+                        instruction.disassemble(cpu.pc)
+                    )?;
+                }
+                Err(_) => writeln!(trace, "{:#010x}: <invalid instruction>", cpu.pc)?,
+            }
+            if let Some(delay) = step_delay {
+                std::thread::sleep(delay);
+            }
+            match (cpu.step(), fetched) {
+                (Err(e), Ok((instruction, width)))
+                    if cpu.continue_on_fault && is_recoverable_fault(&instruction, &e) =>
+                {
+                    writeln!(trace, "{:#010x}: fault skipped: {e:#}", cpu.pc)?;
+                    cpu.pc = cpu.pc.wrapping_add(width.bytes());
+                    Ok(())
+                }
+                (result, _) => result,
+            }
+        })
+    }
+
+    /// Run the program like `run`, until it exits, capturing its output instead of printing it.
+    ///
+    /// The run never enters the debugger, as there's no one to drive it: an `ebreak` stops the run instead,
+    /// with `RunOutcome::Breakpoint`. The run also stops when `max_steps` instructions have been executed,
+    /// or for the other reasons `run` stops, which are reported by `RunResult::outcome`.
+    /// The output is only captured in `RunResult::output`, `output_sink` is left untouched by the run.
+    ///
+    /// # Errors
+    ///
+    /// This method will return the first error returned by `step_once`, other than the program exiting.
+    pub fn run_to_completion(&mut self, max_steps: Option<u64>) -> Result<RunResult> {
+        let output_start = self.output.len();
+        let instret = self.instret;
+        let sink = std::mem::replace(
+            &mut self.output_sink,
+            ProgramOutput::Writer(Box::new(std::io::sink())),
+        );
+        // executing an ebreak sets the debug flag, which is what stops the run
+        let debug = std::mem::replace(&mut self.debug, false);
+        let result = self
+            .run_with_until(max_steps, Self::step_once, |cpu| cpu.debug)
+            .map(|outcome| match outcome {
+                RunOutcome::ConditionMet => RunOutcome::Breakpoint,
+                outcome => outcome,
+            });
+        self.debug = debug;
+        self.output_sink = sink;
+
+        let (exit_code, outcome) = match result {
+            Ok(outcome) => (None, Some(outcome)),
+            Err(e) => match e.downcast_ref::<ProgramExit>() {
+                Some(exit) => (Some(exit.code), None),
+                None => return Err(e),
+            },
+        };
+        Ok(RunResult {
+            exit_code,
+            outcome,
+            output: self.output[output_start..].to_string(),
+            instructions: self.instret - instret,
+        })
+    }
+
+    /// Run the program like `run`, writing each retired instruction to `log` in Spike's commit log format.
+    ///
+    /// See `StepReport::spike_commit_log`.
+    ///
+    /// # Errors
+    ///
+    /// This method will return an error if writing to `log` fails, or for the same reasons as `run`.
+    pub fn run_commit_logged(
+        &mut self,
+        max_steps: Option<u64>,
+        log: &mut dyn Write,
+    ) -> Result<RunOutcome> {
+        self.run_with(max_steps, |cpu| {
+            if let Some(line) = cpu.step_reported()?.spike_commit_log() {
+                writeln!(log, "{line}")?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Run the program like `run`, writing an event to `log` for every instruction retired, every syscall
+    /// (with the input it read and the output it wrote) or, when running `bare_metal`, every trap an `ecall` takes,
+    /// and the exit or fault ending the run.
+    ///
+    /// The events are JSON lines, see `event_log::TraceEvent`, and can be read back with `event_log::read_events`.
+    ///
+    /// # Errors
+    ///
+    /// This method will return an error if writing to `log` fails, or for the same reasons as `run`.
+    pub fn run_recorded(
+        &mut self,
+        max_steps: Option<u64>,
+        log: &mut dyn Write,
+    ) -> Result<RunOutcome> {
+        let recorder = Recorder::attach(self);
+        let outcome = self.run_with(max_steps, |cpu| recorder.step(cpu, log));
+        Recorder::detach(self);
+        outcome
+    }
+
+    /// `step` the CPU, and report what the step did.
+    ///
+    /// # Errors
+    ///
+    /// This method will return the error returned by `step`.
+    pub fn step_reported(&mut self) -> Result<StepReport> {
+        let pc = self.pc;
+        let (instruction, encoding) = if self.hooks.contains_key(&pc) {
+            (None, None)
+        } else {
+            let fetched = self
+                .memory
+                .fetch_and_decode_with(pc, self.custom_decoder.as_ref())
+                .ok();
+            (
+                fetched.map(|(instruction, _)| instruction),
+                fetched.and_then(|(_, width)| Some((self.memory.read(pc, width).ok()?, width))),
+            )
+        };
+        let before = self.registers;
+        self.memory.take_access();
+        self.step()?;
+        let destination = instruction
+            .as_ref()
+            .and_then(Rv32imInstruction::destination);
+        let registers = written_registers(destination, &before, &self.registers)
+            .map(|reg| (reg, self.registers[reg]))
+            .collect();
+        let memory_access = self.memory.take_access();
+        Ok(StepReport {
+            pc,
+            instruction,
+            encoding,
+            next_pc: self.pc,
+            registers,
+            memory_access,
+        })
+    }
+
+    /// Iterate over the steps of the program, as reported by `step_reported`.
+    ///
+    /// The iterator ends after the program halts, or after yielding the first error (e.g. a fault, or the program exiting).
+    pub fn steps(&mut self) -> impl Iterator<Item = Result<StepReport>> + '_ {
+        let mut done = false;
+        std::iter::from_fn(move || {
+            if done {
+                return None;
+            }
+            let report = self.step_reported();
+            done = report.is_err() || self.halted;
+            Some(report)
+        })
+    }
+
+    /// `run`, with `step` stepping the CPU.
+    fn run_with(
+        &mut self,
+        max_steps: Option<u64>,
+        step: impl FnMut(&mut Self) -> Result<()>,
+    ) -> Result<RunOutcome> {
+        self.run_with_until(max_steps, step, |_| false)
+    }
+
+    /// `run_until`, with `step` stepping the CPU.
+    fn run_with_until(
+        &mut self,
+        max_steps: Option<u64>,
+        mut step: impl FnMut(&mut Self) -> Result<()>,
+        mut condition: impl FnMut(&Self) -> bool,
+    ) -> Result<RunOutcome> {
+        let mut steps: u64 = 0;
+        loop {
+            if max_steps.is_some_and(|max_steps| steps >= max_steps) {
+                return Ok(RunOutcome::StepLimitReached);
+            }
+            // we can't safely stop a thread mid-step, so we cooperatively check the flag instead
+            if steps.is_multiple_of(INTERRUPT_CHECK_INTERVAL)
+                && self.interrupt.swap(false, Ordering::Relaxed)
+            {
+                return Ok(RunOutcome::Interrupted);
+            }
+            step(self)?;
+            steps += 1;
+            if self.halted {
+                return Ok(RunOutcome::Halted);
+            }
+            if condition(self) {
+                return Ok(RunOutcome::ConditionMet);
+            }
+        }
+    }
+
+    /// Move the program counter to `pc`, e.g. to skip over, or re-run, a section of code.
+    ///
+    /// # Errors
+    ///
+    /// This method will return an error, leaving the program counter unchanged,
+    /// if `pc` isn't aligned to an instruction boundary (see `compressed_enabled`) or is outside of the text section.
+    pub fn set_pc(&mut self, pc: u32) -> Result<()> {
+        if !is_pc_aligned(pc, self.compressed_enabled()) {
+            let align = if self.compressed_enabled() {
+                COMPRESSED_INSTRUCTION_ALIGN
+            } else {
+                INSTRUCTION_ALIGN
+            };
+            bail!("Program counter must be {align}-byte aligned: {pc:#010x}");
+        }
+        if pc.wrapping_sub(self.memory.text_base()) >= self.memory.code_size() {
+            bail!("Program counter out of bounds: {pc:#010x}");
+        }
+        self.pc = pc;
+        Ok(())
+    }
+
+    /// whether compressed (RVC) instructions can be executed, which makes 2-byte aligned instruction addresses valid.
+    ///
+    /// They can be, unless `isa` is set to an ISA without the C extension.
+    #[must_use]
+    pub fn compressed_enabled(&self) -> bool {
+        self.isa.as_ref().is_none_or(|isa| isa.has(Extension::C))
+    }
+
+    /// Fetch and decode the instruction at `pc`, including any custom instructions.
+    ///
+    /// # Errors
+    ///
+    /// This method will return an error if the instruction cannot be fetched or decoded.
+    pub fn fetch_and_decode(&self, pc: u32) -> Result<Rv32imInstruction> {
+        self.memory
+            .fetch_and_decode_with(pc, self.custom_decoder.as_ref())
+            .map(|(instruction, _)| instruction)
+    }
+
+    /// Set how many of the most recent steps can be undone, forgetting any steps already recorded.
+    ///
+    /// A depth of 0 disables the history, which is the default.
+    pub fn set_undo_depth(&mut self, depth: usize) {
+        self.history = History::new(depth);
+    }
+
+    /// get the number of steps that can currently be undone
+    #[must_use]
+    pub fn undoable_steps(&self) -> usize {
+        self.history.len()
+    }
+
+    /// Undo the most recent step, restoring the registers, memory, CSRs, program break, and program counter
+    /// it changed, along with the calls being tracked (e.g. by the return address guard).
+    ///
+    /// Output already produced by the step, and input already consumed by it, can't be taken back.
+    ///
+    /// # Errors
+    ///
+    /// This method will return an error if there are no steps to undo.
+    pub fn undo(&mut self) -> Result<()> {
+        let Some(record) = self.history.pop() else {
+            bail!("No steps to undo");
+        };
+        self.revert(record)
+    }
+
+    /// Record the state the next step can overwrite, so it can be reverted. The stores it makes are added afterwards.
+    ///
+    /// The calls being tracked are only recorded when `makes_calls` (the step can make or return from a call,
+    /// or the return address guard is watching the stack), and the history is enabled, as copying them is costly.
+    fn undo_record(&self, makes_calls: bool) -> UndoRecord {
+        let calls = (makes_calls && self.history.is_enabled()).then(|| {
+            Box::new(CallTracking {
+                call_stack: self.call_stack.clone(),
+                ra_guard: self.ra_guard.clone(),
+                callee_saved_checker: self.callee_saved_checker.clone(),
+            })
+        });
+        UndoRecord {
+            pc: self.pc,
+            registers: self.registers,
+            memory: Vec::new(),
+            csrs: self.csrs,
+            heap_end: self.memory.heap_end(),
+            halted: self.halted,
+            instret: self.instret,
+            calls,
+        }
+    }
+
+    /// Restore the state a step overwrote, as recorded in `record`.
+    fn revert(&mut self, record: UndoRecord) -> Result<()> {
+        // revert the stores in reverse, so overlapping stores restore the oldest value
+        for write in record.memory.iter().rev() {
+            self.memory.revert(write)?;
+        }
+        self.registers = record.registers;
+        self.csrs = record.csrs;
+        self.memory.revert_heap_end(record.heap_end);
+        self.halted = record.halted;
+        self.instret = record.instret;
+        self.pc = record.pc;
+        if let Some(calls) = record.calls {
+            self.call_stack = calls.call_stack;
+            self.ra_guard = calls.ra_guard;
+            self.callee_saved_checker = calls.callee_saved_checker;
+        }
+        Ok(())
+    }
+
+    /// Execute backwards, undoing steps until the program counter reaches one of the `breakpoints` or an `ebreak`,
+    /// or there are no more steps to undo (see `set_undo_depth` to record more of them).
+    ///
+    /// At least one step is undone, so reverse continuing from a breakpoint finds its previous hit.
+    /// Like `undo`, output and input can't be taken back.
+    pub fn reverse_continue(&mut self, breakpoints: &[u32]) -> ReverseStop {
+        while self.undo().is_ok() {
+            let at_ebreak = matches!(
+                self.fetch_and_decode(self.pc),
+                Ok(Rv32imInstruction::IType {
+                    operation: ITypeOperation::Ebreak,
+                    ..
+                })
+            );
+            if at_ebreak || breakpoints.contains(&self.pc) {
+                return ReverseStop::Breakpoint;
+            }
+        }
+        ReverseStop::StartOfHistory
+    }
+
     /// Execute the current instruction and update the program counter.
     /// This method will fetch, decode, and execute the instruction at the current program counter.
     /// It will then update the program counter to the next instruction, branch, or jump as necessary.
@@ -102,17 +943,20 @@ impl Cpu32Bit {
     ///
     /// This method will return an error if the instruction cannot be fetched, decoded, or executed.
     /// This can happen if the program counter is out of bounds or misaligned, if the instruction is invalid or
-    /// results in an invalid memory/register read / write, if a zero pointer is dereferenced, etc.
+    /// results in an invalid memory/register read / write, if a zero pointer is dereferenced (see `Trap::NullPointerDereference`), etc.
     pub fn step(&mut self) -> Result<()> {
-        // fetch and decode the instruction
-        let instruction = self.memory.fetch_and_decode(self.pc)?;
-
-        if self.debug {
-            debugger::clear_screen();
-            println!("Program Output:\n{}", self.output);
-            println!();
+        if self.debug && self.pending_steps == 0 {
+            let budget_exhausted = self.continue_remaining.take() == Some(0);
+            self.return_stop = None;
+            self.print_debugger_output();
             debugger::print_screen(self);
             println!();
+            if budget_exhausted {
+                println!(
+                    "Continue budget of {} instructions exhausted, the program is still running",
+                    self.continue_budget.unwrap_or_default()
+                );
+            }
             // pause execution until user input is received
             // this is useful for debugging, as it allows the user to inspect the CPU's state at each step
             // and to step through the program one instruction at a time
@@ -121,39 +965,449 @@ impl Cpu32Bit {
                 std::io::stdin().read_line(&mut input)?;
                 match DebuggerCommand::from(input.trim()) {
                     DebuggerCommand::ContinueToNextBreakpoint => {
-                        self.debug = false;
-                        println!("{}", self.output);
+                        self.continue_to_next_breakpoint();
+                        if !self.watch_output {
+                            println!("{}", self.output);
+                        }
                         break;
                     }
-                    DebuggerCommand::StepToNextInstruction => {
-                        println!("{}", self.output);
+                    DebuggerCommand::Step(count) => {
+                        self.pending_steps = count;
+                        if !self.watch_output {
+                            println!("{}", self.output);
+                        }
                         break;
                     }
                     DebuggerCommand::ExitProgram => {
                         anyhow::bail!("User requested to quit");
                     }
-                    DebuggerCommand::Unknown => {
+                    DebuggerCommand::Finish => {
+                        self.finish_function();
+                        if !self.watch_output {
+                            println!("{}", self.output);
+                        }
+                        break;
+                    }
+                    DebuggerCommand::TrapOnWrite(register) => {
+                        self.trap_on_write(register);
+                        self.continue_to_next_breakpoint();
+                        if !self.watch_output {
+                            println!("{}", self.output);
+                        }
+                        break;
+                    }
+                    command => {
+                        let message = self.inspect(command, input.trim());
                         debugger::clear_screen();
                         debugger::print_screen(self);
-                        println!("Unknown command: {}", input.trim());
+                        println!("{message}");
+                    }
+                }
+            }
+        }
+
+        self.pending_steps = self.pending_steps.saturating_sub(1);
+        let result = self.step_once();
+        if self
+            .return_stop
+            .is_some_and(|(pc, sp)| pc == self.pc && self.registers[RegisterMapping::Sp] >= sp)
+        {
+            // the function being finished returned
+            self.debug = true;
+        }
+        if let Some(remaining) = &mut self.continue_remaining {
+            *remaining = remaining.saturating_sub(1);
+            if *remaining == 0 {
+                // re-enter the debugger, it reports that the budget was exhausted
+                self.debug = true;
+            }
+        }
+        result
+    }
+
+    /// Run a debugger command that doesn't resume execution, returning the message to show for it.
+    fn inspect(&mut self, command: DebuggerCommand, input: &str) -> String {
+        match command {
+            DebuggerCommand::PrintMemoryMap => debugger::memory_map(self),
+            DebuggerCommand::Print(target) => debugger::print_target(self, &target),
+            DebuggerCommand::SetPc(pc) => match self.set_pc(pc) {
+                Ok(()) => format!("pc set to {pc:#010x}"),
+                Err(e) => format!("Error: {e}"),
+            },
+            DebuggerCommand::Disassemble(operand) => {
+                debugger::disassemble_at(self, operand.resolve(self))
+            }
+            DebuggerCommand::Examine {
+                addr,
+                count,
+                size,
+                format,
+            } => debugger::examine(self, addr.resolve(self), count, size, format),
+            DebuggerCommand::Back => match self.undo() {
+                Ok(()) => format!("Stepped back to {:#010x}", self.pc),
+                Err(e) => format!("Error: {e}"),
+            },
+            DebuggerCommand::ReverseContinue(breakpoint) => {
+                match self.reverse_continue(breakpoint.as_slice()) {
+                    ReverseStop::Breakpoint => {
+                        format!("Reversed to the breakpoint at {:#010x}", self.pc)
+                    }
+                    ReverseStop::StartOfHistory => {
+                        format!("Reversed to the start of the history at {:#010x}", self.pc)
                     }
                 }
             }
+            DebuggerCommand::Unknown => format!("Unknown command: {input}"),
+            DebuggerCommand::ContinueToNextBreakpoint
+            | DebuggerCommand::Step(_)
+            | DebuggerCommand::ExitProgram
+            | DebuggerCommand::Finish
+            | DebuggerCommand::TrapOnWrite(_) => {
+                unreachable!("commands that resume are handled by step")
+            }
+        }
+    }
+
+    /// Show the programs output at the debugger prompt.
+    ///
+    /// Normally the screen is cleared and all of the output is reprinted.
+    /// When watching the output, the screen is left as is, so output streamed to the terminal stays where it was printed,
+    /// and only the output produced since the last prompt is shown, if it went to another sink (e.g. a file).
+    fn print_debugger_output(&mut self) {
+        if self.watch_output {
+            let to_terminal = matches!(self.output_sink, ProgramOutput::Stdout);
+            let new_output = self.new_output();
+            if !to_terminal && !new_output.is_empty() {
+                println!("Program Output:\n{new_output}");
+            }
+        } else {
+            debugger::clear_screen();
+            println!("Program Output:\n{}", self.output);
         }
+        println!();
+    }
+
+    /// Get the output produced since the last call, so it can be displayed incrementally.
+    pub fn new_output(&mut self) -> &str {
+        // undoing a step can take back output that was already shown
+        let shown = self.output_shown.min(self.output.len());
+        self.output_shown = self.output.len();
+        self.output.get(shown..).unwrap_or_default()
+    }
+
+    /// Continue until the current function returns to its caller.
+    ///
+    /// The return address and stack pointer are the ones recorded when the function was called, so this works
+    /// after the function has made calls of its own (overwriting `ra`). As recursive calls return to the same address,
+    /// only a return that leaves the stack pointer at or above its value at the call stops execution.
+    /// Outside of any recorded call (e.g. in the startup code), the function returns to `ra`.
+    fn finish_function(&mut self) {
+        self.continue_to_next_breakpoint();
+        self.return_stop = Some(self.call_stack.innermost().map_or(
+            (
+                self.registers[RegisterMapping::Ra],
+                self.registers[RegisterMapping::Sp],
+            ),
+            |frame| (frame.return_address, frame.sp),
+        ));
+    }
+
+    /// Stop execution (entering the debugger) once the next instruction that writes `register` has executed.
+    ///
+    /// The trap is one-shot, it's cleared once it fires. Unlike a watchpoint on the value,
+    /// it fires on any write, even one that leaves the value unchanged.
+    pub const fn trap_on_write(&mut self, register: RegisterMapping) {
+        self.write_trap = Some(register);
+    }
+
+    /// Enter the debugger if the step that just executed, writing `destination`, wrote the trapped register.
+    fn check_write_trap(
+        &mut self,
+        before: &RegisterFile32Bit,
+        destination: Option<RegisterMapping>,
+    ) {
+        if let Some(register) = self.write_trap {
+            if written_registers(destination, before, &self.registers).any(|reg| reg == register) {
+                // the trap is one-shot
+                self.write_trap = None;
+                self.debug = true;
+            }
+        }
+    }
+
+    /// Leave the debugger until the next ebreak, or until the continue budget is exhausted.
+    const fn continue_to_next_breakpoint(&mut self) {
+        self.debug = false;
+        self.continue_remaining = self.continue_budget;
+    }
+
+    /// Execute the current instruction and update the program counter, without involving the debugger.
+    ///
+    /// An ebreak cancels any remaining steps of a multi-step debugger command, so the debugger prompts again.
+    /// When running `bare_metal`, an instruction that raises an exception jumps to the trap handler instead of failing.
+    ///
+    /// # Errors
+    ///
+    /// This method will return an error if the instruction cannot be fetched, decoded, or executed, see `step`.
+    pub fn step_once(&mut self) -> Result<()> {
+        let pc = self.pc;
+        match self.execute_next() {
+            Err(error) if self.bare_metal => {
+                let Some((cause, tval)) = self.exception(pc, &error) else {
+                    return Err(error);
+                };
+                let mut record = self.undo_record(false);
+                record.pc = pc;
+                if self.take_trap(pc, cause, tval).is_err() {
+                    // without a trap handler, the exception stops the program like it would otherwise
+                    return Err(error);
+                }
+                // the step counts as retired, like an `ecall` that jumps to the handler
+                self.instret += 1;
+                self.history.push(record);
+                Ok(())
+            }
+            result => result,
+        }
+    }
+
+    /// The exception code and `mtval` of the exception that `error`, from executing the instruction at `pc`, raises,
+    /// or `None` if it isn't an exception (e.g. the program halted).
+    ///
+    /// Other than the traps with an exception code, instructions that can't be decoded, that are outside of `isa`,
+    /// or that access a CSR that doesn't exist (or is read-only) are illegal,
+    /// and a pc outside of the text section is an instruction access fault.
+    fn exception(&self, pc: u32, error: &anyhow::Error) -> Option<(u32, u32)> {
+        if let Some(trap) = error.downcast_ref::<Trap>() {
+            return Some((trap.mcause()?, trap.mtval()));
+        }
+        if pc.wrapping_sub(self.memory.text_base()) >= self.memory.code_size() {
+            return Some((mcause::INSTRUCTION_ACCESS_FAULT, pc));
+        }
+        let illegal = self
+            .memory
+            .fetch_and_decode_with(pc, self.custom_decoder.as_ref())
+            .map_or(true, |(instruction, width)| {
+                matches!(instruction, Rv32imInstruction::IType { operation, .. } if operation.is_csr())
+                    || self
+                        .isa
+                        .as_ref()
+                        .is_some_and(|isa| isa.check(&instruction, width, pc).is_err())
+            });
+        illegal.then_some((mcause::ILLEGAL_INSTRUCTION, 0))
+    }
+
+    /// Take an exception raised by the instruction at `pc`, by jumping to the trap handler at `mtvec`
+    /// with `mepc`, `mcause`, and `mtval` set, as bare-metal programs expect, see `bare_metal`.
+    ///
+    /// Only direct mode is supported, so the low bits of `mtvec` are ignored.
+    ///
+    /// # Errors
+    ///
+    /// This method will return an error, leaving the CPU unchanged, if `mtvec` isn't in the text section,
+    /// i.e. the program hasn't set up a trap handler.
+    pub fn take_trap(&mut self, pc: u32, cause: u32, tval: u32) -> Result<()> {
+        let handler = self.csrs.mtvec & !0b11;
+        if handler.wrapping_sub(self.memory.text_base()) >= self.memory.code_size() {
+            bail!(
+                "No trap handler for exception {cause} at {pc:#010x}, mtvec ({:#010x}) isn't in the text section",
+                self.csrs.mtvec
+            );
+        }
+        self.csrs.mepc = pc;
+        self.csrs.mcause = cause;
+        self.csrs.mtval = tval;
+        self.pc = handler;
+        Ok(())
+    }
+
+    /// Execute the current instruction (or the hook in its place), see `step_once`.
+    fn execute_next(&mut self) -> Result<()> {
+        if let Some(hook) = self.hooks.remove(&self.pc) {
+            return self.call_hook(hook);
+        }
+        // fetch and decode the instruction (in `step`, this happens after the debugger, as it may have moved the pc)
+        if !is_pc_aligned(self.pc, self.compressed_enabled()) {
+            bail!(Trap::InstructionAddressMisaligned { target: self.pc });
+        }
+        let (instruction, width) = self
+            .memory
+            .fetch_and_decode_with(self.pc, self.custom_decoder.as_ref())?;
+        if let Some(isa) = &self.isa {
+            isa.check(&instruction, width, self.pc)?;
+        }
+        if matches!(
+            instruction,
+            Rv32imInstruction::IType {
+                operation: ITypeOperation::Ebreak,
+                ..
+            }
+        ) {
+            self.pending_steps = 0;
+        }
+
+        // remember what the step overwrites, so it can be undone
+        let makes_calls = self.ra_guard.is_some()
+            || matches!(
+                instruction,
+                Rv32imInstruction::UJType { .. }
+                    | Rv32imInstruction::IType {
+                        operation: ITypeOperation::Jalr,
+                        ..
+                    }
+            );
+        let mut record = self.undo_record(makes_calls);
+        let (pc, registers) = (record.pc, record.registers);
 
         // execute the instruction, updating the CPU's state as necessary (e.g. updating registers and memory, incrementing the program counter, etc.)
-        self.execute(instruction)?;
+        self.halted = false;
+        // drop any stores left over from a failed step, so they aren't attributed to this one
+        self.memory.take_journal();
+        self.execute_with_width(instruction, width)?;
+        record.memory = self.memory.take_journal();
+
+        if !is_pc_aligned(self.pc, self.compressed_enabled()) {
+            // the jump or branch traps, rather than the instruction at its target
+            let target = self.pc;
+            self.revert(record)?;
+            bail!(Trap::InstructionAddressMisaligned { target });
+        }
+        let guarded = self.ra_guard.as_mut().map_or(Ok(()), |guard| {
+            guard.observe(
+                &instruction,
+                &registers,
+                &record.memory,
+                &self.memory,
+                self.registers[RegisterMapping::Sp],
+            )
+        });
+        let checked = guarded.and_then(|()| {
+            self.callee_saved_checker
+                .as_mut()
+                .map_or(Ok(()), |checker| {
+                    checker.observe(&instruction, &self.registers, self.pc)
+                })
+        });
+        if let Err(e) = checked {
+            // report the corruption at the store, or the return, that caused it, as if it never executed
+            self.revert(record)?;
+            return Err(e);
+        }
+
+        self.instret += 1;
+        self.check_write_trap(&registers, instruction.destination());
+        if let Some(profile) = self.hot_profile.as_mut() {
+            profile.record(pc);
+        }
+        self.call_stack
+            .observe(&instruction, &self.registers, self.pc);
+        self.history.push(record);
+        Ok(())
+    }
 
+    /// Run the `hook` registered at the pc in place of the function there, then return to the caller.
+    fn call_hook(&mut self, mut hook: FunctionHook) -> Result<()> {
+        let mut record = self.undo_record(true);
+        let (pc, registers) = (record.pc, record.registers);
+        self.halted = false;
+        self.memory.take_journal();
+        // the hook is taken out of the cpu while it runs, so it can borrow the cpu mutably
+        let result = hook(self);
+        self.hooks.entry(pc).or_insert(hook);
+        result?;
+        // return to the caller, like `ret` (`jalr zero, 0(ra)`) would
+        self.pc = self.registers[RegisterMapping::Ra] & !1;
+        self.call_stack.returned_to(self.pc);
+        self.instret += 1;
+        self.check_write_trap(&registers, None);
+
+        record.memory = self.memory.take_journal();
+        self.history.push(record);
         Ok(())
     }
 }
 
+/// The registers a step wrote: those whose values changed, plus the instruction's `destination` even if its value didn't.
+fn written_registers<'a>(
+    destination: Option<RegisterMapping>,
+    before: &'a RegisterFile32Bit,
+    after: &'a RegisterFile32Bit,
+) -> impl Iterator<Item = RegisterMapping> + 'a {
+    (1..REGISTERS_COUNT)
+        .filter_map(|i| RegisterMapping::try_from(i).ok())
+        .filter(move |&reg| before[reg] != after[reg] || destination == Some(reg))
+}
+
+/// whether `error`, from executing `instruction`, is a bad load or store that execution can continue past
+fn is_recoverable_fault(instruction: &Rv32imInstruction, error: &anyhow::Error) -> bool {
+    let is_memory_access = matches!(
+        instruction,
+        Rv32imInstruction::IType {
+            operation: ITypeOperation::Lb
+                | ITypeOperation::Lh
+                | ITypeOperation::Lw
+                | ITypeOperation::Lbu
+                | ITypeOperation::Lhu,
+            ..
+        } | Rv32imInstruction::SType { .. }
+    );
+    // other traps, like halting or detecting corruption, and exiting, aren't faults
+    let is_fault = error.downcast_ref::<Trap>().map_or_else(
+        || !error.is::<ProgramExit>(),
+        |trap| {
+            matches!(
+                trap,
+                Trap::LoadAddressMisaligned { .. }
+                    | Trap::StoreAddressMisaligned { .. }
+                    | Trap::NullPointerDereference { .. }
+                    | Trap::GpRelativeAccessOutsideStaticData { .. }
+            )
+        },
+    );
+    is_memory_access && is_fault
+}
+
+/// Run the CPU on a worker thread, interrupting it once `timeout` has elapsed.
+///
+/// # Errors
+///
+/// This function will return the first error returned by `Cpu32Bit::step`,
+/// or an error if the worker thread panics.
+pub fn run_with_timeout(cpu: &mut Cpu32Bit, timeout: Duration) -> Result<RunOutcome> {
+    let interrupt = cpu.interrupt_handle();
+    let (done_tx, done_rx) = mpsc::channel();
+
+    let outcome = std::thread::scope(|scope| {
+        let worker = scope.spawn(move || {
+            let outcome = cpu.run(None);
+            // the receiver may have already given up waiting, that's fine
+            let _ = done_tx.send(());
+            outcome
+        });
+
+        if matches!(
+            done_rx.recv_timeout(timeout),
+            Err(RecvTimeoutError::Timeout)
+        ) {
+            interrupt.store(true, Ordering::Relaxed);
+        }
+
+        worker
+            .join()
+            .map_err(|_| anyhow!("CPU worker thread panicked"))?
+    });
+
+    // the run may have finished on its own after we raised the flag, don't let it leak into the next run
+    interrupt.store(false, Ordering::Relaxed);
+    outcome
+}
+
 impl fmt::Display for Cpu32Bit {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         writeln!(f, "CPU32Bit {{")?;
         writeln!(f, "    memory bus layout: {{")?;
         writeln!(f, "        text: {{")?;
-        writeln!(f, "            start: {:#010x},", self.memory.entrypoint())?;
+        writeln!(f, "            start: {:#010x},", self.memory.text_base())?;
         writeln!(f, "            size: {}", self.memory.code_size())?;
         writeln!(f, "        }},")?;
         writeln!(f, "        data: {{")?;
@@ -165,7 +1419,7 @@ impl fmt::Display for Cpu32Bit {
         // print the 4 instructions before the current instruction
         for offset in (1..=4).rev() {
             let addr = self.pc.wrapping_sub(offset * 4);
-            if let Ok(instruction) = self.memory.fetch_and_decode(addr) {
+            if let Ok(instruction) = self.fetch_and_decode(addr) {
                 writeln!(f, "        {addr:#010x}: {instruction},")?;
             } else {
                 writeln!(f, "        {addr:#010x}: <invalid instruction>,")?;
@@ -175,7 +1429,7 @@ impl fmt::Display for Cpu32Bit {
             f,
             "   ---> {:#010x}: {},",
             self.pc,
-            self.memory.fetch_and_decode(self.pc).map_or_else(
+            self.fetch_and_decode(self.pc).map_or_else(
                 |_| "<invalid instruction>".to_string(),
                 |instruction| format!("{instruction}")
             )
@@ -183,7 +1437,7 @@ impl fmt::Display for Cpu32Bit {
         // print the 4 instructions after the current instruction
         for offset in 1..=4 {
             let addr = self.pc.wrapping_add(offset * 4);
-            if let Ok(instruction) = self.memory.fetch_and_decode(addr) {
+            if let Ok(instruction) = self.fetch_and_decode(addr) {
                 writeln!(f, "        {addr:#010x}: {instruction},")?;
             } else {
                 writeln!(f, "        {addr:#010x}: <invalid instruction>,")?;
@@ -191,47 +1445,1218 @@ impl fmt::Display for Cpu32Bit {
         }
         writeln!(f, "    }},")?;
         write!(f, "    registers: {{")?;
-        writeln!(
-            f,
-            "    {}",
-            self.registers.to_string().replace('\n', "\n        ")
-        )?;
+        // with poisoning on, reads of registers that were never written (or were poisoned) are likely bugs
+        let registers = if self.poison_temporaries {
+            format!("{:#}", self.registers)
+        } else {
+            self.registers.to_string()
+        };
+        writeln!(f, "    {}", registers.replace('\n', "\n        "))?;
         writeln!(f, "    }},")?;
         write!(f, "}}")
     }
 }
 
-mod debugger {
-    pub fn clear_screen() {
-        print!("{esc}[2J{esc}[1;1H", esc = 27 as char);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        emulator::{cpu::memory::MemoryWrite, decode::Decode32BitInstruction as _},
+        instruction_set_definition::CustomInstruction,
+        utils::words_to_bytes,
+    };
+
+    /// Build a CPU whose text section contains the given instructions, starting at `0x0040_0000`.
+    fn cpu_from_words(words: &[u32]) -> Cpu32Bit {
+        let text = words_to_bytes(words);
+        Cpu32Bit::new(&text, &[], 0x0040_0000, None).unwrap()
+    }
+
+    #[test]
+    fn test_seed_memory_then_run() -> Result<()> {
+        // room for three instructions, and a word of data at 0x0040100c
+        let mut cpu = Cpu32Bit::new(&[0; 12], &[0; 4], 0x0040_0000, None)?;
+        let code = words_to_bytes(&[
+            0x0040_1537_u32, // lui a0, 0x401
+            0x00c5_2583,     // lw a1, 12(a0)
+            0x0015_8593,     // addi a1, a1, 1
+        ]);
+        // the text section is read-only to the program, so installing code has to bypass that
+        assert!(cpu.memory_mut().write_bytes(0x0040_0000, &code).is_err());
+        cpu.memory_mut().force_write_bytes(0x0040_0000, &code)?;
+        cpu.memory_mut()
+            .write_bytes(0x0040_100c, &41_u32.to_le_bytes())?;
+
+        cpu.run(Some(3))?;
+        assert_eq!(cpu.registers[RegisterMapping::A1], 42);
+
+        // the data section can be force written too, but not past the end of a region
+        cpu.memory_mut().force_write_bytes(0x0040_100c, &[7])?;
+        assert_eq!(cpu.memory.read(0x0040_100c, Size::Byte)?, 7);
+        assert!(cpu
+            .memory_mut()
+            .force_write_bytes(0x0040_000a, &[0; 4])
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_tls() -> Result<()> {
+        // lw a0, 4(tp)
+        let mut cpu = cpu_from_words(&[0x0042_2503]);
+        let error = cpu.step_once().expect_err("without TLS, tp is null");
+        assert!(matches!(
+            error.downcast_ref(),
+            Some(Trap::NullPointerDereference { addr: 4, .. })
+        ));
+
+        let tls = cpu.set_up_tls(&[1, 0, 0, 0, 42, 0, 0, 0], 4)?;
+        assert_eq!(cpu.registers[RegisterMapping::Tp], tls);
+        assert!(tls.is_multiple_of(TLS_ALIGN));
+        assert!(cpu.memory.heap_end() >= tls + MIN_TLS_SIZE);
+        cpu.step_once()?;
+        assert_eq!(cpu.registers[RegisterMapping::A0], 42);
+        Ok(())
+    }
+
+    #[test]
+    fn test_cpu_is_send() {
+        fn assert_send<T: Send>() {}
+        assert_send::<Cpu32Bit>();
+    }
+
+    #[test]
+    fn test_run_step_limit() -> Result<()> {
+        // addi a0, a0, 1; jal x0, -4
+        let mut cpu = cpu_from_words(&[0x0015_0513, 0xffdf_f06f]);
+        assert_eq!(cpu.run(Some(10))?, RunOutcome::StepLimitReached);
+        assert_eq!(cpu.registers[RegisterMapping::A0], 5);
+        Ok(())
+    }
+
+    #[test]
+    fn test_run_until() -> Result<()> {
+        let mut cpu = cpu_from_words(&[
+            0x0015_0513, // loop: addi a0, a0, 1
+            0x0015_8593, // addi a1, a1, 1
+            0xff9f_f06f, // j loop
+        ]);
+
+        // run to an address
+        let outcome = cpu.run_until(|cpu| cpu.pc == 0x0040_0008, Some(100))?;
+        assert_eq!(outcome, RunOutcome::ConditionMet);
+        assert_eq!(cpu.instret, 2);
+        // the condition already holds, but is only checked after stepping, so this runs to the next iteration
+        let outcome = cpu.run_until(|cpu| cpu.pc == 0x0040_0008, Some(100))?;
+        assert_eq!(outcome, RunOutcome::ConditionMet);
+        assert_eq!(cpu.instret, 5);
+
+        // run until a register holds a value, stopping right after the instruction that wrote it
+        let outcome = cpu.run_until(|cpu| cpu.registers[RegisterMapping::A0] == 5, Some(100))?;
+        assert_eq!(outcome, RunOutcome::ConditionMet);
+        assert_eq!(cpu.pc, 0x0040_0004);
+        assert_eq!(cpu.registers[RegisterMapping::A1], 4);
+
+        // the step limit still applies
+        let outcome = cpu.run_until(|_| false, Some(10))?;
+        assert_eq!(outcome, RunOutcome::StepLimitReached);
+        Ok(())
+    }
+
+    #[test]
+    fn test_run_with_timeout_interrupts_infinite_loop() -> Result<()> {
+        // jal x0, 0
+        let mut cpu = cpu_from_words(&[0x0000_006f]);
+        let timeout = Duration::from_millis(100);
+
+        let start = Instant::now();
+        let outcome = run_with_timeout(&mut cpu, timeout)?;
+        let elapsed = start.elapsed();
+
+        assert_eq!(outcome, RunOutcome::Interrupted);
+        assert!(
+            elapsed >= timeout,
+            "returned before the timeout: {elapsed:?}"
+        );
+        assert!(
+            elapsed < timeout * 20,
+            "took far longer than the timeout: {elapsed:?}"
+        );
+
+        // the flag was cleared, so the cpu can be run again
+        assert_eq!(cpu.run(Some(1))?, RunOutcome::StepLimitReached);
+        Ok(())
+    }
+
+    #[test]
+    fn test_counters_advance() -> Result<()> {
+        let mut cpu = cpu_from_words(&[
+            0xc020_2573, // rdinstret a0
+            0x0012_8293, // addi t0, t0, 1
+            0xc020_25f3, // rdinstret a1
+            0xc000_2673, // rdcycle a2
+        ]);
+        cpu.run(Some(4))?;
+        assert!(cpu.registers[RegisterMapping::A1] > cpu.registers[RegisterMapping::A0]);
+        assert_ne!(cpu.registers[RegisterMapping::A2], 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_state_hash() -> Result<()> {
+        let words = [0x0012_8293, 0x0012_8293]; // addi t0, t0, 1
+        let mut cpu = cpu_from_words(&words);
+        let mut reference = cpu_from_words(&words);
+        assert_eq!(cpu.state_hash(), reference.state_hash());
+
+        cpu.step_once()?;
+        assert_ne!(cpu.state_hash(), reference.state_hash());
+        reference.step_once()?;
+        assert_eq!(cpu.state_hash(), reference.state_hash());
+
+        let addr = cpu.memory.dram_start();
+        cpu.memory.write(addr, 0xff, Size::Byte)?;
+        assert_ne!(cpu.state_hash(), reference.state_hash());
+        Ok(())
+    }
+
+    #[test]
+    fn test_isa_gates_decoding() -> Result<()> {
+        let mut cpu = cpu_from_words(&[
+            0x0030_0513, // li a0, 3
+            0x02a5_0533, // mul a0, a0, a0
+        ]);
+        cpu.isa = Some("rv32i".parse()?);
+        cpu.step_once()?;
+        let error = cpu.step_once().unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("needs the M extension, which isn't part of rv32i"));
+
+        cpu.isa = Some("rv32im".parse()?);
+        cpu.step_once()?;
+        assert_eq!(cpu.registers[RegisterMapping::A0], 9);
+        Ok(())
+    }
+
+    #[test]
+    fn test_hot_profile_finds_the_loop() -> Result<()> {
+        let mut cpu = cpu_from_words(&[
+            0x00a0_0293, // li t0, 10
+            0x0000_0313, // li t1, 0
+            0x0013_0313, // loop: addi t1, t1, 1
+            0xfff2_8293, // addi t0, t0, -1
+            0xfe02_9ce3, // bnez t0, loop
+            0x0003_0513, // mv a0, t1
+        ]);
+        cpu.hot_profile = Some(HotProfile::new());
+        cpu.run(Some(33))?;
+
+        let profile = cpu.hot_profile.as_ref().unwrap();
+        assert_eq!(
+            profile.hottest(4),
+            [
+                (0x0040_0008, 10),
+                (0x0040_000c, 10),
+                (0x0040_0010, 10),
+                (0x0040_0000, 1)
+            ]
+        );
+        let report = profile.report(3, &cpu.memory, None);
+        assert_eq!(report.lines().count(), 4);
+        assert!(report
+            .lines()
+            .nth(1)
+            .unwrap()
+            .ends_with("0x00400008  addi t1, t1, 1"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_timing_report() -> Result<()> {
+        let mut cpu = cpu_from_words(&[0x0012_8293, 0x0012_8293]); // addi t0, t0, 1
+        assert_eq!(
+            cpu.counters().timing_report(),
+            "no instructions executed (cost model: 1 cycle per instruction)"
+        );
+
+        cpu.run(Some(2))?;
+        assert_eq!(
+            cpu.counters().timing_report(),
+            "2 instructions, 2 cycles, CPI 1.00 (cost model: 1 cycle per instruction)"
+        );
+
+        let counters = Counters {
+            cycle: 5,
+            time: 0,
+            instret: 3,
+        };
+        assert!(counters.timing_report().contains("CPI 1.67"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_set_pc() {
+        // addi t0, t0, 1; jal x0, -4
+        let mut cpu = cpu_from_words(&[0x0012_8293, 0xffdf_f06f]);
+        assert!(cpu.set_pc(0x0040_0001).is_err(), "misaligned pc accepted");
+        assert!(
+            cpu.set_pc(0x0050_0000).is_err(),
+            "out of bounds pc accepted"
+        );
+        assert_eq!(cpu.pc, 0x0040_0000);
+        assert!(cpu.set_pc(0x0040_0004).is_ok());
+        assert_eq!(cpu.pc, 0x0040_0004);
+        assert_eq!(debugger::print_target(&cpu, "pc"), "pc = 0x00400004");
     }
 
-    pub fn print_screen(cpu: &super::Cpu32Bit) {
-        // print cpu state
-        println!("CPU state:");
-        println!("{cpu}");
-        //print instructions
-        println!("Press 'c' to continue to the next breakpoint");
-        println!("Press 's' or the Enter key to step to the next instruction");
-        println!("Press 'q' to quit the program");
+    #[test]
+    fn test_set_pc_with_compressed_instructions() -> Result<()> {
+        // c.nop; c.nop; addi t0, t0, 1
+        let mut text = [0x0001_u16.to_le_bytes(), 0x0001_u16.to_le_bytes()].concat();
+        text.extend(0x0012_8293_u32.to_le_bytes());
+        let mut cpu = Cpu32Bit::new(&text, &[], 0x0040_0000, None)?;
+        assert!(cpu.compressed_enabled());
+        cpu.set_pc(0x0040_0002)?;
+        assert_eq!(cpu.pc, 0x0040_0002);
+
+        cpu.isa = Some("rv32i".parse()?);
+        assert!(!cpu.compressed_enabled());
+        assert!(cpu.set_pc(0x0040_0002).is_err(), "misaligned pc accepted");
+        assert_eq!(cpu.pc, 0x0040_0002);
+        // the pc was already misaligned, so fetching from it traps
+        let error = cpu.step().expect_err("the pc is misaligned without RVC");
+        assert_eq!(
+            error.downcast_ref::<Trap>(),
+            Some(&Trap::InstructionAddressMisaligned {
+                target: 0x0040_0002
+            })
+        );
+        Ok(())
     }
 
-    #[allow(clippy::module_name_repetitions)]
-    pub enum DebuggerCommand {
-        ContinueToNextBreakpoint,
-        StepToNextInstruction,
-        ExitProgram,
-        Unknown,
+    #[test]
+    fn test_misaligned_jump_target() -> Result<()> {
+        let mut cpu = cpu_from_words(&[
+            0x0060_00ef, // jal ra, 6
+            0x0000_0013, // nop
+            0x0000_0013, // nop
+        ]);
+        // with RVC, the target is aligned
+        cpu.step()?;
+        assert_eq!(cpu.pc, 0x0040_0006);
+
+        cpu = cpu_from_words(&[
+            0x0060_00ef, // jal ra, 6
+            0x0000_0013, // nop
+            0x0000_0013, // nop
+        ]);
+        cpu.isa = Some("rv32i".parse()?);
+        let error = cpu
+            .step()
+            .expect_err("the target is misaligned without RVC");
+        assert_eq!(
+            error.downcast_ref::<Trap>(),
+            Some(&Trap::InstructionAddressMisaligned {
+                target: 0x0040_0006
+            })
+        );
+        // the jump traps, without linking
+        assert_eq!(cpu.pc, 0x0040_0000);
+        assert_eq!(cpu.registers[RegisterMapping::Ra], 0x0040_0000);
+        assert_eq!(cpu.instret, 0);
+
+        // jalr clears the low bit of the target, but not the one above it
+        cpu = cpu_from_words(&[
+            0x0035_0067, // jalr zero, 3(a0)
+        ]);
+        cpu.isa = Some("rv32i".parse()?);
+        cpu.registers[RegisterMapping::A0] = 0x0040_0000;
+        let error = cpu
+            .step()
+            .expect_err("the target is misaligned without RVC");
+        assert_eq!(
+            error.downcast_ref::<Trap>(),
+            Some(&Trap::InstructionAddressMisaligned {
+                target: 0x0040_0002
+            })
+        );
+        Ok(())
     }
 
-    impl From<&str> for DebuggerCommand {
-        fn from(s: &str) -> Self {
-            match s.trim() {
-                "c" => Self::ContinueToNextBreakpoint,
-                "s" | "" => Self::StepToNextInstruction,
-                "q" => Self::ExitProgram,
-                _ => Self::Unknown,
+    #[test]
+    fn test_undo_steps() -> Result<()> {
+        let mut cpu = cpu_from_words(&[
+            0x0012_8293, // addi t0, t0, 1
+            0xffc1_0113, // addi sp, sp, -4
+            0x0051_2023, // sw t0, 0(sp)
+        ]);
+        cpu.registers[RegisterMapping::Sp] = cpu.memory.dram_start() + 0x100;
+        cpu.set_undo_depth(history::DEFAULT_UNDO_DEPTH);
+        let registers = cpu.registers;
+
+        cpu.run(Some(3))?;
+        assert_eq!(cpu.undoable_steps(), 3);
+        assert_eq!(
+            cpu.memory
+                .read(cpu.memory.dram_start() + 0xfc, Size::Word)?,
+            1
+        );
+
+        for _ in 0..3 {
+            cpu.undo()?;
+        }
+        assert_eq!(cpu.pc, 0x0040_0000);
+        assert_eq!(cpu.registers, registers);
+        assert_eq!(cpu.instret, 0);
+        assert_eq!(
+            cpu.memory
+                .read(cpu.memory.dram_start() + 0xfc, Size::Word)?,
+            0
+        );
+        assert!(cpu.undo().is_err(), "undid more steps than were taken");
+        Ok(())
+    }
+
+    #[test]
+    fn test_undo_restores_the_rest_of_the_state() -> Result<()> {
+        let mut cpu = cpu_from_words(&[
+            0x0050_0293, // li t0, 5
+            0x3402_9073, // csrw mscratch, t0
+            0x0100_0513, // li a0, 16
+            0x0090_0893, // li a7, 9
+            0x0000_0073, // ecall (sbrk 16)
+            0x0040_00ef, // jal ra, 4
+            0x0470_0893, // li a7, 71
+            0x0000_0073, // ecall (halt)
+        ]);
+        cpu.set_undo_depth(history::DEFAULT_UNDO_DEPTH);
+        let heap_end = cpu.memory.heap_end();
+        for _ in 0..8 {
+            cpu.step_once()?;
+        }
+        assert!(cpu.halted);
+        assert_eq!(cpu.csrs.mscratch, 5);
+        assert_eq!(cpu.memory.heap_end(), heap_end + 16);
+        assert!(cpu.call_stack.innermost().is_some());
+
+        cpu.undo()?;
+        assert!(!cpu.halted);
+        assert_eq!(cpu.instret, 7);
+        for _ in 0..7 {
+            cpu.undo()?;
+        }
+        assert_eq!(cpu.pc, 0x0040_0000);
+        assert_eq!(cpu.instret, 0);
+        assert_eq!(cpu.csrs.mscratch, 0);
+        assert_eq!(cpu.memory.heap_end(), heap_end);
+        assert!(cpu.call_stack.innermost().is_none());
+        assert!(!cpu.registers.is_written(RegisterMapping::T0));
+        Ok(())
+    }
+
+    #[test]
+    fn test_reverse_continue() -> Result<()> {
+        let mut cpu = cpu_from_words(&[
+            0x0000_0293, // li t0, 0
+            0x0012_8293, // addi t0, t0, 1
+            0x0052_a313, // slti t1, t0, 5
+            0xfe03_1ce3, // bnez t1, -8
+            0x0002_8513, // mv a0, t0
+        ]);
+        let loop_start = 0x0040_0004;
+        cpu.set_undo_depth(usize::MAX);
+
+        // the state every time the loop started
+        let mut hits = Vec::new();
+        let start = cpu.state_hash();
+        while cpu.pc != 0x0040_0010 {
+            if cpu.pc == loop_start {
+                hits.push(cpu.state_hash());
             }
+            cpu.step_once()?;
+        }
+        assert_eq!(cpu.registers[RegisterMapping::T0], 5);
+
+        // back through every hit, most recent first
+        for (iteration, hit) in hits.iter().enumerate().rev() {
+            assert_eq!(cpu.reverse_continue(&[loop_start]), ReverseStop::Breakpoint);
+            assert_eq!(cpu.pc, loop_start);
+            assert_eq!(cpu.registers[RegisterMapping::T0] as usize, iteration);
+            assert_eq!(cpu.state_hash(), *hit);
+        }
+        assert_eq!(
+            cpu.reverse_continue(&[loop_start]),
+            ReverseStop::StartOfHistory
+        );
+        assert_eq!(cpu.state_hash(), start);
+        assert_eq!(cpu.instret, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_undo_depth_is_bounded() -> Result<()> {
+        // addi t0, t0, 1; jal x0, -4
+        let mut cpu = cpu_from_words(&[0x0012_8293, 0xffdf_f06f]);
+        cpu.set_undo_depth(2);
+        cpu.run(Some(5))?;
+        assert_eq!(cpu.undoable_steps(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_undo_is_disabled_by_default() -> Result<()> {
+        let mut cpu = cpu_from_words(&[0x0012_8293]); // addi t0, t0, 1
+        cpu.step_once()?;
+        assert_eq!(cpu.undoable_steps(), 0);
+        assert!(cpu.undo().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_function_hook() -> Result<()> {
+        let mut cpu = cpu_from_words(&[
+            0x0070_0513, // li a0, 7
+            0x0080_00ef, // jal ra, square
+            0x0005_0593, // mv a1, a0
+            0x0000_0000, // square: not a valid instruction, the hook runs instead
+        ]);
+        cpu.hooks.insert(
+            0x0040_000c,
+            Box::new(|cpu| {
+                let value = cpu.registers[RegisterMapping::A0];
+                cpu.registers[RegisterMapping::A0] = value.wrapping_mul(value);
+                Ok(())
+            }),
+        );
+        cpu.set_undo_depth(history::DEFAULT_UNDO_DEPTH);
+
+        cpu.run(Some(3))?;
+        // the hook returned to the caller
+        assert_eq!(cpu.pc, 0x0040_0008);
+        assert_eq!(cpu.registers[RegisterMapping::A0], 49);
+        cpu.step()?;
+        assert_eq!(cpu.registers[RegisterMapping::A1], 49);
+        assert_eq!(cpu.instret, 4);
+
+        // the hook can be undone like any other step
+        cpu.undo()?;
+        cpu.undo()?;
+        assert_eq!(cpu.pc, 0x0040_000c);
+        assert_eq!(cpu.registers[RegisterMapping::A0], 7);
+        Ok(())
+    }
+
+    #[test]
+    fn test_custom_instruction() -> Result<()> {
+        // a custom-0 instruction squaring rs1 into rd: square a0, a0
+        let mut cpu = cpu_from_words(&[0x0005_050b]);
+        cpu.registers[RegisterMapping::A0] = 7;
+        assert!(
+            cpu.step().is_err(),
+            "custom opcode decoded without a decoder"
+        );
+
+        cpu.custom_decoder = Some(Box::new(|machine_code| {
+            (machine_code & 0b111_1111 == 0b000_1011)
+                .then(|| CustomInstruction::new("square", machine_code))
+        }));
+        assert!(
+            cpu.step().is_err(),
+            "custom instruction executed without an executor"
+        );
+        assert_eq!(cpu.pc, 0x0040_0000);
+
+        cpu.custom_executor = Some(Box::new(|cpu, instruction| {
+            let value = cpu.registers[instruction.rs1()];
+            cpu.registers[instruction.rd()] = value.wrapping_mul(value);
+            Ok(())
+        }));
+        assert_eq!(
+            cpu.fetch_and_decode(cpu.pc)?,
+            Rv32imInstruction::Custom(CustomInstruction::new("square", 0x0005_050b))
+        );
+        cpu.step()?;
+        assert_eq!(cpu.registers[RegisterMapping::A0], 49);
+        assert_eq!(cpu.pc, 0x0040_0004);
+        Ok(())
+    }
+
+    #[test]
+    fn test_step_reports_custom_instruction_memory_access() -> Result<()> {
+        // a custom-0 instruction storing rs1 to the address in rd: push a0, sp
+        let mut cpu = cpu_from_words(&[0x0005_010b]);
+        cpu.custom_decoder = Some(Box::new(|machine_code| {
+            (machine_code & 0b111_1111 == 0b000_1011)
+                .then(|| CustomInstruction::new("push", machine_code))
+        }));
+        cpu.custom_executor = Some(Box::new(|cpu, instruction| {
+            let addr = cpu.registers[instruction.rd()] - 4;
+            let value = cpu.registers[instruction.rs1()];
+            cpu.memory.store(addr, value, Size::Word)
+        }));
+        cpu.registers[RegisterMapping::A0] = 7;
+
+        let report = cpu.step_reported()?;
+        assert_eq!(
+            report.memory_access,
+            Some(MemoryAccess {
+                addr: STACK_CEILING - 4,
+                size: Size::Word,
+                value: 7,
+                store: true,
+            })
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_step_count() -> Result<()> {
+        // addi a0, a0, 1; jal x0, -4
+        let mut cpu = cpu_from_words(&[0x0015_0513, 0xffdf_f06f]);
+        cpu.debug = true;
+        // as if the user entered `s 100`
+        cpu.pending_steps = 100;
+        let mut steps = 0;
+        while cpu.pending_steps > 0 {
+            cpu.step()?;
+            steps += 1;
+        }
+        assert_eq!(steps, 100);
+        assert_eq!(cpu.instret, 100);
+        assert_eq!(cpu.registers[RegisterMapping::A0], 50);
+        Ok(())
+    }
+
+    #[test]
+    fn test_step_count_stops_at_ebreak() -> Result<()> {
+        // addi a0, a0, 1; ebreak; addi a0, a0, 1
+        let mut cpu = cpu_from_words(&[0x0015_0513, 0x0010_0073, 0x0015_0513]);
+        cpu.debug = true;
+        cpu.pending_steps = 100;
+        cpu.step()?;
+        assert_eq!(cpu.pending_steps, 99);
+        cpu.step()?;
+        assert_eq!(cpu.pending_steps, 0);
+        assert_eq!(cpu.pc, 0x0040_0008);
+        Ok(())
+    }
+
+    #[test]
+    fn test_halt_is_resumable() -> Result<()> {
+        let mut cpu = cpu_from_words(&[
+            0x0012_8293, // addi t0, t0, 1
+            0x0470_0893, // li a7, 71
+            0x0000_0073, // ecall
+            0x0012_8293, // addi t0, t0, 1
+        ]);
+        assert_eq!(cpu.run(None)?, RunOutcome::Halted);
+        assert!(cpu.halted);
+        // the state is intact, and the pc is past the halt
+        assert_eq!(cpu.registers[RegisterMapping::T0], 1);
+        assert_eq!(cpu.pc, 0x0040_000c);
+
+        cpu.step()?;
+        assert!(!cpu.halted);
+        assert_eq!(cpu.registers[RegisterMapping::T0], 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_ra_guard_catches_clobbered_return_address() {
+        let mut cpu = cpu_from_words(&[
+            0xff01_0113, // addi sp, sp, -16
+            0x0011_2623, // sw ra, 12(sp)
+            0x0001_2623, // sw zero, 12(sp)
+        ]);
+        cpu.ra_guard = Some(ReturnAddressGuard::new());
+        let sp = cpu.registers[RegisterMapping::Sp] - 16;
+
+        let error = cpu.run(None).expect_err("clobbering ra should trap");
+        assert_eq!(
+            error.downcast_ref::<Trap>(),
+            Some(&Trap::ReturnAddressCorrupted {
+                slot: sp + 12,
+                value: 0
+            })
+        );
+        // the store is rolled back, as if it never executed
+        assert_eq!(cpu.pc, 0x0040_0008);
+        assert_eq!(cpu.instret, 2);
+        assert_eq!(
+            cpu.memory.read(sp + 12, Size::Word).ok(),
+            Some(cpu.registers[RegisterMapping::Ra])
+        );
+        assert_eq!(
+            cpu.ra_guard.as_ref().map(|guard| guard.slots().collect()),
+            Some(vec![sp + 12])
+        );
+    }
+
+    #[test]
+    fn test_ra_guard_allows_epilogue() -> Result<()> {
+        let mut cpu = cpu_from_words(&[
+            0xff01_0113, // addi sp, sp, -16
+            0x0011_2623, // sw ra, 12(sp)
+            0x00c1_2083, // lw ra, 12(sp)
+            0x0101_0113, // addi sp, sp, 16
+            0xfe01_0113, // addi sp, sp, -32
+            0x0001_2e23, // sw zero, 28(sp)
+        ]);
+        cpu.ra_guard = Some(ReturnAddressGuard::new());
+        cpu.run(Some(6))?;
+        assert_eq!(
+            cpu.ra_guard.as_ref().map(|guard| guard.slots().count()),
+            Some(0)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_ra_guard_store_at_the_end_of_the_address_space() -> Result<()> {
+        let memory = MemoryBus::new(0x0040_0000, &[0; 4], &[])?;
+        let mut guard = ReturnAddressGuard::new();
+        let nop = Rv32imInstruction::from_machine_code(0x0000_0013)?;
+        let write = MemoryWrite {
+            addr: 0xFFFF_FFFC,
+            size: Size::Word,
+            previous: 0,
+        };
+        guard.observe(&nop, &RegisterFile32Bit::new(), &[write], &memory, 0)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_callee_saved_checker_catches_clobbered_register() {
+        let mut cpu = cpu_from_words(&[
+            0x0080_00ef, // jal ra, 8
+            0x0000_0013, // nop
+            0x0014_8493, // addi s1, s1, 1
+            0x0000_8067, // jalr zero, 0(ra)
+        ]);
+        cpu.callee_saved_checker = Some(CalleeSavedChecker::new());
+
+        let error = cpu.run(None).expect_err("clobbering s1 should trap");
+        assert_eq!(
+            error.downcast_ref::<Trap>(),
+            Some(&Trap::CalleeSavedRegisterClobbered {
+                function: 0x0040_0008,
+                register: RegisterMapping::S1
+            })
+        );
+        assert_eq!(
+            error.to_string(),
+            "Function at 0x00400008 clobbered callee-saved register s1"
+        );
+        // the return is rolled back, so the function is still on the checker's stack
+        assert_eq!(cpu.pc, 0x0040_000c);
+        assert_eq!(
+            cpu.callee_saved_checker
+                .as_ref()
+                .map(CalleeSavedChecker::depth),
+            Some(1)
+        );
+    }
+
+    #[test]
+    fn test_callee_saved_checker_allows_restored_registers() -> Result<()> {
+        let mut cpu = cpu_from_words(&[
+            0x0080_00ef, // jal ra, 8
+            0x0000_0013, // nop
+            0xff01_0113, // addi sp, sp, -16
+            0x0091_2623, // sw s1, 12(sp)
+            0x0014_8493, // addi s1, s1, 1
+            0x0015_0513, // addi a0, a0, 1
+            0x00c1_2483, // lw s1, 12(sp)
+            0x0101_0113, // addi sp, sp, 16
+            0x0000_8067, // jalr zero, 0(ra)
+        ]);
+        cpu.callee_saved_checker = Some(CalleeSavedChecker::new());
+        cpu.run(Some(9))?;
+        assert_eq!(cpu.pc, 0x0040_0008);
+        assert_eq!(
+            cpu.callee_saved_checker
+                .as_ref()
+                .map(CalleeSavedChecker::depth),
+            Some(0)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_compressed_jal_saves_pc_plus_2() -> Result<()> {
+        // c.jal 8
+        let mut cpu = Cpu32Bit::new(&0x2021_u16.to_le_bytes(), &[], 0x0040_0000, None)?;
+        cpu.step_once()?;
+        assert_eq!(cpu.registers[RegisterMapping::Ra], 0x0040_0002);
+        assert_eq!(cpu.pc, 0x0040_0008);
+        Ok(())
+    }
+
+    #[test]
+    fn test_compressed_loop() -> Result<()> {
+        // sum 5 + 4 + 3 + 2 + 1 into a1, in compressed instructions, then a full-width one
+        let code: Vec<u8> = [
+            0x4515_u16, // c.li a0, 5
+            0x4581,     // c.li a1, 0
+            0x95aa,     // loop: c.add a1, a0
+            0x157d,     // c.addi a0, -1
+            0xfd75,     // c.bnez a0, loop
+            0x862e,     // c.mv a2, a1
+            0x0693,     // addi a3, a2, 1 (low half)
+            0x0016,     // addi a3, a2, 1 (high half)
+        ]
+        .iter()
+        .flat_map(|half| half.to_le_bytes())
+        .collect();
+        let mut cpu = Cpu32Bit::new(&code, &[], 0x0040_0000, None)?;
+        cpu.run(Some(19))?;
+        assert_eq!(cpu.registers[RegisterMapping::A0], 0);
+        assert_eq!(cpu.registers[RegisterMapping::A2], 15);
+        assert_eq!(cpu.registers[RegisterMapping::A3], 16);
+        assert_eq!(cpu.pc, 0x0040_0010);
+        Ok(())
+    }
+
+    #[test]
+    fn test_running_off_the_end_of_code() {
+        // add a0, a0, a1, with nothing after it
+        let mut cpu = cpu_from_words(&[0x00b5_0533]);
+        let error = cpu
+            .run(None)
+            .expect_err("running past the last instruction should stop the program");
+        assert_eq!(
+            error.downcast_ref::<Trap>(),
+            Some(&Trap::RanOffEndOfCode { pc: 0x0040_0004 })
+        );
+        assert_eq!(cpu.instret, 1);
+
+        // jumping elsewhere outside of the code is still out of bounds
+        cpu.pc = 0x0040_0100;
+        let error = cpu.step().expect_err("the pc is out of bounds");
+        assert!(error.downcast_ref::<Trap>().is_none());
+    }
+
+    #[test]
+    fn test_jal_saves_pc_plus_4() -> Result<()> {
+        // jal ra, 8
+        let mut cpu = cpu_from_words(&[0x0080_00ef]);
+        cpu.step_once()?;
+        assert_eq!(cpu.registers[RegisterMapping::Ra], 0x0040_0004);
+        assert_eq!(cpu.pc, 0x0040_0008);
+        Ok(())
+    }
+
+    #[test]
+    fn test_decode_cache() -> Result<()> {
+        let mut cpu = cpu_from_words(&[
+            0x0015_0513, // addi a0, a0, 1
+            0x0000_100f, // fence.i
+        ]);
+
+        cpu.fetch_and_decode(0x0040_0000)?;
+        cpu.fetch_and_decode(0x0040_0000)?;
+        let stats = cpu.memory.decode_cache_stats();
+        assert_eq!((stats.hits, stats.misses, stats.entries), (1, 1, 1));
+
+        // executing the fence.i invalidates every cached instruction
+        cpu.step_once()?;
+        cpu.step_once()?;
+        assert_eq!(cpu.memory.decode_cache_stats().entries, 0);
+        cpu.fetch_and_decode(0x0040_0000)?;
+        let stats = cpu.memory.decode_cache_stats();
+        assert_eq!((stats.hits, stats.misses, stats.entries), (2, 3, 1));
+        Ok(())
+    }
+
+    #[test]
+    fn test_continue_budget() -> Result<()> {
+        // j .
+        let mut cpu = cpu_from_words(&[0x0000_006f]);
+        cpu.continue_budget = Some(10);
+        cpu.continue_to_next_breakpoint();
+
+        cpu.run(Some(9))?;
+        assert!(!cpu.debug);
+        // the debugger prompts again instead of running the loop forever
+        cpu.run(Some(1))?;
+        assert!(cpu.debug);
+        Ok(())
+    }
+
+    #[test]
+    fn test_csrs() -> Result<()> {
+        let mut cpu = cpu_from_words(&[0x0000_0013, 0x0000_0013]); // nop; nop
+        cpu.step_once()?;
+        cpu.step_once()?;
+        assert_eq!(cpu.read_csr(csr::INSTRET), Some(2));
+        assert_eq!(cpu.read_csr_by_name("minstret"), Some(2));
+        assert!(cpu.write_csr(csr::INSTRET, 0).is_err());
+
+        cpu.write_csr_by_name("mtvec", 0x0040_0100)?;
+        assert_eq!(cpu.read_csr(csr::MTVEC), Some(0x0040_0100));
+        assert_eq!(cpu.read_csr(0x7FF), None);
+
+        // RV32IMC, unless the C extension is turned off
+        assert_eq!(cpu.read_csr_by_name("misa"), Some(0x4000_1104));
+        cpu.isa = Some("rv32im".parse()?);
+        assert_eq!(cpu.read_csr_by_name("misa"), Some(0x4000_1100));
+        Ok(())
+    }
+
+    #[test]
+    fn test_bare_metal_traps() -> Result<()> {
+        let mut cpu = cpu_from_words(&[
+            0x0000_0297, // auipc t0, 0
+            0x0142_8293, // addi t0, t0, 20
+            0x3052_9073, // csrw mtvec, t0
+            0x0000_0073, // ecall
+            0x1800_2573, // csrr a0, satp
+            0x3020_0073, // mret
+        ]);
+        cpu.bare_metal = true;
+        for _ in 0..4 {
+            cpu.step_once()?;
         }
+        // the ecall jumps to the handler
+        assert_eq!(cpu.pc, 0x0040_0014);
+        assert_eq!(cpu.read_csr_by_name("mcause"), Some(11));
+        assert_eq!(cpu.read_csr_by_name("mepc"), Some(0x0040_000c));
+
+        // and an unsupported CSR is an illegal instruction
+        cpu.pc = 0x0040_0010;
+        cpu.step_once()?;
+        assert_eq!(cpu.pc, 0x0040_0014);
+        assert_eq!(cpu.read_csr_by_name("mcause"), Some(2));
+        assert_eq!(cpu.read_csr_by_name("mepc"), Some(0x0040_0010));
+        // both trapping steps are counted
+        assert_eq!(cpu.instret, 5);
+        // which the handler returns to
+        cpu.step_once()?;
+        assert_eq!(cpu.pc, 0x0040_0010);
+        // taking the trap can be undone
+        cpu.set_undo_depth(4);
+        cpu.step_once()?;
+        assert_eq!(cpu.pc, 0x0040_0014);
+        assert_eq!(cpu.instret, 7);
+        cpu.undo()?;
+        assert_eq!(cpu.pc, 0x0040_0010);
+        assert_eq!(cpu.instret, 6);
+
+        // without a trap handler, the exception stops the program
+        let mut cpu = cpu_from_words(&[0x0000_0073]); // ecall
+        cpu.bare_metal = true;
+        let error = cpu.step_once().expect_err("there is no trap handler");
+        assert!(
+            error
+                .to_string()
+                .starts_with("No trap handler for exception 11"),
+            "{error}"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_run_traced() -> Result<()> {
+        let mut cpu = cpu_from_words(&[0x0015_0513, 0x0015_0513, 0x0015_0513]); // addi a0, a0, 1
+        let mut trace = Vec::new();
+        let delay = Duration::from_millis(20);
+
+        let start = Instant::now();
+        cpu.run_traced(Some(3), &mut trace, Some(delay))?;
+        // one delay per instruction
+        assert!(start.elapsed() >= delay * 3);
+
+        let trace = String::from_utf8(trace)?;
+        assert_eq!(trace.lines().count(), 3);
+        assert_eq!(trace.lines().next(), Some("0x00400000: addi a0, a0, 1"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_run_traced_continue_on_fault() -> Result<()> {
+        let mut cpu = cpu_from_words(&[
+            0x0000_2503, // lw a0, 0(zero)
+            0x0015_8593, // addi a1, a1, 1
+            0xfe05_2e23, // sw zero, -4(a0)
+            0x0015_8593, // addi a1, a1, 1
+            0x0000_0000, // illegal instruction
+        ]);
+        cpu.continue_on_fault = true;
+        let mut trace = Vec::new();
+        let error = cpu
+            .run_traced(None, &mut trace, None)
+            .expect_err("an illegal instruction still stops the program");
+        assert!(error.downcast_ref::<Trap>().is_none());
+        assert_eq!(cpu.pc, 0x0040_0010);
+        assert_eq!(cpu.registers[RegisterMapping::A1], 2);
+
+        let trace = String::from_utf8(trace)?;
+        let faults: Vec<_> = trace
+            .lines()
+            .filter(|line| line.contains("fault skipped"))
+            .collect();
+        assert_eq!(faults.len(), 2, "{trace}");
+        assert!(faults[0].starts_with("0x00400000: fault skipped: "));
+        assert!(faults[0].ends_with("Null pointer dereference: 0x00000000"));
+        assert!(faults[1].starts_with("0x00400008: "));
+        Ok(())
+    }
+
+    #[test]
+    fn test_trap_on_write() -> Result<()> {
+        let mut cpu = cpu_from_words(&[
+            0x0015_8593, // addi a1, a1, 1
+            0x0005_0513, // mv a0, a0
+            0x0015_0513, // addi a0, a0, 1
+            0x0015_0513, // addi a0, a0, 1
+        ]);
+        cpu.trap_on_write(RegisterMapping::A0);
+        cpu.run(Some(1))?;
+        assert!(!cpu.debug);
+        // writing the same value still counts
+        cpu.run(Some(1))?;
+        assert!(cpu.debug);
+        assert_eq!(cpu.pc, 0x0040_0008);
+        assert_eq!(cpu.registers[RegisterMapping::A0], 0);
+
+        // it's one-shot
+        cpu.debug = false;
+        cpu.run(Some(2))?;
+        assert!(!cpu.debug);
+        assert_eq!(cpu.registers[RegisterMapping::A0], 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_trap_on_write_by_syscall() -> Result<()> {
+        let mut cpu = cpu_from_words(&[
+            0x0090_0893, // li a7, 9
+            0x0000_0073, // ecall (sbrk 0, returning the heap's end in a0)
+            0x0000_0013, // nop
+        ]);
+        cpu.trap_on_write(RegisterMapping::A0);
+        cpu.run(Some(1))?;
+        assert!(!cpu.debug);
+        cpu.run(Some(1))?;
+        assert!(cpu.debug);
+        assert_eq!(cpu.pc, 0x0040_0008);
+        Ok(())
+    }
+
+    #[test]
+    fn test_finish_function() -> Result<()> {
+        let mut cpu = cpu_from_words(&[
+            0x0080_00ef, // jal ra, 8
+            0x0000_0013, // nop
+            0x0015_0513, // addi a0, a0, 1
+            0x0015_0513, // addi a0, a0, 1
+            0x0000_8067, // ret
+        ]);
+        cpu.step_once()?;
+        cpu.finish_function();
+
+        cpu.run(Some(2))?;
+        assert!(!cpu.debug);
+        // stops back in the caller, just after the call
+        cpu.run(Some(1))?;
+        assert!(cpu.debug);
+        assert_eq!(cpu.pc, 0x0040_0004);
+        Ok(())
+    }
+
+    #[test]
+    fn test_finish_function_after_a_nested_call() -> Result<()> {
+        let mut cpu = cpu_from_words(&[
+            0x0080_00ef, // jal ra, 8
+            0x0000_0013, // nop
+            0xffc1_0113, // addi sp, sp, -4
+            0x0011_2023, // sw ra, 0(sp)
+            0x0100_00ef, // jal ra, 16
+            0x0001_2083, // lw ra, 0(sp)
+            0x0041_0113, // addi sp, sp, 4
+            0x0000_8067, // ret
+            0x0015_0513, // addi a0, a0, 1
+            0x0000_8067, // ret
+        ]);
+        // in the outer function, after it called the inner one, so ra is the inner function's return address
+        cpu.run(Some(6))?;
+        assert_eq!(cpu.pc, 0x0040_0014);
+        cpu.finish_function();
+
+        cpu.run(Some(2))?;
+        assert!(!cpu.debug);
+        // stops back in the outer function's caller, not where the inner function returned to
+        cpu.run(Some(1))?;
+        assert!(cpu.debug);
+        assert_eq!(cpu.pc, 0x0040_0004);
+        Ok(())
+    }
+
+    #[test]
+    fn test_gp_relative_load() -> Result<()> {
+        // the data is linked at 0x10000000 and gp points 2KiB into it, as the GNU linker places it
+        let text = words_to_bytes(&[
+            0x8001_a503u32, // lw a0, -2048(gp)
+            0x8041_a583,    // lw a1, -2044(gp)
+        ]);
+        let data = words_to_bytes(&[0x1234_5678u32, 0x9abc_def0]);
+        let memory = MemoryBus::with_data_base(0x0040_0000, &text, &data, 0x1000_0000)?;
+        let mut cpu = Cpu32Bit::with_memory(memory, Some(0x1000_0800));
+
+        cpu.run(Some(2))?;
+        assert_eq!(cpu.registers[RegisterMapping::A0], 0x1234_5678);
+        assert_eq!(cpu.registers[RegisterMapping::A1], 0x9abc_def0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_new_output() -> Result<()> {
+        let mut cpu = cpu_from_words(&[
+            0x0010_0893, // li a7, 1
+            0x0000_0073, // ecall
+            0x0000_0073, // ecall
+        ]);
+        cpu.output_sink = ProgramOutput::Writer(Box::new(std::io::sink()));
+        cpu.registers[RegisterMapping::A0] = 12;
+
+        assert_eq!(cpu.new_output(), "");
+        cpu.run(Some(2))?;
+        assert_eq!(cpu.new_output(), "12");
+        assert_eq!(cpu.new_output(), "");
+        cpu.registers[RegisterMapping::A0] = 345;
+        cpu.run(Some(1))?;
+        assert_eq!(cpu.new_output(), "345");
+        assert_eq!(cpu.output, "12345");
+        Ok(())
+    }
+
+    #[test]
+    fn test_step_reports_memory_access() -> Result<()> {
+        let mut cpu = cpu_from_words(&[
+            0x00a1_2423, // sw a0, 8(sp)
+            0x0081_0583, // lb a1, 8(sp)
+            0x0015_0513, // addi a0, a0, 1
+        ]);
+        let sp = cpu.registers[RegisterMapping::Sp] - 16;
+        cpu.registers[RegisterMapping::Sp] = sp;
+        cpu.registers[RegisterMapping::A0] = 0xdead_beef;
+
+        let reports = cpu.steps().take(3).collect::<Result<Vec<_>>>()?;
+        assert_eq!(
+            reports[0].memory_access,
+            Some(MemoryAccess {
+                addr: sp + 8,
+                size: Size::Word,
+                value: 0xdead_beef,
+                store: true,
+            })
+        );
+        assert_eq!(
+            reports[1].memory_access,
+            Some(MemoryAccess {
+                addr: sp + 8,
+                size: Size::Byte,
+                value: 0xef,
+                store: false,
+            })
+        );
+        assert_eq!(reports[2].memory_access, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_spike_commit_log() -> Result<()> {
+        let mut cpu = cpu_from_words(&[
+            0x0070_0513, // li a0, 7
+            0x0070_0513, // li a0, 7
+            0x00a1_2423, // sw a0, 8(sp)
+            0x0081_0583, // lb a1, 8(sp)
+            0x0080_006f, // j 8
+        ]);
+        let sp = cpu.registers[RegisterMapping::Sp];
+
+        let mut log = Vec::new();
+        cpu.run_commit_logged(Some(5), &mut log)?;
+        let log = String::from_utf8(log)?;
+        let lines: Vec<_> = log.lines().collect();
+        assert_eq!(
+            lines,
+            [
+                "core   0: 3 0x00400000 (0x00700513) x10 0x00000007".to_string(),
+                // rewriting the same value is still logged
+                "core   0: 3 0x00400004 (0x00700513) x10 0x00000007".to_string(),
+                format!(
+                    "core   0: 3 0x00400008 (0x00a12423) mem 0x{:08x} 0x00000007",
+                    sp + 8
+                ),
+                format!(
+                    "core   0: 3 0x0040000c (0x00810583) x11 0x00000007 mem 0x{:08x}",
+                    sp + 8
+                ),
+                "core   0: 3 0x00400010 (0x0080006f)".to_string(),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_steps() -> Result<()> {
+        let mut cpu = cpu_from_words(&[
+            0x0070_0513, // li a0, 7
+            0x0080_006f, // j 8
+            0x0000_0000, // skipped
+            0x00a5_05b3, // add a1, a0, a0
+            0x00a0_0893, // li a7, 10
+            0x0000_0073, // ecall
+        ]);
+
+        let reports = cpu.steps().take(3).collect::<Result<Vec<_>>>()?;
+        assert_eq!(
+            reports.iter().map(|report| report.pc).collect::<Vec<_>>(),
+            [0x0040_0000, 0x0040_0004, 0x0040_000c]
+        );
+        assert_eq!(reports[0].registers, [(RegisterMapping::A0, 7)]);
+        assert_eq!(reports[1].next_pc, 0x0040_000c);
+        assert!(reports[1].registers.is_empty());
+        assert_eq!(reports[2].registers, [(RegisterMapping::A1, 14)]);
+        assert!(reports[2]
+            .instruction
+            .is_some_and(|instruction| instruction.disassemble(0x0040_000c) == "add a1, a0, a0"));
+
+        // the iterator ends with the program exiting
+        let rest: Vec<_> = cpu.steps().collect();
+        assert_eq!(rest.len(), 2);
+        assert!(rest[1]
+            .as_ref()
+            .is_err_and(anyhow::Error::is::<crate::emulator::execute::ProgramExit>));
+        Ok(())
+    }
+
+    #[test]
+    fn test_status_line() -> Result<()> {
+        let mut cpu = cpu_from_words(&[
+            0x0010_0513, // addi a0, zero, 1
+            0x0020_0593, // addi a1, zero, 2
+        ]);
+        cpu.step()?;
+        let status = cpu.status_line();
+        assert!(status.contains("pc=0x00400004"), "{status}");
+        assert!(status.contains("instret=1"), "{status}");
+        assert!(status.contains("a0=0x00000001"), "{status}");
+        assert!(status.contains("addi"), "{status}");
+        assert_eq!(status.lines().count(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_debugger_memory_map() {
+        // jal x0, 0
+        let cpu = cpu_from_words(&[0x0000_006f]);
+        let map = debugger::memory_map(&cpu);
+        assert!(map.contains("0x00400000 0x00400004 0x00000004 r-x  text"));
+        assert!(map.contains(&format!("{:#010x}", cpu.memory.dram_start())));
+        assert!(map.contains("rw-  data"));
+        assert!(map.contains("sp:   0x7fffeffc"));
     }
 }
@@ -0,0 +1,111 @@
+/*
+MIT License
+
+Copyright (c) 2024 Anthony Rubick
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+//! Exact per-instruction execution counts, for finding the hot spots of a program
+use std::collections::HashMap;
+
+use crate::emulator::{decode::CustomDecoder, fetch::Fetch32BitInstruction as _};
+
+use super::memory::MemoryBus;
+
+/// Counts how many times the instruction at each address was executed.
+#[derive(Debug, Clone, Default)]
+pub struct HotProfile {
+    counts: HashMap<u32, u64>,
+}
+
+impl HotProfile {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Count an execution of the instruction at `pc`.
+    pub fn record(&mut self, pc: u32) {
+        *self.counts.entry(pc).or_default() += 1;
+    }
+
+    /// get how many times the instruction at `pc` was executed
+    #[must_use]
+    pub fn count(&self, pc: u32) -> u64 {
+        self.counts.get(&pc).copied().unwrap_or_default()
+    }
+
+    /// Get the `n` most executed instruction addresses, with their execution counts, most executed first.
+    ///
+    /// Ties are broken by address, so the order is deterministic.
+    #[must_use]
+    pub fn hottest(&self, n: usize) -> Vec<(u32, u64)> {
+        let mut counts: Vec<_> = self
+            .counts
+            .iter()
+            .map(|(&pc, &count)| (pc, count))
+            .collect();
+        counts.sort_unstable_by(|(a_pc, a_count), (b_pc, b_count)| {
+            b_count.cmp(a_count).then(a_pc.cmp(b_pc))
+        });
+        counts.truncate(n);
+        counts
+    }
+
+    /// Format the `n` hottest instructions, one per line, with their execution counts and disassembly.
+    #[must_use]
+    pub fn report(
+        &self,
+        n: usize,
+        memory: &MemoryBus,
+        custom_decoder: Option<&CustomDecoder>,
+    ) -> String {
+        let rows = self.hottest(n).into_iter().map(|(pc, count)| {
+            let instruction = memory
+                .fetch_and_decode_with(pc, custom_decoder)
+                .map_or_else(
+                    |_| "<invalid instruction>".to_string(),
+                    |(instruction, _)| instruction.disassemble(pc),
+                );
+            format!("{count:>12}  {pc:#010x}  {instruction}")
+        });
+        std::iter::once(format!("{:>12}  {:<10}  instruction", "count", "address"))
+            .chain(rows)
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hottest() {
+        let mut profile = HotProfile::new();
+        for pc in [0x10, 0x14, 0x14, 0x18, 0x18, 0x0c] {
+            profile.record(pc);
+        }
+        assert_eq!(profile.count(0x14), 2);
+        assert_eq!(profile.count(0x20), 0);
+        assert_eq!(profile.hottest(3), [(0x14, 2), (0x18, 2), (0x0c, 1)]);
+        assert_eq!(profile.hottest(10).len(), 4);
+    }
+}
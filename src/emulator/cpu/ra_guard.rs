@@ -0,0 +1,125 @@
+/*
+MIT License
+
+Copyright (c) 2024 Anthony Rubick
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+//! Best-effort detection of saved return addresses being clobbered, e.g. by a stack buffer overflow
+use std::collections::BTreeSet;
+
+use anyhow::Result;
+
+use crate::{
+    emulator::trap::Trap,
+    instruction_set_definition::{
+        operations::{ITypeOperation, STypeOperation},
+        Rv32imInstruction,
+    },
+};
+
+use super::{
+    memory::{MemoryBus, MemoryWrite},
+    registers::{RegisterFile32Bit, RegisterMapping},
+    Size,
+};
+
+/// Watches the stack slots `ra` is saved to, and traps when one is overwritten with an address outside of the text section.
+///
+/// Slots are recognized by the standard prologue and epilogue: a slot is watched from `sw ra, offset(base)`
+/// until `ra` is reloaded from it with `lw ra, offset(base)`, or the stack pointer moves above it.
+#[derive(Debug, Clone, Default)]
+pub struct ReturnAddressGuard {
+    slots: BTreeSet<u32>,
+}
+
+impl ReturnAddressGuard {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// get the addresses of the slots currently being watched
+    pub fn slots(&self) -> impl Iterator<Item = u32> + '_ {
+        self.slots.iter().copied()
+    }
+
+    /// Check the stores `instruction` made, then update the watched slots for it.
+    ///
+    /// `registers` are the values of the registers before the instruction executed,
+    /// and `writes` are the stores it made.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Trap::ReturnAddressCorrupted` if a store left a watched slot holding an address outside of the text section,
+    /// leaving the watched slots as they were, so the store can be rolled back.
+    pub fn observe(
+        &mut self,
+        instruction: &Rv32imInstruction,
+        registers: &RegisterFile32Bit,
+        writes: &[MemoryWrite],
+        memory: &MemoryBus,
+        sp: u32,
+    ) -> Result<()> {
+        match *instruction {
+            // prologue, `ra` is saved
+            Rv32imInstruction::SType {
+                operation: STypeOperation::Sw,
+                rs1,
+                rs2: RegisterMapping::Ra,
+                imm,
+                ..
+            } => {
+                self.slots.insert(registers[rs1].wrapping_add_signed(imm));
+                return Ok(());
+            }
+            // epilogue, `ra` is restored
+            Rv32imInstruction::IType {
+                operation: ITypeOperation::Lw,
+                rd: RegisterMapping::Ra,
+                rs1,
+                imm,
+                ..
+            } => {
+                self.slots.remove(&registers[rs1].wrapping_add_signed(imm));
+            }
+            _ => {}
+        }
+
+        for write in writes {
+            // the last byte stored, which doesn't wrap, as stores can't run past the end of memory
+            let last = write.addr.saturating_add(write.size.bytes() - 1);
+            // a slot overlaps the store if it starts less than 4 bytes before its end,
+            // and slots below the stack pointer belong to frames that have been popped
+            let overlapping = self
+                .slots
+                .range(write.addr.saturating_sub(3)..=last)
+                .filter(|&&slot| slot >= sp);
+            for &slot in overlapping {
+                let value = memory.read(slot, Size::Word)?;
+                if value.wrapping_sub(memory.text_base()) >= memory.code_size() {
+                    return Err(Trap::ReturnAddressCorrupted { slot, value }.into());
+                }
+            }
+        }
+        self.slots = self.slots.split_off(&sp);
+        Ok(())
+    }
+}
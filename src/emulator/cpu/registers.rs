@@ -28,6 +28,7 @@ use std::{
 };
 
 use anyhow::bail;
+use serde::{de::Error as _, Deserialize, Deserializer, Serialize};
 
 use super::REGISTERS_COUNT;
 
@@ -68,9 +69,114 @@ pub enum RegisterMapping {
     T6 = 31,
 }
 
+impl RegisterMapping {
+    /// get the name of the register in the standard calling convention, e.g. `zero`, `ra`, `a0`
+    #[must_use]
+    pub const fn abi_name(self) -> &'static str {
+        const ABI_NAMES: [&str; REGISTERS_COUNT as usize] = [
+            "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3",
+            "a4", "a5", "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11",
+            "t3", "t4", "t5", "t6",
+        ];
+        ABI_NAMES[self as usize]
+    }
+
+    /// get the role of the register in the standard calling convention, e.g. `stack pointer`
+    #[must_use]
+    pub const fn role(self) -> &'static str {
+        match self {
+            Self::Zero => "hard-wired zero",
+            Self::Ra => "return address",
+            Self::Sp => "stack pointer",
+            Self::Gp => "global pointer",
+            Self::Tp => "thread pointer",
+            Self::T0 | Self::T1 | Self::T2 | Self::T3 | Self::T4 | Self::T5 | Self::T6 => {
+                "temporary"
+            }
+            Self::S0 => "saved register / frame pointer",
+            Self::S1
+            | Self::S2
+            | Self::S3
+            | Self::S4
+            | Self::S5
+            | Self::S6
+            | Self::S7
+            | Self::S8
+            | Self::S9
+            | Self::S10
+            | Self::S11 => "saved register",
+            Self::A0 | Self::A1 => "function argument / return value",
+            Self::A2 | Self::A3 | Self::A4 | Self::A5 | Self::A6 | Self::A7 => "function argument",
+        }
+    }
+
+    /// get who is responsible for preserving the register across calls,
+    /// `None` for registers that aren't allocated by the calling convention (`zero`, `gp`, `tp`)
+    #[must_use]
+    pub const fn saver(self) -> Option<Saver> {
+        match self {
+            Self::Zero | Self::Gp | Self::Tp => None,
+            Self::Sp
+            | Self::S0
+            | Self::S1
+            | Self::S2
+            | Self::S3
+            | Self::S4
+            | Self::S5
+            | Self::S6
+            | Self::S7
+            | Self::S8
+            | Self::S9
+            | Self::S10
+            | Self::S11 => Some(Saver::Callee),
+            _ => Some(Saver::Caller),
+        }
+    }
+}
+
+/// Who is responsible for preserving a register across a function call.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Saver {
+    /// The caller must save the register if it needs its value after the call.
+    Caller,
+    /// The callee must restore the register before returning.
+    Callee,
+}
+
+impl fmt::Display for Saver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Caller => write!(f, "caller-saved"),
+            Self::Callee => write!(f, "callee-saved"),
+        }
+    }
+}
+
+/// Registers are displayed by number (e.g. `x10`), or by ABI name (e.g. `a0`) with the alternate flag (`{:#}`).
 impl fmt::Display for RegisterMapping {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "x{:02}", *self as u8)
+        if f.alternate() {
+            write!(f, "{}", self.abi_name())
+        } else {
+            write!(f, "x{:02}", *self as u8)
+        }
+    }
+}
+
+/// Registers are parsed from their ABI name (e.g. `a0`, or `fp` for `s0`), or their number (e.g. `x10`).
+impl std::str::FromStr for RegisterMapping {
+    type Err = anyhow::Error;
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        if name == "fp" {
+            return Ok(Self::S0);
+        }
+        if let Some(number) = name.strip_prefix('x').and_then(|n| n.parse::<u8>().ok()) {
+            return Self::try_from(number);
+        }
+        (0..REGISTERS_COUNT)
+            .filter_map(|i| Self::try_from(i).ok())
+            .find(|reg| reg.abi_name() == name)
+            .ok_or_else(|| anyhow::anyhow!("Unknown register: {name}"))
     }
 }
 
@@ -91,9 +197,55 @@ impl TryFrom<u8> for RegisterMapping {
     }
 }
 
-#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
+/// The general purpose registers.
+///
+/// `x0` is hardwired to zero: it always reads as 0, and writes to it are discarded.
+///
+/// The registers written since reset are tracked, and displaying the registers with the alternate flag (`{:#}`)
+/// marks the others as `<uninit>`.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 pub struct RegisterFile32Bit {
+    /// `x0` is rejected if it isn't 0 when deserializing, e.g. from a hand-edited snapshot
+    #[serde(deserialize_with = "hardwired_zero")]
     registers: [u32; REGISTERS_COUNT as usize],
+    /// where writes to `x0` through `IndexMut` end up, it's never read
+    #[serde(skip)]
+    discarded: u32,
+    /// a bit for each register, set once it's been written, the registers of a snapshot were all written
+    #[serde(skip, default = "all_written")]
+    written: u32,
+}
+
+const fn all_written() -> u32 {
+    u32::MAX
+}
+
+/// Deserialize the registers, checking that `x0` is 0.
+fn hardwired_zero<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<[u32; REGISTERS_COUNT as usize], D::Error> {
+    let registers = <[u32; REGISTERS_COUNT as usize]>::deserialize(deserializer)?;
+    if registers[0] != 0 {
+        return Err(D::Error::custom(format!(
+            "x0 is {:#x}, but it's hardwired to zero",
+            registers[0]
+        )));
+    }
+    Ok(registers)
+}
+
+impl PartialEq for RegisterFile32Bit {
+    fn eq(&self, other: &Self) -> bool {
+        self.registers == other.registers
+    }
+}
+
+impl Eq for RegisterFile32Bit {}
+
+impl std::hash::Hash for RegisterFile32Bit {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.registers.hash(state);
+    }
 }
 
 impl Index<RegisterMapping> for RegisterFile32Bit {
@@ -105,16 +257,27 @@ impl Index<RegisterMapping> for RegisterFile32Bit {
 
 impl IndexMut<RegisterMapping> for RegisterFile32Bit {
     fn index_mut(&mut self, index: RegisterMapping) -> &mut Self::Output {
-        assert!(index != RegisterMapping::Zero, "Cannot write to the zero register");
+        if index == RegisterMapping::Zero {
+            return &mut self.discarded;
+        }
+        self.written |= 1 << index as u8;
         &mut self.registers[index as usize]
     }
 }
 
+impl Default for RegisterFile32Bit {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl RegisterFile32Bit {
     #[must_use]
     pub const fn new() -> Self {
         Self {
             registers: [0; REGISTERS_COUNT as usize],
+            discarded: 0,
+            written: 0,
         }
     }
 
@@ -123,8 +286,36 @@ impl RegisterFile32Bit {
         self.registers[reg as usize]
     }
 
-    pub fn write(&mut self, reg: RegisterMapping, value: u32) {
-        self.registers[reg as usize] = value;
+    /// Write `value` to `reg`, writes to `x0` are discarded.
+    pub const fn write(&mut self, reg: RegisterMapping, value: u32) {
+        if !matches!(reg, RegisterMapping::Zero) {
+            self.registers[reg as usize] = value;
+            self.written |= 1 << reg as u8;
+        }
+    }
+
+    /// Read `reg`, interpreting its value as a two's complement signed integer.
+    #[must_use]
+    #[allow(clippy::cast_possible_wrap)]
+    pub const fn read_signed(&self, reg: RegisterMapping) -> i32 {
+        self.read(reg) as i32
+    }
+
+    /// Write the two's complement representation of `value` to `reg`, see `write`.
+    #[allow(clippy::cast_sign_loss)]
+    pub const fn write_signed(&mut self, reg: RegisterMapping, value: i32) {
+        self.write(reg, value as u32);
+    }
+
+    /// whether `reg` has been written since reset, `x0` always counts as written
+    #[must_use]
+    pub const fn is_written(&self, reg: RegisterMapping) -> bool {
+        matches!(reg, RegisterMapping::Zero) || self.written & (1 << reg as u8) != 0
+    }
+
+    /// Mark `reg` as not written, e.g. after its value is poisoned.
+    pub const fn mark_uninitialized(&mut self, reg: RegisterMapping) {
+        self.written &= !(1 << reg as u8);
     }
 }
 
@@ -135,24 +326,102 @@ impl fmt::Display for RegisterFile32Bit {
             " a1 ", " a2 ", " a3 ", " a4 ", " a5 ", " a6 ", " a7 ", " s2 ", " s3 ", " s4 ", " s5 ",
             " s6 ", " s7 ", " s8 ", " s9 ", " s10", " s11", " t3 ", " t4 ", " t5 ", " t6 ",
         ];
+        let mark_uninitialized = f.alternate();
+        let register = |i: u8| {
+            let reg = RegisterMapping::try_from(i).expect("Invalid register number");
+            let marker = if mark_uninitialized && !self.is_written(reg) {
+                " <uninit>"
+            } else {
+                ""
+            };
+            format!(
+                "x{i:02}({})={:#010x}{marker}",
+                abi[i as usize],
+                self.read(reg)
+            )
+        };
         let mut output = String::new();
         for i in (0..REGISTERS_COUNT).step_by(4) {
             output = format!(
-                "{output}\nx{:02}({})={:#010x} x{:02}({})={:#010x} x{:02}({})={:#010x} x{:02}({})={:#010x}",
-                i,
-                abi[i as usize],
-                self.read(RegisterMapping::try_from(i).expect("Invalid register number")),
-                i + 1,
-                abi[i as usize+ 1],
-                self.read(RegisterMapping::try_from(i + 1).expect("Invalid register number")),
-                i + 2,
-                abi[i as usize + 2],
-                self.read(RegisterMapping::try_from(i + 2).expect("Invalid register number")),
-                i + 3,
-                abi[i as usize + 3],
-                self.read(RegisterMapping::try_from(i + 3).expect("Invalid register number")),
+                "{output}\n{} {} {} {}",
+                register(i),
+                register(i + 1),
+                register(i + 2),
+                register(i + 3),
             );
         }
         write!(f, "{output}")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_registers() -> anyhow::Result<()> {
+        assert_eq!("a0".parse::<RegisterMapping>()?, RegisterMapping::A0);
+        assert_eq!("x10".parse::<RegisterMapping>()?, RegisterMapping::A0);
+        assert_eq!("fp".parse::<RegisterMapping>()?, RegisterMapping::S0);
+        assert_eq!("zero".parse::<RegisterMapping>()?, RegisterMapping::Zero);
+        assert!("x32".parse::<RegisterMapping>().is_err());
+        assert!("b0".parse::<RegisterMapping>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_display_marks_uninitialized_registers() {
+        let mut registers = RegisterFile32Bit::new();
+        registers.write(RegisterMapping::A0, 5);
+        registers[RegisterMapping::A1] = 6;
+
+        let marked = format!("{registers:#}");
+        assert!(marked.contains("x10( a0 )=0x00000005 x11"), "{marked}");
+        assert!(marked.contains("x11( a1 )=0x00000006\n"), "{marked}");
+        assert!(marked.contains("x12( a2 )=0x00000000 <uninit>"), "{marked}");
+        assert!(
+            !marked.contains("x00(zero)=0x00000000 <uninit>"),
+            "{marked}"
+        );
+        assert!(!registers.to_string().contains("<uninit>"));
+
+        registers.mark_uninitialized(RegisterMapping::A0);
+        assert!(format!("{registers:#}").contains("x10( a0 )=0x00000005 <uninit>"));
+    }
+
+    #[test]
+    fn test_signed_access() {
+        let mut registers = RegisterFile32Bit::new();
+        registers.write_signed(RegisterMapping::A0, -1);
+        assert_eq!(registers[RegisterMapping::A0], 0xFFFF_FFFF);
+        assert_eq!(registers.read_signed(RegisterMapping::A0), -1);
+
+        registers[RegisterMapping::A1] = 0x8000_0000;
+        assert_eq!(registers.read_signed(RegisterMapping::A1), i32::MIN);
+
+        // writes to zero are still discarded
+        registers.write_signed(RegisterMapping::Zero, -1);
+        assert_eq!(registers.read_signed(RegisterMapping::Zero), 0);
+    }
+
+    #[test]
+    fn test_display_abi_names() {
+        assert_eq!(format!("{:#}", RegisterMapping::Zero), "zero");
+        assert_eq!(format!("{:#}", RegisterMapping::Ra), "ra");
+        assert_eq!(format!("{:#}", RegisterMapping::Sp), "sp");
+        assert_eq!(format!("{:#}", RegisterMapping::S0), "s0");
+        assert_eq!(format!("{:#}", RegisterMapping::T6), "t6");
+        assert_eq!(format!("{}", RegisterMapping::Zero), "x00");
+        assert_eq!(format!("{}", RegisterMapping::Sp), "x02");
+    }
+
+    #[test]
+    fn test_writes_to_zero_are_discarded() {
+        let mut registers = RegisterFile32Bit::new();
+        registers[RegisterMapping::Zero] = 5;
+        assert_eq!(registers[RegisterMapping::Zero], 0);
+        registers.write(RegisterMapping::Zero, 5);
+        assert_eq!(registers.read(RegisterMapping::Zero), 0);
+        assert_eq!(registers, RegisterFile32Bit::new());
+    }
+}
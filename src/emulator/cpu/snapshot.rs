@@ -0,0 +1,130 @@
+/*
+MIT License
+
+Copyright (c) 2024 Anthony Rubick
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+//! Snapshots of the CPU's registers, for resuming or reproducing a state in a fresh run
+use std::path::Path;
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use super::{registers::RegisterFile32Bit, Cpu32Bit};
+
+/// The program counter and general purpose registers of a CPU.
+///
+/// This is a subset of a `CoreDump`, so a core dump can be read as a register snapshot.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+pub struct RegisterSnapshot {
+    pub pc: u32,
+    pub registers: RegisterFile32Bit,
+}
+
+impl RegisterSnapshot {
+    /// Capture the registers of the given CPU.
+    #[must_use]
+    pub const fn new(cpu: &Cpu32Bit) -> Self {
+        Self {
+            pc: cpu.pc,
+            registers: cpu.registers,
+        }
+    }
+
+    /// Set the registers and program counter of the given CPU to the snapshot's.
+    ///
+    /// # Errors
+    ///
+    /// This method will return an error, leaving the CPU unchanged, if the snapshot's program counter isn't valid for the loaded program.
+    pub fn apply(&self, cpu: &mut Cpu32Bit) -> Result<()> {
+        cpu.set_pc(self.pc)?;
+        cpu.registers = self.registers;
+        Ok(())
+    }
+
+    /// Serialize the snapshot as JSON and write it to the given path.
+    ///
+    /// # Errors
+    ///
+    /// This method will return an error if the file cannot be written.
+    pub fn write_to(&self, path: &Path) -> Result<()> {
+        let file = std::fs::File::create(path)?;
+        serde_json::to_writer(std::io::BufWriter::new(file), self)?;
+        Ok(())
+    }
+
+    /// Read a snapshot previously written with `write_to`, or the registers of a core dump.
+    ///
+    /// # Errors
+    ///
+    /// This method will return an error if the file cannot be read or doesn't contain a register snapshot,
+    /// including one where `x0` isn't 0.
+    pub fn read_from(path: &Path) -> Result<Self> {
+        let file = std::fs::File::open(path)?;
+        Ok(serde_json::from_reader(std::io::BufReader::new(file))?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{emulator::cpu::registers::RegisterMapping, utils::words_to_bytes};
+
+    #[test]
+    fn test_round_trip() -> Result<()> {
+        // addi a0, a0, 1; jal x0, -4
+        let text = words_to_bytes(&[0x0015_0513_u32, 0xffdf_f06f]);
+        let mut cpu = Cpu32Bit::new(&text, &[], 0x0040_0000, None)?;
+        cpu.run(Some(3))?;
+        let snapshot = RegisterSnapshot::new(&cpu);
+
+        let path = std::env::temp_dir().join(format!("snapshot-test-{}.json", std::process::id()));
+        snapshot.write_to(&path)?;
+        let read = RegisterSnapshot::read_from(&path);
+        std::fs::remove_file(&path)?;
+        let read = read?;
+        assert_eq!(read, snapshot);
+
+        let mut fresh = Cpu32Bit::new(&text, &[], 0x0040_0000, None)?;
+        read.apply(&mut fresh)?;
+        assert_eq!(fresh.pc, 0x0040_0004);
+        assert_eq!(fresh.registers[RegisterMapping::A0], 2);
+        assert_eq!(fresh.registers, cpu.registers);
+        Ok(())
+    }
+
+    #[test]
+    fn test_nonzero_x0_is_rejected() -> Result<()> {
+        let cpu = Cpu32Bit::new(&[0x13, 0, 0, 0], &[], 0x0040_0000, None)?;
+        let mut json = serde_json::to_value(RegisterSnapshot::new(&cpu))?;
+        json["registers"]["registers"][0] = 5.into();
+
+        let path =
+            std::env::temp_dir().join(format!("snapshot-x0-test-{}.json", std::process::id()));
+        std::fs::write(&path, json.to_string())?;
+        let read = RegisterSnapshot::read_from(&path);
+        std::fs::remove_file(&path)?;
+        let error = read.expect_err("x0 is hardwired to zero");
+        assert!(error.to_string().contains("hardwired to zero"), "{error}");
+        Ok(())
+    }
+}
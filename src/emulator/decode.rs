@@ -23,17 +23,23 @@ SOFTWARE.
 */
 
 //! Decoding of RISC-V instructions from 32-bit machine code
-use anyhow::{bail, Result};
+use anyhow::{anyhow, bail, Result};
 
 use crate::instruction_set_definition::{
     operations::{
         ITypeOperation, RTypeOperation, SBTypeOperation, STypeOperation, UJTypeOperation,
         UTypeOperation,
     },
-    Rv32imInstruction,
+    CustomInstruction, Rv32imInstruction,
 };
 
-use super::cpu::registers::RegisterMapping;
+use super::cpu::{registers::RegisterMapping, Size};
+
+/// A user-provided decoder for instructions outside of RV32IM.
+///
+/// It's given the machine code of any instruction the standard decoder doesn't recognize,
+/// and returns `None` if it doesn't recognize it either.
+pub type CustomDecoder = Box<dyn Fn(u32) -> Option<CustomInstruction> + Send>;
 
 #[allow(clippy::module_name_repetitions)]
 pub trait Decode32BitInstruction {
@@ -50,6 +56,41 @@ pub trait Decode32BitInstruction {
     fn from_machine_code(machine_code: u32) -> Result<Self>
     where
         Self: Sized;
+
+    /// Decode a 32-bit machine code into an instruction,
+    /// falling back to the `custom_decoder` (if any) when the machine code isn't recognized
+    ///
+    /// # Errors
+    /// - if neither the standard decoder nor the custom decoder recognize the machine code
+    fn from_machine_code_or_custom(
+        machine_code: u32,
+        custom_decoder: Option<&CustomDecoder>,
+    ) -> Result<Self>
+    where
+        Self: Sized;
+
+    /// Decode a 16-bit compressed (RVC) machine code into the equivalent full-width instruction
+    ///
+    /// # Errors
+    /// - if the machine code isn't a supported compressed instruction
+    fn from_compressed_machine_code(machine_code: u16) -> Result<Self>
+    where
+        Self: Sized;
+
+    /// Decode machine code of the given width, a compressed instruction if `width` is `Size::Half`
+    ///
+    /// # Errors
+    /// - if the machine code isn't a supported instruction of that width
+    fn from_machine_code_with_width(machine_code: u32, width: Size) -> Result<Self>
+    where
+        Self: Sized,
+    {
+        match width {
+            #[allow(clippy::cast_possible_truncation)] // compressed instructions are 16 bits
+            Size::Half => Self::from_compressed_machine_code(machine_code as u16),
+            Size::Byte | Size::Word => Self::from_machine_code(machine_code),
+        }
+    }
 }
 
 impl Decode32BitInstruction for Rv32imInstruction {
@@ -122,7 +163,14 @@ impl Decode32BitInstruction for Rv32imInstruction {
                     (0b000_0011, 0b010, _) => ITypeOperation::Lw,
                     (0b000_0011, 0b100, _) => ITypeOperation::Lbu,
                     (0b000_0011, 0b101, _) => ITypeOperation::Lhu,
-                    // fence and fence.i instructions
+                    // fence and fence.i instructions, fence.tso and pause are specific fence encodings
+                    (0b000_1111, 0b000, 0b1000_0011_0011) => ITypeOperation::FenceTso,
+                    (0b000_1111, 0b000, 0b0000_0001_0000)
+                        if matches!(rd, Ok(RegisterMapping::Zero))
+                            && matches!(rs1, Ok(RegisterMapping::Zero)) =>
+                    {
+                        ITypeOperation::Pause
+                    }
                     (0b000_1111, 0b000, _) => ITypeOperation::Fence,
                     (0b000_1111, 0b001, _) => ITypeOperation::FenceI,
                     // I-type arithmetic instructions
@@ -147,6 +195,16 @@ impl Decode32BitInstruction for Rv32imInstruction {
                         imm &= 0b11111;
                         ITypeOperation::Srai
                     }
+                    // RV64 shifts, which have a 6-bit shift amount
+                    (0b001_0011, 0b001 | 0b101, immediate)
+                        if immediate >> 6 == 0
+                            || (funct3 == 0b101 && immediate >> 6 == 0b01_0000) =>
+                    {
+                        bail!(
+                            "Shift amount {} out of range for RV32 (must be 0-31)\n machine code: {machine_code:#010x}",
+                            immediate & 0b11_1111
+                        )
+                    }
                     (0b001_0011, 0b010, _) => ITypeOperation::Slti,
                     (0b001_0011, 0b011, _) => ITypeOperation::Sltiu,
                     (0b001_0011, 0b100, _) => ITypeOperation::Xori,
@@ -155,11 +213,41 @@ impl Decode32BitInstruction for Rv32imInstruction {
                     // system instructions
                     (0b111_0011, 0b000, 0b0000_0000_0000) => ITypeOperation::Ecall,
                     (0b111_0011, 0b000, 0b0000_0000_0001) => ITypeOperation::Ebreak,
+                    (0b111_0011, 0b000, 0b0011_0000_0010) => ITypeOperation::Mret,
+                    // counter reads, i.e. `csrrs rd, <counter>, x0`
+                    (0b111_0011, 0b010, csr) if matches!(rs1, Ok(RegisterMapping::Zero)) => {
+                        match csr {
+                            0xC00 => ITypeOperation::Rdcycle,
+                            0xC01 => ITypeOperation::Rdtime,
+                            0xC02 => ITypeOperation::Rdinstret,
+                            0xC80 => ITypeOperation::Rdcycleh,
+                            0xC81 => ITypeOperation::Rdtimeh,
+                            0xC82 => ITypeOperation::Rdinstreth,
+                            _ => ITypeOperation::Csrrs,
+                        }
+                    }
+                    // CSR instructions, whether the CSR exists is checked when they're executed
+                    (0b111_0011, 0b001, _) => ITypeOperation::Csrrw,
+                    (0b111_0011, 0b010, _) => ITypeOperation::Csrrs,
+                    (0b111_0011, 0b011, _) => ITypeOperation::Csrrc,
+                    (0b111_0011, 0b101, _) => ITypeOperation::Csrrwi,
+                    (0b111_0011, 0b110, _) => ITypeOperation::Csrrsi,
+                    (0b111_0011, 0b111, _) => ITypeOperation::Csrrci,
                     _ => bail!("Unknown I-type instruction\n machine code: {machine_code:#010x}"),
                 };
 
-                // if the instruction is not one of the unsigned instructions, sign extend the immediate
-                if !matches!(operation, ITypeOperation::Sltiu) {
+                // sign extend the immediate, even for sltiu, which compares against it as unsigned
+                // (the counter reads and CSR instructions keep the CSR number as their immediate)
+                if !matches!(
+                    operation,
+                    ITypeOperation::Rdcycle
+                        | ITypeOperation::Rdcycleh
+                        | ITypeOperation::Rdtime
+                        | ITypeOperation::Rdtimeh
+                        | ITypeOperation::Rdinstret
+                        | ITypeOperation::Rdinstreth
+                ) && !operation.is_csr()
+                {
                     imm = imm << 20 >> 20;
                 }
 
@@ -269,6 +357,358 @@ impl Decode32BitInstruction for Rv32imInstruction {
             ),
         }
     }
+
+    fn from_machine_code_or_custom(
+        machine_code: u32,
+        custom_decoder: Option<&CustomDecoder>,
+    ) -> Result<Self> {
+        Self::from_machine_code(machine_code).or_else(|error| {
+            custom_decoder
+                .and_then(|decode| decode(machine_code))
+                .map(Self::Custom)
+                .ok_or(error)
+        })
+    }
+
+    fn from_compressed_machine_code(machine_code: u16) -> Result<Self> {
+        let machine_code = u32::from(machine_code);
+        match machine_code & 0b11 {
+            0b00 => decode_compressed_quadrant_0(machine_code),
+            0b01 => decode_compressed_quadrant_1(machine_code),
+            0b10 => decode_compressed_quadrant_2(machine_code),
+            _ => bail!("Not a compressed instruction\n machine code: {machine_code:#06x}"),
+        }
+    }
+}
+
+/// The error for compressed machine code that isn't a supported RV32C instruction,
+/// e.g. the floating point loads and stores, or reserved encodings
+fn unsupported_compressed(machine_code: u32) -> anyhow::Error {
+    anyhow!("Unsupported compressed instruction\n machine code: {machine_code:#06x}")
+}
+
+/// Get the register named by a 3-bit compressed register field, which can only name `s0`-`s1` and `a0`-`a5` (x8-x15)
+fn compressed_register(field: u32) -> Result<RegisterMapping> {
+    RegisterMapping::try_from(8 + (field & 0b111) as u8)
+}
+
+/// Decode a quadrant 0 compressed instruction: `c.addi4spn`, `c.lw`, and `c.sw`
+fn decode_compressed_quadrant_0(machine_code: u32) -> Result<Rv32imInstruction> {
+    let rd_rs2 = compressed_register(machine_code >> 2)?;
+    let rs1 = compressed_register(machine_code >> 7)?;
+
+    match machine_code >> 13 {
+        // c.addi4spn (addi rd', sp, nzuimm), a zero immediate is reserved (and all zeroes is illegal)
+        0b000 if compressed_addi4spn_offset(machine_code) != 0 => Ok(Rv32imInstruction::IType {
+            operation: ITypeOperation::Addi,
+            rd: rd_rs2,
+            funct3: 0b000,
+            rs1: RegisterMapping::Sp,
+            imm: compressed_addi4spn_offset(machine_code),
+        }),
+        // c.lw (lw rd', offset(rs1'))
+        0b010 => Ok(Rv32imInstruction::IType {
+            operation: ITypeOperation::Lw,
+            rd: rd_rs2,
+            funct3: 0b010,
+            rs1,
+            imm: compressed_lw_offset(machine_code),
+        }),
+        // c.sw (sw rs2', offset(rs1'))
+        0b110 => Ok(Rv32imInstruction::SType {
+            operation: STypeOperation::Sw,
+            funct3: 0b010,
+            rs1,
+            rs2: rd_rs2,
+            imm: compressed_lw_offset(machine_code),
+        }),
+        _ => Err(unsupported_compressed(machine_code)),
+    }
+}
+
+/// Decode a quadrant 1 compressed instruction: the immediate arithmetic, `c.li`, `c.lui`, the register-register
+/// arithmetic on the compressed registers, jumps, and branches
+fn decode_compressed_quadrant_1(machine_code: u32) -> Result<Rv32imInstruction> {
+    let rd_rs1 = RegisterMapping::try_from(((machine_code >> 7) & 0b11111) as u8)?;
+    let imm = compressed_imm6(machine_code);
+
+    match (machine_code >> 13) & 0b111 {
+        // c.addi (addi rd, rd, nzimm), or c.nop when rd is zero
+        0b000 => Ok(Rv32imInstruction::IType {
+            operation: ITypeOperation::Addi,
+            rd: rd_rs1,
+            funct3: 0b000,
+            rs1: rd_rs1,
+            imm,
+        }),
+        // c.jal (jal ra, offset) and c.j (jal zero, offset)
+        funct3 @ (0b001 | 0b101) => Ok(Rv32imInstruction::UJType {
+            operation: UJTypeOperation::Jal,
+            rd: if funct3 == 0b001 {
+                RegisterMapping::Ra
+            } else {
+                RegisterMapping::Zero
+            },
+            imm: compressed_jump_offset(machine_code),
+        }),
+        // c.li (addi rd, zero, imm)
+        0b010 => Ok(Rv32imInstruction::IType {
+            operation: ITypeOperation::Addi,
+            rd: rd_rs1,
+            funct3: 0b000,
+            rs1: RegisterMapping::Zero,
+            imm,
+        }),
+        // c.addi16sp (addi sp, sp, nzimm), a zero immediate is reserved
+        0b011 if rd_rs1 == RegisterMapping::Sp && compressed_addi16sp_offset(machine_code) != 0 => {
+            Ok(Rv32imInstruction::IType {
+                operation: ITypeOperation::Addi,
+                rd: RegisterMapping::Sp,
+                funct3: 0b000,
+                rs1: RegisterMapping::Sp,
+                imm: compressed_addi16sp_offset(machine_code),
+            })
+        }
+        // c.lui (lui rd, nzimm), the immediate is sign extended like a full `lui`'s
+        #[allow(clippy::cast_sign_loss)]
+        0b011 if rd_rs1 != RegisterMapping::Sp && imm != 0 => Ok(Rv32imInstruction::UType {
+            operation: UTypeOperation::Lui,
+            rd: rd_rs1,
+            imm: imm as u32,
+        }),
+        0b100 => decode_compressed_arithmetic(machine_code),
+        // c.beqz (beq rs1', zero, offset) and c.bnez (bne rs1', zero, offset)
+        funct3 @ (0b110 | 0b111) => Ok(Rv32imInstruction::SBType {
+            operation: if funct3 == 0b110 {
+                SBTypeOperation::Beq
+            } else {
+                SBTypeOperation::Bne
+            },
+            // the funct3 of beq (0b000) or bne (0b001)
+            funct3: u8::from(funct3 == 0b111),
+            rs1: compressed_register(machine_code >> 7)?,
+            rs2: RegisterMapping::Zero,
+            imm: compressed_branch_offset(machine_code),
+        }),
+        _ => Err(unsupported_compressed(machine_code)),
+    }
+}
+
+/// Decode the arithmetic on the compressed registers in quadrant 1:
+/// `c.srli`, `c.srai`, `c.andi`, `c.sub`, `c.xor`, `c.or`, and `c.and`
+fn decode_compressed_arithmetic(machine_code: u32) -> Result<Rv32imInstruction> {
+    let rd_rs1 = compressed_register(machine_code >> 7)?;
+    let rs2 = compressed_register(machine_code >> 2)?;
+    let imm = compressed_imm6(machine_code);
+    // shift amounts above 31 (shamt[5], bit 12, set) are RV64 only
+    let shift_in_range = machine_code & (1 << 12) == 0;
+
+    let itype = |operation, funct3, imm| Rv32imInstruction::IType {
+        operation,
+        rd: rd_rs1,
+        funct3,
+        rs1: rd_rs1,
+        imm,
+    };
+    let rtype = |operation, funct3, funct7| Rv32imInstruction::RType {
+        operation,
+        rd: rd_rs1,
+        funct3,
+        rs1: rd_rs1,
+        rs2,
+        funct7,
+    };
+
+    Ok(
+        match ((machine_code >> 10) & 0b11, (machine_code >> 5) & 0b11) {
+            (0b00, _) if shift_in_range => itype(ITypeOperation::Srli, 0b101, imm & 0b11111),
+            (0b01, _) if shift_in_range => itype(ITypeOperation::Srai, 0b101, imm & 0b11111),
+            (0b10, _) => itype(ITypeOperation::Andi, 0b111, imm),
+            // with bit 12 set, these are RV64's c.subw and c.addw
+            (0b11, 0b00) if shift_in_range => rtype(RTypeOperation::Sub, 0b000, 0b010_0000),
+            (0b11, 0b01) if shift_in_range => rtype(RTypeOperation::Xor, 0b100, 0b000_0000),
+            (0b11, 0b10) if shift_in_range => rtype(RTypeOperation::Or, 0b110, 0b000_0000),
+            (0b11, 0b11) if shift_in_range => rtype(RTypeOperation::And, 0b111, 0b000_0000),
+            _ => return Err(unsupported_compressed(machine_code)),
+        },
+    )
+}
+
+/// Decode a quadrant 2 compressed instruction: `c.slli`, the stack-pointer-relative loads and stores,
+/// `c.jr`, `c.jalr`, `c.mv`, `c.add`, and `c.ebreak`
+fn decode_compressed_quadrant_2(machine_code: u32) -> Result<Rv32imInstruction> {
+    // the full-width register fields (bits 7-11 and 2-6) are always valid register mappings
+    let rd_rs1 = RegisterMapping::try_from(((machine_code >> 7) & 0b11111) as u8)?;
+    let rs2 = RegisterMapping::try_from(((machine_code >> 2) & 0b11111) as u8)?;
+    let bit_12 = machine_code & (1 << 12) != 0;
+
+    match (machine_code >> 13) & 0b111 {
+        // c.slli (slli rd, rd, shamt), shift amounts above 31 are RV64 only
+        0b000 if !bit_12 => Ok(Rv32imInstruction::IType {
+            operation: ITypeOperation::Slli,
+            rd: rd_rs1,
+            funct3: 0b001,
+            rs1: rd_rs1,
+            imm: compressed_imm6(machine_code) & 0b11111,
+        }),
+        // c.lwsp (lw rd, offset(sp)), rd can't be zero
+        0b010 if rd_rs1 != RegisterMapping::Zero => Ok(Rv32imInstruction::IType {
+            operation: ITypeOperation::Lw,
+            rd: rd_rs1,
+            funct3: 0b010,
+            rs1: RegisterMapping::Sp,
+            imm: compressed_lwsp_offset(machine_code),
+        }),
+        // c.jr (jalr zero, 0(rs1)) and c.jalr (jalr ra, 0(rs1))
+        0b100 if rs2 == RegisterMapping::Zero && rd_rs1 != RegisterMapping::Zero => {
+            Ok(Rv32imInstruction::IType {
+                operation: ITypeOperation::Jalr,
+                rd: if bit_12 {
+                    RegisterMapping::Ra
+                } else {
+                    RegisterMapping::Zero
+                },
+                funct3: 0b000,
+                rs1: rd_rs1,
+                imm: 0,
+            })
+        }
+        // c.ebreak
+        0b100 if bit_12 && rs2 == RegisterMapping::Zero => Ok(Rv32imInstruction::IType {
+            operation: ITypeOperation::Ebreak,
+            rd: RegisterMapping::Zero,
+            funct3: 0b000,
+            rs1: RegisterMapping::Zero,
+            imm: 1,
+        }),
+        // c.mv (add rd, zero, rs2) and c.add (add rd, rd, rs2)
+        0b100 if rs2 != RegisterMapping::Zero => Ok(Rv32imInstruction::RType {
+            operation: RTypeOperation::Add,
+            rd: rd_rs1,
+            funct3: 0b000,
+            rs1: if bit_12 {
+                rd_rs1
+            } else {
+                RegisterMapping::Zero
+            },
+            rs2,
+            funct7: 0b000_0000,
+        }),
+        // c.swsp (sw rs2, offset(sp))
+        0b110 => Ok(Rv32imInstruction::SType {
+            operation: STypeOperation::Sw,
+            funct3: 0b010,
+            rs1: RegisterMapping::Sp,
+            rs2,
+            imm: compressed_swsp_offset(machine_code),
+        }),
+        _ => Err(unsupported_compressed(machine_code)),
+    }
+}
+
+/// Sign extend the lowest `bits` bits of `value`
+#[allow(clippy::cast_possible_wrap)]
+const fn sign_extend(value: u32, bits: u32) -> i32 {
+    ((value << (32 - bits)) as i32) >> (32 - bits)
+}
+
+/// Reassemble the 6-bit signed immediate of `c.addi`, `c.li`, `c.andi`, and `c.lui`, or the shift amount of the shifts
+///
+/// the immediate bits are stored as imm[5] in bit 12, and imm[4:0] in bits 6-2
+const fn compressed_imm6(machine_code: u32) -> i32 {
+    sign_extend(
+        ((machine_code >> 7) & 0b10_0000) | ((machine_code >> 2) & 0b1_1111),
+        6,
+    )
+}
+
+/// Reassemble the immediate of a `c.addi4spn`, a zero-extended multiple of 4 up to 1020
+///
+/// the immediate bits are stored as nzuimm[5:4|9:6|2|3] in bits 12-5
+#[allow(clippy::cast_possible_wrap)] // the immediate is at most 10 bits
+const fn compressed_addi4spn_offset(machine_code: u32) -> i32 {
+    (((machine_code >> 7) & 0b11_0000) // 5th:4th bits
+        | ((machine_code >> 1) & 0b11_1100_0000) // 9th:6th bits
+        | ((machine_code >> 4) & 0b100) // 2nd bit
+        | ((machine_code >> 2) & 0b1000)) as i32 // 3rd bit
+}
+
+/// Reassemble the immediate of a `c.addi16sp`, a sign-extended multiple of 16 from -512 to 496
+///
+/// the immediate bits are stored as nzimm[9] in bit 12, and nzimm[4|6|8:7|5] in bits 6-2
+const fn compressed_addi16sp_offset(machine_code: u32) -> i32 {
+    sign_extend(
+        ((machine_code >> 3) & 0b10_0000_0000) // 9th bit
+            | ((machine_code >> 2) & 0b1_0000) // 4th bit
+            | ((machine_code << 1) & 0b100_0000) // 6th bit
+            | ((machine_code << 4) & 0b1_1000_0000) // 8th:7th bits
+            | ((machine_code << 3) & 0b10_0000), // 5th bit
+        10,
+    )
+}
+
+/// Reassemble the offset of a `c.lw`/`c.sw`, a zero-extended multiple of 4 up to 124
+///
+/// the offset bits are stored as imm[5:3] in bits 12-10, imm[2] in bit 6, and imm[6] in bit 5
+#[allow(clippy::cast_possible_wrap)] // the offset is at most 7 bits
+const fn compressed_lw_offset(machine_code: u32) -> i32 {
+    (((machine_code >> 7) & 0b11_1000) // 5th:3rd bits
+        | ((machine_code >> 4) & 0b100) // 2nd bit
+        | ((machine_code << 1) & 0b100_0000)) as i32 // 6th bit
+}
+
+/// Reassemble the offset of a `c.beqz`/`c.bnez`, a sign-extended multiple of 2 from -256 to 254
+///
+/// the offset bits are stored as imm[8|4:3] in bits 12-10, and imm[7:6|2:1|5] in bits 6-2
+const fn compressed_branch_offset(machine_code: u32) -> i32 {
+    sign_extend(
+        ((machine_code >> 4) & 0b1_0000_0000) // 8th bit
+            | ((machine_code >> 7) & 0b1_1000) // 4th:3rd bits
+            | ((machine_code << 1) & 0b1100_0000) // 7th:6th bits
+            | ((machine_code >> 2) & 0b110) // 2nd:1st bits, 0th bit is always 0
+            | ((machine_code << 3) & 0b10_0000), // 5th bit
+        9,
+    )
+}
+
+/// Reassemble the offset of a `c.j`/`c.jal`, in the same 21-bit form as the immediate of a full `jal`
+///
+/// the offset bits are stored as imm[11|4|9:8|10|6|7|3:1|5] in bits 12-2
+const fn compressed_jump_offset(machine_code: u32) -> u32 {
+    let offset = ((machine_code >> 1) & 0b1000_0000_0000) // 11th bit
+        | ((machine_code >> 7) & 0b1_0000) // 4th bit
+        | ((machine_code >> 1) & 0b11_0000_0000) // 9th:8th bits
+        | ((machine_code << 2) & 0b100_0000_0000) // 10th bit
+        | ((machine_code >> 1) & 0b100_0000) // 6th bit
+        | ((machine_code << 1) & 0b1000_0000) // 7th bit
+        | ((machine_code >> 2) & 0b1110) // 3rd:1st bits, 0th bit is always 0
+        | ((machine_code << 3) & 0b10_0000); // 5th bit
+
+    // sign extend the 12-bit offset to 21 bits
+    if offset & 0b1000_0000_0000 == 0 {
+        offset
+    } else {
+        offset | 0b1_1111_1111_0000_0000_0000
+    }
+}
+
+/// Reassemble the offset of a `c.lwsp`, a zero-extended multiple of 4 up to 252
+///
+/// the offset bits are stored as imm[5] in bit 12, and imm[4:2|7:6] in bits 6-2
+#[allow(clippy::cast_possible_wrap)] // the offset is at most 8 bits
+const fn compressed_lwsp_offset(machine_code: u32) -> i32 {
+    (((machine_code >> 7) & 0b10_0000) // 5th bit
+        | ((machine_code >> 2) & 0b1_1100) // 4th:2nd bits
+        | ((machine_code << 4) & 0b1100_0000)) as i32 // 7th:6th bits
+}
+
+/// Reassemble the offset of a `c.swsp`, a zero-extended multiple of 4 up to 252
+///
+/// the offset bits are stored as imm[5:2|7:6] in bits 12-7
+#[allow(clippy::cast_possible_wrap)] // the offset is at most 8 bits
+const fn compressed_swsp_offset(machine_code: u32) -> i32 {
+    (((machine_code >> 7) & 0b11_1100) // 5th:2nd bits
+        | ((machine_code >> 1) & 0b1100_0000)) as i32 // 7th:6th bits
 }
 
 #[cfg(test)]
@@ -311,6 +751,22 @@ mod tests {
         Ok(())
     }
     #[test]
+    fn test_sltiu_sign_extends_immediate() -> Result<()> {
+        // sltiu a0, a1, -2048
+        let instruction = Rv32imInstruction::from_machine_code(0x8005_b513)?;
+        assert_eq!(
+            instruction,
+            Rv32imInstruction::IType {
+                operation: ITypeOperation::Sltiu,
+                rs1: RegisterMapping::A1,
+                rd: RegisterMapping::A0,
+                funct3: 0b011,
+                imm: -2048,
+            }
+        );
+        Ok(())
+    }
+    #[test]
     fn test_sb() -> Result<()> {
         let machine_code: u32 = 0b1111_1110_0011_0010_0000_1000_0010_0011;
         let instruction = Rv32imInstruction::from_machine_code(machine_code)?;
@@ -371,6 +827,189 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_compressed_jumps() -> Result<()> {
+        // c.jal 8
+        assert_eq!(
+            Rv32imInstruction::from_compressed_machine_code(0x2021)?,
+            Rv32imInstruction::UJType {
+                operation: UJTypeOperation::Jal,
+                rd: RegisterMapping::Ra,
+                imm: 8,
+            }
+        );
+        // c.j -4
+        assert_eq!(
+            Rv32imInstruction::from_compressed_machine_code(0xbff5)?,
+            Rv32imInstruction::UJType {
+                operation: UJTypeOperation::Jal,
+                rd: RegisterMapping::Zero,
+                imm: 0b1_1111_1111_1111_1111_1100,
+            }
+        );
+        // c.jr a0
+        assert_eq!(
+            Rv32imInstruction::from_compressed_machine_code(0x8502)?,
+            Rv32imInstruction::IType {
+                operation: ITypeOperation::Jalr,
+                rd: RegisterMapping::Zero,
+                funct3: 0,
+                rs1: RegisterMapping::A0,
+                imm: 0,
+            }
+        );
+        // c.jalr a0
+        assert_eq!(
+            Rv32imInstruction::from_compressed_machine_code(0x9502)?,
+            Rv32imInstruction::IType {
+                operation: ITypeOperation::Jalr,
+                rd: RegisterMapping::Ra,
+                funct3: 0,
+                rs1: RegisterMapping::A0,
+                imm: 0,
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_compressed_lwsp() -> Result<()> {
+        let lw = |rd, imm| Rv32imInstruction::IType {
+            operation: ITypeOperation::Lw,
+            rd,
+            funct3: 0b010,
+            rs1: RegisterMapping::Sp,
+            imm,
+        };
+        for (machine_code, expected) in [
+            (0x4502, lw(RegisterMapping::A0, 0)),
+            (0x4512, lw(RegisterMapping::A0, 4)),
+            (0x40f2, lw(RegisterMapping::Ra, 28)),
+            (0x5502, lw(RegisterMapping::A0, 32)),
+            (0x4406, lw(RegisterMapping::S0, 64)),
+            // the maximum offset
+            (0x5ffe, lw(RegisterMapping::T6, 252)),
+        ] {
+            assert_eq!(
+                Rv32imInstruction::from_compressed_machine_code(machine_code)?,
+                expected,
+                "{machine_code:#06x}"
+            );
+        }
+        // c.lwsp with rd = zero is reserved
+        assert!(Rv32imInstruction::from_compressed_machine_code(0x4002).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_compressed_swsp() -> Result<()> {
+        let sw = |rs2, imm| Rv32imInstruction::SType {
+            operation: STypeOperation::Sw,
+            funct3: 0b010,
+            rs1: RegisterMapping::Sp,
+            rs2,
+            imm,
+        };
+        for (machine_code, expected) in [
+            (0xc02a, sw(RegisterMapping::A0, 0)),
+            (0xc202, sw(RegisterMapping::Zero, 4)),
+            (0xce06, sw(RegisterMapping::Ra, 28)),
+            (0xc0aa, sw(RegisterMapping::A0, 64)),
+            // the maximum offset
+            (0xdffe, sw(RegisterMapping::T6, 252)),
+        ] {
+            assert_eq!(
+                Rv32imInstruction::from_compressed_machine_code(machine_code)?,
+                expected,
+                "{machine_code:#06x}"
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_compressed_expands_to_full_width() -> Result<()> {
+        for (compressed, full) in [
+            (0x1fe8, 0x3fc1_0513), // c.addi4spn a0, sp, 1020
+            (0x0040, 0x0041_0413), // c.addi4spn s0, sp, 4
+            (0x5fe8, 0x07c7_a503), // c.lw a0, 124(a5)
+            (0x4044, 0x0044_2483), // c.lw s1, 4(s0)
+            (0xc2b0, 0x04c6_a023), // c.sw a2, 64(a3)
+            (0x0001, 0x0000_0013), // c.nop
+            (0x1501, 0xfe05_0513), // c.addi a0, -32
+            (0x02fd, 0x01f2_8293), // c.addi t0, 31
+            (0x55fd, 0xfff0_0593), // c.li a1, -1
+            (0x4095, 0x0050_0093), // c.li ra, 5
+            (0x6505, 0x0000_1537), // c.lui a0, 1
+            (0x757d, 0xffff_f537), // c.lui a0, 0xfffff
+            (0x637d, 0x0001_f337), // c.lui t1, 0x1f
+            (0x7101, 0xe001_0113), // c.addi16sp sp, -512
+            (0x617d, 0x1f01_0113), // c.addi16sp sp, 496
+            (0x817d, 0x01f5_5513), // c.srli a0, 31
+            (0x8485, 0x4014_d493), // c.srai s1, 1
+            (0x9bc1, 0xff07_f793), // c.andi a5, -16
+            (0x8d0d, 0x40b5_0533), // c.sub a0, a1
+            (0x8c25, 0x0094_4433), // c.xor s0, s1
+            (0x8e55, 0x00d6_6633), // c.or a2, a3
+            (0x8f7d, 0x00f7_7733), // c.and a4, a5
+            (0xd101, 0xf005_00e3), // c.beqz a0, -256
+            (0xecfd, 0x0e04_9f63), // c.bnez s1, 254
+            (0x02fe, 0x01f2_9293), // c.slli t0, 31
+            (0x857e, 0x01f0_0533), // c.mv a0, t6
+            (0x912a, 0x00a1_0133), // c.add sp, a0
+            (0x9002, 0x0010_0073), // c.ebreak
+        ] {
+            assert_eq!(
+                Rv32imInstruction::from_compressed_machine_code(compressed)?,
+                Rv32imInstruction::from_machine_code(full)?,
+                "{compressed:#06x}"
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_unsupported_compressed() {
+        for machine_code in [
+            0x0000, // all zeroes is illegal
+            0x6101, // c.addi16sp with a zero immediate is reserved
+            0x6501, // c.lui with a zero immediate is reserved
+            0x9101, // c.srli with a shift amount of 32 is RV64 only
+            0x9d0d, // c.subw is RV64 only
+            0x6188, // c.flw, floating point isn't supported
+            0x8002, // c.jr with rs1 = zero is reserved
+        ] {
+            let error = Rv32imInstruction::from_compressed_machine_code(machine_code)
+                .expect_err("the machine code isn't a supported compressed instruction");
+            assert!(
+                error
+                    .to_string()
+                    .starts_with("Unsupported compressed instruction"),
+                "{machine_code:#06x}: {error}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_shift_amount_out_of_range() {
+        // slli a0, a0, 32 (RV64 only)
+        let error = Rv32imInstruction::from_machine_code(0x0205_1513)
+            .expect_err("shift amounts above 31 aren't valid in RV32");
+        assert!(
+            error
+                .to_string()
+                .starts_with("Shift amount 32 out of range for RV32 (must be 0-31)"),
+            "{error}"
+        );
+        // srai a0, a0, 63 (RV64 only)
+        let error = Rv32imInstruction::from_machine_code(0x43f5_5513)
+            .expect_err("shift amounts above 31 aren't valid in RV32");
+        assert!(error.to_string().starts_with("Shift amount 63"), "{error}");
+        // slli with srai's funct7 is still just unknown
+        let error = Rv32imInstruction::from_machine_code(0x4015_1513).unwrap_err();
+        assert!(error.to_string().starts_with("Unknown I-type instruction"));
+    }
+
     #[test]
     fn test_auipc() -> Result<()> {
         let machine_code: u32 = 0x0fc1_0497;
@@ -401,9 +1040,115 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_fence_variants() -> Result<()> {
+        let operation = |machine_code| -> Result<ITypeOperation> {
+            match Rv32imInstruction::from_machine_code(machine_code)? {
+                Rv32imInstruction::IType { operation, .. } => Ok(operation),
+                instruction => bail!("not an I-type instruction: {instruction}"),
+            }
+        };
+        // fence
+        assert_eq!(operation(0x0ff0_000f)?, ITypeOperation::Fence);
+        // fence rw, rw
+        assert_eq!(operation(0x0330_000f)?, ITypeOperation::Fence);
+        // fence.tso
+        assert_eq!(operation(0x8330_000f)?, ITypeOperation::FenceTso);
+        // pause
+        assert_eq!(operation(0x0100_000f)?, ITypeOperation::Pause);
+        Ok(())
+    }
+
+    #[test]
+    fn test_rdcycle() -> Result<()> {
+        let machine_code: u32 = 0xc000_2673;
+        let instruction = Rv32imInstruction::from_machine_code(machine_code)?;
+        assert_eq!(
+            instruction,
+            Rv32imInstruction::IType {
+                operation: ITypeOperation::Rdcycle,
+                rs1: RegisterMapping::Zero,
+                rd: RegisterMapping::A2,
+                funct3: 0b010,
+                imm: 0xC00,
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_csr_instructions() -> Result<()> {
+        let csr = |operation, rd, rs1, funct3, imm| Rv32imInstruction::IType {
+            operation,
+            rd,
+            funct3,
+            rs1,
+            imm,
+        };
+        for (machine_code, expected) in [
+            (
+                0x3405_9573, // csrrw a0, mscratch, a1
+                csr(
+                    ITypeOperation::Csrrw,
+                    RegisterMapping::A0,
+                    RegisterMapping::A1,
+                    0b001,
+                    0x340,
+                ),
+            ),
+            (
+                0x3000_2573, // csrrs a0, mstatus, zero
+                csr(
+                    ITypeOperation::Csrrs,
+                    RegisterMapping::A0,
+                    RegisterMapping::Zero,
+                    0b010,
+                    0x300,
+                ),
+            ),
+            (
+                0xc005_2073, // csrrs zero, cycle, a0, which isn't a counter read
+                csr(
+                    ITypeOperation::Csrrs,
+                    RegisterMapping::Zero,
+                    RegisterMapping::A0,
+                    0b010,
+                    0xC00,
+                ),
+            ),
+            (
+                0x3050_f673, // csrrci a2, mtvec, 1, the immediate is kept in rs1
+                csr(
+                    ITypeOperation::Csrrci,
+                    RegisterMapping::A2,
+                    RegisterMapping::Ra,
+                    0b111,
+                    0x305,
+                ),
+            ),
+            (
+                0xf140_2573, // csrrs a0, mhartid, zero, the CSR number isn't sign extended
+                csr(
+                    ITypeOperation::Csrrs,
+                    RegisterMapping::A0,
+                    RegisterMapping::Zero,
+                    0b010,
+                    0xF14,
+                ),
+            ),
+        ] {
+            assert_eq!(
+                Rv32imInstruction::from_machine_code(machine_code)?,
+                expected,
+                "{machine_code:#010x}"
+            );
+        }
+        Ok(())
+    }
+
     #[test]
     fn test_lbu_negative_offset() -> Result<()> {
-        let machine_code: u32 = 0xff434483;
+        let machine_code: u32 = 0xff43_4483;
         let instruction = Rv32imInstruction::from_machine_code(machine_code)?;
         assert_eq!(
             instruction,
@@ -420,7 +1165,7 @@ mod tests {
 
     #[test]
     fn test_lbu_positive_offset() -> Result<()> {
-        let machine_code: u32 = 0x00c34483;
+        let machine_code: u32 = 0x00c3_4483;
         let instruction = Rv32imInstruction::from_machine_code(machine_code)?;
         assert_eq!(
             instruction,
@@ -437,7 +1182,7 @@ mod tests {
 
     #[test]
     fn test_lhu_negative_offset() -> Result<()> {
-        let machine_code: u32 = 0xff435483;
+        let machine_code: u32 = 0xff43_5483;
         let instruction = Rv32imInstruction::from_machine_code(machine_code)?;
         assert_eq!(
             instruction,
@@ -454,7 +1199,7 @@ mod tests {
 
     #[test]
     fn test_lhu_positive_offset() -> Result<()> {
-        let machine_code: u32 = 0x00c35483;
+        let machine_code: u32 = 0x00c3_5483;
         let instruction = Rv32imInstruction::from_machine_code(machine_code)?;
         assert_eq!(
             instruction,
@@ -26,21 +26,55 @@ SOFTWARE.
 #![allow(clippy::cast_sign_loss)]
 #![allow(clippy::cast_possible_truncation)]
 
-use anyhow::{bail, Result};
+use std::fmt;
+
+use anyhow::{bail, Context as _, Result};
 
 use crate::instruction_set_definition::{
     operations::{
         ITypeOperation, RTypeOperation, SBTypeOperation, STypeOperation, UJTypeOperation,
         UTypeOperation,
     },
-    Rv32imInstruction,
+    CustomInstruction, Rv32imInstruction,
 };
 
 use super::cpu::{
+    io::{Clock, ProgramInput, ProgramOutput, Rng},
     memory::MemoryBus,
     registers::{RegisterFile32Bit, RegisterMapping},
-    Cpu32Bit, Size,
+    Counters, Cpu32Bit, Size,
 };
+use super::trap::{mcause, Trap};
+
+/// A user-provided executor for the instructions produced by a `CustomDecoder`.
+///
+/// The program counter is advanced to the next instruction after the executor returns successfully.
+/// Loads and stores should go through `MemoryBus::load` and `MemoryBus::store`, so `Cpu32Bit::step_reported` reports them.
+pub type CustomExecutor = Box<dyn FnMut(&mut Cpu32Bit, CustomInstruction) -> Result<()> + Send>;
+
+/// The value the temporaries are overwritten with after an `ecall`, see `Cpu32Bit::poison_temporaries`.
+pub const ECALL_POISON: u32 = 0xDEAD_BEEF;
+
+/// The registers the calling convention doesn't preserve across a call, which a syscall may clobber.
+const TEMPORARIES: [RegisterMapping; 7] = [
+    RegisterMapping::T0,
+    RegisterMapping::T1,
+    RegisterMapping::T2,
+    RegisterMapping::T3,
+    RegisterMapping::T4,
+    RegisterMapping::T5,
+    RegisterMapping::T6,
+];
+
+/// What the M extension's divisions (`div`, `divu`, `rem`, `remu`) do when dividing by zero.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum DivByZeroPolicy {
+    /// Produce the results the spec defines: quotients of all ones, and remainders equal to the dividend.
+    #[default]
+    SpecDefault,
+    /// Fault, to catch programs that divide by zero by mistake.
+    Trap,
+}
 
 #[allow(clippy::module_name_repetitions)]
 pub trait Execute32BitInstruction {
@@ -57,13 +91,25 @@ pub trait Execute32BitInstruction {
     ///
     /// Returns an error if the instruction cannot be executed.
     /// This can happen if the instruction is invalid, if the instruction is not implemented, if the instruction results in an invalid memory/register read / write, etc.
-    fn execute(&mut self, instruction: Self::InstructionSet) -> Result<()>;
+    fn execute(&mut self, instruction: Self::InstructionSet) -> Result<()> {
+        self.execute_with_width(instruction, Size::Word)
+    }
+
+    /// Execute the given instruction, which occupied `width` bytes in memory (see `Fetch32BitInstruction::fetch_and_decode_with`).
+    ///
+    /// The width determines where the next instruction is, and so the return address saved by `jal`/`jalr`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the instruction cannot be executed, see `execute`.
+    fn execute_with_width(&mut self, instruction: Self::InstructionSet, width: Size) -> Result<()>;
 }
 
 impl Execute32BitInstruction for Cpu32Bit {
     type InstructionSet = Rv32imInstruction;
 
-    fn execute(&mut self, instruction: Self::InstructionSet) -> Result<()> {
+    #[allow(clippy::too_many_lines)]
+    fn execute_with_width(&mut self, instruction: Self::InstructionSet, width: Size) -> Result<()> {
         match instruction {
             Self::InstructionSet::IType {
                 operation,
@@ -72,17 +118,47 @@ impl Execute32BitInstruction for Cpu32Bit {
                 rs1,
                 imm,
             } => {
-                execute_itype_instruction(
-                    &mut self.debug,
-                    &mut self.pc,
-                    &mut self.output,
-                    &mut self.registers,
-                    &mut self.memory,
-                    operation,
-                    rd,
-                    rs1,
-                    imm,
-                )?;
+                if operation == ITypeOperation::Mret {
+                    self.pc = self.csrs.mepc;
+                    return Ok(());
+                }
+                if self.bare_metal {
+                    // a bare-metal program handles its own environment calls and breakpoints
+                    let cause = match operation {
+                        ITypeOperation::Ecall => Some(mcause::ECALL_FROM_M_MODE),
+                        ITypeOperation::Ebreak => Some(mcause::BREAKPOINT),
+                        _ => None,
+                    };
+                    if let Some(cause) = cause {
+                        return self.take_trap(self.pc, cause, 0);
+                    }
+                }
+                if operation.is_csr() {
+                    self.execute_csr(operation, rd, rs1, imm)?;
+                } else {
+                    let counters = self.counters();
+                    execute_itype_instruction(
+                        &mut self.debug,
+                        &mut self.halted,
+                        &mut self.pc,
+                        width,
+                        &mut self.output,
+                        &mut self.output_sink,
+                        &mut self.input,
+                        self.clock,
+                        &mut self.rng,
+                        counters,
+                        &mut self.registers,
+                        &mut self.memory,
+                        operation,
+                        rd,
+                        rs1,
+                        imm,
+                    )?;
+                    if operation == ITypeOperation::Ecall && self.poison_temporaries {
+                        poison_temporaries(&mut self.registers);
+                    }
+                }
                 if operation == ITypeOperation::Jalr {
                     // if the instruction is a jalr, the program counter is already updated
                     // by the execute_itype_instruction function
@@ -96,7 +172,14 @@ impl Execute32BitInstruction for Cpu32Bit {
                 rs1,
                 rs2,
                 funct7: _,
-            } => execute_rtype_instruction(&mut self.registers, operation, rd, rs1, rs2)?,
+            } => execute_rtype_instruction(
+                &mut self.registers,
+                self.div_by_zero,
+                operation,
+                rd,
+                rs1,
+                rs2,
+            )?,
             Self::InstructionSet::SType {
                 operation,
                 funct3: _,
@@ -118,12 +201,21 @@ impl Execute32BitInstruction for Cpu32Bit {
                 rs2,
                 imm,
             } => {
-                execute_sbtype_instruction(&mut self.pc, &self.registers, operation, rs1, rs2, imm);
+                execute_sbtype_instruction(
+                    &mut self.pc,
+                    width,
+                    &self.registers,
+                    operation,
+                    rs1,
+                    rs2,
+                    imm,
+                );
             }
             Self::InstructionSet::UJType { operation, rd, imm } => {
                 return {
                     execute_ujtype_instruction(
                         &mut self.pc,
+                        width,
                         &mut self.registers,
                         operation,
                         rd,
@@ -135,17 +227,79 @@ impl Execute32BitInstruction for Cpu32Bit {
             Self::InstructionSet::UType { operation, rd, imm } => {
                 execute_utype_instruction(self.pc, &mut self.registers, operation, rd, imm);
             }
+            Self::InstructionSet::Custom(instruction) => {
+                let Some(mut executor) = self.custom_executor.take() else {
+                    bail!("No executor registered for custom instruction: {instruction}");
+                };
+                // the executor is taken out of the cpu while it runs, so it can borrow the cpu mutably
+                let result = executor(self, instruction);
+                self.custom_executor = Some(executor);
+                result?;
+            }
+        }
+        self.pc = self.pc.wrapping_add(width.bytes());
+        Ok(())
+    }
+}
+
+impl Cpu32Bit {
+    /// Execute a CSR instruction, atomically reading the CSR at `csr` into `rd` and updating it.
+    ///
+    /// As the spec requires, `csrrw`/`csrrwi` with `rd` = `x0` don't read the CSR,
+    /// and `csrrs`/`csrrc` (and their immediate forms) with `rs1` (or the immediate) = 0 don't write it.
+    fn execute_csr(
+        &mut self,
+        operation: ITypeOperation,
+        rd: RegisterMapping,
+        rs1: RegisterMapping,
+        csr: i32,
+    ) -> Result<()> {
+        let csr = (csr & 0xFFF) as u16;
+        let source = match operation {
+            ITypeOperation::Csrrwi | ITypeOperation::Csrrsi | ITypeOperation::Csrrci => rs1 as u32,
+            _ => self.registers[rs1],
+        };
+        let swap = matches!(operation, ITypeOperation::Csrrw | ITypeOperation::Csrrwi);
+
+        let old = if swap && rd == RegisterMapping::Zero {
+            0
+        } else {
+            self.read_csr(csr)
+                .with_context(|| format!("Unsupported CSR: {csr:#05x}"))?
+        };
+        if swap || rs1 != RegisterMapping::Zero {
+            let new = match operation {
+                ITypeOperation::Csrrs | ITypeOperation::Csrrsi => old | source,
+                ITypeOperation::Csrrc | ITypeOperation::Csrrci => old & !source,
+                _ => source,
+            };
+            self.write_csr(csr, new)?;
         }
-        self.pc += 4;
+        self.registers[rd] = old;
         Ok(())
     }
 }
 
+/// Overwrite the registers a syscall may clobber with `ECALL_POISON`.
+fn poison_temporaries(regs: &mut RegisterFile32Bit) {
+    for register in TEMPORARIES {
+        regs[register] = ECALL_POISON;
+        regs.mark_uninitialized(register);
+    }
+}
+
 #[allow(clippy::too_many_arguments)]
 fn execute_itype_instruction(
     debug: &mut bool,
+    halted: &mut bool,
     pc: &mut u32,
+    width: Size,
     output: &mut String,
+    output_sink: &mut ProgramOutput,
+    input: &mut ProgramInput,
+    clock: Clock,
+    rng: &mut Rng,
+    counters: Counters,
     regs: &mut RegisterFile32Bit, // needs mutable access to the registers
     memory: &mut MemoryBus, // needs immutable access to the memory, except for the ReadString syscall which needs mutable access
     operation: ITypeOperation,
@@ -157,23 +311,17 @@ fn execute_itype_instruction(
         ITypeOperation::Addi => regs[rd] = regs[rs1].wrapping_add(imm as u32),
         ITypeOperation::Andi => regs[rd] = regs[rs1] & (imm as u32),
         ITypeOperation::Jalr => {
-            let t = *pc + 4;
+            let t = pc.wrapping_add(width.bytes());
             *pc = regs[rs1].wrapping_add(imm as u32) & !1;
-            if rd != RegisterMapping::Zero {
-                regs[rd] = t;
-            }
+            regs[rd] = t;
         }
         ITypeOperation::Lb => {
-            regs[rd] = ((memory.read(regs[rs1].wrapping_add_signed(imm), Size::Byte)? as i32) << 24
-                >> 24) as u32;
+            regs[rd] = ((load(memory, regs, rs1, imm, Size::Byte)? as i32) << 24 >> 24) as u32;
         }
         ITypeOperation::Lh => {
-            regs[rd] = ((memory.read(regs[rs1].wrapping_add_signed(imm), Size::Half)? as i32) << 16
-                >> 16) as u32;
-        }
-        ITypeOperation::Lw => {
-            regs[rd] = memory.read(regs[rs1].wrapping_add_signed(imm), Size::Word)?;
+            regs[rd] = ((load(memory, regs, rs1, imm, Size::Half)? as i32) << 16 >> 16) as u32;
         }
+        ITypeOperation::Lw => regs[rd] = load(memory, regs, rs1, imm, Size::Word)?,
         ITypeOperation::Ori => regs[rd] = regs[rs1] | (imm as u32),
         ITypeOperation::Slli => regs[rd] = regs[rs1] << (imm & 0b11111),
         ITypeOperation::Slti => regs[rd] = u32::from((regs[rs1] as i32) < imm),
@@ -181,22 +329,37 @@ fn execute_itype_instruction(
         ITypeOperation::Srai => regs[rd] = ((regs[rs1] as i32) >> (imm & 0b11111)) as u32,
         ITypeOperation::Srli => regs[rd] = regs[rs1] >> (imm & 0b11111),
         ITypeOperation::Xori => regs[rd] = regs[rs1] ^ (imm as u32),
-        ITypeOperation::Lbu => {
-            regs[rd] = memory.read(regs[rs1].wrapping_add_signed(imm), Size::Byte)?;
+        ITypeOperation::Lbu => regs[rd] = load(memory, regs, rs1, imm, Size::Byte)?,
+        ITypeOperation::Lhu => regs[rd] = load(memory, regs, rs1, imm, Size::Half)?,
+        // there is a single hart, and no caches, so memory is always coherent
+        ITypeOperation::Fence | ITypeOperation::FenceTso => {}
+        // instructions decoded before the fence may no longer match memory
+        ITypeOperation::FenceI => memory.clear_decode_cache(),
+        ITypeOperation::Pause => std::hint::spin_loop(),
+        ITypeOperation::Ecall => {
+            process_ecall(regs, memory, halted, output, output_sink, input, clock, rng)?;
         }
-        ITypeOperation::Lhu => {
-            regs[rd] = memory.read(regs[rs1].wrapping_add_signed(imm), Size::Half)?;
-        }
-        ITypeOperation::Fence => unimplemented!("fence instruction not implemented"),
-        ITypeOperation::FenceI => unimplemented!("fence.i instruction not implemented"),
-        ITypeOperation::Ecall => process_ecall(regs, memory, output)?,
         ITypeOperation::Ebreak => *debug = true,
+        ITypeOperation::Rdcycle => regs[rd] = counters.cycle as u32,
+        ITypeOperation::Rdcycleh => regs[rd] = (counters.cycle >> 32) as u32,
+        ITypeOperation::Rdtime => regs[rd] = counters.time as u32,
+        ITypeOperation::Rdtimeh => regs[rd] = (counters.time >> 32) as u32,
+        ITypeOperation::Rdinstret => regs[rd] = counters.instret as u32,
+        ITypeOperation::Rdinstreth => regs[rd] = (counters.instret >> 32) as u32,
+        ITypeOperation::Csrrw
+        | ITypeOperation::Csrrs
+        | ITypeOperation::Csrrc
+        | ITypeOperation::Csrrwi
+        | ITypeOperation::Csrrsi
+        | ITypeOperation::Csrrci => unreachable!("CSR instructions are executed by execute_csr"),
+        ITypeOperation::Mret => unreachable!("mret is executed by execute_with_width"),
     }
     Ok(())
 }
 
 fn execute_rtype_instruction(
     regs: &mut RegisterFile32Bit,
+    div_by_zero: DivByZeroPolicy,
     operation: RTypeOperation,
     rd: RegisterMapping,
     rs1: RegisterMapping,
@@ -218,32 +381,33 @@ fn execute_rtype_instruction(
         RTypeOperation::Mul => regs[rd] = regs[rs1].wrapping_mul(regs[rs2]),
         // Multiply High
         RTypeOperation::Mulh => {
-            regs[rd] = ((regs[rs1] as i32 as i64 * regs[rs2] as i32 as i64) as u64 >> 32) as u32;
+            regs[rd] =
+                ((i64::from(regs[rs1] as i32) * i64::from(regs[rs2] as i32)) as u64 >> 32) as u32;
         }
-        RTypeOperation::Mulhu => regs[rd] = ((regs[rs1] as u64 * regs[rs2] as u64) >> 32) as u32,
-        RTypeOperation::Mulhsu => {
-            regs[rd] = ((regs[rs1] as i32 as i64 * regs[rs2] as i64) as u64 >> 32) as u32;
+        RTypeOperation::Mulhu => {
+            regs[rd] = ((u64::from(regs[rs1]) * u64::from(regs[rs2])) >> 32) as u32;
         }
-        RTypeOperation::Div => {
-            regs[rd] = (regs[rs1] as i32)
-                .checked_div(regs[rs2] as i32)
-                .ok_or_else(|| anyhow::anyhow!("Division by zero"))? as u32;
-        }
-        RTypeOperation::Divu => {
-            regs[rd] = regs[rs1]
-                .checked_div(regs[rs2])
-                .ok_or_else(|| anyhow::anyhow!("Division by zero"))?;
-        }
-        RTypeOperation::Rem => {
-            regs[rd] = (regs[rs1] as i32)
-                .checked_rem(regs[rs2] as i32)
-                .ok_or_else(|| anyhow::anyhow!("Division by zero"))? as u32;
+        // Multiply High Signed-Unsigned, rs1 is sign extended and rs2 is zero extended to 64 bits,
+        // the product of the two always fits in an i64
+        RTypeOperation::Mulhsu => {
+            regs[rd] = ((i64::from(regs[rs1] as i32) * i64::from(regs[rs2])) as u64 >> 32) as u32;
         }
-        RTypeOperation::Remu => {
-            regs[rd] = regs[rs1]
-                .checked_rem(regs[rs2])
-                .ok_or_else(|| anyhow::anyhow!("Division by zero"))?;
+        RTypeOperation::Div | RTypeOperation::Divu | RTypeOperation::Rem | RTypeOperation::Remu
+            if regs[rs2] == 0 =>
+        {
+            if div_by_zero == DivByZeroPolicy::Trap {
+                bail!(Trap::DivisionByZero);
+            }
+            regs[rd] = match operation {
+                RTypeOperation::Div | RTypeOperation::Divu => u32::MAX,
+                _ => regs[rs1],
+            };
         }
+        // the overflowing division (i32::MIN / -1) wraps, giving i32::MIN with a remainder of 0
+        RTypeOperation::Div => regs[rd] = (regs[rs1] as i32).wrapping_div(regs[rs2] as i32) as u32,
+        RTypeOperation::Divu => regs[rd] = regs[rs1] / regs[rs2],
+        RTypeOperation::Rem => regs[rd] = (regs[rs1] as i32).wrapping_rem(regs[rs2] as i32) as u32,
+        RTypeOperation::Remu => regs[rd] = regs[rs1] % regs[rs2],
     }
     Ok(())
 }
@@ -256,56 +420,96 @@ fn execute_stype_instruction(
     rs2: RegisterMapping,
     offset: i32,
 ) -> Result<()> {
-    match operation {
-        STypeOperation::Sb => {
-            memory.write(regs[rs1].wrapping_add_signed(offset), regs[rs2], Size::Byte)
-        }
-        STypeOperation::Sh => {
-            memory.write(regs[rs1].wrapping_add_signed(offset), regs[rs2], Size::Half)
-        }
-        STypeOperation::Sw => {
-            memory.write(regs[rs1].wrapping_add_signed(offset), regs[rs2], Size::Word)
-        }
+    let size = match operation {
+        STypeOperation::Sb => Size::Byte,
+        STypeOperation::Sh => Size::Half,
+        STypeOperation::Sw => Size::Word,
+    };
+    let addr = regs[rs1].wrapping_add_signed(offset);
+    if rs1 == RegisterMapping::Gp {
+        memory
+            .check_gp_relative(addr, true)
+            .with_context(|| describe_access("store to", addr, regs, rs1, offset))?;
+    }
+    memory
+        .store(addr, regs[rs2], size)
+        .with_context(|| describe_access("store to", addr, regs, rs1, offset))
+}
+
+/// Load a `size`-bit value from the address `rs1 + offset`.
+fn load(
+    memory: &MemoryBus,
+    regs: &RegisterFile32Bit,
+    rs1: RegisterMapping,
+    offset: i32,
+    size: Size,
+) -> Result<u32> {
+    let addr = regs[rs1].wrapping_add_signed(offset);
+    if rs1 == RegisterMapping::Gp {
+        memory
+            .check_gp_relative(addr, false)
+            .with_context(|| describe_access("load from", addr, regs, rs1, offset))?;
     }
+    memory
+        .load(addr, size)
+        .with_context(|| describe_access("load from", addr, regs, rs1, offset))
+}
+
+/// Describe how the address of a faulting load or store was computed,
+/// so a bad base register (e.g. a corrupt `sp`) is easy to spot.
+fn describe_access(
+    access: &str,
+    addr: u32,
+    regs: &RegisterFile32Bit,
+    base: RegisterMapping,
+    offset: i32,
+) -> String {
+    format!(
+        "{access} {addr:#010x} computed from {base:#}={:#010x} + offset {offset}",
+        regs[base]
+    )
 }
 
 fn execute_sbtype_instruction(
     pc: &mut u32,
+    width: Size,
     regs: &RegisterFile32Bit,
     operation: SBTypeOperation,
     rs1: RegisterMapping,
     rs2: RegisterMapping,
     offset: i32,
 ) {
+    // the pc is advanced past the branch afterwards
+    let offset = offset - width.bytes() as i32;
     match operation {
         SBTypeOperation::Beq => {
             if regs[rs1] == regs[rs2] {
-                *pc = pc.wrapping_add_signed(offset - 4);
+                *pc = pc.wrapping_add_signed(offset);
             }
         }
         SBTypeOperation::Bge => {
             if (regs[rs1] as i32) >= (regs[rs2] as i32) {
-                *pc = pc.wrapping_add_signed(offset - 4);
+                *pc = pc.wrapping_add_signed(offset);
             }
         }
         SBTypeOperation::Blt => {
             if (regs[rs1] as i32) < (regs[rs2] as i32) {
-                *pc = pc.wrapping_add_signed(offset - 4);
+                *pc = pc.wrapping_add_signed(offset);
             }
         }
         SBTypeOperation::Bne => {
             if regs[rs1] != regs[rs2] {
-                *pc = pc.wrapping_add_signed(offset - 4);
+                *pc = pc.wrapping_add_signed(offset);
             }
         }
         SBTypeOperation::Bltu => {
             if regs[rs1] < regs[rs2] {
-                *pc = pc.wrapping_add_signed(offset - 4);
+                *pc = pc.wrapping_add_signed(offset);
             }
         }
         SBTypeOperation::Bgeu => {
             if regs[rs1] >= regs[rs2] {
-                *pc = pc.wrapping_add_signed(offset - 4);
+                *pc = pc.wrapping_add_signed(offset);
             }
         }
     }
@@ -313,6 +517,7 @@ fn execute_sbtype_instruction(
 
 fn execute_ujtype_instruction(
     pc: &mut u32,
+    width: Size,
     regs: &mut RegisterFile32Bit,
     operation: UJTypeOperation,
     rd: RegisterMapping,
@@ -320,9 +525,7 @@ fn execute_ujtype_instruction(
 ) {
     match operation {
         UJTypeOperation::Jal => {
-            if rd != RegisterMapping::Zero {
-                regs[rd] = *pc + 4;
-            }
+            regs[rd] = pc.wrapping_add(width.bytes());
             *pc = pc.wrapping_add_signed(((offset as i32) << 12) >> 12);
         }
     }
@@ -358,19 +561,25 @@ fn execute_utype_instruction(
 /// # Register Updates
 ///
 /// * `a0` - The return value of the syscall.
+#[allow(clippy::too_many_arguments)]
 fn process_ecall(
     regs: &mut RegisterFile32Bit,
     memory: &mut MemoryBus,
+    halted: &mut bool,
     output: &mut String,
+    output_sink: &mut ProgramOutput,
+    input: &mut ProgramInput,
+    clock: Clock,
+    rng: &mut Rng,
 ) -> Result<()> {
     match Syscall::from(regs[RegisterMapping::A7]) {
         Syscall::PrintInt => {
             let out = &regs[RegisterMapping::A0].to_string();
-            output.push_str(&regs[RegisterMapping::A0].to_string());
-            print!("{out}");
+            emit(output, output_sink, out)?;
         }
         Syscall::PrintString => {
             let mut addr = regs[RegisterMapping::A0];
+            let mut out = String::new();
             loop {
                 let byte = memory.read(addr, Size::Byte).map_err(|e| {
                     anyhow::anyhow!(
@@ -383,80 +592,158 @@ fn process_ecall(
                     break;
                 }
                 let byte = (byte & 0xff) as u8 as char;
-                output.push(byte);
-                print!("{byte}");
+                out.push(byte);
                 addr += 1;
             }
+            emit(output, output_sink, &out)?;
         }
         Syscall::ReadInt => {
-            let mut input = String::new();
-            std::io::stdin().read_line(&mut input)?;
-            let value = input.trim().parse::<i32>()? as u32;
+            let mut line = String::new();
+            input.read_line(&mut line)?;
+            let value = line.trim().parse::<i32>()? as u32;
             regs[RegisterMapping::A0] = value;
         }
         Syscall::ReadString => {
-            let mut input = String::new();
-            std::io::stdin().read_line(&mut input)?;
+            let mut line = String::new();
+            input.read_line(&mut line)?;
 
             let addr = regs[RegisterMapping::A0];
+            // the buffer holds a1 bytes including the null terminator,
+            // a buffer of 0 bytes still gets the null terminator
             let max_len = regs[RegisterMapping::A1] as usize;
             let mut i = 0;
-            for byte in input.bytes() {
-                if i >= max_len - 1 {
-                    break;
-                }
+            for byte in line.bytes().take(max_len.saturating_sub(1)) {
                 memory.write(addr + i as u32, u32::from(byte), Size::Byte)?;
                 i += 1;
             }
             // ensure the last byte is the null terminator
             memory.write(addr + i as u32, 0, Size::Byte)?;
         }
-        Syscall::Exit => bail!("Program exited with code: 0"),
+        Syscall::Exit => return Err(ProgramExit { code: 0 }.into()),
         Syscall::PrintChar => {
             let out = char::from((regs[RegisterMapping::A0] & 0xff) as u8);
-            output.push(out);
-            println!("{out}");
+            emit(output, output_sink, out.encode_utf8(&mut [0; 4]))?;
         }
         Syscall::ReadChar => {
-            let mut input = String::new();
-            std::io::stdin().read_line(&mut input)?;
-            let value = input.trim().chars().next().unwrap() as u8;
-            regs[RegisterMapping::A0] = u32::from(value);
+            let mut line = String::new();
+            input.read_line(&mut line)?;
+            // -1 at the end of the input, or for a blank line
+            regs[RegisterMapping::A0] = line
+                .trim_end_matches(['\n', '\r'])
+                .chars()
+                .next()
+                .map_or(u32::MAX, u32::from);
         }
         Syscall::Time => {
-            let time = std::time::SystemTime::now()
-                .duration_since(std::time::UNIX_EPOCH)
-                .map_err(|e| anyhow::anyhow!("Error getting time: {}", e))?;
-            regs[RegisterMapping::A0] = time.as_millis() as u32;
-            regs[RegisterMapping::A1] = (time.as_millis() >> 32) as u32;
+            // the milliseconds fit in 64 bits for the next ~584 million years
+            let millis = clock.now()?.as_millis() as u64;
+            regs[RegisterMapping::A0] = millis as u32;
+            regs[RegisterMapping::A1] = (millis >> 32) as u32;
         }
         Syscall::Sleep => {
-            let duration = std::time::Duration::from_millis(u64::from(regs[RegisterMapping::A0]));
-            std::thread::sleep(duration);
+            // time doesn't pass on a fixed clock, so there's no point waiting
+            if clock == Clock::System {
+                let duration =
+                    std::time::Duration::from_millis(u64::from(regs[RegisterMapping::A0]));
+                std::thread::sleep(duration);
+            }
+        }
+        syscall @ (Syscall::RandSeed | Syscall::RandInt | Syscall::RandIntRange) => {
+            process_random(syscall, regs, rng)?;
         }
+        Syscall::Sbrk => process_sbrk(regs, memory)?,
+        Syscall::MemInfo => {
+            regs[RegisterMapping::A0] = memory.heap_base();
+            regs[RegisterMapping::A1] = memory.heap_end();
+            regs[RegisterMapping::A2] = memory.heap_ceiling();
+        }
+        Syscall::Halt => *halted = true,
         Syscall::PrintIntHex => {
             let out = &format!("{:#x}", regs[RegisterMapping::A0]);
-            output.push_str(out);
-            print!("{out}");
+            emit(output, output_sink, out)?;
         }
         Syscall::PrintIntBinary => {
             let out = &format!("{:#b}", regs[RegisterMapping::A0]);
-            output.push_str(out);
-            print!("{out}");
+            emit(output, output_sink, out)?;
         }
         Syscall::PrintIntUnsigned => {
             let out = &format!("{}", regs[RegisterMapping::A0]);
-            output.push_str(out);
-            print!("{out}");
+            emit(output, output_sink, out)?;
+        }
+        Syscall::Exit2 => {
+            return Err(ProgramExit {
+                code: regs[RegisterMapping::A0],
+            }
+            .into())
         }
-        Syscall::Exit2 => bail!("Program exited with code: {}", regs[RegisterMapping::A0]),
         Syscall::UnSupported => bail!("Unsupported syscall number: {}", regs[RegisterMapping::A7]),
     }
     Ok(())
 }
 
+/// Process the sbrk syscall, see `process_ecall`.
+///
+/// The heap grows up towards the stack, so it can't grow past the stack pointer.
+fn process_sbrk(regs: &mut RegisterFile32Bit, memory: &mut MemoryBus) -> Result<()> {
+    let increment = regs[RegisterMapping::A0] as i32;
+    let sp = regs[RegisterMapping::Sp];
+    if memory
+        .heap_end()
+        .checked_add_signed(increment)
+        .is_some_and(|new_end| new_end > sp)
+    {
+        bail!(
+            "Cannot grow the heap by {increment} bytes from {:#010x}, it would collide with the stack (sp = {sp:#010x})",
+            memory.heap_end()
+        );
+    }
+    regs[RegisterMapping::A0] = memory.sbrk(increment)?;
+    Ok(())
+}
+
+/// Process the random syscalls, see `process_ecall`.
+fn process_random(syscall: Syscall, regs: &mut RegisterFile32Bit, rng: &mut Rng) -> Result<()> {
+    match syscall {
+        Syscall::RandSeed => *rng = Rng::seeded(u64::from(regs[RegisterMapping::A1])),
+        Syscall::RandInt => regs[RegisterMapping::A0] = rng.next_u32(),
+        Syscall::RandIntRange => {
+            let bound = regs[RegisterMapping::A1];
+            if bound == 0 {
+                bail!("RandIntRange: the upper bound (a1) must be greater than 0");
+            }
+            regs[RegisterMapping::A0] = rng.next_below(bound);
+        }
+        _ => unreachable!("{} isn't a random syscall", syscall.name()),
+    }
+    Ok(())
+}
+
+/// Append `text` to the programs output buffer, and write it to the output sink.
+fn emit(output: &mut String, output_sink: &mut ProgramOutput, text: &str) -> Result<()> {
+    output.push_str(text);
+    output_sink.write_str(text)?;
+    Ok(())
+}
+
+/// The error returned when the program makes an exit syscall.
+///
+/// This lets callers tell a program that exited apart from one that faulted.
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
-enum Syscall {
+pub struct ProgramExit {
+    pub code: u32,
+}
+
+impl fmt::Display for ProgramExit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Program exited with code: {}", self.code)
+    }
+}
+
+impl std::error::Error for ProgramExit {}
+
+/// The syscalls a program can make with `ecall`, selected by the number in `a7`.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Syscall {
     /// Print an integer to the console.
     /// # Inputs:
     /// a0 - the integer to print
@@ -478,15 +765,21 @@ enum Syscall {
     /// a0 - the address of the buffer to read the string into
     /// a1 - the maximum number of characters to read
     ReadString = 8,
+    /// Grow (or shrink) the heap by moving the program break
+    /// # Inputs:
+    /// a0 - the number of bytes to move the program break by (signed)
+    /// # Outputs:
+    /// a0 - the previous program break, i.e. the address of the newly allocated memory
+    Sbrk = 9,
     /// Exit the program with code 0
     Exit = 10,
     /// Print an ascii character to the console.
     /// # Inputs:
     /// a0 - the ascii character to print (only the lower 8 bits are used)
     PrintChar = 11,
-    /// Read an ascii character from the console.
+    /// Read a character from the console.
     /// # Outputs:
-    /// a0 - the ascii character read from the console
+    /// a0 - the character read from the console (its Unicode code point), or -1 at the end of the input or for a blank line
     ReadChar = 12,
     /// get the current Unix time (milliseconds since 1 January 1970)
     /// # Outputs:
@@ -509,11 +802,36 @@ enum Syscall {
     /// # Inputs:
     /// a0 - the integer to print
     PrintIntUnsigned = 36,
-    // RandSeed = 40,
-    // RandInt = 41,
-    // RandIntRange = 42,
+    /// Seed the pseudo-random number generator
+    /// # Inputs:
+    /// a0 - the index of the generator (ignored, there is only one)
+    /// a1 - the seed
+    RandSeed = 40,
+    /// Get a pseudo-random integer
+    /// # Inputs:
+    /// a0 - the index of the generator (ignored, there is only one)
+    /// # Outputs:
+    /// a0 - the random integer
+    RandInt = 41,
+    /// Get a pseudo-random integer in a range
+    /// # Inputs:
+    /// a0 - the index of the generator (ignored, there is only one)
+    /// a1 - the upper bound of the range (exclusive)
+    /// # Outputs:
+    /// a0 - the random integer, in 0..a1
+    RandIntRange = 42,
     // RandFloat = 43,
     // RandDouble = 44,
+    /// Query the layout of the heap (not present in RARS)
+    /// # Outputs:
+    /// a0 - the address the heap starts at
+    /// a1 - the current program break (the first address past the end of the heap)
+    /// a2 - the highest address the heap may grow to
+    MemInfo = 70,
+    /// Pause the machine, returning control to the embedder with its state intact (not present in RARS)
+    ///
+    /// Unlike exiting, execution can be resumed from the next instruction.
+    Halt = 71,
     /// Exit the program with the given exit code
     /// # Inputs:
     /// a0 - the exit code
@@ -521,23 +839,727 @@ enum Syscall {
     UnSupported,
 }
 
+impl Syscall {
+    /// every supported syscall
+    const SUPPORTED: &'static [Self] = &[
+        Self::PrintInt,
+        Self::PrintString,
+        Self::ReadInt,
+        Self::ReadString,
+        Self::Sbrk,
+        Self::Exit,
+        Self::PrintChar,
+        Self::ReadChar,
+        Self::Time,
+        Self::Sleep,
+        Self::PrintIntHex,
+        Self::PrintIntBinary,
+        Self::PrintIntUnsigned,
+        Self::RandSeed,
+        Self::RandInt,
+        Self::RandIntRange,
+        Self::MemInfo,
+        Self::Halt,
+        Self::Exit2,
+    ];
+
+    /// List the number and name of every supported syscall, in order of number.
+    pub fn all() -> impl Iterator<Item = (u32, &'static str)> {
+        Self::SUPPORTED
+            .iter()
+            .map(|&syscall| (syscall as u32, syscall.name()))
+    }
+
+    /// get the name of the syscall, as used by RARS
+    #[must_use]
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::PrintInt => "PrintInt",
+            Self::PrintString => "PrintString",
+            Self::ReadInt => "ReadInt",
+            Self::ReadString => "ReadString",
+            Self::Sbrk => "Sbrk",
+            Self::Exit => "Exit",
+            Self::PrintChar => "PrintChar",
+            Self::ReadChar => "ReadChar",
+            Self::Time => "Time",
+            Self::Sleep => "Sleep",
+            Self::PrintIntHex => "PrintIntHex",
+            Self::PrintIntBinary => "PrintIntBinary",
+            Self::PrintIntUnsigned => "PrintIntUnsigned",
+            Self::RandSeed => "RandSeed",
+            Self::RandInt => "RandInt",
+            Self::RandIntRange => "RandIntRange",
+            Self::MemInfo => "MemInfo",
+            Self::Halt => "Halt",
+            Self::Exit2 => "Exit2",
+            Self::UnSupported => "UnSupported",
+        }
+    }
+}
+
 impl From<u32> for Syscall {
     fn from(value: u32) -> Self {
-        match value {
-            1 => Self::PrintInt,
-            4 => Self::PrintString,
-            5 => Self::ReadInt,
-            8 => Self::ReadString,
-            10 => Self::Exit,
-            11 => Self::PrintChar,
-            12 => Self::ReadChar,
-            30 => Self::Time,
-            32 => Self::Sleep,
-            34 => Self::PrintIntHex,
-            35 => Self::PrintIntBinary,
-            36 => Self::PrintIntUnsigned,
-            93 => Self::Exit2,
-            _ => Self::UnSupported,
+        Self::SUPPORTED
+            .iter()
+            .copied()
+            .find(|&syscall| syscall as u32 == value)
+            .unwrap_or(Self::UnSupported)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::emulator::decode::Decode32BitInstruction as _;
+
+    /// Decode the given machine code and execute it on the given CPU.
+    fn execute_machine_code(cpu: &mut Cpu32Bit, machine_code: u32) -> Result<()> {
+        let instruction = Rv32imInstruction::from_machine_code(machine_code)?;
+        cpu.execute(instruction)
+    }
+
+    #[test]
+    fn test_all_syscalls() {
+        let all: Vec<_> = Syscall::all().collect();
+        assert!(all.contains(&(4, "PrintString")));
+        assert!(all.iter().all(|&(_, name)| name != "UnSupported"));
+        // every listed syscall is recognized by its number
+        for (number, name) in all {
+            assert_eq!(Syscall::from(number).name(), name);
+        }
+    }
+
+    #[test]
+    fn test_write_to_zero_is_discarded() -> Result<()> {
+        let mut cpu = Cpu32Bit::new(&[], &[], 0x0040_0000, None)?;
+        cpu.registers[RegisterMapping::A0] = 7;
+        // addi zero, a0, 5
+        execute_machine_code(&mut cpu, 0x0055_0013)?;
+        assert_eq!(cpu.registers[RegisterMapping::Zero], 0);
+        assert_eq!(cpu.pc, 0x0040_0004);
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_to_zero_still_accesses_memory() -> Result<()> {
+        let mut cpu = Cpu32Bit::new(&[], &[0x78, 0x56, 0x34, 0x12], 0x0040_0000, None)?;
+        cpu.registers[RegisterMapping::A0] = cpu.memory.dram_start();
+        // lw zero, 0(a0)
+        execute_machine_code(&mut cpu, 0x0005_2003)?;
+        assert_eq!(cpu.registers[RegisterMapping::Zero], 0);
+        assert_eq!(cpu.pc, 0x0040_0004);
+
+        // the load still happens, so a bad address still faults
+        cpu.registers[RegisterMapping::A0] = 0x0000_0010;
+        let error = execute_machine_code(&mut cpu, 0x0005_2003)
+            .expect_err("the load from the null guard should fault");
+        assert_eq!(
+            error.downcast_ref::<Trap>(),
+            Some(&Trap::NullPointerDereference {
+                addr: 0x0000_0010,
+                store: false
+            })
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_pc_wraps_at_end_of_address_space() -> Result<()> {
+        let mut cpu = Cpu32Bit::new(&[], &[], 0x0040_0000, None)?;
+        cpu.pc = 0xFFFF_FFFC;
+        // addi a0, a0, 1
+        execute_machine_code(&mut cpu, 0x0015_0513)?;
+        assert_eq!(cpu.pc, 0);
+
+        cpu.pc = 0xFFFF_FFFC;
+        // jal ra, 8
+        execute_machine_code(&mut cpu, 0x0080_00ef)?;
+        assert_eq!(cpu.registers[RegisterMapping::Ra], 0);
+
+        // the wrapped pc is outside of the text section, so fetching from it fails instead of panicking
+        assert!(cpu.fetch_and_decode(0).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_auipc_wraps_at_end_of_address_space() -> Result<()> {
+        let mut cpu = Cpu32Bit::new(&[], &[], 0x0040_0000, None)?;
+
+        // auipc a0, 0x1: pc + 0x1000 carries past u32::MAX
+        cpu.pc = 0xFFFF_F800;
+        execute_machine_code(&mut cpu, 0x0000_1517)?;
+        assert_eq!(cpu.registers[RegisterMapping::A0], 0x0000_0800);
+
+        // auipc a0, 0xfffff: the immediate is sign extended, so this is pc - 0x1000
+        cpu.pc = 0xFFFF_F800;
+        execute_machine_code(&mut cpu, 0xffff_f517)?;
+        assert_eq!(cpu.registers[RegisterMapping::A0], 0xFFFF_E800);
+        cpu.pc = 0x0000_0800;
+        execute_machine_code(&mut cpu, 0xffff_f517)?;
+        assert_eq!(cpu.registers[RegisterMapping::A0], 0xFFFF_F800);
+
+        // auipc a0, 0x80000: the most negative offset, -2GiB
+        cpu.pc = 0xFFFF_FFFC;
+        execute_machine_code(&mut cpu, 0x8000_0517)?;
+        assert_eq!(cpu.registers[RegisterMapping::A0], 0x7FFF_FFFC);
+        assert_eq!(cpu.pc, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_gp_relative_access_outside_static_data() -> Result<()> {
+        let mut cpu = Cpu32Bit::new(&[], &[1, 2, 3, 4], 0x0040_0000, None)?;
+        cpu.memory.set_gp_check(true);
+        let past_static_data = cpu.memory.heap_base();
+
+        // lw a0, -2048(gp), gp-relative accesses to the static data are fine
+        cpu.registers[RegisterMapping::Gp] = cpu.memory.dram_start() + 2048;
+        execute_machine_code(&mut cpu, 0x8001_a503)?;
+        assert_eq!(cpu.registers[RegisterMapping::A0], 0x0403_0201);
+
+        // lw a0, 0(gp), past the end of the static data
+        cpu.registers[RegisterMapping::Gp] = past_static_data;
+        let error = execute_machine_code(&mut cpu, 0x0001_a503).expect_err("load should trap");
+        assert_eq!(
+            error.downcast_ref::<Trap>(),
+            Some(&Trap::GpRelativeAccessOutsideStaticData {
+                addr: past_static_data,
+                store: false
+            })
+        );
+        // sw a0, 0(gp)
+        let error = execute_machine_code(&mut cpu, 0x00a1_a023).expect_err("store should trap");
+        assert_eq!(
+            error.downcast_ref::<Trap>(),
+            Some(&Trap::GpRelativeAccessOutsideStaticData {
+                addr: past_static_data,
+                store: true
+            })
+        );
+
+        // accesses relative to other registers aren't checked, lw a0, 0(sp)
+        cpu.registers[RegisterMapping::Sp] = past_static_data;
+        execute_machine_code(&mut cpu, 0x0001_2503)?;
+        // and neither are gp-relative ones when the check is off
+        cpu.memory.set_gp_check(false);
+        execute_machine_code(&mut cpu, 0x0001_a503)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_fault_names_base_register() {
+        let mut cpu = Cpu32Bit::new(&[], &[], 0x0040_0000, None).unwrap();
+        cpu.registers[RegisterMapping::Sp] = 0x0000_0010;
+        // sw ra, -4(sp)
+        let error = execute_machine_code(&mut cpu, 0xfe11_2e23).expect_err("store should fault");
+        assert_eq!(
+            error.to_string(),
+            "store to 0x0000000c computed from sp=0x00000010 + offset -4"
+        );
+        assert_eq!(
+            error.downcast_ref::<Trap>(),
+            Some(&Trap::NullPointerDereference {
+                addr: 0x0000_000c,
+                store: true
+            })
+        );
+
+        cpu.registers[RegisterMapping::Sp] = 0x9000_0000;
+        // lw a0, 8(sp)
+        let error = execute_machine_code(&mut cpu, 0x0081_2503).expect_err("load should fault");
+        assert_eq!(
+            error.to_string(),
+            "load from 0x90000008 computed from sp=0x90000000 + offset 8"
+        );
+    }
+
+    #[test]
+    fn test_fences_are_no_ops() -> Result<()> {
+        // fence, fence.i, fence.tso, pause
+        for machine_code in [0x0ff0_000f, 0x0000_100f, 0x8330_000f, 0x0100_000f] {
+            let mut cpu = Cpu32Bit::new(&[], &[], 0x0040_0000, None)?;
+            let registers = cpu.registers;
+            execute_machine_code(&mut cpu, machine_code)?;
+            assert_eq!(cpu.registers, registers);
+            assert_eq!(cpu.pc, 0x0040_0004);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_string_into_tiny_buffers() -> Result<()> {
+        for (max_len, expected) in [(0, &[0, 0xff, 0xff]), (1, &[0, 0xff, 0xff]), (3, b"hi\0")] {
+            let mut cpu = Cpu32Bit::new(&[], &[0xff; 4], 0x0040_0000, None)?;
+            cpu.input =
+                ProgramInput::Reader(Box::new(std::io::Cursor::new(b"hi there\n".to_vec())));
+            let buffer = cpu.memory.dram_start();
+            cpu.registers[RegisterMapping::A0] = buffer;
+            cpu.registers[RegisterMapping::A1] = max_len;
+            cpu.registers[RegisterMapping::A7] = 8;
+            // ecall
+            execute_machine_code(&mut cpu, 0x0000_0073)?;
+            assert_eq!(
+                cpu.memory.read_bytes(buffer, 3)?,
+                expected,
+                "a1 = {max_len}"
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_char() -> Result<()> {
+        for (input, expected) in [
+            (&b"a\n"[..], u32::from('a')),
+            (b" b\n", u32::from(' ')),
+            ("é\n".as_bytes(), 0xe9),
+            (b"\n", u32::MAX),
+            (b"", u32::MAX),
+        ] {
+            let mut cpu = Cpu32Bit::new(&[], &[], 0x0040_0000, None)?;
+            cpu.input = ProgramInput::Reader(Box::new(std::io::Cursor::new(input.to_vec())));
+            cpu.registers[RegisterMapping::A7] = 12;
+            // ecall
+            execute_machine_code(&mut cpu, 0x0000_0073)?;
+            assert_eq!(cpu.registers[RegisterMapping::A0], expected, "{input:?}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_meminfo_reports_heap_break() -> Result<()> {
+        let mut cpu = Cpu32Bit::new(&[], &[], 0x0040_0000, None)?;
+        cpu.registers[RegisterMapping::A7] = 70;
+        // ecall
+        execute_machine_code(&mut cpu, 0x0000_0073)?;
+        let heap_base = cpu.registers[RegisterMapping::A0];
+        let initial_break = cpu.registers[RegisterMapping::A1];
+        let heap_ceiling = cpu.registers[RegisterMapping::A2];
+        assert_eq!(heap_base, initial_break);
+        assert!(heap_base >= cpu.memory.dram_start());
+        assert!(heap_ceiling > heap_base);
+
+        assert_eq!(cpu.memory.sbrk(256)?, initial_break);
+
+        execute_machine_code(&mut cpu, 0x0000_0073)?;
+        assert_eq!(cpu.registers[RegisterMapping::A0], heap_base);
+        assert_eq!(cpu.registers[RegisterMapping::A1], initial_break + 256);
+        assert_eq!(cpu.registers[RegisterMapping::A2], heap_ceiling);
+        Ok(())
+    }
+
+    #[test]
+    fn test_sbrk() -> Result<()> {
+        let mut cpu = Cpu32Bit::new(&[], &[], 0x0040_0000, None)?;
+        let heap_base = cpu.memory.heap_base();
+        for expected in [heap_base, heap_base + 1024] {
+            cpu.registers[RegisterMapping::A7] = 9;
+            cpu.registers[RegisterMapping::A0] = 1024;
+            // ecall
+            execute_machine_code(&mut cpu, 0x0000_0073)?;
+            assert_eq!(cpu.registers[RegisterMapping::A0], expected);
+        }
+        assert_eq!(cpu.memory.heap_end(), heap_base + 2048);
+
+        // the allocated memory can be used
+        cpu.memory
+            .write(heap_base + 2044, 0xdead_beef, Size::Word)?;
+        assert_eq!(cpu.memory.read(heap_base + 2044, Size::Word)?, 0xdead_beef);
+
+        // shrinking returns the previous break too
+        cpu.registers[RegisterMapping::A0] = -2048_i32 as u32;
+        execute_machine_code(&mut cpu, 0x0000_0073)?;
+        assert_eq!(cpu.registers[RegisterMapping::A0], heap_base + 2048);
+        assert_eq!(cpu.memory.heap_end(), heap_base);
+        Ok(())
+    }
+
+    #[test]
+    fn test_sbrk_into_the_stack() {
+        let mut cpu = Cpu32Bit::new(&[], &[], 0x0040_0000, None).unwrap();
+        let heap_base = cpu.memory.heap_base();
+        cpu.registers[RegisterMapping::Sp] = heap_base + 0x100;
+        cpu.registers[RegisterMapping::A7] = 9;
+        cpu.registers[RegisterMapping::A0] = 0x200;
+        // ecall
+        let error = execute_machine_code(&mut cpu, 0x0000_0073)
+            .expect_err("the heap can't grow past the stack pointer");
+        assert!(
+            error.to_string().contains("collide with the stack"),
+            "{error}"
+        );
+        assert_eq!(cpu.memory.heap_end(), heap_base);
+    }
+
+    #[test]
+    fn test_ecall_poisons_temporaries() -> Result<()> {
+        let mut cpu = Cpu32Bit::new(&[], &[], 0x0040_0000, None)?;
+        cpu.poison_temporaries = true;
+        cpu.registers[RegisterMapping::T0] = 1;
+        cpu.registers[RegisterMapping::S0] = 2;
+        // print the integer in a0
+        cpu.registers[RegisterMapping::A7] = 1;
+        cpu.output_sink = ProgramOutput::Writer(Box::new(std::io::sink()));
+        // ecall
+        execute_machine_code(&mut cpu, 0x0000_0073)?;
+        assert_eq!(cpu.registers[RegisterMapping::T0], ECALL_POISON);
+        assert_eq!(cpu.registers[RegisterMapping::S0], 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_time_is_split_across_a0_and_a1() -> Result<()> {
+        // a time whose milliseconds don't fit in 32 bits
+        let millis: u64 = 1_700_000_000_123;
+        let mut cpu = Cpu32Bit::new(&[], &[], 0x0040_0000, None)?;
+        cpu.clock = Clock::Fixed(std::time::Duration::from_millis(millis));
+        cpu.registers[RegisterMapping::A7] = 30;
+        // ecall
+        execute_machine_code(&mut cpu, 0x0000_0073)?;
+        let low = u64::from(cpu.registers[RegisterMapping::A0]);
+        let high = u64::from(cpu.registers[RegisterMapping::A1]);
+        assert_eq!((high << 32) | low, millis);
+        Ok(())
+    }
+
+    #[test]
+    fn test_counters() -> Result<()> {
+        let mut cpu = Cpu32Bit::new(&[], &[], 0x0040_0000, None)?;
+        cpu.instret = 0x1_0000_0002;
+
+        // rdinstret a0
+        execute_machine_code(&mut cpu, 0xc020_2573)?;
+        assert_eq!(cpu.registers[RegisterMapping::A0], 2);
+        // rdinstreth a0
+        execute_machine_code(&mut cpu, 0xc820_2573)?;
+        assert_eq!(cpu.registers[RegisterMapping::A0], 1);
+        // rdcycle a0
+        execute_machine_code(&mut cpu, 0xc000_2573)?;
+        assert_eq!(cpu.registers[RegisterMapping::A0], 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_csr_instructions() -> Result<()> {
+        let mut cpu = Cpu32Bit::new(&[], &[], 0x0040_0000, None)?;
+        cpu.registers[RegisterMapping::A1] = 0b1100;
+        cpu.registers[RegisterMapping::A3] = 0b0011;
+        cpu.registers[RegisterMapping::A5] = 0b0110;
+
+        // csrrw a0, mscratch, a1
+        execute_machine_code(&mut cpu, 0x3405_9573)?;
+        assert_eq!(cpu.registers[RegisterMapping::A0], 0);
+        assert_eq!(cpu.csrs.mscratch, 0b1100);
+        // csrrs a2, mscratch, a3
+        execute_machine_code(&mut cpu, 0x3406_a673)?;
+        assert_eq!(cpu.registers[RegisterMapping::A2], 0b1100);
+        assert_eq!(cpu.csrs.mscratch, 0b1111);
+        // csrrc a4, mscratch, a5
+        execute_machine_code(&mut cpu, 0x3407_b773)?;
+        assert_eq!(cpu.registers[RegisterMapping::A4], 0b1111);
+        assert_eq!(cpu.csrs.mscratch, 0b1001);
+
+        // csrrwi a0, mtvec, 5
+        execute_machine_code(&mut cpu, 0x3052_d573)?;
+        assert_eq!(cpu.csrs.mtvec, 5);
+        // csrrsi a1, mtvec, 2
+        execute_machine_code(&mut cpu, 0x3051_65f3)?;
+        assert_eq!(cpu.registers[RegisterMapping::A1], 5);
+        assert_eq!(cpu.csrs.mtvec, 0b111);
+        // csrrci a2, mtvec, 1
+        execute_machine_code(&mut cpu, 0x3050_f673)?;
+        assert_eq!(cpu.registers[RegisterMapping::A2], 0b111);
+        assert_eq!(cpu.csrs.mtvec, 0b110);
+        Ok(())
+    }
+
+    #[test]
+    fn test_csr_zero_register_semantics() -> Result<()> {
+        let mut cpu = Cpu32Bit::new(&[], &[], 0x0040_0000, None)?;
+        cpu.instret = 7;
+
+        // csrrs a0, mstatus, zero only reads
+        cpu.csrs.mstatus = 0x1800;
+        execute_machine_code(&mut cpu, 0x3000_2573)?;
+        assert_eq!(cpu.registers[RegisterMapping::A0], 0x1800);
+        // so reading a read-only CSR with csrrs is fine, but writing it isn't
+        cpu.registers[RegisterMapping::A0] = 1;
+        assert!(execute_machine_code(&mut cpu, 0xc005_2073).is_err()); // csrrs zero, cycle, a0
+
+        // csrrw zero, minstret, a1 writes without reading
+        cpu.registers[RegisterMapping::A1] = 100;
+        execute_machine_code(&mut cpu, 0xb025_9073)?;
+        assert_eq!(cpu.counters().instret, 100);
+        assert_eq!(cpu.counters().cycle, 7);
+        // csrrs a0, minstret, zero
+        execute_machine_code(&mut cpu, 0xb020_2573)?;
+        assert_eq!(cpu.registers[RegisterMapping::A0], 100);
+        // csrrw zero, mcycle, zero
+        execute_machine_code(&mut cpu, 0xb000_1073)?;
+        assert_eq!(cpu.counters().cycle, 0);
+
+        // unimplemented CSRs are rejected
+        let error = execute_machine_code(&mut cpu, 0x7c00_2573).unwrap_err(); // csrrs a0, 0x7c0, zero
+        assert_eq!(error.to_string(), "Unsupported CSR: 0x7c0");
+        Ok(())
+    }
+
+    #[test]
+    fn test_mulhsu() -> Result<()> {
+        // mulhsu a0, a1, a2
+        let machine_code: u32 = 0x02c5_a533;
+        // (rs1, rs2, expected rd), rs1 is signed and rs2 is unsigned
+        let cases: [(u32, u32, u32); 7] = [
+            // -1 * (2^32 - 1) = -(2^32 - 1)
+            (0xFFFF_FFFF, 0xFFFF_FFFF, 0xFFFF_FFFF),
+            // -2 * 2^31 = -2^32
+            (0xFFFF_FFFE, 0x8000_0000, 0xFFFF_FFFF),
+            // -2^31 * (2^32 - 1) = -2^63 + 2^31
+            (0x8000_0000, 0xFFFF_FFFF, 0x8000_0000),
+            // (2^31 - 1) * (2^32 - 1)
+            (0x7FFF_FFFF, 0xFFFF_FFFF, 0x7FFF_FFFE),
+            // 2 * 2^31 = 2^32
+            (0x0000_0002, 0x8000_0000, 0x0000_0001),
+            // -1 * 1 = -1
+            (0xFFFF_FFFF, 0x0000_0001, 0xFFFF_FFFF),
+            // -1 * 0 = 0
+            (0xFFFF_FFFF, 0x0000_0000, 0x0000_0000),
+        ];
+
+        assert_rtype("mulhsu", machine_code, &cases)
+    }
+
+    /// Execute the R-type instruction `op a0, a1, a2` for each `(a1, a2, expected a0)` case.
+    fn assert_rtype(name: &str, machine_code: u32, cases: &[(u32, u32, u32)]) -> Result<()> {
+        for &(rs1, rs2, expected) in cases {
+            let mut cpu = Cpu32Bit::new(&[], &[], 0x0040_0000, None)?;
+            cpu.registers[RegisterMapping::A1] = rs1;
+            cpu.registers[RegisterMapping::A2] = rs2;
+            execute_machine_code(&mut cpu, machine_code)?;
+            assert_eq!(
+                cpu.registers[RegisterMapping::A0],
+                expected,
+                "{name} {rs1:#010x}, {rs2:#010x}"
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_add_sub() -> Result<()> {
+        assert_rtype(
+            "add",
+            0x00c5_8533,
+            &[
+                (1, 2, 3),
+                (0xFFFF_FFFF, 1, 0),
+                (0x7FFF_FFFF, 1, 0x8000_0000),
+                (0x8000_0000, 0x8000_0000, 0),
+            ],
+        )?;
+        assert_rtype(
+            "sub",
+            0x40c5_8533,
+            &[
+                (3, 2, 1),
+                (0, 1, 0xFFFF_FFFF),
+                (0x8000_0000, 1, 0x7FFF_FFFF),
+                (5, 5, 0),
+            ],
+        )
+    }
+
+    #[test]
+    fn test_logical() -> Result<()> {
+        let cases = |f: fn(u32, u32) -> u32| {
+            [(0xF0F0_F0F0, 0xFF00_FF00), (0xFFFF_FFFF, 0), (0, 0)].map(|(a, b)| (a, b, f(a, b)))
+        };
+        assert_rtype("and", 0x00c5_f533, &cases(|a, b| a & b))?;
+        assert_rtype("or", 0x00c5_e533, &cases(|a, b| a | b))?;
+        assert_rtype("xor", 0x00c5_c533, &cases(|a, b| a ^ b))
+    }
+
+    #[test]
+    fn test_shifts() -> Result<()> {
+        assert_rtype(
+            "sll",
+            0x00c5_9533,
+            &[
+                (1, 31, 0x8000_0000),
+                // only the low 5 bits of the shift amount are used
+                (1, 32, 1),
+                (0xFFFF_FFFF, 4, 0xFFFF_FFF0),
+            ],
+        )?;
+        assert_rtype(
+            "srl",
+            0x00c5_d533,
+            &[
+                (0x8000_0000, 31, 1),
+                (0x8000_0000, 33, 0x4000_0000),
+                (0xFFFF_FFFF, 0, 0xFFFF_FFFF),
+            ],
+        )?;
+        assert_rtype(
+            "sra",
+            0x40c5_d533,
+            &[
+                // the sign bit is shifted in
+                (0x8000_0000, 31, 0xFFFF_FFFF),
+                (0x8000_0000, 4, 0xF800_0000),
+                (0x7FFF_FFFF, 30, 1),
+                (0xFFFF_FFF0, 36, 0xFFFF_FFFF),
+            ],
+        )
+    }
+
+    #[test]
+    fn test_set_less_than() -> Result<()> {
+        assert_rtype(
+            "slt",
+            0x00c5_a533,
+            &[
+                // -1 < 1
+                (0xFFFF_FFFF, 1, 1),
+                (1, 0xFFFF_FFFF, 0),
+                // i32::MIN < i32::MAX
+                (0x8000_0000, 0x7FFF_FFFF, 1),
+                (5, 5, 0),
+            ],
+        )?;
+        assert_rtype(
+            "sltu",
+            0x00c5_b533,
+            &[
+                // u32::MAX > 1
+                (0xFFFF_FFFF, 1, 0),
+                (1, 0xFFFF_FFFF, 1),
+                (0x7FFF_FFFF, 0x8000_0000, 1),
+                (0, 0, 0),
+            ],
+        )
+    }
+
+    #[test]
+    fn test_mul() -> Result<()> {
+        assert_rtype(
+            "mul",
+            0x02c5_8533,
+            &[
+                (6, 7, 42),
+                // -1 * -1 = 1
+                (0xFFFF_FFFF, 0xFFFF_FFFF, 1),
+                // the high bits are discarded
+                (0x1_0000, 0x1_0000, 0),
+                (0x8000_0000, 2, 0),
+            ],
+        )?;
+        assert_rtype(
+            "mulh",
+            0x02c5_9533,
+            &[
+                // -1 * -1 = 1
+                (0xFFFF_FFFF, 0xFFFF_FFFF, 0),
+                // -2^31 * -2^31 = 2^62
+                (0x8000_0000, 0x8000_0000, 0x4000_0000),
+                // -1 * 1 = -1
+                (0xFFFF_FFFF, 1, 0xFFFF_FFFF),
+                (0x1_0000, 0x1_0000, 1),
+            ],
+        )?;
+        assert_rtype(
+            "mulhu",
+            0x02c5_b533,
+            &[
+                // (2^32 - 1)^2 = 2^64 - 2^33 + 1
+                (0xFFFF_FFFF, 0xFFFF_FFFF, 0xFFFF_FFFE),
+                (0x8000_0000, 0x8000_0000, 0x4000_0000),
+                (0xFFFF_FFFF, 1, 0),
+            ],
+        )
+    }
+
+    #[test]
+    fn test_div_rem() -> Result<()> {
+        assert_rtype(
+            "div",
+            0x02c5_c533,
+            &[
+                (42, 5, 8),
+                // rounds towards zero: -7 / 2 = -3
+                (0xFFFF_FFF9, 2, 0xFFFF_FFFD),
+                // 7 / -2 = -3
+                (7, 0xFFFF_FFFE, 0xFFFF_FFFD),
+                // -7 / -2 = 3
+                (0xFFFF_FFF9, 0xFFFF_FFFE, 3),
+                // overflow: i32::MIN / -1 = i32::MIN
+                (0x8000_0000, 0xFFFF_FFFF, 0x8000_0000),
+            ],
+        )?;
+        assert_rtype(
+            "divu",
+            0x02c5_d533,
+            &[
+                (42, 5, 8),
+                (0xFFFF_FFF9, 2, 0x7FFF_FFFC),
+                (1, 0xFFFF_FFFF, 0),
+            ],
+        )?;
+        assert_rtype(
+            "rem",
+            0x02c5_e533,
+            &[
+                (42, 5, 2),
+                // the sign of the remainder follows the dividend: -7 % 2 = -1
+                (0xFFFF_FFF9, 2, 0xFFFF_FFFF),
+                // 7 % -2 = 1
+                (7, 0xFFFF_FFFE, 1),
+                // overflow: i32::MIN % -1 = 0
+                (0x8000_0000, 0xFFFF_FFFF, 0),
+            ],
+        )?;
+        assert_rtype(
+            "remu",
+            0x02c5_f533,
+            &[(42, 5, 2), (0xFFFF_FFF9, 2, 1), (1, 0xFFFF_FFFF, 1)],
+        )
+    }
+
+    #[test]
+    fn test_div_by_zero_spec_default() -> Result<()> {
+        // the quotient is all ones, and the remainder is the dividend
+        assert_rtype(
+            "div",
+            0x02c5_c533,
+            &[(42, 0, u32::MAX), (0xFFFF_FFF9, 0, u32::MAX)],
+        )?;
+        assert_rtype("divu", 0x02c5_d533, &[(42, 0, u32::MAX)])?;
+        assert_rtype(
+            "rem",
+            0x02c5_e533,
+            &[(42, 0, 42), (0xFFFF_FFF9, 0, 0xFFFF_FFF9)],
+        )?;
+        assert_rtype("remu", 0x02c5_f533, &[(42, 0, 42)])
+    }
+
+    #[test]
+    fn test_div_by_zero_trap() {
+        for (name, machine_code) in [
+            ("div", 0x02c5_c533),
+            ("divu", 0x02c5_d533),
+            ("rem", 0x02c5_e533),
+            ("remu", 0x02c5_f533),
+        ] {
+            let mut cpu = Cpu32Bit::new(&[], &[], 0x0040_0000, None).unwrap();
+            cpu.div_by_zero = DivByZeroPolicy::Trap;
+            cpu.registers[RegisterMapping::A0] = 7;
+            cpu.registers[RegisterMapping::A1] = 42;
+            let error = execute_machine_code(&mut cpu, machine_code)
+                .expect_err(&format!("{name} by zero should trap"));
+            assert_eq!(error.downcast_ref::<Trap>(), Some(&Trap::DivisionByZero));
+            assert_eq!(cpu.registers[RegisterMapping::A0], 7, "{name}");
         }
     }
 }
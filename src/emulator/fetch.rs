@@ -28,9 +28,70 @@ use crate::instruction_set_definition::Rv32imInstruction;
 
 use super::{
     cpu::{memory::MemoryBus, Size},
-    decode::Decode32BitInstruction,
+    decode::{CustomDecoder, Decode32BitInstruction},
+    trap::Trap,
 };
 
+/// The size of a full-width (uncompressed) instruction, in bytes.
+pub const INSTRUCTION_SIZE: u32 = Size::Word.bytes();
+
+/// The alignment instructions require, in bytes, without the C (compressed instructions) extension.
+pub const INSTRUCTION_ALIGN: u32 = INSTRUCTION_SIZE;
+
+/// The alignment instructions require, in bytes, with the C extension.
+pub const COMPRESSED_INSTRUCTION_ALIGN: u32 = Size::Half.bytes();
+
+/// whether `pc` is aligned to an instruction boundary, with (`compressed`) or without the C extension
+#[must_use]
+pub const fn is_pc_aligned(pc: u32, compressed: bool) -> bool {
+    let align = if compressed {
+        COMPRESSED_INSTRUCTION_ALIGN
+    } else {
+        INSTRUCTION_ALIGN
+    };
+    pc.is_multiple_of(align)
+}
+
+/// The width of the instruction starting with the halfword `low_half`:
+/// compressed instructions are the ones whose lowest two bits aren't 0b11.
+#[must_use]
+pub const fn instruction_width(low_half: u32) -> Size {
+    if low_half & 0b11 == 0b11 {
+        Size::Word
+    } else {
+        Size::Half
+    }
+}
+
+/// Split a text section loaded at `base` into its instructions, as (address, machine code, width),
+/// walking it by the width each instruction's encoding declares (see `instruction_width`).
+///
+/// An instruction cut short by the end of the text section is left out, see `instructions_end`.
+pub fn instructions(text: &[u8], base: u32) -> impl Iterator<Item = (u32, u32, Size)> + '_ {
+    let mut offset = 0;
+    std::iter::from_fn(move || {
+        let low_half = text.get(offset..offset + 2)?;
+        let width = instruction_width(u32::from(u16::from_le_bytes([low_half[0], low_half[1]])));
+        let bytes = text.get(offset..offset + width.bytes() as usize)?;
+        let machine_code = bytes
+            .iter()
+            .rev()
+            .fold(0, |code, &byte| code << 8 | u32::from(byte));
+        #[allow(clippy::cast_possible_truncation)] // the text section is less than 4GB
+        let addr = base.wrapping_add(offset as u32);
+        offset += width.bytes() as usize;
+        Some((addr, machine_code, width))
+    })
+}
+
+/// The offset into `text` just past the last whole instruction, see `instructions`.
+#[must_use]
+pub fn instructions_end(text: &[u8]) -> usize {
+    instructions(text, 0)
+        .last()
+        .map_or(0, |(offset, _, width)| (offset + width.bytes()) as usize)
+}
+
 #[allow(clippy::module_name_repetitions)]
 pub trait Fetch32BitInstruction {
     type InstructionSet;
@@ -49,7 +110,24 @@ pub trait Fetch32BitInstruction {
     ///
     /// Returns an error if the instruction cannot be fetched from the memory.
     /// this can happen if the memory is out of bounds, if the memory is not readable, if the memory is outside of the text segment, etc.
-    fn fetch_and_decode(&self, pc: Self::PC) -> Result<Self::InstructionSet>;
+    fn fetch_and_decode(&self, pc: Self::PC) -> Result<Self::InstructionSet> {
+        self.fetch_and_decode_with(pc, None)
+            .map(|(instruction, _)| instruction)
+    }
+
+    /// Fetch and decode the instruction at the given program counter,
+    /// consulting the `custom_decoder` (if any) for instructions the standard decoder doesn't recognize.
+    ///
+    /// Also returns the width of the instruction in memory, which is `Size::Half` for compressed instructions.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the instruction cannot be fetched from the memory, or cannot be decoded.
+    fn fetch_and_decode_with(
+        &self,
+        pc: Self::PC,
+        custom_decoder: Option<&CustomDecoder>,
+    ) -> Result<(Self::InstructionSet, Size)>;
 }
 
 impl Fetch32BitInstruction for MemoryBus {
@@ -57,14 +135,103 @@ impl Fetch32BitInstruction for MemoryBus {
     type PC = u32;
     const INSTRUCTION_SIZE: Size = Size::Word;
 
-    fn fetch_and_decode(&self, pc: Self::PC) -> Result<Self::InstructionSet> {
-        if pc.wrapping_sub(self.entrypoint()) >= self.code_size() {
+    fn fetch_and_decode_with(
+        &self,
+        pc: Self::PC,
+        custom_decoder: Option<&CustomDecoder>,
+    ) -> Result<(Self::InstructionSet, Size)> {
+        if pc == self.text_base().wrapping_add(self.code_size()) {
+            return Err(Trap::RanOffEndOfCode { pc }.into());
+        }
+        if pc.wrapping_sub(self.text_base()) >= self.code_size() {
             bail!("Program counter out of bounds: {:#010x}", pc);
         }
+        if let Some(cached) = self.cached_instruction(pc) {
+            return Ok(cached);
+        }
+
+        let low_half = self.read(pc, Size::Half)?;
+        if instruction_width(low_half) == Size::Half {
+            #[allow(clippy::cast_possible_truncation)] // a halfword read fits in 16 bits
+            let instruction = Rv32imInstruction::from_compressed_machine_code(low_half as u16)?;
+            self.cache_instruction(pc, instruction, Size::Half);
+            return Ok((instruction, Size::Half));
+        }
 
         // read the instruction from memory
         let instruction = self.read(pc, Self::INSTRUCTION_SIZE)?;
         // decode the instruction
-        Rv32imInstruction::from_machine_code(instruction)
+        let instruction =
+            Rv32imInstruction::from_machine_code_or_custom(instruction, custom_decoder)?;
+        // custom instructions aren't cached, as the custom decoder can be replaced at any time
+        if !matches!(instruction, Rv32imInstruction::Custom(_)) {
+            self.cache_instruction(pc, instruction, Self::INSTRUCTION_SIZE);
+        }
+        Ok((instruction, Self::INSTRUCTION_SIZE))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::words_to_bytes;
+
+    #[test]
+    fn test_is_pc_aligned() {
+        for (pc, aligned, compressed_aligned) in [
+            (0x0040_0000, true, true),
+            (0x0040_0004, true, true),
+            (0x0040_0002, false, true),
+            (0x0040_0001, false, false),
+            (0x0040_0003, false, false),
+        ] {
+            assert_eq!(is_pc_aligned(pc, false), aligned, "{pc:#010x}");
+            assert_eq!(is_pc_aligned(pc, true), compressed_aligned, "{pc:#010x}");
+        }
+    }
+
+    #[test]
+    fn test_instructions() {
+        let mut text = 0x4501_u16.to_le_bytes().to_vec(); // c.li a0, 0
+        text.extend(0x0015_0513_u32.to_le_bytes()); // addi a0, a0, 1
+        text.extend(0x0505_u16.to_le_bytes()); // c.addi a0, 1
+        assert_eq!(
+            instructions(&text, 0x0040_0000).collect::<Vec<_>>(),
+            [
+                (0x0040_0000, 0x4501, Size::Half),
+                (0x0040_0002, 0x0015_0513, Size::Word),
+                (0x0040_0006, 0x0505, Size::Half),
+            ]
+        );
+        assert_eq!(instructions_end(&text), 8);
+
+        // the first half of a full-width instruction, cut short
+        text.extend(0x0513_u16.to_le_bytes());
+        assert_eq!(instructions(&text, 0x0040_0000).count(), 3);
+        assert_eq!(instructions_end(&text), 8);
+    }
+
+    #[test]
+    fn test_fetch_past_the_last_instruction() -> Result<()> {
+        // addi a0, a0, 1; addi a0, a0, 1
+        let code = words_to_bytes(&[0x0015_0513_u32; 2]);
+        let memory = MemoryBus::new(0x0040_0000, &code, &[])?;
+        // the text region is exactly the code, with no padding after it
+        assert_eq!(memory.code_size(), 8);
+        memory.fetch_and_decode(0x0040_0004)?;
+
+        let error = memory
+            .fetch_and_decode(0x0040_0008)
+            .expect_err("there is no instruction after the last one");
+        assert_eq!(
+            error.downcast_ref::<Trap>(),
+            Some(&Trap::RanOffEndOfCode { pc: 0x0040_0008 })
+        );
+        // further past the end is just out of bounds
+        let error = memory
+            .fetch_and_decode(0x0040_000c)
+            .expect_err("the pc is outside of the code");
+        assert!(error.downcast_ref::<Trap>().is_none());
+        Ok(())
     }
 }
@@ -0,0 +1,453 @@
+/*
+MIT License
+
+Copyright (c) 2024 Anthony Rubick
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+//! Disassembly listings of a program's text section, diffs between them, and the code reachable in them
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::Write as _,
+};
+
+use crate::{
+    emulator::cpu::registers::RegisterMapping,
+    instruction_set_definition::{
+        operations::{ITypeOperation, UJTypeOperation},
+        Rv32imInstruction,
+    },
+};
+
+use super::{
+    cpu::Size,
+    decode::Decode32BitInstruction as _,
+    fetch::{instruction_width, instructions, is_pc_aligned},
+};
+
+/// Disassemble the text section of a program loaded at `base` into a listing,
+/// with one instruction per line as `address: machine code  disassembly`.
+///
+/// `labels` maps addresses to the names of the symbols there (e.g. functions), which are printed before
+/// the instruction at that address, and after jumps and branches to that address.
+/// Compressed instructions are listed with their 16-bit machine code,
+/// and encodings that don't decode to an instruction are listed as `.word` (or `.half`).
+#[must_use]
+pub fn listing(text: &[u8], base: u32, labels: &BTreeMap<u32, String>) -> String {
+    let mut listing = String::new();
+    for (addr, machine_code, disassembly) in disassemble(text, base, labels) {
+        if let Some(label) = labels.get(&addr) {
+            if !listing.is_empty() {
+                listing.push('\n');
+            }
+            let _ = writeln!(listing, "{addr:#010x} <{label}>:");
+        }
+        let machine_code = match instruction_width(machine_code) {
+            Size::Half => format!("{machine_code:04x}    "),
+            Size::Byte | Size::Word => format!("{machine_code:08x}"),
+        };
+        let _ = writeln!(listing, "  {addr:#010x}: {machine_code}  {disassembly}");
+    }
+    listing
+}
+
+/// Compare the disassembly of two text sections, loaded at `old_base` and `new_base`, instruction by instruction.
+///
+/// Returns a line per instruction, like a unified diff: instructions only in `old` are prefixed with `-`,
+/// instructions only in `new` with `+`, and common instructions with a space.
+/// Every line has the address of the instruction (in `new` for common instructions).
+/// The sections don't need to be the same length.
+///
+/// Instructions are compared by their machine code, so a jump or branch that moved along with its target
+/// (keeping the same offset) is unchanged, even though the target address in its disassembly differs.
+#[must_use]
+pub fn diff(old: &[u8], old_base: u32, new: &[u8], new_base: u32) -> String {
+    let old = disassemble(old, old_base, &BTreeMap::new());
+    let new = disassemble(new, new_base, &BTreeMap::new());
+
+    // only the instructions between the common prefix and suffix need to be searched for changes
+    let prefix = old
+        .iter()
+        .zip(&new)
+        .take_while(|(old, new)| old.1 == new.1)
+        .count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(old, new)| old.1 == new.1)
+        .count();
+    let machine_code = |instructions: &[(u32, u32, String)]| -> Vec<u32> {
+        instructions[prefix..instructions.len() - suffix]
+            .iter()
+            .map(|&(_, machine_code, _)| machine_code)
+            .collect()
+    };
+    let edits = std::iter::repeat_n(Edit::Keep, prefix)
+        .chain(edit_script(&machine_code(&old), &machine_code(&new)))
+        .chain(std::iter::repeat_n(Edit::Keep, suffix));
+
+    let mut diff = String::new();
+    let (mut i, mut j) = (0, 0);
+    for edit in edits {
+        match edit {
+            Edit::Keep => {
+                let _ = writeln!(diff, "  {:#010x}: {}", new[j].0, new[j].2);
+                i += 1;
+                j += 1;
+            }
+            Edit::Delete => {
+                let _ = writeln!(diff, "- {:#010x}: {}", old[i].0, old[i].2);
+                i += 1;
+            }
+            Edit::Insert => {
+                let _ = writeln!(diff, "+ {:#010x}: {}", new[j].0, new[j].2);
+                j += 1;
+            }
+        }
+    }
+    diff
+}
+
+/// A step of an edit script turning one sequence into another.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum Edit {
+    Keep,
+    Delete,
+    Insert,
+}
+
+/// The most deletions and insertions `edit_script` searches for, which bounds the memory its trace takes.
+const MAX_EDIT_DISTANCE: isize = 1024;
+
+/// The shortest edit script turning `old` into `new`, found with Myers' algorithm.
+///
+/// This takes O((N + M) D) time and O(D²) space, where D is the number of deletions and insertions,
+/// so it's cheap for the small changes diffs usually show. Deletions come before insertions where either order works.
+///
+/// If D is more than `MAX_EDIT_DISTANCE` (e.g. the sequences are unrelated), the script deletes all of `old`
+/// then inserts all of `new` instead.
+#[allow(clippy::cast_possible_wrap, clippy::cast_sign_loss)] // slice lengths fit in an isize, and indices are never negative
+fn edit_script<T: PartialEq>(old: &[T], new: &[T]) -> Vec<Edit> {
+    let max = (old.len() + new.len()) as isize;
+    // the furthest x (position in `old`) reached on each diagonal k = x - y, indexed by k + max + 1
+    let mut furthest = vec![0_isize; 2 * max as usize + 3];
+    // the diagonals -d - 1 ..= d + 1 of `furthest` before each round d, for backtracking
+    let mut trace = Vec::new();
+    'search: for d in 0..=max {
+        if d > MAX_EDIT_DISTANCE {
+            return std::iter::repeat_n(Edit::Delete, old.len())
+                .chain(std::iter::repeat_n(Edit::Insert, new.len()))
+                .collect();
+        }
+        trace.push(furthest[(max - d) as usize..=(max + d + 2) as usize].to_vec());
+        for k in (-d..=d).step_by(2) {
+            let index = (k + max + 1) as usize;
+            // extend the furthest path from the neighbouring diagonals, by an insertion (down) or a deletion (right)
+            let mut x = if k == -d || (k != d && furthest[index - 1] < furthest[index + 1]) {
+                furthest[index + 1]
+            } else {
+                furthest[index - 1] + 1
+            };
+            let mut y = x - k;
+            // then follow the common elements
+            while matches!(
+                (old.get(x as usize), new.get(y as usize)),
+                (Some(old), Some(new)) if old == new
+            ) {
+                x += 1;
+                y += 1;
+            }
+            furthest[index] = x;
+            if x as usize >= old.len() && y as usize >= new.len() {
+                break 'search;
+            }
+        }
+    }
+
+    let mut edits = Vec::new();
+    let (mut x, mut y) = (old.len() as isize, new.len() as isize);
+    for (d, saved) in trace.iter().enumerate().rev() {
+        let d = d as isize;
+        let furthest = |k: isize| saved[(k + d + 1) as usize];
+        let k = x - y;
+        let previous_k = if k == -d || (k != d && furthest(k - 1) < furthest(k + 1)) {
+            k + 1
+        } else {
+            k - 1
+        };
+        let previous_x = furthest(previous_k);
+        let previous_y = previous_x - previous_k;
+        while x > previous_x && y > previous_y {
+            edits.push(Edit::Keep);
+            x -= 1;
+            y -= 1;
+        }
+        if d > 0 {
+            edits.push(if x == previous_x {
+                Edit::Insert
+            } else {
+                Edit::Delete
+            });
+        }
+        (x, y) = (previous_x, previous_y);
+    }
+    edits.reverse();
+    edits
+}
+
+/// Find the addresses of the instructions reachable from `entry` in the text section loaded at `base`,
+/// by following the direct branches and jumps from it (recursive disassembly).
+///
+/// Unlike a linear sweep, this skips data embedded in the text section, as long as control never falls into it.
+/// The targets of `jalr` depend on registers so they're unknown, and not followed, but calls (jumps that link)
+/// are assumed to return to the next instruction. Encodings that don't decode to an instruction end the path reaching them.
+#[must_use]
+pub fn trace_reachable(text: &[u8], base: u32, entry: u32) -> BTreeSet<u32> {
+    let mut reachable = BTreeSet::new();
+    let mut worklist = vec![entry];
+    while let Some(addr) = worklist.pop() {
+        if !is_pc_aligned(addr, true) || addr < base || reachable.contains(&addr) {
+            continue;
+        }
+        let offset = (addr - base) as usize;
+        let Some((_, machine_code, width)) = text
+            .get(offset..)
+            .and_then(|text| instructions(text, addr).next())
+        else {
+            continue;
+        };
+        let Ok(instruction) = Rv32imInstruction::from_machine_code_with_width(machine_code, width)
+        else {
+            continue;
+        };
+        reachable.insert(addr);
+
+        worklist.extend(instruction.branch_target(addr));
+        let falls_through = match instruction {
+            Rv32imInstruction::UJType {
+                operation: UJTypeOperation::Jal,
+                rd,
+                ..
+            }
+            | Rv32imInstruction::IType {
+                operation: ITypeOperation::Jalr,
+                rd,
+                ..
+            } => rd != RegisterMapping::Zero,
+            _ => true,
+        };
+        if falls_through {
+            worklist.push(addr.wrapping_add(width.bytes()));
+        }
+    }
+    reachable
+}
+
+/// Disassemble every instruction of the text section loaded at `base`, as (address, machine code, disassembly),
+/// annotating jumps and branches to the addresses in `labels` with their names.
+fn disassemble(text: &[u8], base: u32, labels: &BTreeMap<u32, String>) -> Vec<(u32, u32, String)> {
+    instructions(text, base)
+        .map(|(addr, machine_code, width)| {
+            let disassembly = Rv32imInstruction::from_machine_code_with_width(machine_code, width)
+                .map_or_else(
+                    |_| match width {
+                        Size::Half => format!(".half {machine_code:#06x}"),
+                        Size::Byte | Size::Word => format!(".word {machine_code:#010x}"),
+                    },
+                    |instruction| instruction.disassemble_with_symbols(addr, labels),
+                );
+            (addr, machine_code, disassembly)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::words_to_bytes;
+
+    #[test]
+    fn test_listing() {
+        let text = words_to_bytes(&[
+            0x0010_0513u32, // addi a0, zero, 1
+            0xffff_ffff,    // invalid
+            0xffdf_f06f,    // jal x0, -4
+        ]);
+        let labels = BTreeMap::from([
+            (0x0040_0000, String::from("main")),
+            (0x0040_0008, String::from("loop")),
+        ]);
+
+        let listing = listing(&text, 0x0040_0000, &labels);
+        let lines: Vec<_> = listing.lines().collect();
+        assert_eq!(lines[0], "0x00400000 <main>:");
+        assert!(lines[1].starts_with("  0x00400000: 00100513  addi"));
+        assert_eq!(lines[2], "  0x00400004: ffffffff  .word 0xffffffff");
+        assert_eq!(lines[3], "");
+        assert_eq!(lines[4], "0x00400008 <loop>:");
+        assert_eq!(lines[5], "  0x00400008: ffdff06f  jal zero, 0x00400004");
+
+        // jumps to a label are annotated with it
+        let labels = BTreeMap::from([(0x0040_0004, String::from("loop"))]);
+        let listing = super::listing(&text, 0x0040_0000, &labels);
+        assert!(listing.ends_with("  0x00400008: ffdff06f  jal zero, 0x00400004 <loop>\n"));
+    }
+
+    #[test]
+    fn test_listing_compressed() {
+        let mut text = 0x4501_u16.to_le_bytes().to_vec(); // c.li a0, 0
+        text.extend(0x0015_0513_u32.to_le_bytes()); // addi a0, a0, 1
+        text.extend(0x0000_u16.to_le_bytes()); // the defined illegal instruction
+        text.extend(0xbfed_u16.to_le_bytes()); // c.j -6
+
+        let listing = listing(&text, 0x0040_0000, &BTreeMap::new());
+        let lines: Vec<_> = listing.lines().collect();
+        assert_eq!(
+            lines,
+            [
+                "  0x00400000: 4501      addi a0, zero, 0",
+                "  0x00400002: 00150513  addi a0, a0, 1",
+                "  0x00400006: 0000      .half 0x0000",
+                "  0x00400008: bfed      jal zero, 0x00400002",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_diff() {
+        let old = words_to_bytes(&[
+            0x0010_0513, // addi a0, zero, 1
+            0x0020_0593, // addi a1, zero, 2
+            0x00b5_0533, // add a0, a0, a1
+        ]);
+        let new = words_to_bytes(&[
+            0x0010_0513, // addi a0, zero, 1
+            0x0030_0593, // addi a1, zero, 3
+            0x00b5_0533, // add a0, a0, a1
+            0x0000_0073, // ecall
+        ]);
+
+        let diff = diff(&old, 0x0040_0000, &new, 0x0040_0000);
+        let lines: Vec<_> = diff.lines().collect();
+        assert_eq!(
+            lines,
+            [
+                "  0x00400000: addi a0, zero, 1",
+                "- 0x00400004: addi a1, zero, 2",
+                "+ 0x00400004: addi a1, zero, 3",
+                "  0x00400008: add a0, a0, a1",
+                "+ 0x0040000c: ecall",
+            ]
+        );
+
+        // identical sections have no changes
+        assert!(super::diff(&old, 0x0040_0000, &old, 0x0040_0000)
+            .lines()
+            .all(|line| line.starts_with(' ')));
+    }
+
+    #[test]
+    fn test_diff_moved_branch() {
+        let old = words_to_bytes(&[
+            0x0015_0513, // addi a0, a0, 1
+            0xfe05_1ee3, // bne a0, zero, -4
+        ]);
+        let new = words_to_bytes(&[
+            0x0000_0013, // nop
+            0x0015_0513, // addi a0, a0, 1
+            0xfe05_1ee3, // bne a0, zero, -4
+        ]);
+
+        // the branch moved along with its target, so only the nop is new
+        let diff = diff(&old, 0x0040_0000, &new, 0x0040_0000);
+        let lines: Vec<_> = diff.lines().collect();
+        assert_eq!(
+            lines,
+            [
+                "+ 0x00400000: addi zero, zero, 0",
+                "  0x00400004: addi a0, a0, 1",
+                "  0x00400008: bnez a0, 0x00400004",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_edit_script() {
+        use Edit::{Delete, Insert, Keep};
+        assert_eq!(edit_script::<u8>(&[], &[]), []);
+        assert_eq!(edit_script(b"ab", b""), [Delete, Delete]);
+        assert_eq!(edit_script(b"", b"ab"), [Insert, Insert]);
+        assert_eq!(
+            edit_script(b"abcabba", b"cbabac"),
+            [Delete, Delete, Keep, Insert, Keep, Keep, Delete, Keep, Insert]
+        );
+        // the script is always a shortest one
+        let (old, new) = (b"the quick brown fox", b"a quick brown dog");
+        let script = edit_script(old, new);
+        let kept = script.iter().filter(|&&edit| edit == Keep).count();
+        assert_eq!(kept, 14);
+        assert_eq!(script.len(), old.len() + new.len() - kept);
+    }
+
+    #[test]
+    fn test_edit_script_of_unrelated_sequences() {
+        // the shortest script keeps one element, but finding it would take too much memory
+        let old: Vec<u32> = (0..2000).collect();
+        let new: Vec<u32> = (0..2000).rev().collect();
+        let script = edit_script(&old, &new);
+        assert!(script[..2000].iter().all(|&edit| edit == Edit::Delete));
+        assert!(script[2000..].iter().all(|&edit| edit == Edit::Insert));
+        assert_eq!(script.len(), 4000);
+    }
+
+    #[test]
+    fn test_trace_reachable() {
+        let text = words_to_bytes(&[
+            0x00c0_006f, // jal x0, 12
+            0x0000_0000, // data
+            0x0010_0513, // data that decodes as addi a0, zero, 1
+            0x0005_0463, // beq a0, zero, 8
+            0x0080_00ef, // jal ra, 8 (a call, which returns here)
+            0x0000_8067, // jalr zero, 0(ra)
+            0x0015_0513, // addi a0, a0, 1 (the callee)
+            0x0000_8067, // jalr zero, 0(ra)
+            0x0000_0013, // nop, unreachable
+        ]);
+        let base = 0x0040_0000;
+
+        let reachable = trace_reachable(&text, base, base);
+        let offsets: Vec<_> = reachable.iter().map(|addr| addr - base).collect();
+        assert_eq!(offsets, [0, 12, 16, 20, 24, 28]);
+    }
+
+    #[test]
+    fn test_trace_reachable_compressed() {
+        let mut text = 0x4501_u16.to_le_bytes().to_vec(); // c.li a0, 0
+        text.extend(0x0005_0363_u32.to_le_bytes()); // beqz a0, 6
+        text.extend(0x0505_u16.to_le_bytes()); // c.addi a0, 1
+        text.extend(0x8082_u16.to_le_bytes()); // c.jr ra
+        let base = 0x0040_0000;
+
+        let reachable = trace_reachable(&text, base, base);
+        let offsets: Vec<_> = reachable.iter().map(|addr| addr - base).collect();
+        assert_eq!(offsets, [0, 2, 6, 8]);
+    }
+}
@@ -26,3 +26,6 @@ pub mod cpu;
 pub mod decode;
 pub mod execute;
 pub mod fetch;
+pub mod listing;
+pub mod trap;
+pub mod verify;
@@ -0,0 +1,249 @@
+/*
+MIT License
+
+Copyright (c) 2024 Anthony Rubick
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+//! Typed errors for the exceptional conditions a RISC-V hart can encounter
+use std::fmt;
+
+use super::cpu::registers::RegisterMapping;
+
+/// An exceptional condition caused by the instruction being executed.
+///
+/// These are returned as errors from `Cpu32Bit::step`, and can be told apart from other errors by downcasting.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Trap {
+    /// A jump or taken branch targeted an address that isn't aligned to an instruction boundary,
+    /// 4 bytes, or 2 with the C extension.
+    InstructionAddressMisaligned { target: u32 },
+    /// A halfword or word was loaded from an address that isn't aligned to its size.
+    LoadAddressMisaligned { addr: u32 },
+    /// A halfword or word was stored to an address that isn't aligned to its size.
+    StoreAddressMisaligned { addr: u32 },
+    /// A load (or a store, if `store`) addressed the guard range at the bottom of the address space.
+    NullPointerDereference { addr: u32, store: bool },
+    /// A load (or a store, if `store`) relative to `gp` addressed memory outside of the static data,
+    /// meaning `gp` or the data doesn't match the layout the program was linked (and relaxed) with.
+    GpRelativeAccessOutsideStaticData { addr: u32, store: bool },
+    /// A store overwrote a saved return address with an address outside of the text section.
+    ReturnAddressCorrupted { slot: u32, value: u32 },
+    /// The function at `function` returned without restoring the callee-saved `register`.
+    CalleeSavedRegisterClobbered {
+        function: u32,
+        register: RegisterMapping,
+    },
+    /// The program stored `value` to the `tohost` exit device, see `MemoryBus::set_tohost`.
+    ///
+    /// riscv-tests store 1 on success, and `(test number << 1) | 1` on failure.
+    Halt { value: u32 },
+    /// Execution ran past the last instruction in the text section (to `pc`) without the program exiting,
+    /// e.g. because `main` returned without the startup code calling exit.
+    RanOffEndOfCode { pc: u32 },
+    /// A `div`, `divu`, `rem`, or `remu` divided by zero, with `DivByZeroPolicy::Trap`.
+    DivisionByZero,
+}
+
+impl Trap {
+    /// whether the trap is a `Halt` reporting success
+    #[must_use]
+    pub const fn passed(&self) -> bool {
+        matches!(self, Self::Halt { value: 1 })
+    }
+
+    /// The exception code the privileged spec assigns to the trap, i.e. the value written to `mcause`.
+    ///
+    /// Null pointer dereferences are access faults, and return address corruption and clobbered callee-saved
+    /// registers are reported like watchpoints (debug triggers raise breakpoint exceptions).
+    /// Returns `None` for `Halt`, as halting the machine isn't an exception,
+    /// and for `DivisionByZero`, as dividing by zero isn't one in RISC-V either.
+    #[must_use]
+    pub const fn mcause(&self) -> Option<u32> {
+        match self {
+            Self::InstructionAddressMisaligned { .. } => {
+                Some(mcause::INSTRUCTION_ADDRESS_MISALIGNED)
+            }
+            Self::RanOffEndOfCode { .. } => Some(mcause::INSTRUCTION_ACCESS_FAULT),
+            Self::ReturnAddressCorrupted { .. } | Self::CalleeSavedRegisterClobbered { .. } => {
+                Some(mcause::BREAKPOINT)
+            }
+            Self::LoadAddressMisaligned { .. } => Some(mcause::LOAD_ADDRESS_MISALIGNED),
+            Self::NullPointerDereference { store: false, .. }
+            | Self::GpRelativeAccessOutsideStaticData { store: false, .. } => {
+                Some(mcause::LOAD_ACCESS_FAULT)
+            }
+            Self::StoreAddressMisaligned { .. } => Some(mcause::STORE_ADDRESS_MISALIGNED),
+            Self::NullPointerDereference { store: true, .. }
+            | Self::GpRelativeAccessOutsideStaticData { store: true, .. } => {
+                Some(mcause::STORE_ACCESS_FAULT)
+            }
+            Self::Halt { .. } | Self::DivisionByZero => None,
+        }
+    }
+
+    /// The value the privileged spec writes to `mtval` along with `mcause`, the faulting address, or 0 if there isn't one.
+    #[must_use]
+    pub const fn mtval(&self) -> u32 {
+        match self {
+            Self::InstructionAddressMisaligned { target: addr }
+            | Self::LoadAddressMisaligned { addr }
+            | Self::StoreAddressMisaligned { addr }
+            | Self::NullPointerDereference { addr, .. }
+            | Self::GpRelativeAccessOutsideStaticData { addr, .. }
+            | Self::RanOffEndOfCode { pc: addr } => *addr,
+            Self::ReturnAddressCorrupted { .. }
+            | Self::CalleeSavedRegisterClobbered { .. }
+            | Self::Halt { .. }
+            | Self::DivisionByZero => 0,
+        }
+    }
+}
+
+/// The exception codes written to `mcause` (with the interrupt bit clear), from the privileged spec.
+pub mod mcause {
+    pub const INSTRUCTION_ADDRESS_MISALIGNED: u32 = 0;
+    pub const INSTRUCTION_ACCESS_FAULT: u32 = 1;
+    pub const ILLEGAL_INSTRUCTION: u32 = 2;
+    pub const BREAKPOINT: u32 = 3;
+    pub const LOAD_ADDRESS_MISALIGNED: u32 = 4;
+    pub const LOAD_ACCESS_FAULT: u32 = 5;
+    pub const STORE_ADDRESS_MISALIGNED: u32 = 6;
+    pub const STORE_ACCESS_FAULT: u32 = 7;
+    pub const ECALL_FROM_U_MODE: u32 = 8;
+    pub const ECALL_FROM_S_MODE: u32 = 9;
+    pub const ECALL_FROM_M_MODE: u32 = 11;
+    pub const INSTRUCTION_PAGE_FAULT: u32 = 12;
+    pub const LOAD_PAGE_FAULT: u32 = 13;
+    pub const STORE_PAGE_FAULT: u32 = 15;
+}
+
+impl fmt::Display for Trap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InstructionAddressMisaligned { target } => {
+                write!(f, "Instruction address misaligned: {target:#010x}")
+            }
+            Self::LoadAddressMisaligned { addr } => {
+                write!(f, "Load address misaligned: {addr:#010x}")
+            }
+            Self::StoreAddressMisaligned { addr } => {
+                write!(f, "Store address misaligned: {addr:#010x}")
+            }
+            Self::NullPointerDereference { addr, .. } => {
+                write!(f, "Null pointer dereference: {addr:#010x}")
+            }
+            Self::GpRelativeAccessOutsideStaticData { addr, store } => write!(
+                f,
+                "gp-relative {} outside of the static data: {addr:#010x}, the data layout doesn't match the one the program was linked with",
+                if *store { "store" } else { "load" }
+            ),
+            Self::ReturnAddressCorrupted { slot, value } => write!(
+                f,
+                "Return address corruption detected: the ra saved at {slot:#010x} was overwritten with {value:#010x}"
+            ),
+            Self::CalleeSavedRegisterClobbered { function, register } => write!(
+                f,
+                "Function at {function:#010x} clobbered callee-saved register {register:#}"
+            ),
+            Self::Halt { value: 1 } => write!(f, "Halted by tohost: passed"),
+            Self::Halt { value } => write!(
+                f,
+                "Halted by tohost: failed with code {value:#x} (test {})",
+                value >> 1
+            ),
+            Self::RanOffEndOfCode { pc } => write!(
+                f,
+                "Program ran off the end of the code at {pc:#010x} without calling exit"
+            ),
+            Self::DivisionByZero => write!(f, "Division by zero"),
+        }
+    }
+}
+
+impl std::error::Error for Trap {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mcause() {
+        let addr = 0x0000_0010;
+        let causes = [
+            (Trap::InstructionAddressMisaligned { target: addr }, Some(0)),
+            (Trap::RanOffEndOfCode { pc: addr }, Some(1)),
+            (
+                Trap::ReturnAddressCorrupted {
+                    slot: addr,
+                    value: 0,
+                },
+                Some(3),
+            ),
+            (
+                Trap::CalleeSavedRegisterClobbered {
+                    function: addr,
+                    register: RegisterMapping::S0,
+                },
+                Some(3),
+            ),
+            (Trap::LoadAddressMisaligned { addr }, Some(4)),
+            (Trap::NullPointerDereference { addr, store: false }, Some(5)),
+            (Trap::StoreAddressMisaligned { addr }, Some(6)),
+            (Trap::NullPointerDereference { addr, store: true }, Some(7)),
+            (
+                Trap::GpRelativeAccessOutsideStaticData { addr, store: false },
+                Some(5),
+            ),
+            (
+                Trap::GpRelativeAccessOutsideStaticData { addr, store: true },
+                Some(7),
+            ),
+            (Trap::Halt { value: 1 }, None),
+            (Trap::DivisionByZero, None),
+        ];
+        for (trap, cause) in causes {
+            assert_eq!(trap.mcause(), cause, "{trap}");
+        }
+    }
+
+    #[test]
+    fn test_mtval() {
+        let addr = 0x0000_0010;
+        let values = [
+            (Trap::InstructionAddressMisaligned { target: addr }, addr),
+            (Trap::RanOffEndOfCode { pc: addr }, addr),
+            (Trap::LoadAddressMisaligned { addr }, addr),
+            (Trap::NullPointerDereference { addr, store: true }, addr),
+            (
+                Trap::ReturnAddressCorrupted {
+                    slot: addr,
+                    value: 0,
+                },
+                0,
+            ),
+            (Trap::Halt { value: 3 }, 0),
+            (Trap::DivisionByZero, 0),
+        ];
+        for (trap, value) in values {
+            assert_eq!(trap.mtval(), value, "{trap}");
+        }
+    }
+}
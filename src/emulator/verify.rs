@@ -0,0 +1,240 @@
+/*
+MIT License
+
+Copyright (c) 2024 Anthony Rubick
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+//! Static checks of a program's text section, run without executing it
+use std::{collections::BTreeSet, fmt};
+
+use crate::instruction_set_definition::{isa::Isa, Rv32imInstruction};
+
+use super::{
+    decode::Decode32BitInstruction as _,
+    fetch::{instructions, instructions_end},
+};
+
+/// A problem found in the text section.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Problem {
+    /// The encoding doesn't decode to an instruction, with the decoder's reason
+    InvalidInstruction(String),
+    /// The instruction isn't part of the selected ISA, with the reason
+    OutsideIsa(String),
+    /// A branch or jump targets an address outside of the text section
+    TargetOutOfRange { target: u32 },
+    /// A branch or jump targets an address in the text section that isn't the start of an instruction
+    TargetMidInstruction { target: u32 },
+    /// The text section ends partway through an instruction
+    Truncated,
+}
+
+/// A problem found in the text section, along with the address of the offending instruction.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Diagnostic {
+    pub addr: u32,
+    pub problem: Problem,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#010x}: ", self.addr)?;
+        match &self.problem {
+            Problem::InvalidInstruction(reason) => write!(f, "invalid instruction: {reason}"),
+            Problem::OutsideIsa(reason) => write!(f, "{reason}"),
+            Problem::TargetOutOfRange { target } => {
+                write!(
+                    f,
+                    "branch target {target:#010x} is outside of the text section"
+                )
+            }
+            Problem::TargetMidInstruction { target } => {
+                write!(
+                    f,
+                    "branch target {target:#010x} is in the middle of an instruction"
+                )
+            }
+            Problem::Truncated => write!(f, "text section ends partway through an instruction"),
+        }
+    }
+}
+
+/// Statically check the text section of a program loaded at `base`, without executing it.
+///
+/// Every instruction is decoded, walking the text by the width each encoding declares (compressed instructions
+/// are 2 bytes), and all the problems found are reported at once: encodings that aren't valid instructions,
+/// instructions outside of `isa` (if given), and branches or jumps whose target isn't the start of
+/// an instruction in the text section. `jalr` targets depend on registers, so they aren't checked.
+#[must_use]
+pub fn verify(text: &[u8], base: u32, isa: Option<&Isa>) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+
+    let starts: BTreeSet<u32> = instructions(text, base).map(|(addr, ..)| addr).collect();
+    #[allow(clippy::cast_possible_truncation)] // the text section is less than 4GB
+    let end = base.wrapping_add(instructions_end(text) as u32);
+    for (addr, machine_code, width) in instructions(text, base) {
+        let instruction = match Rv32imInstruction::from_machine_code_with_width(machine_code, width)
+        {
+            Ok(instruction) => instruction,
+            Err(e) => {
+                // the decoder's errors are multi-line, the first line is the reason
+                let reason = e.to_string().lines().next().unwrap_or_default().to_string();
+                diagnostics.push(Diagnostic {
+                    addr,
+                    problem: Problem::InvalidInstruction(format!(
+                        "{reason} ({machine_code:#0width$x})",
+                        width = 2 + 2 * width.bytes() as usize
+                    )),
+                });
+                continue;
+            }
+        };
+        if let Some(Err(e)) = isa.map(|isa| isa.check(&instruction, width, addr)) {
+            diagnostics.push(Diagnostic {
+                addr,
+                problem: Problem::OutsideIsa(e.to_string()),
+            });
+        }
+        if let Some(target) = instruction.branch_target(addr) {
+            let problem = if target < base || target >= end {
+                Some(Problem::TargetOutOfRange { target })
+            } else if !starts.contains(&target) {
+                Some(Problem::TargetMidInstruction { target })
+            } else {
+                None
+            };
+            diagnostics.extend(problem.map(|problem| Diagnostic { addr, problem }));
+        }
+    }
+    #[allow(clippy::cast_possible_truncation)] // the text section is less than 4GB
+    if end != base.wrapping_add(text.len() as u32) {
+        diagnostics.push(Diagnostic {
+            addr: end,
+            problem: Problem::Truncated,
+        });
+    }
+    diagnostics
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+
+    use super::*;
+    use crate::utils::words_to_bytes;
+
+    const BASE: u32 = 0x0040_0000;
+
+    #[test]
+    fn test_valid_program() {
+        let text = words_to_bytes(&[
+            0x0015_0513, // addi a0, a0, 1
+            0xffdf_f06f, // jal x0, -4
+        ]);
+        assert_eq!(verify(&text, BASE, None), vec![]);
+    }
+
+    #[test]
+    fn test_jal_out_of_range() {
+        let text = words_to_bytes(&[
+            0x0015_0513, // addi a0, a0, 1
+            0x1000_006f, // jal x0, 256
+        ]);
+        assert_eq!(
+            verify(&text, BASE, None),
+            vec![Diagnostic {
+                addr: BASE + 4,
+                problem: Problem::TargetOutOfRange {
+                    target: BASE + 4 + 256
+                },
+            }]
+        );
+    }
+
+    #[test]
+    fn test_reports_every_problem() -> Result<()> {
+        let text = words_to_bytes(&[
+            0xffff_ffff, // not an instruction
+            0x02b5_0533, // mul a0, a0, a1
+            0x0005_0863, // beqz a0, 16
+        ]);
+        let diagnostics = verify(&text, BASE, Some(&"rv32i".parse()?));
+        let problems: Vec<_> = diagnostics.iter().map(|d| (d.addr, &d.problem)).collect();
+        assert_eq!(problems.len(), 3, "{diagnostics:?}");
+        assert!(matches!(
+            problems[0],
+            (BASE, Problem::InvalidInstruction(_))
+        ));
+        assert_eq!(
+            problems[1],
+            (
+                BASE + 4,
+                &Problem::OutsideIsa(String::from(
+                    "`mul a0, a0, a1` at 0x00400004 needs the M extension, which isn't part of rv32i"
+                ))
+            )
+        );
+        assert_eq!(
+            problems[2],
+            (BASE + 8, &Problem::TargetOutOfRange { target: BASE + 24 })
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_compressed_instructions() -> Result<()> {
+        let mut text = 0x4501_u16.to_le_bytes().to_vec(); // c.li a0, 0
+        text.extend(0x0005_0363_u32.to_le_bytes()); // beqz a0, 6 (into the next instruction)
+        text.extend(0x0015_0513_u32.to_le_bytes()); // addi a0, a0, 1
+        text.extend(0x0513_u16.to_le_bytes()); // the first half of another addi
+        let diagnostics = verify(&text, BASE, None);
+        assert_eq!(
+            diagnostics,
+            vec![
+                Diagnostic {
+                    addr: BASE + 2,
+                    problem: Problem::TargetMidInstruction { target: BASE + 8 },
+                },
+                Diagnostic {
+                    addr: BASE + 10,
+                    problem: Problem::Truncated,
+                },
+            ]
+        );
+
+        // without the C extension, compressed instructions are outside of the ISA
+        let diagnostics = verify(&text[..10], BASE, Some(&"rv32i".parse()?));
+        assert!(matches!(
+            diagnostics[..],
+            [
+                Diagnostic {
+                    addr: BASE,
+                    problem: Problem::OutsideIsa(_),
+                },
+                Diagnostic {
+                    problem: Problem::TargetMidInstruction { .. },
+                    ..
+                }
+            ]
+        ));
+        Ok(())
+    }
+}
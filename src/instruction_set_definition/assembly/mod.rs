@@ -0,0 +1,366 @@
+/*
+MIT License
+
+Copyright (c) 2024 Anthony Rubick
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+//! A minimal assembler for single instructions, the inverse of `disassembly`, see `program` for whole programs
+use anyhow::{bail, Context as _, Result};
+
+use crate::emulator::cpu::registers::RegisterMapping;
+
+pub mod program;
+
+/// the register-register instructions, as (mnemonic, funct3, funct7)
+const RTYPE: &[(&str, u32, u32)] = &[
+    ("add", 0b000, 0b000_0000),
+    ("sub", 0b000, 0b010_0000),
+    ("sll", 0b001, 0b000_0000),
+    ("slt", 0b010, 0b000_0000),
+    ("sltu", 0b011, 0b000_0000),
+    ("xor", 0b100, 0b000_0000),
+    ("srl", 0b101, 0b000_0000),
+    ("sra", 0b101, 0b010_0000),
+    ("or", 0b110, 0b000_0000),
+    ("and", 0b111, 0b000_0000),
+    ("mul", 0b000, 0b000_0001),
+    ("mulh", 0b001, 0b000_0001),
+    ("mulhsu", 0b010, 0b000_0001),
+    ("mulhu", 0b011, 0b000_0001),
+    ("div", 0b100, 0b000_0001),
+    ("divu", 0b101, 0b000_0001),
+    ("rem", 0b110, 0b000_0001),
+    ("remu", 0b111, 0b000_0001),
+];
+
+/// the register-immediate arithmetic instructions, as (mnemonic, funct3)
+const ITYPE: &[(&str, u32)] = &[
+    ("addi", 0b000),
+    ("slti", 0b010),
+    ("sltiu", 0b011),
+    ("xori", 0b100),
+    ("ori", 0b110),
+    ("andi", 0b111),
+];
+
+/// the shifts by an immediate, as (mnemonic, funct3, funct7)
+const SHIFTS: &[(&str, u32, u32)] = &[
+    ("slli", 0b001, 0b000_0000),
+    ("srli", 0b101, 0b000_0000),
+    ("srai", 0b101, 0b010_0000),
+];
+
+/// the loads, as (mnemonic, funct3)
+const LOADS: &[(&str, u32)] = &[
+    ("lb", 0b000),
+    ("lh", 0b001),
+    ("lw", 0b010),
+    ("lbu", 0b100),
+    ("lhu", 0b101),
+];
+
+/// the stores, as (mnemonic, funct3)
+const STORES: &[(&str, u32)] = &[("sb", 0b000), ("sh", 0b001), ("sw", 0b010)];
+
+/// the branches, as (mnemonic, funct3)
+const BRANCHES: &[(&str, u32)] = &[
+    ("beq", 0b000),
+    ("bne", 0b001),
+    ("blt", 0b100),
+    ("bge", 0b101),
+    ("bltu", 0b110),
+    ("bgeu", 0b111),
+];
+
+/// the system instructions without operands, as (mnemonic, funct12)
+const SYSTEM: &[(&str, u32)] = &[("ecall", 0), ("ebreak", 1), ("mret", 0b0011_0000_0010)];
+
+/// Assemble a single RV32IM instruction, e.g. `add a0, a1, a2`, into its machine code.
+///
+/// Registers can be given by ABI name or number, immediates in decimal or hex (with a `0x` prefix),
+/// loads and stores take their address as `offset(base)`, and branch and jump targets are offsets from the instruction.
+/// Pseudo-instructions aren't supported.
+///
+/// # Errors
+///
+/// This function will return an error if the instruction isn't supported, has the wrong number of operands,
+/// or an operand is invalid or out of range.
+pub fn assemble(instruction: &str) -> Result<u32> {
+    let instruction = instruction.trim();
+    let (mnemonic, operands) = instruction
+        .split_once(char::is_whitespace)
+        .unwrap_or((instruction, ""));
+    let operands: Vec<&str> = operands
+        .split(',')
+        .map(str::trim)
+        .filter(|operand| !operand.is_empty())
+        .collect();
+    assemble_operands(mnemonic, &operands)
+        .with_context(|| format!("Cannot assemble `{instruction}`"))
+}
+
+fn assemble_operands(mnemonic: &str, operands: &[&str]) -> Result<u32> {
+    let find = |table: &[(&str, u32)]| {
+        table
+            .iter()
+            .find(|(name, _)| *name == mnemonic)
+            .map(|&(_, funct3)| funct3)
+    };
+    if let Some(&(_, funct3, funct7)) = RTYPE.iter().find(|(name, ..)| *name == mnemonic) {
+        let [rd, rs1, rs2] = registers(operands)?;
+        return Ok(funct7 << 25 | rs2 << 20 | rs1 << 15 | funct3 << 12 | rd << 7 | 0b011_0011);
+    }
+    if let Some(funct12) = find(SYSTEM) {
+        let [] = exactly(operands)?;
+        return Ok(funct12 << 20 | 0b111_0011);
+    }
+    if let Some(funct3) = find(ITYPE) {
+        let [rd, rs1, imm] = exactly(operands)?;
+        return Ok(itype(
+            0b001_0011,
+            funct3,
+            register(rd)?,
+            register(rs1)?,
+            signed(imm, 12)?,
+        ));
+    }
+    if let Some(&(_, funct3, funct7)) = SHIFTS.iter().find(|(name, ..)| *name == mnemonic) {
+        let [rd, rs1, shamt] = exactly(operands)?;
+        let shamt = immediate(shamt)?;
+        if !(0..32).contains(&shamt) {
+            bail!("Shift amount {shamt} out of range for RV32 (must be 0-31)");
+        }
+        #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)] // checked above
+        let imm = funct7 << 5 | shamt as u32;
+        return Ok(itype(
+            0b001_0011,
+            funct3,
+            register(rd)?,
+            register(rs1)?,
+            imm,
+        ));
+    }
+    if let Some(funct3) = find(LOADS) {
+        let [rd, address] = exactly(operands)?;
+        let (offset, base) = address_operand(address)?;
+        return Ok(itype(0b000_0011, funct3, register(rd)?, base, offset));
+    }
+    if let Some(funct3) = find(STORES) {
+        let [rs2, address] = exactly(operands)?;
+        let (offset, base) = address_operand(address)?;
+        return Ok(stype(0b010_0011, funct3, base, register(rs2)?, offset));
+    }
+    if let Some(funct3) = find(BRANCHES) {
+        let [rs1, rs2, offset] = exactly(operands)?;
+        let offset = even(signed(offset, 13)?)?;
+        let imm = (offset >> 12 & 1) << 11
+            | (offset >> 5 & 0x3f) << 5
+            | (offset >> 1 & 0xf) << 1
+            | (offset >> 11 & 1);
+        return Ok(stype(
+            0b110_0011,
+            funct3,
+            register(rs1)?,
+            register(rs2)?,
+            imm,
+        ));
+    }
+    match mnemonic {
+        "jal" => {
+            let [rd, offset] = exactly(operands)?;
+            let offset = even(signed(offset, 21)?)?;
+            let imm = (offset >> 20 & 1) << 19
+                | (offset >> 1 & 0x3ff) << 9
+                | (offset >> 11 & 1) << 8
+                | (offset >> 12 & 0xff);
+            Ok(imm << 12 | register(rd)? << 7 | 0b110_1111)
+        }
+        "jalr" => {
+            let [rd, address] = exactly(operands)?;
+            let (offset, base) = address_operand(address)?;
+            Ok(itype(0b110_0111, 0b000, register(rd)?, base, offset))
+        }
+        "lui" | "auipc" => {
+            let [rd, imm] = exactly(operands)?;
+            let imm = immediate(imm)?;
+            if !(0..1 << 20).contains(&imm) {
+                bail!("Immediate {imm} out of range (must be 0-0xfffff)");
+            }
+            let opcode = if mnemonic == "lui" {
+                0b011_0111
+            } else {
+                0b001_0111
+            };
+            #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)] // checked above
+            Ok((imm as u32) << 12 | register(rd)? << 7 | opcode)
+        }
+        _ => bail!("Unknown or unsupported instruction: {mnemonic}"),
+    }
+}
+
+/// encode an I-type instruction, `imm` is the 12-bit immediate
+const fn itype(opcode: u32, funct3: u32, rd: u32, rs1: u32, imm: u32) -> u32 {
+    (imm & 0xfff) << 20 | rs1 << 15 | funct3 << 12 | rd << 7 | opcode
+}
+
+/// encode an S-type (or SB-type) instruction, `imm` is the 12-bit immediate as laid out in the instruction
+const fn stype(opcode: u32, funct3: u32, rs1: u32, rs2: u32, imm: u32) -> u32 {
+    (imm >> 5 & 0x7f) << 25 | rs2 << 20 | rs1 << 15 | funct3 << 12 | (imm & 0x1f) << 7 | opcode
+}
+
+/// get the operands, checking there are exactly `N`
+fn exactly<'a, const N: usize>(operands: &[&'a str]) -> Result<[&'a str; N]> {
+    operands
+        .try_into()
+        .map_err(|_| anyhow::anyhow!("Expected {N} operands, found {}", operands.len()))
+}
+
+/// get the register numbers of the operands, checking there are exactly `N`
+fn registers<const N: usize>(operands: &[&str]) -> Result<[u32; N]> {
+    let operands: [&str; N] = exactly(operands)?;
+    let mut registers = [0; N];
+    for (register_number, operand) in registers.iter_mut().zip(operands) {
+        *register_number = register(operand)?;
+    }
+    Ok(registers)
+}
+
+fn register(operand: &str) -> Result<u32> {
+    Ok(u32::from(operand.parse::<RegisterMapping>()? as u8))
+}
+
+/// parse an immediate in decimal, or hex with a `0x` prefix
+fn immediate(operand: &str) -> Result<i64> {
+    let (negative, magnitude) = operand
+        .strip_prefix('-')
+        .map_or((false, operand), |magnitude| (true, magnitude));
+    let magnitude = magnitude
+        .strip_prefix("0x")
+        .map_or_else(|| magnitude.parse(), |hex| i64::from_str_radix(hex, 16))
+        .with_context(|| format!("Invalid immediate: {operand}"))?;
+    Ok(if negative { -magnitude } else { magnitude })
+}
+
+/// parse a signed immediate that must fit in `bits` bits, returning its two's complement encoding
+fn signed(operand: &str, bits: u32) -> Result<u32> {
+    let imm = immediate(operand)?;
+    let limit = 1_i64 << (bits - 1);
+    if !(-limit..limit).contains(&imm) {
+        bail!(
+            "Immediate {imm} out of range (must be {}-{})",
+            -limit,
+            limit - 1
+        );
+    }
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)] // checked above
+    Ok(imm as u32)
+}
+
+/// check a branch or jump offset is a multiple of 2
+fn even(offset: u32) -> Result<u32> {
+    if offset & 1 != 0 {
+        bail!("Branch and jump offsets must be a multiple of 2");
+    }
+    Ok(offset)
+}
+
+/// parse an address operand, `offset(base)`, as (offset, base register number)
+fn address_operand(operand: &str) -> Result<(u32, u32)> {
+    let Some((offset, base)) = operand
+        .strip_suffix(')')
+        .and_then(|operand| operand.split_once('('))
+    else {
+        bail!("Expected an address like `offset(register)`, found {operand}");
+    };
+    let offset = if offset.trim().is_empty() {
+        "0"
+    } else {
+        offset.trim()
+    };
+    Ok((signed(offset, 12)?, register(base.trim())?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        emulator::decode::Decode32BitInstruction as _,
+        instruction_set_definition::Rv32imInstruction,
+    };
+
+    #[test]
+    fn test_assemble() -> Result<()> {
+        let cases = [
+            ("add a0, a1, a2", 0x00c5_8533),
+            ("srai a0, a0, 1", 0x4015_5513),
+            ("addi sp, sp, -16", 0xff01_0113),
+            ("lw a0, -2048(gp)", 0x8001_a503),
+            ("sw ra, -4(sp)", 0xfe11_2e23),
+            ("bne t0, zero, -4", 0xfe02_9ee3),
+            ("jal ra, 8", 0x0080_00ef),
+            ("jalr zero, 0(ra)", 0x0000_8067),
+            ("lui a0, 0x11", 0x0001_1537),
+            ("ecall", 0x0000_0073),
+            ("mret", 0x3020_0073),
+            ("mul x10, x11, x12", 0x02c5_8533),
+        ];
+        for (instruction, machine_code) in cases {
+            assert_eq!(
+                assemble(instruction)?,
+                machine_code,
+                "{instruction}: {:#010x}",
+                assemble(instruction)?
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_assemble_round_trips_through_disassembly() -> Result<()> {
+        for instruction in [
+            "sub a0, a1, a2",
+            "xori t0, t1, -1",
+            "slli s1, s2, 31",
+            "lbu a0, 3(a1)",
+            "sh a0, 6(sp)",
+            "blt a0, a1, 0x00400010",
+            "sltiu a0, a1, -2048",
+            "mret",
+        ] {
+            let pc = 0x0040_0000;
+            // branch targets are disassembled as addresses, but assembled from offsets
+            let source = instruction.replace("0x00400010", "16");
+            let decoded = Rv32imInstruction::from_machine_code(assemble(&source)?)?;
+            assert_eq!(decoded.disassemble_with(pc, false), instruction);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_assemble_errors() {
+        assert!(assemble("addi a0, a0, 2048").is_err());
+        assert!(assemble("slli a0, a0, 32").is_err());
+        assert!(assemble("add a0, a1").is_err());
+        assert!(assemble("add a0, a1, b2").is_err());
+        assert!(assemble("beq a0, a1, 3").is_err());
+        assert!(assemble("li a0, 1").is_err());
+    }
+}
@@ -0,0 +1,662 @@
+/*
+MIT License
+
+Copyright (c) 2024 Anthony Rubick
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+//! Assembling whole programs, with labels, a data section, and common pseudo-instructions
+use std::collections::HashMap;
+
+use anyhow::{bail, Context as _, Result};
+
+use crate::utils::words_to_bytes;
+
+use super::{assemble, immediate, BRANCHES};
+
+/// The address programs are assembled at by default, the same as GCC's default for RV32.
+pub const DEFAULT_ENTRYPOINT: u32 = 0x0001_0000;
+
+/// the gap between the end of the text section and the data section, the same as `MemoryBus::new` leaves
+const DATA_GAP: u32 = 0x1000;
+
+/// the page size ELF segments are aligned to
+const PAGE_SIZE: u32 = 0x1000;
+
+/// An assembled program, ready to be loaded or written out.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Program {
+    /// The machine code of the text section, which starts at the entrypoint.
+    pub text: Vec<u8>,
+    /// The contents of the data section.
+    pub data: Vec<u8>,
+    /// The address of the first instruction, where the text section is loaded.
+    pub entrypoint: u32,
+    /// The address the data section is loaded at.
+    pub data_address: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Section {
+    Text,
+    Data,
+}
+
+/// an instruction of the source, before its labels are resolved
+struct SourceInstruction<'a> {
+    line: usize,
+    statement: &'a str,
+    /// the offset of the instruction from the start of the text section
+    offset: u32,
+    mnemonic: &'a str,
+    operands: Vec<&'a str>,
+}
+
+/// Assemble a program, with its text section starting at `entrypoint`.
+///
+/// The source has one instruction, directive, or label per line (a label can also prefix an instruction or directive),
+/// and `#` starts a comment. Execution starts at the first instruction.
+/// On top of what `assemble` supports, branch and jump targets can be labels, and the following are supported:
+/// - the directives `.text`, `.data`, `.globl`, `.word`, `.half`, `.byte`, `.ascii`, `.asciz`, `.string`, `.space`, and `.align`,
+///   data directives can only be used in the data section, where `.word` can also be given labels, storing their addresses.
+/// - the pseudo-instructions `nop`, `mv`, `not`, `neg`, `li`, `la`, `j`, `jal` with only a target, `call`, `ret`, `jr`, `beqz`, and `bnez`.
+///
+/// # Errors
+///
+/// This function will return an error, naming the line, if the source contains anything unsupported or invalid,
+/// or refers to a label that isn't defined.
+pub fn assemble_program(source: &str, entrypoint: u32) -> Result<Program> {
+    let mut section = Section::Text;
+    let mut labels: HashMap<&str, (Section, u32)> = HashMap::new();
+    let mut instructions = Vec::new();
+    let mut text_size = 0;
+    let mut data = Vec::new();
+    // the words of data that are the address of a label, and the lines they're on
+    let mut label_words = Vec::new();
+    let mut label_lines = Vec::new();
+
+    for (line, statement) in (1..).zip(source.lines().map(strip_comment)) {
+        let mut statement = statement.trim();
+        while let Some((label, rest)) = split_label(statement) {
+            #[allow(clippy::cast_possible_truncation)] // the data section is less than 4GB
+            let offset = match section {
+                Section::Text => text_size,
+                Section::Data => data.len() as u32,
+            };
+            if labels.insert(label, (section, offset)).is_some() {
+                bail!("On line {line}: The label `{label}` is defined more than once");
+            }
+            statement = rest.trim_start();
+        }
+        if statement.is_empty() {
+            continue;
+        }
+        let (mnemonic, operands) = statement
+            .split_once(char::is_whitespace)
+            .map_or((statement, ""), |(mnemonic, operands)| {
+                (mnemonic, operands.trim())
+            });
+        if let Some(directive) = mnemonic.strip_prefix('.') {
+            match directive {
+                "text" => section = Section::Text,
+                "data" => section = Section::Data,
+                // every label is visible, so there's nothing to do
+                "globl" | "global" => {}
+                _ if section == Section::Data => {
+                    // the data section starts past the text, which is at least as long as it's been so far
+                    let data_start =
+                        u64::from(entrypoint) + u64::from(text_size) + u64::from(DATA_GAP);
+                    let room = (1_u64 << 32).saturating_sub(data_start);
+                    data_directive(directive, operands, &mut data, &mut label_words, room)
+                        .with_context(|| format!("On line {line}: Invalid `{statement}`"))?;
+                    label_lines.resize(label_words.len(), line);
+                }
+                _ => bail!("On line {line}: `.{directive}` is not supported in the text section"),
+            }
+            continue;
+        }
+        if section == Section::Data {
+            bail!(
+                "On line {line}: Instructions can only be in the text section, found `{statement}`"
+            );
+        }
+        let operands: Vec<&str> = operands
+            .split(',')
+            .map(str::trim)
+            .filter(|operand| !operand.is_empty())
+            .collect();
+        // labels aren't known yet, but they don't change how many instructions a pseudo-instruction expands to
+        let size = expand(mnemonic, &operands, &|_| Ok(0))
+            .with_context(|| format!("On line {line}: Cannot assemble `{statement}`"))?
+            .len();
+        instructions.push(SourceInstruction {
+            line,
+            statement,
+            offset: text_size,
+            mnemonic,
+            operands,
+        });
+        #[allow(clippy::cast_possible_truncation)]
+        // a pseudo-instruction expands to at most 2 instructions
+        let size = size as u32 * 4;
+        text_size = text_size
+            .checked_add(size)
+            .with_context(|| format!("On line {line}: The text section is too large"))?;
+    }
+
+    let data_address = entrypoint
+        .checked_add(text_size)
+        .and_then(|text_end| text_end.checked_add(DATA_GAP))
+        .filter(|&data_address| u64::from(data_address) + data.len() as u64 <= 1 << 32)
+        .context("The program doesn't fit in the address space")?;
+    let address = |label: &str| {
+        labels
+            .get(label)
+            .map(|&(section, offset)| match section {
+                Section::Text => entrypoint + offset,
+                Section::Data => data_address + offset,
+            })
+            .ok_or_else(|| anyhow::anyhow!("Undefined label `{label}`"))
+    };
+    for ((offset, label), line) in label_words.into_iter().zip(label_lines) {
+        let address = address(label).with_context(|| format!("On line {line}: Invalid `.word`"))?;
+        data[offset..offset + 4].copy_from_slice(&address.to_le_bytes());
+    }
+    let mut text = Vec::new();
+    for instruction in instructions {
+        let words = instruction.encode(entrypoint + instruction.offset, &address)?;
+        text.extend(words_to_bytes(&words));
+    }
+
+    Ok(Program {
+        text,
+        data,
+        entrypoint,
+        data_address,
+    })
+}
+
+impl SourceInstruction<'_> {
+    /// Assemble the instruction at `pc`, looking up the addresses of labels with `address`.
+    fn encode(&self, pc: u32, address: &dyn Fn(&str) -> Result<u32>) -> Result<Vec<u32>> {
+        let resolve = |target: &str| -> Result<i64> {
+            immediate(target).or_else(|_| Ok(i64::from(address(target)?) - i64::from(pc)))
+        };
+        expand(self.mnemonic, &self.operands, &resolve)
+            .and_then(|expanded| expanded.iter().map(|source| assemble(source)).collect())
+            .with_context(|| {
+                format!(
+                    "On line {}: Cannot assemble `{}`",
+                    self.line, self.statement
+                )
+            })
+    }
+}
+
+/// Expand an instruction or pseudo-instruction into the instructions `assemble` understands.
+///
+/// `resolve` turns a branch or jump target, a label or an offset, into an offset from the instruction.
+fn expand(
+    mnemonic: &str,
+    operands: &[&str],
+    resolve: &dyn Fn(&str) -> Result<i64>,
+) -> Result<Vec<String>> {
+    let is_branch = BRANCHES.iter().any(|(name, _)| *name == mnemonic);
+    Ok(match (mnemonic, operands) {
+        ("nop", []) => vec![String::from("addi zero, zero, 0")],
+        ("mv", [rd, rs]) => vec![format!("addi {rd}, {rs}, 0")],
+        ("not", [rd, rs]) => vec![format!("xori {rd}, {rs}, -1")],
+        ("neg", [rd, rs]) => vec![format!("sub {rd}, zero, {rs}")],
+        ("li", [rd, imm]) => {
+            let value = immediate(imm)?;
+            if !(i64::from(i32::MIN)..=i64::from(u32::MAX)).contains(&value) {
+                bail!("Immediate {value} doesn't fit in 32 bits");
+            }
+            if (-2048..2048).contains(&value) {
+                vec![format!("addi {rd}, zero, {value}")]
+            } else {
+                #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)] // checked above
+                let (upper, lower) = split_immediate(value as u32);
+                if lower == 0 {
+                    vec![format!("lui {rd}, {upper:#x}")]
+                } else {
+                    vec![
+                        format!("lui {rd}, {upper:#x}"),
+                        format!("addi {rd}, {rd}, {lower}"),
+                    ]
+                }
+            }
+        }
+        ("la", [rd, label]) => {
+            #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+            // the address space is 32 bits
+            let (upper, lower) = split_immediate(resolve(label)? as u32);
+            vec![
+                format!("auipc {rd}, {upper:#x}"),
+                format!("addi {rd}, {rd}, {lower}"),
+            ]
+        }
+        ("j", [target]) => vec![format!("jal zero, {}", resolve(target)?)],
+        ("jal" | "call", [target]) => vec![format!("jal ra, {}", resolve(target)?)],
+        ("jal", [rd, target]) => vec![format!("jal {rd}, {}", resolve(target)?)],
+        ("ret", []) => vec![String::from("jalr zero, 0(ra)")],
+        ("jr", [rs]) => vec![format!("jalr zero, 0({rs})")],
+        ("beqz", [rs, target]) => vec![format!("beq {rs}, zero, {}", resolve(target)?)],
+        ("bnez", [rs, target]) => vec![format!("bne {rs}, zero, {}", resolve(target)?)],
+        (_, [rs1, rs2, target]) if is_branch => {
+            vec![format!("{mnemonic} {rs1}, {rs2}, {}", resolve(target)?)]
+        }
+        _ => vec![format!("{mnemonic} {}", operands.join(", "))],
+    })
+}
+
+/// Split a 32-bit value into the 20-bit upper immediate of a `lui` or `auipc`, and the signed 12-bit immediate added to it.
+const fn split_immediate(value: u32) -> (u32, i32) {
+    // the lower 12 bits are sign extended, so round the upper part up when they're negative
+    let upper = value.wrapping_add(0x800) >> 12;
+    #[allow(clippy::cast_possible_wrap)]
+    let lower = value.wrapping_sub(upper << 12) as i32;
+    (upper, lower)
+}
+
+/// Append the contents of a data directive to `data`.
+///
+/// Words that are the address of a label are left as 0, and added to `label_words`, as (offset, label), to be filled in later.
+fn data_directive<'a>(
+    directive: &str,
+    operands: &'a str,
+    data: &mut Vec<u8>,
+    label_words: &mut Vec<(usize, &'a str)>,
+    room: u64,
+) -> Result<()> {
+    // the size the data section would have after padding it, which must fit in the `room` left in the address space
+    let padded_size = |size: Option<usize>| {
+        size.filter(|&size| size as u64 <= room)
+            .context("The data section doesn't fit in the address space")
+    };
+    let values = || {
+        operands
+            .split(',')
+            .map(|value| immediate(value.trim()))
+            .collect::<Result<Vec<_>>>()
+    };
+    match directive {
+        "word" => {
+            for value in operands.split(',').map(str::trim) {
+                match immediate(value) {
+                    // values are truncated to the size of the directive, like GNU as
+                    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+                    Ok(value) => data.extend((value as u32).to_le_bytes()),
+                    Err(_) if is_label(value) => {
+                        label_words.push((data.len(), value));
+                        data.extend([0; 4]);
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+        #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+        "half" => data.extend(
+            values()?
+                .iter()
+                .flat_map(|&value| (value as u16).to_le_bytes()),
+        ),
+        #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+        "byte" => data.extend(values()?.iter().map(|&value| value as u8)),
+        "ascii" => data.extend(string_literal(operands)?),
+        "asciz" | "string" => {
+            data.extend(string_literal(operands)?);
+            data.push(0);
+        }
+        "space" | "zero" => {
+            let size =
+                usize::try_from(immediate(operands)?).context("The size must not be negative")?;
+            data.resize(padded_size(data.len().checked_add(size))?, 0);
+        }
+        "align" => {
+            let alignment = u32::try_from(immediate(operands)?)
+                .ok()
+                .and_then(|power| 1_usize.checked_shl(power))
+                .context("Invalid alignment")?;
+            data.resize(
+                padded_size(data.len().checked_next_multiple_of(alignment))?,
+                0,
+            );
+        }
+        _ => bail!("Unsupported directive `.{directive}`"),
+    }
+    Ok(())
+}
+
+/// Parse a double quoted string literal, with C-style escapes.
+fn string_literal(literal: &str) -> Result<Vec<u8>> {
+    let Some(contents) = literal
+        .strip_prefix('"')
+        .and_then(|literal| literal.strip_suffix('"'))
+    else {
+        bail!("Expected a double quoted string, found {literal}");
+    };
+    let mut bytes = Vec::new();
+    let mut chars = contents.chars();
+    while let Some(c) = chars.next() {
+        let c = if c == '\\' {
+            match chars.next() {
+                Some('n') => '\n',
+                Some('t') => '\t',
+                Some('r') => '\r',
+                Some('0') => '\0',
+                Some(c @ ('\\' | '"' | '\'')) => c,
+                Some(c) => bail!("Unknown escape sequence `\\{c}`"),
+                None => bail!("Unterminated escape sequence"),
+            }
+        } else {
+            c
+        };
+        let mut buffer = [0; 4];
+        bytes.extend_from_slice(c.encode_utf8(&mut buffer).as_bytes());
+    }
+    Ok(bytes)
+}
+
+/// Remove the comment, if any, from a line, ignoring `#`s in string literals.
+fn strip_comment(line: &str) -> &str {
+    let mut in_string = false;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_string => escaped = true,
+            '"' => in_string = !in_string,
+            '#' if !in_string => return &line[..i],
+            _ => {}
+        }
+    }
+    line
+}
+
+/// Split a leading `label:` off of a statement.
+fn split_label(statement: &str) -> Option<(&str, &str)> {
+    let (label, rest) = statement.split_once(':')?;
+    is_label(label).then_some((label, rest))
+}
+
+/// Check whether `name` is a valid label.
+fn is_label(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with(|c: char| c.is_ascii_digit())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '$'))
+}
+
+impl Program {
+    /// Get the program as a flat binary, the memory image from the entrypoint to the end of the data section.
+    #[must_use]
+    pub fn to_flat_binary(&self) -> Vec<u8> {
+        let mut binary = self.text.clone();
+        binary.resize((self.data_address - self.entrypoint) as usize, 0);
+        binary.extend_from_slice(&self.data);
+        binary
+    }
+
+    /// Get the program as a minimal, statically linked, RV32 ELF executable.
+    ///
+    /// The executable has a loadable segment for each of the text and data sections, so it can be run by other tools too.
+    #[must_use]
+    #[allow(clippy::cast_possible_truncation)] // the sections are less than 4GB
+    pub fn to_elf(&self) -> Vec<u8> {
+        const EHDR_SIZE: u32 = 52;
+        const PHDR_SIZE: u32 = 32;
+        const SHDR_SIZE: u32 = 40;
+        let shstrtab = b"\0.text\0.data\0.shstrtab\0";
+
+        // segments must be at the same offset into a page in the file as in memory
+        let page_offset =
+            |after: u32, address: u32| after + (address.wrapping_sub(after) % PAGE_SIZE);
+        let text_offset = page_offset(EHDR_SIZE + 2 * PHDR_SIZE, self.entrypoint);
+        let data_offset = page_offset(text_offset + self.text.len() as u32, self.data_address);
+        let shstrtab_offset = data_offset + self.data.len() as u32;
+        // section headers must be 4-byte aligned
+        let shdr_offset = (shstrtab_offset + shstrtab.len() as u32).next_multiple_of(4);
+
+        let mut elf = Vec::new();
+        let push = |fields: &[u32], elf: &mut Vec<u8>| {
+            for field in fields {
+                elf.extend_from_slice(&field.to_le_bytes());
+            }
+        };
+        // ELF header
+        elf.extend_from_slice(&[0x7f, b'E', b'L', b'F', 1, 1, 1, 0]);
+        elf.extend_from_slice(&[0; 8]);
+        elf.extend_from_slice(&2u16.to_le_bytes()); // e_type: executable
+        elf.extend_from_slice(&0xf3u16.to_le_bytes()); // e_machine: RISC-V
+
+        // e_version, e_entry, e_phoff, e_shoff, e_flags
+        push(&[1, self.entrypoint, EHDR_SIZE, shdr_offset, 0], &mut elf);
+        // e_ehsize, e_phentsize, e_phnum, e_shentsize, e_shnum, e_shstrndx
+        for half in [EHDR_SIZE, PHDR_SIZE, 2, SHDR_SIZE, 4, 3] {
+            elf.extend_from_slice(&(half as u16).to_le_bytes());
+        }
+
+        // program headers: PT_LOAD the text (R+X), and the data (R+W)
+        let text_size = self.text.len() as u32;
+        let data_size = self.data.len() as u32;
+        push(
+            &[
+                1,
+                text_offset,
+                self.entrypoint,
+                self.entrypoint,
+                text_size,
+                text_size,
+                0b101,
+                PAGE_SIZE,
+            ],
+            &mut elf,
+        );
+        push(
+            &[
+                1,
+                data_offset,
+                self.data_address,
+                self.data_address,
+                data_size,
+                data_size,
+                0b110,
+                PAGE_SIZE,
+            ],
+            &mut elf,
+        );
+
+        // section contents
+        elf.resize(text_offset as usize, 0);
+        elf.extend_from_slice(&self.text);
+        elf.resize(data_offset as usize, 0);
+        elf.extend_from_slice(&self.data);
+        elf.extend_from_slice(shstrtab);
+        elf.resize(shdr_offset as usize, 0);
+
+        // section headers: null, .text, .data, .shstrtab
+        for (name, kind, flags, address, offset, size) in [
+            (0, 0, 0, 0, 0, 0),
+            (1, 1, 0b110, self.entrypoint, text_offset, text_size),
+            (7, 1, 0b011, self.data_address, data_offset, data_size),
+            (13, 3, 0, 0, shstrtab_offset, shstrtab.len() as u32),
+        ] {
+            push(
+                &[name, kind, flags, address, offset, size, 0, 0, 1, 0],
+                &mut elf,
+            );
+        }
+        elf
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use elf::{endian::AnyEndian, ElfBytes};
+
+    use super::*;
+    use crate::{emulator::cpu::memory::MemoryBus, run_program};
+
+    const COUNTDOWN: &str = r#"
+        .data
+        separator: .asciz ", "   # printed between numbers
+        .text
+        _start:
+            li s0, 3
+        loop:
+            mv a0, s0
+            li a7, 1
+            ecall
+            addi s0, s0, -1
+            beqz s0, done
+            la a0, separator
+            li a7, 4
+            ecall
+            j loop
+        done: li a7, 10
+            ecall
+    "#;
+
+    #[test]
+    fn test_assemble_program() -> Result<()> {
+        let program = assemble_program(COUNTDOWN, DEFAULT_ENTRYPOINT)?;
+        assert_eq!(program.data, b", \0");
+        assert_eq!(program.text.len(), 13 * 4);
+        assert_eq!(program.data_address, DEFAULT_ENTRYPOINT + 13 * 4 + DATA_GAP);
+
+        let output = run_program(&program.text, &program.data, program.entrypoint, "")?;
+        assert_eq!(output, "3, 2, 1");
+        Ok(())
+    }
+
+    #[test]
+    fn test_pseudo_instructions() -> Result<()> {
+        let program = assemble_program(
+            "li a0, 0x12345678\nli a1, -1\nli a2, 0x1000\nret",
+            DEFAULT_ENTRYPOINT,
+        )?;
+        let words: Vec<u32> = program
+            .text
+            .chunks(4)
+            .map(|word| u32::from_le_bytes(word.try_into().unwrap()))
+            .collect();
+        assert_eq!(
+            words,
+            [
+                assemble("lui a0, 0x12345")?,
+                assemble("addi a0, a0, 0x678")?,
+                assemble("addi a1, zero, -1")?,
+                assemble("lui a2, 0x1")?,
+                assemble("jalr zero, 0(ra)")?,
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_data_directives() -> Result<()> {
+        let program = assemble_program(
+            ".data\n.byte 1, 2\n.align 2\n.word 0x11223344\n.half -1\n.ascii \"a#\\\"\"\n.space 2\n",
+            DEFAULT_ENTRYPOINT,
+        )?;
+        assert_eq!(
+            program.data,
+            [1, 2, 0, 0, 0x44, 0x33, 0x22, 0x11, 0xff, 0xff, b'a', b'#', b'"', 0, 0]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_print_labelled_string() -> Result<()> {
+        let program = assemble_program(
+            r#"
+            .data
+            count: .byte 3
+            .align 2
+            greeting: .asciz "hi\n"
+            pointer: .word greeting
+            .text
+                la t0, pointer
+                lw a0, 0(t0)    # load the address of the string through the pointer
+                li a7, 4
+                ecall
+                li a7, 10
+                ecall
+            "#,
+            DEFAULT_ENTRYPOINT,
+        )?;
+        let greeting = program.data_address + 4;
+        let mut expected = vec![3, 0, 0, 0, b'h', b'i', b'\n', 0];
+        expected.extend(greeting.to_le_bytes());
+        assert_eq!(program.data, expected);
+
+        let output = run_program(&program.text, &program.data, program.entrypoint, "")?;
+        assert_eq!(output, "hi\n");
+        Ok(())
+    }
+
+    #[test]
+    fn test_assemble_program_errors() {
+        let error = |source| {
+            format!(
+                "{:#}",
+                assemble_program(source, DEFAULT_ENTRYPOINT).unwrap_err()
+            )
+        };
+        assert!(error("nop\nj nowhere").starts_with("On line 2: "));
+        assert!(error("a: nop\na: nop").contains("defined more than once"));
+        assert!(error(".data\nnop").contains("only be in the text section"));
+        assert!(error(".word 1").contains("not supported in the text section"));
+        assert!(error("frobnicate a0").starts_with("On line 1: "));
+        assert!(error(".data\n.word 1, missing").starts_with("On line 2: "));
+        assert!(error(".data\n.word 1x").starts_with("On line 2: "));
+        // data that can't fit in the address space is rejected instead of allocated
+        assert!(error(".data\n.space 0x7fffffffffff").contains("doesn't fit in the address space"));
+        assert!(error(".data\n.byte 1\n.align 40").contains("doesn't fit in the address space"));
+        assert!(error(".data\n.byte 1\n.align 63").contains("doesn't fit in the address space"));
+        assert!(format!(
+            "{:#}",
+            assemble_program(".data\n.word 1", 0xFFFF_F000).unwrap_err()
+        )
+        .contains("doesn't fit in the address space"));
+    }
+
+    #[test]
+    fn test_elf_loads() -> Result<()> {
+        let program = assemble_program(COUNTDOWN, DEFAULT_ENTRYPOINT)?;
+        let elf = program.to_elf();
+        let (memory, entrypoint, _) =
+            MemoryBus::from_elf(&ElfBytes::<AnyEndian>::minimal_parse(&elf)?)?;
+        assert_eq!(entrypoint, DEFAULT_ENTRYPOINT);
+        assert_eq!(
+            memory.read_bytes(entrypoint, memory.code_size())?,
+            program.text
+        );
+        assert_eq!(memory.dram_start(), program.data_address);
+        assert_eq!(memory.read_bytes(program.data_address, 3)?, program.data);
+
+        let flat = program.to_flat_binary();
+        assert_eq!(&flat[..program.text.len()], program.text);
+        assert_eq!(&flat[flat.len() - 3..], program.data);
+        Ok(())
+    }
+}
@@ -0,0 +1,410 @@
+/*
+MIT License
+
+Copyright (c) 2024 Anthony Rubick
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+//! Rendering of instructions as assembly, in the style of objdump
+use std::collections::BTreeMap;
+
+use crate::emulator::cpu::{csr, registers::RegisterMapping};
+
+use super::{
+    operations::{ITypeOperation, RTypeOperation, SBTypeOperation},
+    Rv32imInstruction,
+};
+
+impl Rv32imInstruction {
+    /// Render the instruction as assembly, as if it were located at `pc`.
+    ///
+    /// Unlike the `Display` impl, pc-relative targets are resolved to absolute addresses,
+    /// registers are shown by ABI name (e.g. `a0` rather than `x10`),
+    /// and pseudo-instructions are used where objdump would use them
+    /// (e.g. `beqz` for `beq rs1, zero, offset`, `snez` for `sltu rd, zero, rs2`, or `neg` for `sub rd, zero, rs2`).
+    #[must_use]
+    pub fn disassemble(&self, pc: u32) -> String {
+        self.disassemble_with(pc, true)
+    }
+
+    /// Render the instruction as assembly like `disassemble`, annotating the target of a jump or branch
+    /// with the name of the symbol there, if `symbols` (names by address) has one, e.g. `jal ra, 0x00400100 <printf>`.
+    #[must_use]
+    pub fn disassemble_with_symbols(&self, pc: u32, symbols: &BTreeMap<u32, String>) -> String {
+        let disassembly = self.disassemble(pc);
+        match self
+            .branch_target(pc)
+            .and_then(|target| symbols.get(&target))
+        {
+            Some(symbol) => format!("{disassembly} <{symbol}>"),
+            None => disassembly,
+        }
+    }
+
+    /// Render the instruction as assembly like `disassemble`, but only using pseudo-instructions if `pseudo_instructions`,
+    /// otherwise the raw form of every instruction is shown (e.g. `xori a0, a1, -1` rather than `not a0, a1`).
+    #[must_use]
+    pub fn disassemble_with(&self, pc: u32, pseudo_instructions: bool) -> String {
+        match *self {
+            Self::RType {
+                operation,
+                rd,
+                rs1,
+                rs2,
+                ..
+            } => pseudo_instructions
+                .then(|| pseudo_rtype(operation, rs1, rs2))
+                .flatten()
+                .map_or_else(
+                    || format!("{operation} {rd:#}, {rs1:#}, {rs2:#}"),
+                    |(pseudo, rs)| format!("{pseudo} {rd:#}, {rs:#}"),
+                ),
+            Self::IType {
+                operation,
+                rd,
+                rs1,
+                imm,
+                ..
+            } => match operation {
+                ITypeOperation::Lb
+                | ITypeOperation::Lh
+                | ITypeOperation::Lw
+                | ITypeOperation::Lbu
+                | ITypeOperation::Lhu
+                | ITypeOperation::Jalr => format!("{operation} {rd:#}, {imm}({rs1:#})"),
+                // `fence` alone orders everything, i.e. `fence iorw, iorw`
+                ITypeOperation::Fence if imm & 0xff != 0xff => {
+                    format!("{operation} {}, {}", fence_set(imm >> 4), fence_set(imm))
+                }
+                ITypeOperation::Fence
+                | ITypeOperation::FenceI
+                | ITypeOperation::FenceTso
+                | ITypeOperation::Pause
+                | ITypeOperation::Ecall
+                | ITypeOperation::Ebreak
+                | ITypeOperation::Mret => operation.to_string(),
+                ITypeOperation::Rdcycle
+                | ITypeOperation::Rdcycleh
+                | ITypeOperation::Rdtime
+                | ITypeOperation::Rdtimeh
+                | ITypeOperation::Rdinstret
+                | ITypeOperation::Rdinstreth => format!("{operation} {rd:#}"),
+                ITypeOperation::Csrrw
+                | ITypeOperation::Csrrs
+                | ITypeOperation::Csrrc
+                | ITypeOperation::Csrrwi
+                | ITypeOperation::Csrrsi
+                | ITypeOperation::Csrrci => {
+                    disassemble_csr(operation, rd, rs1, imm, pseudo_instructions)
+                }
+                // rs == 0, as rs < 1 (unsigned)
+                ITypeOperation::Sltiu if pseudo_instructions && imm == 1 => {
+                    format!("seqz {rd:#}, {rs1:#}")
+                }
+                // all the bits flipped
+                ITypeOperation::Xori if pseudo_instructions && imm == -1 => {
+                    format!("not {rd:#}, {rs1:#}")
+                }
+                _ => format!("{operation} {rd:#}, {rs1:#}, {imm}"),
+            },
+            Self::SType {
+                operation,
+                rs1,
+                rs2,
+                imm,
+                ..
+            } => format!("{operation} {rs2:#}, {imm}({rs1:#})"),
+            Self::SBType {
+                operation,
+                rs1,
+                rs2,
+                ..
+            } => {
+                let target = self.branch_target(pc).unwrap_or_default();
+                let pseudo = pseudo_instructions
+                    .then(|| pseudo_branch(operation, rs1, rs2))
+                    .flatten();
+                pseudo.map_or_else(
+                    || format!("{operation} {rs1:#}, {rs2:#}, {target:#010x}"),
+                    |(pseudo, rs)| format!("{pseudo} {rs:#}, {target:#010x}"),
+                )
+            }
+            Self::UJType { operation, rd, .. } => {
+                let target = self.branch_target(pc).unwrap_or_default();
+                format!("{operation} {rd:#}, {target:#010x}")
+            }
+            Self::UType { operation, rd, imm } => format!("{operation} {rd:#}, {imm:#x}"),
+            Self::Custom(instruction) => {
+                format!("{} {:#010x}", instruction.name, instruction.machine_code)
+            }
+        }
+    }
+}
+
+/// Render a CSR instruction, naming the CSR if it's implemented, and using `csrr`/`csrw` where objdump would.
+fn disassemble_csr(
+    operation: ITypeOperation,
+    rd: RegisterMapping,
+    rs1: RegisterMapping,
+    csr: i32,
+    pseudo_instructions: bool,
+) -> String {
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)] // CSR addresses are 12 bits
+    let csr = (csr & 0xFFF) as u16;
+    let name = csr::csr_name(csr).map_or_else(|| format!("{csr:#x}"), str::to_string);
+    // the immediate forms keep their immediate in the rs1 field
+    let source = match operation {
+        ITypeOperation::Csrrwi | ITypeOperation::Csrrsi | ITypeOperation::Csrrci => {
+            (rs1 as u8).to_string()
+        }
+        _ => format!("{rs1:#}"),
+    };
+    match operation {
+        ITypeOperation::Csrrs if pseudo_instructions && rs1 == RegisterMapping::Zero => {
+            format!("csrr {rd:#}, {name}")
+        }
+        ITypeOperation::Csrrw if pseudo_instructions && rd == RegisterMapping::Zero => {
+            format!("csrw {name}, {source}")
+        }
+        _ => format!("{operation} {rd:#}, {name}, {source}"),
+    }
+}
+
+/// Render the predecessor or successor set in the low 4 bits of a fence's immediate, e.g. `rw`.
+fn fence_set(bits: i32) -> String {
+    let set: String = [(0b1000, 'i'), (0b0100, 'o'), (0b0010, 'r'), (0b0001, 'w')]
+        .iter()
+        .filter(|(bit, _)| bits & bit != 0)
+        .map(|(_, c)| c)
+        .collect();
+    if set.is_empty() {
+        String::from("0")
+    } else {
+        set
+    }
+}
+
+/// Get the pseudo-instruction for a register-register operation with `x0` as an operand,
+/// i.e. a set-less-than that compares a register against `x0` or a negation,
+/// along with the other register.
+const fn pseudo_rtype(
+    operation: RTypeOperation,
+    rs1: RegisterMapping,
+    rs2: RegisterMapping,
+) -> Option<(&'static str, RegisterMapping)> {
+    let rs1_is_zero = matches!(rs1, RegisterMapping::Zero);
+    let rs2_is_zero = matches!(rs2, RegisterMapping::Zero);
+    match (operation, rs1_is_zero, rs2_is_zero) {
+        // 0 < rs (unsigned), i.e. rs != 0
+        (RTypeOperation::Sltu, true, false) => Some(("snez", rs2)),
+        // rs < 0
+        (RTypeOperation::Slt, false, true) => Some(("sltz", rs1)),
+        // 0 < rs
+        (RTypeOperation::Slt, true, false) => Some(("sgtz", rs2)),
+        // 0 - rs
+        (RTypeOperation::Sub, true, false) => Some(("neg", rs2)),
+        _ => None,
+    }
+}
+
+/// Get the pseudo-instruction for a branch that compares a register against `x0`,
+/// along with the register being compared.
+const fn pseudo_branch(
+    operation: SBTypeOperation,
+    rs1: RegisterMapping,
+    rs2: RegisterMapping,
+) -> Option<(&'static str, RegisterMapping)> {
+    let rs1_is_zero = matches!(rs1, RegisterMapping::Zero);
+    let rs2_is_zero = matches!(rs2, RegisterMapping::Zero);
+    match (operation, rs1_is_zero, rs2_is_zero) {
+        (SBTypeOperation::Beq, false, true) => Some(("beqz", rs1)),
+        (SBTypeOperation::Beq, true, false) => Some(("beqz", rs2)),
+        (SBTypeOperation::Bne, false, true) => Some(("bnez", rs1)),
+        (SBTypeOperation::Bne, true, false) => Some(("bnez", rs2)),
+        // rs >= 0
+        (SBTypeOperation::Bge, false, true) => Some(("bgez", rs1)),
+        // 0 >= rs
+        (SBTypeOperation::Bge, true, false) => Some(("blez", rs2)),
+        // rs < 0
+        (SBTypeOperation::Blt, false, true) => Some(("bltz", rs1)),
+        // 0 < rs
+        (SBTypeOperation::Blt, true, false) => Some(("bgtz", rs2)),
+        // unsigned comparisons, and comparisons of two (non-zero or both zero) registers, have no pseudo form
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use anyhow::Result;
+
+    use crate::emulator::decode::Decode32BitInstruction as _;
+
+    const PC: u32 = 0x0040_0010;
+
+    #[test]
+    fn test_registers_render_as_abi_names() -> Result<()> {
+        // addi a0, zero, 5
+        let instruction = Rv32imInstruction::from_machine_code(0x0050_0513)?;
+        assert_eq!(instruction.disassemble(PC), "addi a0, zero, 5");
+        Ok(())
+    }
+
+    #[test]
+    fn test_jump_to_symbol_is_annotated() -> Result<()> {
+        let symbols = BTreeMap::from([(0x0040_0100, String::from("printf"))]);
+        // jal ra, 0xf0 (to 0x00400100)
+        let instruction = Rv32imInstruction::from_machine_code(0x0f00_00ef)?;
+        assert_eq!(
+            instruction.disassemble_with_symbols(PC, &symbols),
+            "jal ra, 0x00400100 <printf>"
+        );
+        // bnez a0, 0xf0 (to 0x00400100)
+        let instruction = Rv32imInstruction::from_machine_code(0x0e05_1863)?;
+        assert_eq!(
+            instruction.disassemble_with_symbols(PC, &symbols),
+            "bnez a0, 0x00400100 <printf>"
+        );
+        // targets without a symbol aren't annotated
+        assert_eq!(
+            instruction.disassemble_with_symbols(PC + 4, &symbols),
+            "bnez a0, 0x00400104"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_fence_variants() -> Result<()> {
+        let disassemble = |machine_code| -> Result<String> {
+            Ok(Rv32imInstruction::from_machine_code(machine_code)?.disassemble(PC))
+        };
+        assert_eq!(disassemble(0x0ff0_000f)?, "fence");
+        assert_eq!(disassemble(0x0330_000f)?, "fence rw, rw");
+        assert_eq!(disassemble(0x8330_000f)?, "fence.tso");
+        assert_eq!(disassemble(0x0100_000f)?, "pause");
+        Ok(())
+    }
+
+    #[test]
+    fn test_csr_instructions() -> Result<()> {
+        let disassemble = |machine_code, pseudo_instructions| -> Result<String> {
+            Ok(Rv32imInstruction::from_machine_code(machine_code)?
+                .disassemble_with(PC, pseudo_instructions))
+        };
+        assert_eq!(disassemble(0x3405_9573, true)?, "csrrw a0, mscratch, a1");
+        assert_eq!(disassemble(0x3050_f673, true)?, "csrrci a2, mtvec, 1");
+        assert_eq!(disassemble(0x3000_2573, true)?, "csrr a0, mstatus");
+        assert_eq!(disassemble(0x3000_2573, false)?, "csrrs a0, mstatus, zero");
+        assert_eq!(disassemble(0xb025_9073, true)?, "csrw minstret, a1");
+        assert_eq!(disassemble(0x7c00_2573, true)?, "csrr a0, 0x7c0");
+        Ok(())
+    }
+
+    #[test]
+    fn test_beq_zero_renders_as_beqz() -> Result<()> {
+        // beq t0, zero, 8
+        let instruction = Rv32imInstruction::from_machine_code(0x0002_8463)?;
+        assert_eq!(instruction.disassemble(PC), "beqz t0, 0x00400018");
+        Ok(())
+    }
+
+    #[test]
+    fn test_bge_zero_rs_renders_as_blez() -> Result<()> {
+        // bge zero, t0, -8
+        let instruction = Rv32imInstruction::from_machine_code(0xfe50_5ce3)?;
+        assert_eq!(instruction.disassemble(PC), "blez t0, 0x00400008");
+        Ok(())
+    }
+
+    #[test]
+    fn test_sltu_zero_rs_renders_as_snez() -> Result<()> {
+        // sltu a0, zero, a1
+        let instruction = Rv32imInstruction::from_machine_code(0x00b0_3533)?;
+        assert_eq!(instruction.disassemble(PC), "snez a0, a1");
+        Ok(())
+    }
+
+    #[test]
+    fn test_slt_rs_zero_renders_as_sltz() -> Result<()> {
+        // slt a0, a1, zero
+        let instruction = Rv32imInstruction::from_machine_code(0x0005_a533)?;
+        assert_eq!(instruction.disassemble(PC), "sltz a0, a1");
+        // slt a0, zero, a1
+        let instruction = Rv32imInstruction::from_machine_code(0x00b0_2533)?;
+        assert_eq!(instruction.disassemble(PC), "sgtz a0, a1");
+        Ok(())
+    }
+
+    #[test]
+    fn test_sltiu_one_renders_as_seqz() -> Result<()> {
+        // sltiu a0, a1, 1
+        let instruction = Rv32imInstruction::from_machine_code(0x0015_b513)?;
+        assert_eq!(instruction.disassemble(PC), "seqz a0, a1");
+        Ok(())
+    }
+
+    #[test]
+    fn test_sub_from_zero_renders_as_neg() -> Result<()> {
+        // sub a0, zero, a1
+        let instruction = Rv32imInstruction::from_machine_code(0x40b0_0533)?;
+        assert_eq!(instruction.disassemble(PC), "neg a0, a1");
+        // sub a0, a1, zero
+        let instruction = Rv32imInstruction::from_machine_code(0x4005_8533)?;
+        assert_eq!(instruction.disassemble(PC), "sub a0, a1, zero");
+        Ok(())
+    }
+
+    #[test]
+    fn test_xori_minus_one_renders_as_not() -> Result<()> {
+        // xori a0, a1, -1
+        let instruction = Rv32imInstruction::from_machine_code(0xfff5_c513)?;
+        assert_eq!(instruction.disassemble(PC), "not a0, a1");
+        // xori a0, a1, 1
+        let instruction = Rv32imInstruction::from_machine_code(0x0015_c513)?;
+        assert_eq!(instruction.disassemble(PC), "xori a0, a1, 1");
+        Ok(())
+    }
+
+    #[test]
+    fn test_raw_disassembly_has_no_pseudo_instructions() -> Result<()> {
+        for (machine_code, raw) in [
+            (0x40b0_0533, "sub a0, zero, a1"),
+            (0xfff5_c513, "xori a0, a1, -1"),
+            (0x0015_b513, "sltiu a0, a1, 1"),
+            (0x00b0_3533, "sltu a0, zero, a1"),
+            (0x0002_8463, "beq t0, zero, 0x00400018"),
+        ] {
+            let instruction = Rv32imInstruction::from_machine_code(machine_code)?;
+            assert_eq!(instruction.disassemble_with(PC, false), raw);
+            assert_ne!(instruction.disassemble(PC), raw);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_branch_between_registers_is_not_a_pseudo_branch() -> Result<()> {
+        // bne a0, a1, 16
+        let instruction = Rv32imInstruction::from_machine_code(0x00b5_1863)?;
+        assert_eq!(instruction.disassemble(PC), "bne a0, a1, 0x00400020");
+        Ok(())
+    }
+}
@@ -0,0 +1,282 @@
+/*
+MIT License
+
+Copyright (c) 2024 Anthony Rubick
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+//! ISA strings like `rv32imac`, which select the extensions a program may use
+use std::{collections::BTreeSet, fmt, str::FromStr};
+
+use anyhow::{bail, Result};
+
+use crate::emulator::cpu::{registers::RegisterMapping, Size};
+
+use super::{
+    operations::{Extension, ITypeOperation},
+    Rv32imInstruction,
+};
+
+/// The extensions with multi-letter names, which follow the single-letter ones in an ISA string, separated by `_`.
+const MULTI_LETTER_EXTENSIONS: [(&str, Extension); 4] = [
+    ("zicsr", Extension::Zicsr),
+    ("zicntr", Extension::Zicntr),
+    ("zifencei", Extension::Zifencei),
+    ("zihintpause", Extension::Zihintpause),
+];
+
+/// An instruction set, as parsed from a march-style ISA string like `rv32imac` or `rv32i_zicsr`.
+///
+/// Only the instructions of the enabled extensions decode, see `check`.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Isa {
+    /// Whether the base is RV32E, which only has 16 registers, rather than RV32I
+    pub embedded: bool,
+    /// The enabled extensions, including `Extension::Base`
+    pub extensions: BTreeSet<Extension>,
+}
+
+impl Isa {
+    /// whether `extension` is enabled
+    #[must_use]
+    pub fn has(&self, extension: Extension) -> bool {
+        self.extensions.contains(&extension)
+    }
+
+    /// Check that `instruction`, decoded from `width` bytes at `pc`, is part of the ISA.
+    ///
+    /// Custom instructions aren't part of any extension, so they're always allowed.
+    ///
+    /// # Errors
+    ///
+    /// if the instruction comes from an extension that isn't enabled, is compressed without the C extension,
+    /// or uses registers above `x15` with the RV32E base.
+    pub fn check(&self, instruction: &Rv32imInstruction, width: Size, pc: u32) -> Result<()> {
+        let disassembly = || instruction.disassemble(pc);
+        if width == Size::Half && !self.has(Extension::C) {
+            bail!(
+                "`{}` at {pc:#010x} is a compressed instruction, but the C extension isn't part of {self}",
+                disassembly()
+            );
+        }
+        if let Some(extension) = instruction.extension().filter(|&e| !self.has(e)) {
+            bail!(
+                "`{}` at {pc:#010x} needs the {extension} extension, which isn't part of {self}",
+                disassembly()
+            );
+        }
+        if self.embedded {
+            if let Some(register) = registers(instruction)
+                .into_iter()
+                .find(|&register| register as u8 >= 16)
+            {
+                bail!(
+                    "`{}` at {pc:#010x} uses {register:#}, but {self} only has the registers x0-x15",
+                    disassembly()
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Isa {
+    type Err = anyhow::Error;
+
+    /// Parse an ISA string: `rv32`, the base (`i` or `e`), any single-letter extensions (`m`, `a`, `c`),
+    /// then any multi-letter extensions, each preceded by `_` (e.g. `_zicsr`). Case is ignored.
+    fn from_str(march: &str) -> Result<Self> {
+        let lower = march.to_ascii_lowercase();
+        let Some(rest) = lower.strip_prefix("rv32") else {
+            let xlen = lower
+                .strip_prefix("rv")
+                .map(|rest| {
+                    rest.split(|c: char| !c.is_ascii_digit())
+                        .next()
+                        .unwrap_or_default()
+                })
+                .filter(|xlen| !xlen.is_empty());
+            match xlen {
+                Some(xlen) => bail!(
+                    "`{march}` is a {xlen}-bit ISA, only 32-bit ISAs (starting with `rv32`) are supported"
+                ),
+                None => bail!("`{march}` isn't an ISA string, expected something like `rv32imac`"),
+            }
+        };
+        let (letters, multi_letter) = rest.split_once('_').unwrap_or((rest, ""));
+
+        let mut letters = letters.chars();
+        let embedded = match letters.next() {
+            Some('i') => false,
+            Some('e') => true,
+            Some('g') => {
+                bail!("`{march}` uses `g`, which includes the F and D extensions, and those aren't supported")
+            }
+            _ => bail!("`{march}` doesn't name a base ISA, `rv32` must be followed by `i` or `e`"),
+        };
+        let mut extensions = BTreeSet::from([Extension::Base]);
+        for letter in letters {
+            let extension = match letter {
+                'm' => Extension::M,
+                'a' => Extension::A,
+                'c' => Extension::C,
+                'i' | 'e' => bail!("`{march}` names the base ISA more than once"),
+                'f' | 'd' | 'q' | 'b' | 'v' | 'h' => bail!(
+                    "`{march}` includes the {} extension, which isn't supported",
+                    letter.to_ascii_uppercase()
+                ),
+                _ => bail!("`{march}` includes an unknown extension `{letter}`"),
+            };
+            if !extensions.insert(extension) {
+                bail!("`{march}` includes the {extension} extension more than once");
+            }
+        }
+        for name in multi_letter.split('_').filter(|name| !name.is_empty()) {
+            let Some(&(_, extension)) = MULTI_LETTER_EXTENSIONS.iter().find(|(n, _)| *n == name)
+            else {
+                bail!("`{march}` includes the extension `{name}`, which isn't supported");
+            };
+            if !extensions.insert(extension) {
+                bail!("`{march}` includes the {extension} extension more than once");
+            }
+        }
+        Ok(Self {
+            embedded,
+            extensions,
+        })
+    }
+}
+
+impl fmt::Display for Isa {
+    /// Render the ISA string in canonical form, e.g. `rv32imc_zicsr`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "rv32{}", if self.embedded { 'e' } else { 'i' })?;
+        for (letter, extension) in [
+            ('m', Extension::M),
+            ('a', Extension::A),
+            ('c', Extension::C),
+        ] {
+            if self.has(extension) {
+                write!(f, "{letter}")?;
+            }
+        }
+        for (name, extension) in MULTI_LETTER_EXTENSIONS {
+            if self.has(extension) {
+                write!(f, "_{name}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The registers `instruction` reads or writes.
+fn registers(instruction: &Rv32imInstruction) -> Vec<RegisterMapping> {
+    match *instruction {
+        Rv32imInstruction::RType { rd, rs1, rs2, .. } => vec![rd, rs1, rs2],
+        // the immediate CSR instructions keep their immediate in the rs1 field
+        Rv32imInstruction::IType {
+            operation: ITypeOperation::Csrrwi | ITypeOperation::Csrrsi | ITypeOperation::Csrrci,
+            rd,
+            ..
+        }
+        | Rv32imInstruction::UJType { rd, .. }
+        | Rv32imInstruction::UType { rd, .. } => vec![rd],
+        Rv32imInstruction::IType { rd, rs1, .. } => vec![rd, rs1],
+        Rv32imInstruction::SType { rs1, rs2, .. } | Rv32imInstruction::SBType { rs1, rs2, .. } => {
+            vec![rs1, rs2]
+        }
+        Rv32imInstruction::Custom(_) => Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::emulator::decode::Decode32BitInstruction as _;
+
+    #[test]
+    fn test_parse() -> Result<()> {
+        let isa: Isa = "rv32imac".parse()?;
+        assert!(!isa.embedded);
+        assert_eq!(
+            isa.extensions,
+            BTreeSet::from([Extension::Base, Extension::M, Extension::A, Extension::C])
+        );
+        assert_eq!(isa.to_string(), "rv32imac");
+
+        let isa: Isa = "RV32E_Zicsr_zifencei".parse()?;
+        assert!(isa.embedded);
+        assert_eq!(
+            isa.extensions,
+            BTreeSet::from([Extension::Base, Extension::Zicsr, Extension::Zifencei])
+        );
+        assert_eq!(isa.to_string(), "rv32e_zicsr_zifencei");
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_rejects_unsupported_isas() {
+        let error = |march: &str| {
+            march
+                .parse::<Isa>()
+                .expect_err("the ISA string should be rejected")
+                .to_string()
+        };
+        assert_eq!(
+            error("rv64gc"),
+            "`rv64gc` is a 64-bit ISA, only 32-bit ISAs (starting with `rv32`) are supported"
+        );
+        assert!(error("rv32gc").contains("F and D extensions"));
+        assert!(error("rv32imfd").contains("the F extension, which isn't supported"));
+        assert!(error("rv32mi").contains("doesn't name a base ISA"));
+        assert!(error("rv32imm").contains("the M extension more than once"));
+        assert!(error("rv32ix").contains("unknown extension `x`"));
+        assert!(error("rv32i_zba").contains("`zba`"));
+        assert!(error("x86").contains("isn't an ISA string"));
+    }
+
+    #[test]
+    fn test_check() -> Result<()> {
+        let rv32i: Isa = "rv32i".parse()?;
+        let rv32imc: Isa = "rv32imc".parse()?;
+        let embedded: Isa = "rv32em".parse()?;
+
+        // mul a0, a0, a1
+        let mul = Rv32imInstruction::from_machine_code(0x02b5_0533)?;
+        rv32imc.check(&mul, Size::Word, 0)?;
+        let error = rv32i.check(&mul, Size::Word, 0).unwrap_err();
+        assert!(error.to_string().contains("needs the M extension"));
+
+        // c.li a0, 1
+        let li = Rv32imInstruction::from_compressed_machine_code(0x4505)?;
+        rv32imc.check(&li, Size::Half, 0)?;
+        let error = rv32i.check(&li, Size::Half, 0).unwrap_err();
+        assert!(error.to_string().contains("is a compressed instruction"));
+
+        // RV32E only has x0-x15, and a6 is x16
+        embedded.check(&mul, Size::Word, 0)?;
+        // addi a6, zero, 1
+        let addi = Rv32imInstruction::from_machine_code(0x0010_0813)?;
+        let error = embedded.check(&addi, Size::Word, 0).unwrap_err();
+        assert!(error.to_string().contains("uses a6"));
+        Ok(())
+    }
+}
@@ -25,12 +25,15 @@ SOFTWARE.
 use derive_more::Display;
 
 use self::operations::{
-    ITypeOperation, RTypeOperation, SBTypeOperation, STypeOperation, UJTypeOperation,
+    Extension, ITypeOperation, RTypeOperation, SBTypeOperation, STypeOperation, UJTypeOperation,
     UTypeOperation,
 };
 #[allow(unused_imports)]
 use crate::emulator::cpu::registers::RegisterMapping;
 
+pub mod assembly;
+pub mod disassembly;
+pub mod isa;
 pub mod operations;
 
 #[derive(Debug, PartialEq, Eq, Copy, Clone, Display)]
@@ -98,4 +101,123 @@ pub enum Rv32imInstruction {
         rd: RegisterMapping,
         imm: u32,
     },
+    /// An instruction outside of RV32IM, see `CustomInstruction`.
+    #[display(fmt = "{_0}")]
+    Custom(CustomInstruction),
+}
+
+impl Rv32imInstruction {
+    /// Get the address a branch or `jal` at `pc` transfers control to, if taken.
+    ///
+    /// Returns `None` for other instructions, including `jalr` whose target depends on a register.
+    #[must_use]
+    pub const fn branch_target(&self, pc: u32) -> Option<u32> {
+        match *self {
+            Self::SBType { imm, .. } => Some(pc.wrapping_add_signed(imm)),
+            Self::UJType { imm, .. } => {
+                // the immediate is a 21-bit signed offset
+                #[allow(clippy::cast_possible_wrap)]
+                Some(pc.wrapping_add_signed(((imm as i32) << 11) >> 11))
+            }
+            _ => None,
+        }
+    }
+
+    /// Get the register the instruction writes its result to.
+    ///
+    /// Returns `None` for instructions without a destination, those that discard their result (`rd` is `zero`),
+    /// and custom instructions.
+    #[must_use]
+    pub fn destination(&self) -> Option<RegisterMapping> {
+        match *self {
+            Self::RType { rd, .. }
+            | Self::IType { rd, .. }
+            | Self::UJType { rd, .. }
+            | Self::UType { rd, .. } => Some(rd).filter(|&rd| rd != RegisterMapping::Zero),
+            Self::SType { .. } | Self::SBType { .. } | Self::Custom(_) => None,
+        }
+    }
+
+    /// get the extension the instruction comes from, or `None` for custom instructions
+    #[must_use]
+    pub const fn extension(&self) -> Option<Extension> {
+        Some(match self {
+            Self::RType { operation, .. } => operation.extension(),
+            Self::IType { operation, .. } => operation.extension(),
+            Self::SType { operation, .. } => operation.extension(),
+            Self::SBType { operation, .. } => operation.extension(),
+            Self::UJType { operation, .. } => operation.extension(),
+            Self::UType { operation, .. } => operation.extension(),
+            Self::Custom(_) => return None,
+        })
+    }
+
+    /// whether the instruction is part of the M (integer multiplication and division) extension
+    #[must_use]
+    pub const fn is_m_extension(&self) -> bool {
+        matches!(self.extension(), Some(Extension::M))
+    }
+}
+
+/// An experimental instruction outside of RV32IM, e.g. one in the reserved custom opcode space.
+///
+/// These are produced by the custom decoder of a `Cpu32Bit`, and executed by its custom executor.
+/// The operand accessors assume the standard R-type field layout, which custom instructions are free to ignore.
+#[derive(Debug, PartialEq, Eq, Copy, Clone, Display)]
+#[display(fmt = "{name:10} {machine_code:#010x}       # Custom:  operation, machine code")]
+pub struct CustomInstruction {
+    /// The mnemonic of the instruction, used when displaying it.
+    pub name: &'static str,
+    /// The raw machine code of the instruction.
+    pub machine_code: u32,
+}
+
+impl CustomInstruction {
+    #[must_use]
+    pub const fn new(name: &'static str, machine_code: u32) -> Self {
+        Self { name, machine_code }
+    }
+
+    /// get the opcode (bits 0-6)
+    #[must_use]
+    pub const fn opcode(&self) -> u32 {
+        self.machine_code & 0b111_1111
+    }
+
+    /// get the register in the rd field (bits 7-11)
+    #[must_use]
+    pub fn rd(&self) -> RegisterMapping {
+        self.register(7)
+    }
+
+    /// get the funct3 field (bits 12-14)
+    #[must_use]
+    pub const fn funct3(&self) -> u32 {
+        (self.machine_code >> 12) & 0b111
+    }
+
+    /// get the register in the rs1 field (bits 15-19)
+    #[must_use]
+    pub fn rs1(&self) -> RegisterMapping {
+        self.register(15)
+    }
+
+    /// get the register in the rs2 field (bits 20-24)
+    #[must_use]
+    pub fn rs2(&self) -> RegisterMapping {
+        self.register(20)
+    }
+
+    /// get the funct7 field (bits 25-31)
+    #[must_use]
+    pub const fn funct7(&self) -> u32 {
+        self.machine_code >> 25
+    }
+
+    /// get the register in the 5-bit field starting at bit `shift`
+    fn register(self, shift: u32) -> RegisterMapping {
+        #[allow(clippy::cast_possible_truncation)] // the field is masked to 5 bits
+        RegisterMapping::try_from(((self.machine_code >> shift) & 0b1_1111) as u8)
+            .expect("a 5-bit field is always a valid register")
+    }
 }
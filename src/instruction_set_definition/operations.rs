@@ -25,6 +25,35 @@ SOFTWARE.
 //! Definitions of the (supported) risc-v instructions    
 use derive_more::Display;
 
+/// The extension of the ISA an instruction comes from.
+#[derive(Debug, PartialEq, Eq, Copy, Clone, Hash, PartialOrd, Ord, Display)]
+pub enum Extension {
+    /// The base integer instruction set (RV32I)
+    #[display(fmt = "I")]
+    Base,
+    /// Integer multiplication and division
+    #[display(fmt = "M")]
+    M,
+    /// Atomic instructions, none of which are implemented yet
+    #[display(fmt = "A")]
+    A,
+    /// Compressed (16-bit) instructions, which expand to the instructions of the other extensions
+    #[display(fmt = "C")]
+    C,
+    /// Instruction-fetch fence (`fence.i`)
+    #[display(fmt = "Zifencei")]
+    Zifencei,
+    /// The base counters and timers (`rdcycle` and friends)
+    #[display(fmt = "Zicntr")]
+    Zicntr,
+    /// The `pause` hint
+    #[display(fmt = "Zihintpause")]
+    Zihintpause,
+    /// Control and status register instructions (`csrrw` and friends)
+    #[display(fmt = "Zicsr")]
+    Zicsr,
+}
+
 #[derive(Debug, PartialEq, Eq, Copy, Clone, Display)]
 pub enum RTypeOperation {
     #[display(fmt = "add")]
@@ -67,6 +96,24 @@ pub enum RTypeOperation {
     Remu,
 }
 
+impl RTypeOperation {
+    /// get the extension the operation comes from
+    #[must_use]
+    pub const fn extension(&self) -> Extension {
+        match self {
+            Self::Mul
+            | Self::Mulh
+            | Self::Mulhu
+            | Self::Mulhsu
+            | Self::Div
+            | Self::Divu
+            | Self::Rem
+            | Self::Remu => Extension::M,
+            _ => Extension::Base,
+        }
+    }
+}
+
 #[derive(Debug, PartialEq, Eq, Copy, Clone, Display)]
 pub enum ITypeOperation {
     #[display(fmt = "addi")]
@@ -104,10 +151,77 @@ pub enum ITypeOperation {
     Fence,
     #[display(fmt = "fence.i")]
     FenceI,
+    /// `fence rw, rw` with total store ordering semantics
+    #[display(fmt = "fence.tso")]
+    FenceTso,
+    /// a hint that the hart is in a spin-wait loop, encoded as `fence w, 0`
+    #[display(fmt = "pause")]
+    Pause,
     #[display(fmt = "ecall")]
     Ecall,
     #[display(fmt = "ebreak")]
     Ebreak,
+    /// return from a machine-mode trap handler, to the address in `mepc`
+    #[display(fmt = "mret")]
+    Mret,
+    // below are the read-only counters from the Zicntr extension,
+    // `csrrs rd, <counter>, x0` is decoded as one of these
+    #[display(fmt = "rdcycle")]
+    Rdcycle,
+    #[display(fmt = "rdcycleh")]
+    Rdcycleh,
+    #[display(fmt = "rdtime")]
+    Rdtime,
+    #[display(fmt = "rdtimeh")]
+    Rdtimeh,
+    #[display(fmt = "rdinstret")]
+    Rdinstret,
+    #[display(fmt = "rdinstreth")]
+    Rdinstreth,
+    // below are the CSR instructions from the Zicsr extension, the CSR's address is kept as the immediate,
+    // and the immediate forms keep their 5-bit (unsigned) source operand in `rs1`
+    #[display(fmt = "csrrw")]
+    Csrrw,
+    #[display(fmt = "csrrs")]
+    Csrrs,
+    #[display(fmt = "csrrc")]
+    Csrrc,
+    #[display(fmt = "csrrwi")]
+    Csrrwi,
+    #[display(fmt = "csrrsi")]
+    Csrrsi,
+    #[display(fmt = "csrrci")]
+    Csrrci,
+}
+
+impl ITypeOperation {
+    /// get the extension the operation comes from
+    #[must_use]
+    pub const fn extension(&self) -> Extension {
+        match self {
+            Self::FenceI => Extension::Zifencei,
+            Self::Pause => Extension::Zihintpause,
+            Self::Rdcycle
+            | Self::Rdcycleh
+            | Self::Rdtime
+            | Self::Rdtimeh
+            | Self::Rdinstret
+            | Self::Rdinstreth => Extension::Zicntr,
+            Self::Csrrw
+            | Self::Csrrs
+            | Self::Csrrc
+            | Self::Csrrwi
+            | Self::Csrrsi
+            | Self::Csrrci => Extension::Zicsr,
+            _ => Extension::Base,
+        }
+    }
+
+    /// whether the operation is one of the CSR instructions (`csrrw` and friends)
+    #[must_use]
+    pub const fn is_csr(&self) -> bool {
+        matches!(self.extension(), Extension::Zicsr)
+    }
 }
 
 #[derive(Debug, PartialEq, Eq, Copy, Clone, Display)]
@@ -121,6 +235,14 @@ pub enum STypeOperation {
     // below are not needed for this project, but included for completeness
 }
 
+impl STypeOperation {
+    /// get the extension the operation comes from, these are all in the base instruction set
+    #[must_use]
+    pub const fn extension(&self) -> Extension {
+        Extension::Base
+    }
+}
+
 #[derive(Debug, PartialEq, Eq, Copy, Clone, Display)]
 pub enum SBTypeOperation {
     #[display(fmt = "beq")]
@@ -138,12 +260,28 @@ pub enum SBTypeOperation {
     Bgeu,
 }
 
+impl SBTypeOperation {
+    /// get the extension the operation comes from, these are all in the base instruction set
+    #[must_use]
+    pub const fn extension(&self) -> Extension {
+        Extension::Base
+    }
+}
+
 #[derive(Debug, PartialEq, Eq, Copy, Clone, Display)]
 pub enum UJTypeOperation {
     #[display(fmt = "jal")]
     Jal,
 }
 
+impl UJTypeOperation {
+    /// get the extension the operation comes from, these are all in the base instruction set
+    #[must_use]
+    pub const fn extension(&self) -> Extension {
+        Extension::Base
+    }
+}
+
 #[derive(Debug, PartialEq, Eq, Copy, Clone, Display)]
 pub enum UTypeOperation {
     // below are not needed for this project, but included for completeness
@@ -152,3 +290,29 @@ pub enum UTypeOperation {
     #[display(fmt = "auipc")]
     Auipc,
 }
+
+impl UTypeOperation {
+    /// get the extension the operation comes from, these are all in the base instruction set
+    #[must_use]
+    pub const fn extension(&self) -> Extension {
+        Extension::Base
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_extension() {
+        assert_eq!(RTypeOperation::Add.extension(), Extension::Base);
+        assert_eq!(RTypeOperation::Sub.extension(), Extension::Base);
+        assert_eq!(RTypeOperation::Mul.extension(), Extension::M);
+        assert_eq!(RTypeOperation::Remu.extension(), Extension::M);
+        assert_eq!(ITypeOperation::Addi.extension(), Extension::Base);
+        assert_eq!(ITypeOperation::FenceI.extension(), Extension::Zifencei);
+        assert_eq!(ITypeOperation::Rdtime.extension(), Extension::Zicntr);
+        assert_eq!(ITypeOperation::Csrrci.extension(), Extension::Zicsr);
+        assert_eq!(SBTypeOperation::Bgeu.extension(), Extension::Base);
+    }
+}
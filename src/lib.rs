@@ -0,0 +1,118 @@
+/*
+MIT License
+
+Copyright (c) 2024 Anthony Rubick
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+//! A simple RISC-V emulator
+pub mod emulator;
+pub mod instruction_set_definition;
+pub mod utils;
+
+use std::{collections::BTreeMap, io::Cursor};
+
+use anyhow::{bail, Result};
+
+use emulator::cpu::{
+    io::{ProgramInput, ProgramOutput},
+    registers::RegisterMapping,
+    Cpu32Bit, RunOutcome, REGISTERS_COUNT,
+};
+use instruction_set_definition::assembly::assemble;
+
+/// The maximum number of instructions `run_program` will execute before giving up.
+pub const RUN_PROGRAM_STEP_LIMIT: u64 = 10_000_000;
+
+/// Run a program to completion, feeding it `input`, and return everything it printed.
+///
+/// The program is loaded the same way the command line loads an ELF file,
+/// with `text` at `entrypoint` and `data` just after it.
+///
+/// # Errors
+///
+/// This function will return an error if the program faults, stops without exiting (e.g. at an `ebreak`),
+/// or doesn't exit within `RUN_PROGRAM_STEP_LIMIT` instructions.
+pub fn run_program(text: &[u8], data: &[u8], entrypoint: u32, input: &str) -> Result<String> {
+    let mut cpu = Cpu32Bit::new(text, data, entrypoint, None)?;
+    cpu.input = ProgramInput::Reader(Box::new(Cursor::new(input.as_bytes().to_vec())));
+
+    let result = cpu.run_to_completion(Some(RUN_PROGRAM_STEP_LIMIT))?;
+    match result.outcome {
+        None => Ok(result.output),
+        Some(RunOutcome::StepLimitReached) => {
+            bail!("Program did not exit within {RUN_PROGRAM_STEP_LIMIT} instructions")
+        }
+        Some(RunOutcome::Interrupted) => bail!("Program was interrupted"),
+        Some(RunOutcome::Halted) => bail!("Program halted at {:#010x}", cpu.pc),
+        Some(RunOutcome::Breakpoint) => {
+            bail!("Program stopped at an ebreak, before {:#010x}", cpu.pc)
+        }
+        Some(RunOutcome::ConditionMet) => {
+            unreachable!("run_to_completion has no condition to meet")
+        }
+    }
+}
+
+/// Execute a single instruction on a fresh CPU, and return the registers afterwards, keyed by ABI name.
+///
+/// `registers` sets the named registers before the instruction runs, see `assembly::assemble` for the syntax of `instruction`.
+/// This is meant for documenting and experimenting with the semantics of instructions:
+///
+/// ```
+/// # use riscv_emulator::eval_instruction;
+/// let registers = eval_instruction("add a0, a1, a2", &[("a1", 3), ("a2", 4)])?;
+/// assert_eq!(registers["a0"], 7);
+///
+/// let registers = eval_instruction("slt a0, a1, a2", &[("a1", -1_i32 as u32), ("a2", 0)])?;
+/// assert_eq!(registers["a0"], 1);
+///
+/// let registers = eval_instruction("srai a0, a1, 4", &[("a1", 0x8000_0000)])?;
+/// assert_eq!(registers["a0"], 0xf800_0000);
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+///
+/// # Errors
+///
+/// This function will return an error if the instruction can't be assembled, a register name is unknown,
+/// or the instruction faults.
+pub fn eval_instruction(
+    instruction: &str,
+    registers: &[(&str, u32)],
+) -> Result<BTreeMap<String, u32>> {
+    let machine_code = assemble(instruction)?;
+    let mut cpu = Cpu32Bit::new(&machine_code.to_le_bytes(), &[], 0x0040_0000, None)?;
+    cpu.output_sink = ProgramOutput::Writer(Box::new(std::io::sink()));
+    for &(name, value) in registers {
+        cpu.registers.write(name.parse()?, value);
+    }
+
+    cpu.step()?;
+
+    Ok((0..REGISTERS_COUNT)
+        .filter_map(|number| RegisterMapping::try_from(number).ok())
+        .map(|register| {
+            (
+                register.abi_name().to_string(),
+                cpu.registers.read(register),
+            )
+        })
+        .collect())
+}
@@ -22,18 +22,48 @@ OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 SOFTWARE.
 */
 
-pub mod emulator;
-pub mod instruction_set_definition;
-pub mod utils;
-
 #[allow(unused_imports)]
-use std::{path::PathBuf, str::FromStr as _};
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{BufReader, BufWriter, Write as _},
+    path::{Path, PathBuf},
+    str::FromStr as _,
+    time::Duration,
+};
 
 use anyhow::{bail, Result};
-use clap::{command, Parser};
-use elf::{endian::AnyEndian, ElfBytes};
-use emulator::cpu::Cpu32Bit;
+use clap::Parser;
+use elf::{
+    abi::{STT_FUNC, STT_NOTYPE},
+    endian::AnyEndian,
+    ElfBytes,
+};
+use riscv_emulator::emulator::{
+    cpu::{
+        callee_saved::CalleeSavedChecker,
+        core_dump::CoreDump,
+        history::DEFAULT_UNDO_DEPTH,
+        io::{ProgramInput, ProgramOutput},
+        memory::{find_symbol, MemoryBus},
+        profile::HotProfile,
+        ra_guard::ReturnAddressGuard,
+        registers::RegisterMapping,
+        snapshot::RegisterSnapshot,
+        Cpu32Bit, RunOutcome, DEFAULT_CONTINUE_BUDGET, REGISTERS_COUNT,
+    },
+    execute::ProgramExit,
+    listing::{diff, listing},
+    trap::Trap,
+    verify::verify,
+};
+use riscv_emulator::instruction_set_definition::{
+    assembly::program::{assemble_program, DEFAULT_ENTRYPOINT},
+    isa::Isa,
+};
+use riscv_emulator::utils::parse_address;
 
+#[allow(clippy::struct_excessive_bools)] // these are independent command line flags
 #[derive(Debug, Parser)]
 #[command(
     name = env!("CARGO_PKG_NAME"),
@@ -42,15 +72,220 @@ use emulator::cpu::Cpu32Bit;
     about = env!("CARGO_PKG_DESCRIPTION")
 )]
 struct Args {
-    #[clap( help="The input binary", value_name="FILE", value_hint=clap::ValueHint::FilePath, required=true, index=1)]
-    input_file: PathBuf,
+    #[clap( help="The input binary", value_name="FILE", value_hint=clap::ValueHint::FilePath, required_unless_present_any=["abi_table", "assemble"], index=1)]
+    input_file: Option<PathBuf>,
     #[clap(short, long, help = "Enable debug mode")]
     debug: bool,
+    #[clap(
+        long,
+        help = "In debug mode, stream the programs output as it's produced instead of reprinting all of it every step",
+        requires = "debug"
+    )]
+    watch_output: bool,
+    #[clap(
+        long,
+        help = "Make the run reproducible: seed the random syscalls with SEED, fix the clock, skip sleeps, and read no input (unless --input is given)",
+        value_name = "SEED"
+    )]
+    deterministic: Option<u64>,
+    #[clap(long, help = "Read the programs input from this file instead of the terminal", value_name = "FILE", value_hint = clap::ValueHint::FilePath)]
+    input: Option<PathBuf>,
+    #[clap(long, help = "Record the input the program reads to this file, so the session can be replayed with --input", value_name = "FILE", value_hint = clap::ValueHint::FilePath)]
+    record_input: Option<PathBuf>,
+    #[clap(long, help = "Write the programs output to this file instead of the terminal (or with --assemble, the assembled program)", value_name = "FILE", value_hint = clap::ValueHint::FilePath)]
+    output: Option<PathBuf>,
+    #[clap(long, help = "Assemble this assembly source file into an ELF executable, written to the --output file, then exit", value_name = "SRC", value_hint = clap::ValueHint::FilePath, requires = "output")]
+    assemble: Option<PathBuf>,
+    #[clap(
+        long,
+        help = "With --assemble, write a flat binary (the memory image from the entrypoint to the end of the data) instead of an ELF",
+        requires = "assemble"
+    )]
+    flat: bool,
+    #[clap(
+        long,
+        help = "Escape control characters in the output written to the --output file, e.g. when it's a log",
+        requires = "output"
+    )]
+    escape_output: bool,
+    #[clap(long, help = "Trap on misaligned halfword and word loads/stores")]
+    strict_alignment: bool,
+    #[clap(
+        long,
+        help = "Only allow the instructions of this ISA, e.g. rv32imc or rv32i_zicsr (by default, every supported instruction is allowed)",
+        value_name = "ISA"
+    )]
+    march: Option<Isa>,
+    #[clap(long, help = "Write a core dump to this file if the program faults", value_name = "PATH", value_hint = clap::ValueHint::FilePath)]
+    core_on_fault: Option<PathBuf>,
+    #[clap(
+        long,
+        help = "Statically check the program for invalid instructions (or ones outside of --march) and branch targets before running it"
+    )]
+    verify: bool,
+    #[clap(
+        long,
+        help = "Print the register numbers, ABI names, and calling convention roles, then exit"
+    )]
+    abi_table: bool,
+    #[clap(
+        long,
+        help = "Trap when a saved return address on the stack is overwritten with an address outside of the program"
+    )]
+    detect_ra_corruption: bool,
+    #[clap(
+        long,
+        help = "Trap when a function returns without restoring a callee-saved register (sp, s0-s11)"
+    )]
+    check_callee_saved: bool,
+    #[clap(
+        long,
+        help = "Trap when a gp-relative load or store lands outside of the static data, which means the data layout doesn't match the one the program was linked with"
+    )]
+    check_gp_relative: bool,
+    #[clap(
+        long,
+        help = "Set the thread pointer (tp) to this address, instead of setting up thread-local storage from the program's .tdata and .tbss sections",
+        value_name = "ADDR",
+        value_parser = parse_address
+    )]
+    tp: Option<u32>,
+    #[clap(long, help = "Start from the registers and pc in this JSON register snapshot (or core dump)", value_name = "FILE", value_hint = clap::ValueHint::FilePath)]
+    registers_from: Option<PathBuf>,
+    #[clap(
+        long,
+        help = "Halt when the program stores to this address (or the program's `tohost` symbol), passing if the value stored is 1",
+        value_name = "ADDR",
+        num_args = 0..=1,
+        value_parser = parse_address
+    )]
+    #[allow(clippy::option_option)]
+    // the flag can be absent, given without an address, or given one
+    tohost: Option<Option<u32>>,
+    #[clap(
+        long,
+        help = "Run the program bare-metal: ecall, ebreak, and exceptions jump to the trap handler at mtvec instead of making syscalls or stopping"
+    )]
+    bare_metal: bool,
+    #[clap(
+        long,
+        help = "Don't print faults, report them through the exit status instead (100 for any fault, or 101-109 for specific traps, see the README)"
+    )]
+    quiet: bool,
+    #[clap(
+        long,
+        help = "Run this many instructions, then print the state of the CPU and exit",
+        value_name = "N"
+    )]
+    run_then_dump: Option<u64>,
+    #[clap(
+        long,
+        help = "Overwrite the temporaries (t0-t6) after every syscall, to catch programs relying on them being preserved"
+    )]
+    poison_temporaries: bool,
+    #[clap(
+        long,
+        help = "Print the instructions and cycles executed, and the cycles per instruction, to stderr after running"
+    )]
+    timing: bool,
+    #[clap(
+        long,
+        help = "Count how many times each instruction executes, and print the N most executed to stderr after running",
+        value_name = "N"
+    )]
+    profile_hot: Option<usize>,
+    #[clap(long, help = "Print each instruction to stderr before executing it")]
+    trace: bool,
+    #[clap(
+        long,
+        help = "Print each retired instruction to stderr in the format of Spike's --log-commits",
+        conflicts_with = "trace"
+    )]
+    log_commits: bool,
+    #[clap(
+        long,
+        help = "Record every instruction retired, syscall (with its input and output), and fault to this file, as JSON lines",
+        value_name = "FILE",
+        value_hint = clap::ValueHint::FilePath,
+        conflicts_with_all = ["trace", "log_commits"]
+    )]
+    record: Option<PathBuf>,
+    #[clap(
+        long,
+        help = "When tracing, log loads and stores that fault and skip over them instead of stopping",
+        requires = "trace"
+    )]
+    continue_on_fault: bool,
+    #[clap(
+        long,
+        help = "Wait this many milliseconds between instructions when tracing",
+        value_name = "MS",
+        requires = "trace"
+    )]
+    step_delay: Option<u64>,
+    #[clap(
+        long,
+        help = "How many instructions continuing in the debugger runs before prompting again, 0 for no limit",
+        value_name = "N",
+        default_value_t = DEFAULT_CONTINUE_BUDGET
+    )]
+    continue_budget: u64,
+    #[clap(
+        long,
+        help = "How many steps the debugger can undo (and reverse continue through), 0 for no limit (64 by default when debugging, otherwise steps aren't recorded)",
+        value_name = "N"
+    )]
+    undo_depth: Option<usize>,
+    #[clap(long, help = "Write a disassembly listing of the text section to this file", value_name = "PATH", value_hint = clap::ValueHint::FilePath)]
+    listing: Option<PathBuf>,
+    #[clap(long, help = "Print the differences between the disassembly of the program's text section and this program's, then exit", value_name = "OTHER", value_hint = clap::ValueHint::FilePath)]
+    diff: Option<PathBuf>,
+}
+
+/// Assemble the source file at `source`, and write the program to `output` as an ELF, or a flat binary if `flat`.
+fn assemble_file(source: &Path, output: &Path, flat: bool) -> Result<()> {
+    let program = assemble_program(&std::fs::read_to_string(source)?, DEFAULT_ENTRYPOINT)?;
+    let binary = if flat {
+        program.to_flat_binary()
+    } else {
+        program.to_elf()
+    };
+    std::fs::write(output, binary)?;
+    Ok(())
+}
+
+/// Render the register number, ABI name, role, and saver of every register as a table.
+fn abi_table() -> String {
+    let mut lines = vec![format!("{:>3} {:<4} {:<32} saver", "reg", "abi", "role")];
+    lines.extend(
+        (0..REGISTERS_COUNT)
+            .filter_map(|i| RegisterMapping::try_from(i).ok())
+            .map(|reg| {
+                format!(
+                    "{:>3} {:<4} {:<32} {}",
+                    format!("x{}", reg as u8),
+                    reg.abi_name(),
+                    reg.role(),
+                    reg.saver()
+                        .map_or_else(|| String::from("-"), |saver| saver.to_string())
+                )
+            }),
+    );
+    lines.join("\n")
 }
 
 fn main() -> Result<()> {
-    let args = Args::parse();
-    let path = args.input_file;
+    let mut args = Args::parse();
+    if args.abi_table {
+        println!("{}", abi_table());
+        return Ok(());
+    }
+    if let (Some(source), Some(output)) = (&args.assemble, &args.output) {
+        return assemble_file(source, output, args.flat);
+    }
+    let Some(path) = args.input_file.take() else {
+        bail!("No input binary given");
+    };
     let debug = args.debug;
 
     // let path = PathBuf::from_str("test_binaries/matrix_mult.bin")?;
@@ -59,57 +294,302 @@ fn main() -> Result<()> {
     let file_data = std::fs::read(path)?;
     let file = ElfBytes::<AnyEndian>::minimal_parse(file_data.as_slice())?;
 
-    let data_header = file.section_header_by_name(".data")?;
-    let (data_section, _data_compression_header) = if let Some(header) = data_header {
-        let (a, b) = file.section_data(&header)?;
-        (Some(a), b)
-    } else {
-        (None, None)
-    };
+    let (memory, _, gp) = MemoryBus::from_elf(&file)?;
+    let text_base = memory.text_base();
+    let text_section = &memory.read_bytes(text_base, memory.code_size())?;
 
-    let entrypoint = u32::try_from(file.ehdr.e_entry)?; // the entrypoint should fit in a u32, if it doesn't, the file is invalid
+    if let Some(path) = &args.listing {
+        write_listing(path, &file, text_section, text_base)?;
+    }
+    if let Some(other) = &args.diff {
+        print!("{}", diff_against(text_section, text_base, other)?);
+        return Ok(());
+    }
 
-    let text_header = file.section_header_by_name(".text")?;
-    let (text_section, _text_compression_header) = if let Some(header) = text_header {
-        let (a, b) = file.section_data(&header)?;
-        (a, b)
-    } else {
-        bail!("No .text section found")
-    };
+    if args.verify {
+        verify_text(text_section, text_base, args.march.as_ref())?;
+    }
 
-    assert!(
-        text_section.len() % 4 == 0,
-        "Text section length is not a multiple of 4, this is not a valid RISC-V binary"
-    );
+    if let Some(problem) = gp.and_then(|gp| memory.check_global_pointer(gp)) {
+        eprintln!("warning: {problem}");
+    }
 
-    // extract `__global_pointer$` from the ELF file, it's a symbol not a section
-    #[allow(clippy::cast_possible_truncation)]
-    let gp = file.symbol_table()?.and_then(|table| {
-        table
-            .0
-            .iter()
-            .find(|symbol| table.1.get(symbol.st_name as usize).unwrap() == "__global_pointer$")
-            .map(|symbol| symbol.st_value as u32)
-    });
-
-    let mut cpu: Cpu32Bit = Cpu32Bit::new(
-        text_section,
-        data_section.unwrap_or_default(),
-        entrypoint,
-        gp,
-    );
+    let mut cpu = Cpu32Bit::with_memory(memory, gp);
+
+    cpu.memory.set_tohost(tohost_address(args.tohost, &file)?);
+    configure_checks(&mut cpu, &args);
+
+    if let Some(tp) = args.tp {
+        cpu.registers[RegisterMapping::Tp] = tp;
+    } else if let Some((tdata, tbss_size)) = tls_sections(&file)? {
+        cpu.set_up_tls(&tdata, tbss_size)?;
+    }
+
+    if let Some(seed) = args.deterministic {
+        cpu.set_deterministic(seed);
+    }
+    if let Some(input) = &args.input {
+        cpu.input = ProgramInput::Reader(Box::new(BufReader::new(File::open(input)?)));
+    }
+    if let Some(record) = &args.record_input {
+        cpu.input = std::mem::take(&mut cpu.input).recorded(Box::new(File::create(record)?));
+    }
+    if let Some(output) = &args.output {
+        cpu.output_sink = output_file(output, args.escape_output)?;
+    }
+
+    if let Some(path) = &args.registers_from {
+        RegisterSnapshot::read_from(path)?.apply(&mut cpu)?;
+    }
 
     if debug {
         // pause before executing the first instruction
         cpu.debug = true;
     }
+    cpu.watch_output = args.watch_output;
+    cpu.continue_budget = Some(args.continue_budget).filter(|&budget| budget > 0);
+    // recording every step is wasted without a debugger to undo them, unless asked for
+    match args.undo_depth {
+        Some(0) => cpu.set_undo_depth(usize::MAX),
+        Some(depth) => cpu.set_undo_depth(depth),
+        None if debug => cpu.set_undo_depth(DEFAULT_UNDO_DEPTH),
+        None => {}
+    }
 
-    loop {
-        if let Err(e) = cpu.step() {
-            eprintln!("Error: {e}");
-            break;
+    let quiet = args.quiet;
+    let result = run(&mut cpu, &args)?;
+    if matches!(result, Ok(RunOutcome::StepLimitReached)) {
+        // only possible with --run-then-dump, as the run is otherwise unlimited
+        eprintln!("{cpu}");
+    }
+    let exit_code = result
+        .as_ref()
+        .err()
+        .and_then(|e| e.downcast_ref::<ProgramExit>())
+        .map(|exit| exit.code);
+    let failure = report(&cpu, result, quiet, args.core_on_fault.as_deref())?;
+    if args.timing {
+        eprintln!("{}", cpu.counters().timing_report());
+    }
+    if let (Some(n), Some(profile)) = (args.profile_hot, cpu.hot_profile.as_ref()) {
+        eprintln!("{}", profile.report(n, &cpu.memory, None));
+    }
+    cpu.output_sink.flush()?;
+
+    match failure {
+        Some(fault) if quiet => std::process::exit(fault_exit_code(&fault)),
+        // a failing test program is reported through the exit status
+        Some(fault) => Err(fault),
+        // the status is the low bits of the code, as on a real system
+        #[allow(clippy::cast_possible_wrap)]
+        None => match exit_code {
+            Some(code) if code != 0 => std::process::exit(code as i32),
+            _ => Ok(()),
+        },
+    }
+}
+
+/// Run the program, tracing or logging it as selected by `args`.
+///
+/// The outer error is for failing to set up the log, the inner result is the run's.
+fn run(cpu: &mut Cpu32Bit, args: &Args) -> Result<Result<RunOutcome>> {
+    Ok(if args.trace {
+        let step_delay = args.step_delay.map(Duration::from_millis);
+        cpu.run_traced(args.run_then_dump, &mut std::io::stderr(), step_delay)
+    } else if args.log_commits {
+        cpu.run_commit_logged(args.run_then_dump, &mut std::io::stderr())
+    } else if let Some(path) = &args.record {
+        let mut log = BufWriter::new(File::create(path)?);
+        let result = cpu.run_recorded(args.run_then_dump, &mut log);
+        log.flush()?;
+        result
+    } else {
+        cpu.run(args.run_then_dump)
+    })
+}
+
+/// Enable the checks and instrumentation selected by `args`.
+fn configure_checks(cpu: &mut Cpu32Bit, args: &Args) {
+    cpu.memory.set_strict_alignment(args.strict_alignment);
+    cpu.isa.clone_from(&args.march);
+    cpu.memory.set_gp_check(args.check_gp_relative);
+    cpu.poison_temporaries = args.poison_temporaries;
+    cpu.continue_on_fault = args.continue_on_fault;
+    cpu.bare_metal = args.bare_metal;
+    if args.detect_ra_corruption {
+        cpu.ra_guard = Some(ReturnAddressGuard::new());
+    }
+    if args.check_callee_saved {
+        cpu.callee_saved_checker = Some(CalleeSavedChecker::new());
+    }
+    if args.profile_hot.is_some() {
+        cpu.hot_profile = Some(HotProfile::new());
+    }
+}
+
+/// Report how the run ended, returning the failure the process should exit with, if any.
+///
+/// Unless `quiet`, faults are printed, and only a failing test program is returned.
+fn report(
+    cpu: &Cpu32Bit,
+    result: Result<RunOutcome>,
+    quiet: bool,
+    core_on_fault: Option<&Path>,
+) -> Result<Option<anyhow::Error>> {
+    match result {
+        Err(e) if e.downcast_ref::<Trap>().is_some_and(Trap::passed) => Ok(None),
+        Err(e) if matches!(e.downcast_ref(), Some(Trap::Halt { .. })) => Ok(Some(e)),
+        // the program finished, just without exiting, so there's nothing to dump either
+        Err(e) if matches!(e.downcast_ref(), Some(Trap::RanOffEndOfCode { .. })) => {
+            if !quiet {
+                eprintln!("Warning: {e}");
+            }
+            Ok(quiet.then_some(e))
+        }
+        // exiting isn't a fault, the exit code becomes the process's exit status
+        Err(e) if e.is::<ProgramExit>() => Ok(None),
+        Err(e) => {
+            if !quiet {
+                eprintln!("Error: {e:#}");
+            }
+            if let Some(path) = core_on_fault {
+                CoreDump::new(cpu, &e).write_to(path)?;
+                if !quiet {
+                    eprintln!("Core dumped to {}", path.display());
+                }
+            }
+            Ok(quiet.then_some(e))
+        }
+        Ok(RunOutcome::Halted) if !quiet => {
+            eprintln!("Program halted at {:#010x}", cpu.pc);
+            Ok(None)
+        }
+        Ok(_) => Ok(None),
+    }
+}
+
+/// The first of the exit statuses `--quiet` reports faults with, see `fault_exit_code`.
+const FAULT_EXIT_CODES: i32 = 100;
+
+/// The exit status `--quiet` reports a fault with, as documented in the README.
+///
+/// The statuses start at `FAULT_EXIT_CODES`, clear of the small codes programs usually exit with.
+fn fault_exit_code(fault: &anyhow::Error) -> i32 {
+    FAULT_EXIT_CODES
+        + match fault.downcast_ref::<Trap>() {
+            // only the library can make division by zero trap, see `DivByZeroPolicy`
+            None | Some(Trap::DivisionByZero) => 0,
+            Some(Trap::LoadAddressMisaligned { .. }) => 1,
+            Some(Trap::StoreAddressMisaligned { .. }) => 2,
+            Some(Trap::NullPointerDereference { .. }) => 3,
+            Some(Trap::ReturnAddressCorrupted { .. }) => 4,
+            Some(Trap::Halt { .. }) => 5,
+            Some(Trap::RanOffEndOfCode { .. }) => 6,
+            Some(Trap::CalleeSavedRegisterClobbered { .. }) => 7,
+            Some(Trap::GpRelativeAccessOutsideStaticData { .. }) => 8,
+            Some(Trap::InstructionAddressMisaligned { .. }) => 9,
+        }
+}
+
+/// Create the file at `path` for the programs output, optionally escaping control characters written to it.
+fn output_file(path: &Path, escape: bool) -> Result<ProgramOutput> {
+    let writer = Box::new(BufWriter::new(File::create(path)?));
+    Ok(if escape {
+        ProgramOutput::Escaped(writer)
+    } else {
+        ProgramOutput::Writer(writer)
+    })
+}
+
+/// Resolve the address of the exit device from the `--tohost` flag, see `Args::tohost`.
+#[allow(clippy::option_option)]
+fn tohost_address(flag: Option<Option<u32>>, file: &ElfBytes<AnyEndian>) -> Result<Option<u32>> {
+    match flag {
+        Some(None) => match find_symbol(file, "tohost")? {
+            Some(tohost) => Ok(Some(tohost)),
+            None => bail!(
+                "--tohost was given without an address, and the program has no `tohost` symbol"
+            ),
+        },
+        flag => Ok(flag.flatten()),
+    }
+}
+
+/// Statically check the text section against `isa` (every supported instruction, if `None`),
+/// printing any problems found.
+fn verify_text(text: &[u8], text_base: u32, isa: Option<&Isa>) -> Result<()> {
+    let diagnostics = verify(text, text_base, isa);
+    if !diagnostics.is_empty() {
+        for diagnostic in &diagnostics {
+            eprintln!("{diagnostic}");
         }
+        bail!("Verification found {} problem(s)", diagnostics.len());
     }
+    Ok(())
+}
 
+/// Get the initial thread-local data (`.tdata`) and the size of the zeroed thread-local data (`.tbss`),
+/// if the program has either section.
+fn tls_sections(file: &ElfBytes<AnyEndian>) -> Result<Option<(Vec<u8>, u32)>> {
+    let tdata = file.section_header_by_name(".tdata")?;
+    let tbss = file.section_header_by_name(".tbss")?;
+    if tdata.is_none() && tbss.is_none() {
+        return Ok(None);
+    }
+    let tdata = match tdata {
+        Some(header) => file.section_data(&header)?.0.to_vec(),
+        None => Vec::new(),
+    };
+    let tbss_size = tbss.map_or(Ok(0), |header| u32::try_from(header.sh_size))?;
+    Ok(Some((tdata, tbss_size)))
+}
+
+/// Write a disassembly listing of the text section to `path`, labelled with the program's symbols.
+fn write_listing(
+    path: &Path,
+    file: &ElfBytes<AnyEndian>,
+    text: &[u8],
+    text_base: u32,
+) -> Result<()> {
+    let labels = text_labels(file, text_base, text.len())?;
+    std::fs::write(path, listing(text, text_base, &labels))?;
     Ok(())
 }
+
+/// Diff the disassembly of `text`, loaded at `text_base`, against the text section of the program at `other`.
+fn diff_against(text: &[u8], text_base: u32, other: &Path) -> Result<String> {
+    let other_data = std::fs::read(other)?;
+    let (other_memory, ..) = MemoryBus::from_elf(&ElfBytes::<AnyEndian>::minimal_parse(
+        other_data.as_slice(),
+    )?)?;
+    let other_base = other_memory.text_base();
+    let other_text = other_memory.read_bytes(other_base, other_memory.code_size())?;
+    Ok(diff(text, text_base, &other_text, other_base))
+}
+
+/// Collect the names of the symbols (functions and labels) in the text section, by address.
+fn text_labels(
+    file: &ElfBytes<AnyEndian>,
+    text_base: u32,
+    text_len: usize,
+) -> Result<BTreeMap<u32, String>> {
+    let mut labels = BTreeMap::new();
+    let Some((symbols, names)) = file.symbol_table()? else {
+        return Ok(labels);
+    };
+    let text = u64::from(text_base)..u64::from(text_base) + text_len as u64;
+    for symbol in symbols.iter().filter(|symbol| {
+        matches!(symbol.st_symtype(), STT_FUNC | STT_NOTYPE) && text.contains(&symbol.st_value)
+    }) {
+        let name = names.get(symbol.st_name as usize)?;
+        // skip unnamed symbols, and mapping symbols like `$x`
+        if name.is_empty() || name.starts_with('$') {
+            continue;
+        }
+        #[allow(clippy::cast_possible_truncation)] // the value is within the text section
+        labels
+            .entry(symbol.st_value as u32)
+            .or_insert_with(|| name.to_string());
+    }
+    Ok(labels)
+}
@@ -78,10 +78,38 @@ pub fn bit_vec_to_int(bits: &[u8]) -> u32 {
         .sum()
 }
 
+/// Lay out 32-bit words (e.g. instructions) as little-endian bytes, the way they sit in memory
+#[must_use]
+pub fn words_to_bytes(words: &[u32]) -> Vec<u8> {
+    words.iter().flat_map(|word| word.to_le_bytes()).collect()
+}
+
+/// Parse an address, given either in hexadecimal with a `0x` prefix or in decimal.
+///
+/// Hexadecimal addresses can be split into groups with `_`, e.g. `0x0040_0000`.
+///
+/// # Errors
+/// - if the address isn't a valid 32-bit number
+pub fn parse_address(s: &str) -> Result<u32> {
+    Ok(match s.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(&hex.replace('_', ""), 16)?,
+        None => s.parse()?,
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_parse_address() {
+        assert_eq!(parse_address("0x0040_0000").ok(), Some(0x0040_0000));
+        assert_eq!(parse_address("0x400000").ok(), Some(0x0040_0000));
+        assert_eq!(parse_address("4194304").ok(), Some(0x0040_0000));
+        assert!(parse_address("0x1_0000_0000").is_err());
+        assert!(parse_address("main").is_err());
+    }
+
     #[test]
     fn test_bit_vec_to_int() {
         // test 32 bits
@@ -0,0 +1,221 @@
+#!/usr/bin/env python3
+"""Build the riscv-tests ELFs in this directory from the sources in isa/.
+
+Each isa/<suite>/<test>.S is preprocessed with cpp, assembled with llvm-mc, and linked by this script
+(there's no RISC-V linker needed) the way riscv-tests' env/p/link.ld does: `.text.init` at 0x80000000,
+then `.tohost`, `.text`, and `.data`, each on a new page. The result is written to ./<suite>-p-<test>.
+
+usage: build.py [test.S ...]    (every test by default)
+"""
+import pathlib
+import struct
+import subprocess
+import sys
+import tempfile
+
+ROOT = pathlib.Path(__file__).resolve().parent
+BASE = 0x8000_0000
+PAGE = 0x1000
+# the output sections, in the order they're laid out, and whether each starts on a new page
+LAYOUT = [('.text.init', False), ('.tohost', True), ('.text', True), ('.data', True), ('.bss', False)]
+
+SHT_PROGBITS, SHT_SYMTAB, SHT_STRTAB, SHT_RELA, SHT_NOBITS = 1, 2, 3, 4, 8
+SHF_WRITE, SHF_ALLOC, SHF_EXECINSTR = 1, 2, 4
+PT_LOAD, PF_X, PF_W, PF_R = 1, 1, 2, 4
+
+
+def sections(obj):
+    """The section headers of the ELF32 object `obj`, as dicts with their names and contents."""
+    shoff, = struct.unpack_from('<I', obj, 32)
+    shnum, shstrndx = struct.unpack_from('<HH', obj, 48)
+    headers = []
+    for index in range(shnum):
+        name, kind, flags, _, offset, size, link, info, align, entsize = struct.unpack_from(
+            '<10I', obj, shoff + index * 40)
+        headers.append(dict(name=name, kind=kind, flags=flags, offset=offset, size=size,
+                            link=link, info=info, align=max(align, 1), entsize=entsize,
+                            data=obj[offset:offset + size] if kind != SHT_NOBITS else b''))
+    names = headers[shstrndx]['data']
+    for header in headers:
+        header['name'] = names[header['name']:names.index(b'\0', header['name'])].decode()
+    return headers
+
+
+def string(table, offset):
+    return table[offset:table.index(b'\0', offset)].decode()
+
+
+def link(obj):
+    """Link the object `obj`, returning the ELF executable."""
+    headers = sections(obj)
+    by_name = {header['name']: index for index, header in enumerate(headers)}
+    for header in headers:
+        if header['flags'] & SHF_ALLOC and header['size'] and header['name'] not in dict(LAYOUT):
+            sys.exit(f'unexpected section {header["name"]}')
+
+    # lay the sections out
+    address = BASE
+    placed = []
+    for name, new_page in LAYOUT:
+        header = headers[by_name[name]] if name in by_name else None
+        if header is None or not header['size']:
+            continue
+        if new_page:
+            address = (address + PAGE - 1) // PAGE * PAGE
+        address = (address + header['align'] - 1) // header['align'] * header['align']
+        header['addr'] = address
+        header['image'] = bytearray(header['data'])
+        placed.append(header)
+        address += header['size']
+
+    # resolve the symbols
+    symtab = next(header for header in headers if header['kind'] == SHT_SYMTAB)
+    names = headers[symtab['link']]['data']
+    symbols = []
+    for offset in range(0, symtab['size'], 16):
+        name, value, size, info, _, shndx = struct.unpack_from('<IIIBBH', symtab['data'], offset)
+        section = headers[shndx] if 0 < shndx < len(headers) else None
+        symbols.append(dict(name=string(names, name), value=value, size=size, info=info, section=section,
+                            undefined=shndx == 0))
+    for symbol in symbols[1:]:
+        if symbol['undefined']:
+            sys.exit(f'undefined symbol {symbol["name"]}')
+        if symbol['section'] is not None and 'addr' in symbol['section']:
+            symbol['value'] += symbol['section']['addr']
+
+    # apply the relocations
+    for rela in (header for header in headers if header['kind'] == SHT_RELA):
+        target = headers[rela['info']]
+        if 'addr' not in target:
+            continue
+        entries = [struct.unpack_from('<IIi', rela['data'], offset) for offset in range(0, rela['size'], 12)]
+        hi20 = {offset: symbols[info >> 8]['value'] + addend - (target['addr'] + offset)
+                for offset, info, addend in entries if info & 0xff == 23}
+        for offset, info, addend in entries:
+            relocate(target, offset, info & 0xff, symbols[info >> 8], addend, hi20)
+
+    return executable(placed, symbols)
+
+
+def relocate(section, offset, kind, symbol, addend, hi20):
+    """Apply a relocation of type `kind` to the instruction (or word) at `offset` into `section`."""
+    image = section['image']
+    pc = section['addr'] + offset
+    value = symbol['value'] + addend
+    insn, = struct.unpack_from('<I', image, offset)
+    if kind == 1:  # R_RISCV_32
+        insn = value & 0xffff_ffff
+    elif kind == 16:  # R_RISCV_BRANCH
+        imm = value - pc
+        insn = (insn & 0x01ff_f07f) | ((imm >> 12 & 1) << 31) | ((imm >> 5 & 0x3f) << 25) \
+            | ((imm >> 1 & 0xf) << 8) | ((imm >> 11 & 1) << 7)
+    elif kind == 17:  # R_RISCV_JAL
+        imm = value - pc
+        insn = (insn & 0xfff) | ((imm >> 20 & 1) << 31) | ((imm >> 1 & 0x3ff) << 21) \
+            | ((imm >> 11 & 1) << 20) | ((imm >> 12 & 0xff) << 12)
+    elif kind in (18, 19):  # R_RISCV_CALL(_PLT), an auipc and jalr pair
+        imm = value - pc
+        jalr, = struct.unpack_from('<I', image, offset + 4)
+        insn = (insn & 0xfff) | ((imm + 0x800) & 0xffff_f000)
+        struct.pack_into('<I', image, offset + 4, (jalr & 0xfffff) | ((imm & 0xfff) << 20))
+    elif kind == 23:  # R_RISCV_PCREL_HI20
+        insn = (insn & 0xfff) | ((value - pc + 0x800) & 0xffff_f000)
+    elif kind in (24, 25):  # R_RISCV_PCREL_LO12_I/S, the symbol is the auipc with the matching R_RISCV_PCREL_HI20
+        imm = hi20[symbol['value'] - section['addr']] & 0xfff
+        insn = itype(insn, imm) if kind == 24 else stype(insn, imm)
+    elif kind == 26:  # R_RISCV_HI20
+        insn = (insn & 0xfff) | ((value + 0x800) & 0xffff_f000)
+    elif kind in (27, 28):  # R_RISCV_LO12_I/S
+        insn = itype(insn, value & 0xfff) if kind == 27 else stype(insn, value & 0xfff)
+    else:
+        sys.exit(f'unsupported relocation type {kind} at {pc:#x}')
+    struct.pack_into('<I', image, offset, insn & 0xffff_ffff)
+
+
+def itype(insn, imm):
+    return (insn & 0x000f_ffff) | (imm << 20)
+
+
+def stype(insn, imm):
+    return (insn & 0x01ff_f07f) | ((imm >> 5) << 25) | ((imm & 0x1f) << 7)
+
+
+def executable(placed, symbols):
+    """Write the ELF executable with the `placed` sections, each in its own segment, and the named `symbols`."""
+    # the symbol table, without the assembler's local labels
+    strtab = bytearray(b'\0')
+    symtab = bytearray(16)
+    index = {id(section): number + 1 for number, section in enumerate(placed)}
+    named = [symbol for symbol in symbols[1:]
+             if symbol['name'] and not symbol['name'].startswith('.L') and id(symbol['section']) in index]
+    # the local symbols come first
+    named.sort(key=lambda symbol: symbol['info'] >> 4 != 0)
+    locals_end = 1 + sum(symbol['info'] >> 4 == 0 for symbol in named)
+    for symbol in named:
+        symtab += struct.pack('<IIIBBH', len(strtab), symbol['value'], symbol['size'], symbol['info'], 0,
+                              index[id(symbol['section'])])
+        strtab += symbol['name'].encode() + b'\0'
+    shstrtab = bytearray(b'\0')
+    shnames = []
+    for name in [section['name'] for section in placed] + ['.symtab', '.strtab', '.shstrtab']:
+        shnames.append(len(shstrtab))
+        shstrtab += name.encode() + b'\0'
+
+    entry = next(symbol['value'] for symbol in symbols if symbol['name'] == '_start')
+    loaded = [section for section in placed if section['kind'] != SHT_NOBITS]
+    out = bytearray(52 + 32 * len(placed))
+    # the contents of the sections, at the same offset into a page in the file as in memory
+    for section in loaded:
+        out += bytes((section['addr'] - len(out)) % PAGE)
+        section['file_offset'] = len(out)
+        out += section['image']
+    tables = []
+    for table in (symtab, strtab, shstrtab):
+        out += bytes(-len(out) % 4)
+        tables.append(len(out))
+        out += table
+    out += bytes(-len(out) % 4)
+    shoff = len(out)
+
+    struct.pack_into('<4s5B7x', out, 0, b'\x7fELF', 1, 1, 1, 0, 0)
+    struct.pack_into('<HHIIIIIHHHHHH', out, 16, 2, 0xf3, 1, entry, 52, shoff, 0, 52, 32, len(placed), 40,
+                     len(placed) + 4, len(placed) + 3)
+    for number, section in enumerate(placed):
+        flags = PF_R | (PF_W if section['flags'] & SHF_WRITE else 0) | (PF_X if section['flags'] & SHF_EXECINSTR else 0)
+        file_size = 0 if section['kind'] == SHT_NOBITS else section['size']
+        struct.pack_into('<8I', out, 52 + 32 * number, PT_LOAD, section.get('file_offset', 0), section['addr'],
+                         section['addr'], file_size, section['size'], flags, PAGE)
+
+    out += bytes(40)
+    for number, section in enumerate(placed):
+        out += struct.pack('<10I', shnames[number], section['kind'], section['flags'], section['addr'],
+                           section.get('file_offset', 0), section['size'], 0, 0, section['align'], 0)
+    names = shnames[len(placed):]
+    out += struct.pack('<10I', names[0], SHT_SYMTAB, 0, 0, tables[0], len(symtab), len(placed) + 2, locals_end, 4, 16)
+    out += struct.pack('<10I', names[1], SHT_STRTAB, 0, 0, tables[1], len(strtab), 0, 0, 1, 0)
+    out += struct.pack('<10I', names[2], SHT_STRTAB, 0, 0, tables[2], len(shstrtab), 0, 0, 1, 0)
+    return bytes(out)
+
+
+def build(source):
+    suite = source.parent.name
+    output = ROOT / f'{suite}-p-{source.stem}'
+    with tempfile.TemporaryDirectory() as tmp:
+        preprocessed = pathlib.Path(tmp) / 'test.s'
+        obj = pathlib.Path(tmp) / 'test.o'
+        subprocess.run(['cpp', '-x', 'assembler-with-cpp', '-P', '-I', ROOT / 'env', source, '-o', preprocessed],
+                       check=True)
+        subprocess.run(['llvm-mc', '--triple=riscv32', '-mattr=+m,-relax', '-filetype=obj', preprocessed,
+                        '-o', obj], check=True)
+        output.write_bytes(link(obj.read_bytes()))
+    print(output.name)
+
+
+def main():
+    sources = [pathlib.Path(arg).resolve() for arg in sys.argv[1:]] or sorted(ROOT.glob('isa/*/*.S'))
+    for source in sources:
+        build(source)
+
+
+if __name__ == '__main__':
+    main()
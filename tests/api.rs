@@ -0,0 +1,124 @@
+//! Tests of the library API
+mod common;
+
+use common::{build_elf, data_address, ENTRYPOINT, HELLO_WORLD};
+use elf::{endian::AnyEndian, ElfBytes};
+use riscv_emulator::{
+    emulator::{
+        cpu::{memory::MemoryBus, Cpu32Bit, RunOutcome, Size},
+        trap::Trap,
+    },
+    utils::words_to_bytes,
+};
+
+#[test]
+fn test_run_program_hello_world() {
+    let text = words_to_bytes(HELLO_WORLD);
+    let output = riscv_emulator::run_program(&text, b"Hello, World!\n\0", ENTRYPOINT, "").unwrap();
+    assert_eq!(output, "Hello, World!\n");
+}
+
+#[test]
+fn test_memory_bus_from_elf() {
+    let bytes = build_elf(HELLO_WORLD, &0x1234_5678u32.to_le_bytes());
+    let elf = ElfBytes::<AnyEndian>::minimal_parse(&bytes).unwrap();
+
+    let (memory, entrypoint, gp) = MemoryBus::from_elf(&elf).unwrap();
+    assert_eq!(entrypoint, ENTRYPOINT);
+    assert_eq!(memory.entrypoint(), ENTRYPOINT);
+    assert_eq!(gp, None);
+    // the first instruction, and the data where it was linked
+    assert_eq!(memory.read(ENTRYPOINT, Size::Word).unwrap(), HELLO_WORLD[0]);
+    assert_eq!(
+        memory.read(data_address(HELLO_WORLD), Size::Word).unwrap(),
+        0x1234_5678
+    );
+
+    let memory = MemoryBus::try_from(&elf).unwrap();
+    assert_eq!(memory.dram_start(), data_address(HELLO_WORLD));
+}
+
+#[test]
+fn test_memory_bus_from_elf_segments() {
+    // a vendored riscv-tests program, linked with the text at 0x80000000 and `tohost` on the next page
+    let bytes = std::fs::read(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/test_binaries/riscv-tests/rv32ui-p-simple"
+    ))
+    .unwrap();
+    let elf = ElfBytes::<AnyEndian>::minimal_parse(&bytes).unwrap();
+
+    let (memory, entrypoint, _) = MemoryBus::from_elf(&elf).unwrap();
+    assert_eq!(memory.text_base(), 0x8000_0000);
+    assert_eq!(entrypoint, 0x8000_0000);
+    // j reset_vector
+    assert_eq!(memory.read(0x8000_0000, Size::Word).unwrap(), 0x0380_006f);
+    assert_eq!(memory.read(0x8000_1000, Size::Word).unwrap(), 0);
+
+    let mut cpu = Cpu32Bit::with_memory(memory, None);
+    cpu.bare_metal = true;
+    cpu.memory_mut().set_tohost(Some(0x8000_1000));
+    let error = cpu.run(None).unwrap_err();
+    assert_eq!(error.downcast_ref::<Trap>(), Some(&Trap::Halt { value: 1 }));
+}
+
+/// Prints 5 factorial, then exits with code 7.
+const FACTORIAL: &[u32] = &[
+    0x0010_0513, // li a0, 1
+    0x0050_0293, // li t0, 5
+    0x0255_0533, // loop: mul a0, a0, t0
+    0xfff2_8293, // addi t0, t0, -1
+    0xfe02_9ce3, // bnez t0, loop
+    0x0010_0893, // li a7, 1
+    0x0000_0073, // ecall
+    0x0070_0513, // li a0, 7
+    0x05d0_0893, // li a7, 93
+    0x0000_0073, // ecall
+];
+
+#[test]
+fn test_run_to_completion() {
+    let text = words_to_bytes(FACTORIAL);
+
+    let mut cpu = Cpu32Bit::new(&text, &[], ENTRYPOINT, None).unwrap();
+    let result = cpu.run_to_completion(None).unwrap();
+    assert_eq!(result.exit_code, Some(7));
+    assert_eq!(result.outcome, None);
+    assert_eq!(result.output, "120");
+    // 2 instructions before the loop, 5 iterations of 3, and 4 after it (the exiting ecall doesn't retire)
+    assert_eq!(result.instructions, 21);
+
+    // running out of steps isn't an error
+    let mut cpu = Cpu32Bit::new(&text, &[], ENTRYPOINT, None).unwrap();
+    let result = cpu.run_to_completion(Some(5)).unwrap();
+    assert_eq!(result.exit_code, None);
+    assert_eq!(result.outcome, Some(RunOutcome::StepLimitReached));
+    assert_eq!(result.output, "");
+    assert_eq!(result.instructions, 5);
+}
+
+#[test]
+fn test_run_to_completion_stops_at_ebreak() {
+    let text = words_to_bytes(&[
+        0x0010_0513_u32, // li a0, 1
+        0x0010_0073,     // ebreak
+        0x0020_0513,     // li a0, 2
+    ]);
+
+    // rather than prompting for debugger commands
+    let mut cpu = Cpu32Bit::new(&text, &[], ENTRYPOINT, None).unwrap();
+    let result = cpu.run_to_completion(None).unwrap();
+    assert_eq!(result.outcome, Some(RunOutcome::Breakpoint));
+    assert_eq!(result.instructions, 2);
+    assert_eq!(cpu.pc, ENTRYPOINT + 8);
+    assert!(!cpu.debug);
+
+    let error = riscv_emulator::run_program(&text, &[], ENTRYPOINT, "").unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        format!(
+            "Program stopped at an ebreak, before {:#010x}",
+            ENTRYPOINT + 8
+        )
+    );
+}
@@ -0,0 +1,47 @@
+//! Regression tests running the sample programs in `test_binaries/` end to end
+//!
+//! Every `<name>.bin` with a companion `<name>.expected` is run to completion,
+//! with `<name>.input` as its input if there is one, and its output is compared against `<name>.expected`.
+use std::{path::Path, process::Command};
+
+const EMULATOR: &str = env!("CARGO_BIN_EXE_riscv-emulator");
+
+#[test]
+fn test_binaries_match_expected_output() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("test_binaries");
+    let mut binaries: Vec<_> = std::fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "bin"))
+        .filter(|path| path.with_extension("expected").exists())
+        .collect();
+    binaries.sort();
+    assert!(
+        !binaries.is_empty(),
+        "no binaries with expected output in {dir:?}"
+    );
+
+    let mut failures = Vec::new();
+    for binary in &binaries {
+        let expected = std::fs::read_to_string(binary.with_extension("expected")).unwrap();
+
+        let mut command = Command::new(EMULATOR);
+        command.arg(binary);
+        let input = binary.with_extension("input");
+        if input.exists() {
+            command.arg("--input").arg(&input);
+        }
+        let output = command.output().unwrap();
+
+        if String::from_utf8_lossy(&output.stdout) != expected {
+            failures.push(format!(
+                "{}:\n--- expected ---\n{expected}\n--- actual ---\n{}\n--- stderr ---\n{}",
+                binary.display(),
+                String::from_utf8_lossy(&output.stdout),
+                String::from_utf8_lossy(&output.stderr),
+            ));
+        }
+    }
+
+    assert!(failures.is_empty(), "{}", failures.join("\n"));
+}
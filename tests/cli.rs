@@ -0,0 +1,554 @@
+//! End to end tests of the command line interface
+mod common;
+
+use std::{
+    io::Write as _,
+    process::{Command, Stdio},
+};
+
+use common::{build_elf, build_elf_with_data_at, write_file, ENTRYPOINT, HELLO_WORLD};
+
+const EMULATOR: &str = env!("CARGO_BIN_EXE_riscv-emulator");
+
+/// Reads two integers and prints their sum.
+const SUM_TWO_INTS: &[u32] = &[
+    0x0050_0893, // li a7, 5
+    0x0000_0073, // ecall
+    0x0005_0293, // mv t0, a0
+    0x0050_0893, // li a7, 5
+    0x0000_0073, // ecall
+    0x0055_0533, // add a0, a0, t0
+    0x0010_0893, // li a7, 1
+    0x0000_0073, // ecall
+    0x00a0_0893, // li a7, 10
+    0x0000_0073, // ecall
+];
+
+#[test]
+fn test_input_from_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let program = write_file(dir.path(), "sum.bin", build_elf(SUM_TWO_INTS, &[]));
+    let input = write_file(dir.path(), "sum.input", "40\n2\n");
+
+    let output = Command::new(EMULATOR)
+        .arg(&program)
+        .arg("--input")
+        .arg(&input)
+        .output()
+        .unwrap();
+
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "42");
+}
+
+#[test]
+fn test_record_then_replay_input() {
+    let dir = tempfile::tempdir().unwrap();
+    let program = write_file(dir.path(), "sum.bin", build_elf(SUM_TWO_INTS, &[]));
+    let recording = dir.path().join("sum.input");
+
+    let mut child = Command::new(EMULATOR)
+        .arg(&program)
+        .arg("--record-input")
+        .arg(&recording)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(b"40\n2\n").unwrap();
+    let recorded = child.wait_with_output().unwrap();
+    assert_eq!(String::from_utf8_lossy(&recorded.stdout), "42");
+    assert_eq!(std::fs::read_to_string(&recording).unwrap(), "40\n2\n");
+
+    let replayed = Command::new(EMULATOR)
+        .arg(&program)
+        .arg("--input")
+        .arg(&recording)
+        .output()
+        .unwrap();
+    assert_eq!(replayed.stdout, recorded.stdout);
+    assert_eq!(replayed.status.code(), recorded.status.code());
+}
+
+#[test]
+fn test_deterministic_runs_are_identical() {
+    let dir = tempfile::tempdir().unwrap();
+    // prints a random number, then the time
+    let program = write_file(
+        dir.path(),
+        "random.bin",
+        build_elf(
+            &[
+                0x0290_0893, // li a7, 41
+                0x0000_0073, // ecall
+                0x0010_0893, // li a7, 1
+                0x0000_0073, // ecall
+                0x01e0_0893, // li a7, 30
+                0x0000_0073, // ecall
+                0x0010_0893, // li a7, 1
+                0x0000_0073, // ecall
+                0x00a0_0893, // li a7, 10
+                0x0000_0073, // ecall
+            ],
+            &[],
+        ),
+    );
+    let run = |seed: &str| {
+        Command::new(EMULATOR)
+            .arg(&program)
+            .args(["--deterministic", seed, "--run-then-dump", "9"])
+            .output()
+            .unwrap()
+    };
+
+    let first = run("42");
+    let second = run("42");
+    assert!(!first.stdout.is_empty());
+    assert_eq!(first.stdout, second.stdout);
+    // the final state of the CPU
+    assert!(!first.stderr.is_empty());
+    assert_eq!(first.stderr, second.stderr);
+
+    assert_ne!(run("43").stdout, first.stdout);
+}
+
+#[test]
+fn test_output_to_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let program = write_file(
+        dir.path(),
+        "hello.bin",
+        build_elf(HELLO_WORLD, b"Hello, World!\n\0"),
+    );
+    let output_path = dir.path().join("hello.output");
+
+    let output = Command::new(EMULATOR)
+        .arg(&program)
+        .arg("--output")
+        .arg(&output_path)
+        .output()
+        .unwrap();
+
+    assert!(output.stdout.is_empty());
+    assert_eq!(
+        std::fs::read_to_string(&output_path).unwrap(),
+        "Hello, World!\n"
+    );
+}
+
+#[test]
+fn test_escape_output() {
+    let dir = tempfile::tempdir().unwrap();
+    // prints an escape sequence that clears the screen
+    let program = write_file(
+        dir.path(),
+        "clear.bin",
+        build_elf(HELLO_WORLD, b"\x1b[2Jcleared\n\0"),
+    );
+    let output_path = dir.path().join("clear.log");
+
+    Command::new(EMULATOR)
+        .arg(&program)
+        .arg("--output")
+        .arg(&output_path)
+        .arg("--escape-output")
+        .output()
+        .unwrap();
+
+    assert_eq!(
+        std::fs::read_to_string(&output_path).unwrap(),
+        "\\x1b[2Jcleared\n"
+    );
+}
+
+#[test]
+fn test_data_loaded_at_linked_address() {
+    // prints the word at 0x20000, which is where the data section is linked
+    let text = &[
+        0x0002_0537, // lui a0, 0x20
+        0x0005_2503, // lw a0, 0(a0)
+        0x0010_0893, // li a7, 1
+        0x0000_0073, // ecall
+        0x00a0_0893, // li a7, 10
+        0x0000_0073, // ecall
+    ];
+    let dir = tempfile::tempdir().unwrap();
+    let program = write_file(
+        dir.path(),
+        "data.bin",
+        build_elf_with_data_at(text, &1234u32.to_le_bytes(), 0x0002_0000),
+    );
+
+    let output = Command::new(EMULATOR).arg(&program).output().unwrap();
+
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "1234");
+}
+
+#[test]
+fn test_abi_table() {
+    let output = Command::new(EMULATOR).arg("--abi-table").output().unwrap();
+    assert!(output.status.success());
+
+    let table = String::from_utf8_lossy(&output.stdout);
+    assert!(table.contains(" x2 sp "), "{table}");
+    for i in 0..12 {
+        let row = table
+            .lines()
+            .find(|line| line.split_whitespace().nth(1) == Some(&format!("s{i}")))
+            .unwrap_or_else(|| panic!("no row for s{i}:\n{table}"));
+        assert!(row.ends_with("callee-saved"), "{row}");
+    }
+}
+
+#[test]
+fn test_registers_from_file() {
+    let text = &[
+        0x0050_0513, // li a0, 5
+        0x0010_0893, // li a7, 1
+        0x0000_0073, // ecall
+        0x00a0_0893, // li a7, 10
+        0x0000_0073, // ecall
+    ];
+    let dir = tempfile::tempdir().unwrap();
+    let program = write_file(dir.path(), "print.bin", build_elf(text, &[]));
+    // start past the `li a0, 5`, with a0 = 77
+    let mut registers = [0u32; 32];
+    registers[10] = 77;
+    let snapshot = format!(
+        r#"{{"pc": {}, "registers": {{"registers": {registers:?}}}}}"#,
+        ENTRYPOINT + 4
+    );
+    let snapshot = write_file(dir.path(), "registers.json", snapshot);
+
+    let output = Command::new(EMULATOR)
+        .arg(&program)
+        .arg("--registers-from")
+        .arg(&snapshot)
+        .output()
+        .unwrap();
+
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "77");
+}
+
+/// Stores `value` to the exit device at 0x10000000, then exits normally if it wasn't halted.
+fn write_tohost(value: u32) -> Vec<u32> {
+    vec![
+        0x1000_0337,                 // lui t1, 0x10000
+        0x0000_0293 | (value << 20), // li t0, value
+        0x0053_2023,                 // sw t0, 0(t1)
+        0x00a0_0893,                 // li a7, 10
+        0x0000_0073,                 // ecall
+    ]
+}
+
+#[test]
+fn test_tohost_pass() {
+    let dir = tempfile::tempdir().unwrap();
+    let program = write_file(dir.path(), "pass.bin", build_elf(&write_tohost(1), &[]));
+
+    let output = Command::new(EMULATOR)
+        .arg(&program)
+        .arg("--tohost")
+        .arg("0x10000000")
+        .output()
+        .unwrap();
+
+    assert!(output.status.success());
+    assert!(output.stderr.is_empty());
+}
+
+#[test]
+fn test_tohost_fail() {
+    let dir = tempfile::tempdir().unwrap();
+    let program = write_file(dir.path(), "fail.bin", build_elf(&write_tohost(3), &[]));
+
+    let output = Command::new(EMULATOR)
+        .arg(&program)
+        .arg("--tohost")
+        .arg("0x10000000")
+        .output()
+        .unwrap();
+
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("failed with code 0x3 (test 1)"));
+}
+
+#[test]
+fn test_quiet_fault_sets_exit_code() {
+    let dir = tempfile::tempdir().unwrap();
+    let program = write_file(
+        dir.path(),
+        "null.bin",
+        build_elf(&[0x0040_2503], &[]), // lw a0, 4(zero)
+    );
+
+    let output = Command::new(EMULATOR)
+        .arg(&program)
+        .arg("--quiet")
+        .output()
+        .unwrap();
+
+    assert!(output.stderr.is_empty());
+    // a null pointer dereference
+    assert_eq!(output.status.code(), Some(103));
+}
+
+#[test]
+fn test_run_then_dump() {
+    let dir = tempfile::tempdir().unwrap();
+    let program = write_file(
+        dir.path(),
+        "hello.bin",
+        build_elf(HELLO_WORLD, b"Hello, World!\n\0"),
+    );
+
+    let output = Command::new(EMULATOR)
+        .arg(&program)
+        .arg("--run-then-dump")
+        .arg("2")
+        .output()
+        .unwrap();
+
+    // stopped before the string was printed
+    assert!(output.stdout.is_empty());
+    let dump = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        dump.contains(&format!("pc: {:#010x},", ENTRYPOINT + 8)),
+        "{dump}"
+    );
+}
+
+#[test]
+fn test_listing() {
+    let dir = tempfile::tempdir().unwrap();
+    let program = write_file(
+        dir.path(),
+        "hello.bin",
+        build_elf(HELLO_WORLD, b"Hello, World!\n\0"),
+    );
+    let listing = dir.path().join("hello.lst");
+
+    let output = Command::new(EMULATOR)
+        .arg(&program)
+        .arg("--listing")
+        .arg(&listing)
+        .output()
+        .unwrap();
+
+    // the program still runs
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "Hello, World!\n");
+    let listing = std::fs::read_to_string(listing).unwrap();
+    assert!(
+        listing.contains(&format!("{ENTRYPOINT:#010x}: ")),
+        "{listing}"
+    );
+    assert!(listing.contains("ecall"), "{listing}");
+}
+
+#[test]
+fn test_diff() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut changed = HELLO_WORLD.to_vec();
+    changed[1] = 0x01c5_0513; // addi a0, a0, 28
+    let data = b"Hello, World!\n\0";
+    let original = write_file(dir.path(), "original.bin", build_elf(HELLO_WORLD, data));
+    let changed = write_file(dir.path(), "changed.bin", build_elf(&changed, data));
+
+    let output = Command::new(EMULATOR)
+        .arg(&original)
+        .arg("--diff")
+        .arg(&changed)
+        .output()
+        .unwrap();
+
+    assert!(output.status.success());
+    let diff = String::from_utf8_lossy(&output.stdout);
+    let changed_lines: Vec<_> = diff.lines().filter(|line| !line.starts_with(' ')).collect();
+    assert_eq!(
+        changed_lines,
+        [
+            format!("- {:#010x}: addi a0, a0, 24", ENTRYPOINT + 4),
+            format!("+ {:#010x}: addi a0, a0, 28", ENTRYPOINT + 4),
+        ],
+        "{diff}"
+    );
+    assert_eq!(diff.lines().count(), HELLO_WORLD.len() + 1);
+}
+
+#[test]
+fn test_assemble_then_run() {
+    let dir = tempfile::tempdir().unwrap();
+    let source = write_file(
+        dir.path(),
+        "hello.s",
+        r#"
+        .data
+        message: .asciz "Hello, world!\n"
+
+        .text
+        _start:
+            la a0, message
+            li a7, 4        # print the message
+            ecall
+            li a7, 10       # exit
+            ecall
+        "#,
+    );
+    let program = dir.path().join("hello.bin");
+
+    let assembled = Command::new(EMULATOR)
+        .arg("--assemble")
+        .arg(&source)
+        .arg("--output")
+        .arg(&program)
+        .output()
+        .unwrap();
+    assert!(
+        assembled.status.success(),
+        "{}",
+        String::from_utf8_lossy(&assembled.stderr)
+    );
+
+    let output = Command::new(EMULATOR).arg(&program).output().unwrap();
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "Hello, world!\n");
+}
+
+#[test]
+fn test_march() {
+    let dir = tempfile::tempdir().unwrap();
+    let program = write_file(dir.path(), "sum.bin", build_elf(SUM_TWO_INTS, &[]));
+
+    let output = Command::new(EMULATOR)
+        .arg(&program)
+        .args(["--march", "rv32i"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+
+    let output = Command::new(EMULATOR)
+        .arg(&program)
+        .args(["--march", "rv64gc"])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains(
+        "`rv64gc` is a 64-bit ISA, only 32-bit ISAs (starting with `rv32`) are supported"
+    ));
+}
+
+#[test]
+fn test_verify_against_march() {
+    let dir = tempfile::tempdir().unwrap();
+    let program = write_file(
+        dir.path(),
+        "mul.bin",
+        build_elf(
+            &[
+                0x02b5_0533, // mul a0, a0, a1
+                0x00a0_0893, // li a7, 10
+                0x0000_0073, // ecall
+            ],
+            &[],
+        ),
+    );
+
+    let output = Command::new(EMULATOR)
+        .arg(&program)
+        .arg("--verify")
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+
+    let output = Command::new(EMULATOR)
+        .arg(&program)
+        .args(["--verify", "--march", "rv32i"])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains(
+        "`mul a0, a0, a1` at 0x00010000 needs the M extension, which isn't part of rv32i"
+    ));
+}
+
+#[test]
+fn test_exit_code_is_the_exit_status() {
+    let dir = tempfile::tempdir().unwrap();
+    let exit = |code: u32| {
+        let program = build_elf(
+            &[
+                0x0000_0513 | code << 20, // li a0, code
+                0x05d0_0893,              // li a7, 93
+                0x0000_0073,              // ecall
+            ],
+            &[],
+        );
+        let program = write_file(dir.path(), &format!("exit{code}.bin"), program);
+        Command::new(EMULATOR).arg(&program).output().unwrap()
+    };
+
+    let output = exit(0);
+    assert!(output.status.success());
+    assert!(output.stderr.is_empty());
+
+    let output = exit(3);
+    assert_eq!(output.status.code(), Some(3));
+    assert!(output.stderr.is_empty());
+
+    // a program exiting with the status of a fault looks the same as the fault with --quiet
+    let output = exit(103);
+    assert_eq!(output.status.code(), Some(103));
+    assert!(output.stderr.is_empty());
+}
+
+#[test]
+fn test_record_events() {
+    use riscv_emulator::emulator::cpu::event_log::{read_events, TraceEvent};
+
+    let dir = tempfile::tempdir().unwrap();
+    let program = write_file(dir.path(), "sum.bin", build_elf(SUM_TWO_INTS, &[]));
+    let input = write_file(dir.path(), "sum.input", "40\n2\n");
+    let record = dir.path().join("sum.events");
+
+    let output = Command::new(EMULATOR)
+        .arg(&program)
+        .arg("--input")
+        .arg(&input)
+        .arg("--record")
+        .arg(&record)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+
+    let events = read_events(std::io::BufReader::new(
+        std::fs::File::open(&record).unwrap(),
+    ))
+    .unwrap();
+    let syscalls: Vec<_> = events
+        .iter()
+        .filter_map(|event| match event {
+            TraceEvent::Syscall {
+                name,
+                input,
+                output,
+                ..
+            } => Some((name.as_str(), input.as_str(), output.as_str())),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(
+        syscalls,
+        [
+            ("ReadInt", "40\n", ""),
+            ("ReadInt", "2\n", ""),
+            ("PrintInt", "", "42"),
+            ("Exit", "", ""),
+        ]
+    );
+    assert_eq!(
+        events
+            .iter()
+            .filter(|event| matches!(event, TraceEvent::Retired { .. }))
+            .count(),
+        SUM_TWO_INTS.len() - 1
+    );
+    assert_eq!(events.last(), Some(&TraceEvent::Exit { code: 0 }));
+}
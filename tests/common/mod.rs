@@ -0,0 +1,61 @@
+//! Helpers shared by the integration tests
+#![allow(dead_code)]
+
+use std::path::{Path, PathBuf};
+
+use riscv_emulator::{
+    instruction_set_definition::assembly::program::Program, utils::words_to_bytes,
+};
+
+/// The address the text section of test programs is linked at.
+pub const ENTRYPOINT: u32 = 0x0001_0000;
+
+/// Prints the null-terminated string at the start of the data section, then exits.
+///
+/// The address of the string (`0x11018`) assumes the program is linked at `ENTRYPOINT`.
+pub const HELLO_WORLD: &[u32] = &[
+    0x0001_1537, // lui a0, 0x11
+    0x0185_0513, // addi a0, a0, 24
+    0x0040_0893, // li a7, 4
+    0x0000_0073, // ecall
+    0x00a0_0893, // li a7, 10
+    0x0000_0073, // ecall
+];
+
+/// The address the data section of test programs is linked at, right after the text section and the gap the emulator expects.
+#[must_use]
+#[allow(clippy::cast_possible_truncation)]
+pub const fn data_address(text: &[u32]) -> u32 {
+    ENTRYPOINT + (text.len() * 4) as u32 + 0x1000
+}
+
+/// Build a minimal, statically linked, RV32 ELF executable with the given `.text` and `.data` sections.
+///
+/// The text is linked at `ENTRYPOINT`, and the data at `data_address(text)`.
+#[must_use]
+pub fn build_elf(text: &[u32], data: &[u8]) -> Vec<u8> {
+    build_elf_with_data_at(text, data, data_address(text))
+}
+
+/// Like `build_elf`, but with the data linked at `data_addr`.
+#[must_use]
+pub fn build_elf_with_data_at(text: &[u32], data: &[u8], data_addr: u32) -> Vec<u8> {
+    Program {
+        text: words_to_bytes(text),
+        data: data.to_vec(),
+        entrypoint: ENTRYPOINT,
+        data_address: data_addr,
+    }
+    .to_elf()
+}
+
+/// Write the given contents to a uniquely named file in `dir`.
+///
+/// # Panics
+///
+/// Panics if the file cannot be written.
+pub fn write_file(dir: &Path, name: &str, contents: impl AsRef<[u8]>) -> PathBuf {
+    let path = dir.join(name);
+    std::fs::write(&path, contents).expect("failed to write test file");
+    path
+}
@@ -0,0 +1,119 @@
+//! Conformance tests running the riscv-tests ISA suite (<https://github.com/riscv-software-src/riscv-tests>)
+//!
+//! The `rv32ui-p-*` and `rv32um-p-*` tests are vendored in `test_binaries/riscv-tests/`, built by its `build.py`
+//! from the sources in `isa/` with the environment in `env/`. Set `RISCV_TESTS_DIR` to run a different build of the
+//! suite instead, such as upstream's ELFs (not the `.dump` files).
+//! Every test runs bare-metal, reports its result through the `tohost` exit device, and must pass.
+use std::{
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    thread::sleep,
+    time::{Duration, Instant},
+};
+
+const EMULATOR: &str = env!("CARGO_BIN_EXE_riscv-emulator");
+
+/// the suites run, the base integer instructions and the M extension
+const SUITES: &[&str] = &["rv32ui-p-", "rv32um-p-"];
+
+/// the upstream tests the emulator can't run, and why
+const UNSUPPORTED: &[(&str, &str)] = &[("rv32ui-p-fence_i", "self-modifying code isn't supported")];
+
+/// how long a single test may run before it's considered hung
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+fn tests_dir() -> PathBuf {
+    std::env::var_os("RISCV_TESTS_DIR").map_or_else(
+        || Path::new(env!("CARGO_MANIFEST_DIR")).join("test_binaries/riscv-tests"),
+        PathBuf::from,
+    )
+}
+
+/// Run the test program, returning the reason it failed if it did.
+fn run(test: &Path) -> Option<String> {
+    let mut child = Command::new(EMULATOR)
+        .arg(test)
+        .arg("--tohost")
+        .arg("--bare-metal")
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    let start = Instant::now();
+    while child.try_wait().unwrap().is_none() {
+        if start.elapsed() > TIMEOUT {
+            child.kill().unwrap();
+            child.wait().unwrap();
+            return Some(format!("timed out after {TIMEOUT:?}"));
+        }
+        sleep(Duration::from_millis(10));
+    }
+
+    // faults are reported on stderr, even when they don't set the exit status
+    let output = child.wait_with_output().unwrap();
+    (!output.status.success() || !output.stderr.is_empty()).then(|| {
+        format!(
+            "{}, {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr)
+        )
+    })
+}
+
+#[test]
+fn test_riscv_tests_pass() {
+    let dir = tests_dir();
+    let entries = std::fs::read_dir(&dir)
+        .unwrap_or_else(|error| panic!("riscv-tests not found in {dir:?}: {error}"));
+    let mut tests: Vec<_> = entries
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_none())
+        .filter(|path| {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| {
+                    SUITES.iter().any(|suite| name.starts_with(suite))
+                        && !UNSUPPORTED.iter().any(|(test, _)| name == *test)
+                })
+        })
+        .collect();
+    tests.sort();
+    assert!(!tests.is_empty(), "no riscv-tests found in {dir:?}");
+
+    let failures: Vec<_> = tests
+        .iter()
+        .filter_map(|test| run(test).map(|reason| format!("{}: {reason}", test.display())))
+        .collect();
+
+    assert!(
+        failures.is_empty(),
+        "{} of {} riscv-tests failed:\n{}",
+        failures.len(),
+        tests.len(),
+        failures.join("\n")
+    );
+}
+
+#[test]
+fn test_vendored_riscv_tests_are_built() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("test_binaries/riscv-tests");
+    let mut sources = 0;
+    for suite in std::fs::read_dir(dir.join("isa")).unwrap() {
+        let suite = suite.unwrap().path();
+        for source in std::fs::read_dir(&suite).unwrap() {
+            let source = source.unwrap().path();
+            let test = format!(
+                "{}-p-{}",
+                suite.file_name().unwrap().to_string_lossy(),
+                source.file_stem().unwrap().to_string_lossy()
+            );
+            assert!(
+                dir.join(&test).is_file(),
+                "{test} isn't built, run build.py"
+            );
+            sources += 1;
+        }
+    }
+    assert!(sources > 0, "no riscv-tests sources found in {dir:?}");
+}